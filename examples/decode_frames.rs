@@ -0,0 +1,82 @@
+//! Rebuild captures from a dump of the board's frame output and print them
+//! as CSV, one block per capture.
+//!
+//! With the console in `output frames`, save what the port sends, then
+//! decode the dump (or pipe the port straight in on stdin):
+//!
+//! ```text
+//! cat /dev/ttyACM0 > dump.bin
+//! cargo run --example decode_frames --features host -- dump.bin
+//! ```
+
+use std::io::{self, Read};
+use std::{env, fs, process};
+
+use lab_3::host::{Assembler, Capture, FrameReader};
+
+fn main() {
+    let bytes = match env::args().nth(1) {
+        Some(path) => fs::read(&path).unwrap_or_else(|e| {
+            eprintln!("can't read {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes).expect("reading stdin");
+            bytes
+        }
+    };
+
+    let mut reader = FrameReader::new();
+    let mut assembler = Assembler::new();
+    let mut rejected = 0;
+
+    for result in reader.push(&bytes) {
+        match result {
+            Ok(frame) => {
+                if let Some(capture) = assembler.push(frame) {
+                    print_capture(&capture);
+                }
+            }
+            // Console replies between frames land here too, so just count
+            Err(_) => rejected += 1,
+        }
+    }
+    eprintln!("{} segments weren't frames", rejected);
+}
+
+fn print_capture(capture: &Capture) {
+    let header = &capture.header;
+    println!(
+        "# capture {} at {} us, {} S/s, {} bit, {} V full scale{}",
+        header.sequence,
+        header.timestamp_us,
+        header.sample_rate_hz,
+        header.resolution_bits,
+        header.full_scale_volts,
+        if header.trusted { "" } else { ", OVERRAN" }
+    );
+
+    if !capture.magnitudes.is_empty() {
+        // The spectrum is half a transform, so the FFT was twice as long
+        let bin_hz = header.sample_rate_hz / (2 * capture.magnitudes.len()) as f32;
+        println!("bin,hz,magnitude");
+        for (bin, magnitude) in capture.magnitudes.iter().enumerate() {
+            println!("{},{},{}", bin, bin as f32 * bin_hz, magnitude);
+        }
+    }
+    if !capture.samples.is_empty() {
+        let volts_per_count =
+            header.full_scale_volts / ((1u32 << header.resolution_bits) - 1) as f32;
+        println!("sample,counts,volts");
+        for (i, &counts) in capture.samples.iter().enumerate() {
+            println!("{},{},{}", i, counts, counts as f32 * volts_per_count);
+        }
+    }
+    if !capture.bands.is_empty() {
+        println!("band,log_energy");
+        for (band, energy) in capture.bands.iter().enumerate() {
+            println!("{},{}", band, energy);
+        }
+    }
+}
@@ -5,7 +5,10 @@ MEMORY
   /* STM32H742xI/743xI/753xI       */
   /* STM32H745xI/747xI/755xI/757xI */
   /* STM32H7A3xI/7B3xI             */
-  FLASH  : ORIGIN = 0x08000000, LENGTH = 2M
+  /* Bank 1 only: bank 2's last two sectors hold the saved settings, and
+     nothing may run from a bank while it's erased, see
+     src/utilities/internal_flash.rs */
+  FLASH  : ORIGIN = 0x08000000, LENGTH = 1M
 
   /* STM32H742xG/743xG       */
   /* STM32H745xG/STM32H747xG */
@@ -29,7 +32,8 @@ MEMORY
   SRAM3 : ORIGIN = 0x30040000, LENGTH = 32K
   SRAM4 : ORIGIN = 0x38000000, LENGTH = 64K
 
-  /* Backup SRAM */
+  /* Backup SRAM. Nothing is linked here: src/utilities/backup.rs keeps
+     its records at fixed offsets, so they survive a reset unmoved */
   BSRAM : ORIGIN = 0x38800000, LENGTH = 4K
 
   /* Instruction TCM */
@@ -0,0 +1,798 @@
+//! Acquisition settings, and the timing they imply.
+
+use log::{info, warn};
+use micromath::F32Ext;
+use num_complex::Complex;
+
+/// ADC sampling time in ADC clock cycles. Longer sampling suits higher
+/// source impedances at the cost of conversion rate.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleTime {
+    T_1_5,
+    T_2_5,
+    T_8_5,
+    T_16_5,
+    T_32_5,
+    T_64_5,
+    T_387_5,
+    T_810_5,
+}
+
+impl SampleTime {
+    /// Every sampling time, shortest first
+    pub const ALL: [SampleTime; 8] = [
+        SampleTime::T_1_5,
+        SampleTime::T_2_5,
+        SampleTime::T_8_5,
+        SampleTime::T_16_5,
+        SampleTime::T_32_5,
+        SampleTime::T_64_5,
+        SampleTime::T_387_5,
+        SampleTime::T_810_5,
+    ];
+
+    /// Length in half ADC clock cycles, which keeps it an integer
+    pub const fn half_cycles(self) -> u32 {
+        match self {
+            SampleTime::T_1_5 => 3,
+            SampleTime::T_2_5 => 5,
+            SampleTime::T_8_5 => 17,
+            SampleTime::T_16_5 => 33,
+            SampleTime::T_32_5 => 65,
+            SampleTime::T_64_5 => 129,
+            SampleTime::T_387_5 => 775,
+            SampleTime::T_810_5 => 1621,
+        }
+    }
+}
+
+/// Settings for one channel in the conversion sequence
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelConfig {
+    pub sample_time: SampleTime,
+}
+
+impl ChannelConfig {
+    pub const fn new(sample_time: SampleTime) -> Self {
+        Self { sample_time }
+    }
+}
+
+/// Fastest rate the ADC can convert one channel at, in samples per second.
+///
+/// Each conversion is the sampling time plus the successive approximation,
+/// which takes `bits / 2 + 0.5` cycles (8.5 at 16 bit). Oversampling by
+/// `oversampling` then takes that many conversions per output sample.
+pub fn max_conversion_rate_hz(
+    adc_clock_hz: u32,
+    sample_time: SampleTime,
+    resolution_bits: u8,
+    oversampling: u16,
+) -> f32 {
+    let conversion_half_cycles = resolution_bits as u32 + 1;
+    let total_half_cycles = sample_time.half_cycles() + conversion_half_cycles;
+
+    2.0 * adc_clock_hz as f32 / total_half_cycles as f32 / oversampling.max(1) as f32
+}
+
+/// How far a measured rate can be from the configured one before it's
+/// worth a warning, as a fraction
+pub const RATE_TOLERANCE: f32 = 0.01;
+
+/// Rate that `samples` conversions taking `elapsed_us` works out to, or
+/// `None` if no time passed to measure
+pub fn measured_rate_hz(samples: usize, elapsed_us: u64) -> Option<f32> {
+    (elapsed_us > 0).then(|| samples as f32 * 1e6 / elapsed_us as f32)
+}
+
+/// Running mean and spread of measured sample rates, so the rate used for
+/// bin frequencies isn't at the mercy of one capture's timestamps
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateStats {
+    count: u32,
+    mean: f32,
+    /// Sum of squared differences from the mean, as in Welford's method
+    m2: f32,
+}
+
+impl RateStats {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, rate_hz: f32) {
+        self.count += 1;
+        let delta = rate_hz - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (rate_hz - self.mean);
+    }
+
+    pub const fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean_hz(&self) -> Option<f32> {
+        (self.count > 0).then_some(self.mean)
+    }
+
+    /// Standard deviation of the measurements, 0 until there are two
+    pub fn jitter_hz(&self) -> f32 {
+        if self.count < 2 {
+            0.0
+        } else {
+            (self.m2 / (self.count - 1) as f32).sqrt()
+        }
+    }
+}
+
+/// Whether `measured_hz` is further from `configured_hz` than `RATE_TOLERANCE`
+pub fn rate_mismatch(measured_hz: f32, configured_hz: f32) -> bool {
+    (measured_hz - configured_hz).abs() > configured_hz * RATE_TOLERANCE
+}
+
+/// How a run of back-to-back captures went, for a soak test: how many
+/// failed, and how far apart the times the rest took spread. A capture
+/// path that's really starting afresh each time takes the same time for
+/// each, to within the timestamps' jitter, however many it's taken.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SoakStats {
+    captures: u32,
+    errors: u32,
+    /// Quickest and slowest of those that didn't fail
+    range_us: Option<(u64, u64)>,
+}
+
+impl SoakStats {
+    pub const fn new() -> Self {
+        Self {
+            captures: 0,
+            errors: 0,
+            range_us: None,
+        }
+    }
+
+    /// Count a capture, which took `elapsed_us`, or `None` if it failed
+    pub fn push(&mut self, elapsed_us: Option<u64>) {
+        self.captures += 1;
+        match (elapsed_us, self.range_us) {
+            (None, _) => self.errors += 1,
+            (Some(us), None) => self.range_us = Some((us, us)),
+            (Some(us), Some((min, max))) => self.range_us = Some((min.min(us), max.max(us))),
+        }
+    }
+
+    pub const fn captures(&self) -> u32 {
+        self.captures
+    }
+
+    pub const fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// The slowest capture's time less the quickest's
+    pub fn spread_us(&self) -> u64 {
+        self.range_us.map_or(0, |(min, max)| max - min)
+    }
+
+    /// The spread, as a fraction of the quickest capture's time
+    pub fn drift(&self) -> f32 {
+        match self.range_us {
+            Some((min, _)) if min > 0 => self.spread_us() as f32 / min as f32,
+            _ => 0.0,
+        }
+    }
+
+    /// Whether there were captures, none failed, and their times stayed
+    /// within `RATE_TOLERANCE` of each other, as the rate they measure would
+    pub fn passed(&self) -> bool {
+        self.captures > 0 && self.errors == 0 && self.drift() <= RATE_TOLERANCE
+    }
+}
+
+/// Combine an in-phase and a quadrature capture into one complex signal,
+/// `I + jQ`, in counts, for a complex FFT. Panics unless all three slices
+/// are the same length.
+///
+/// This only works if both channels sampled each point at the same instant.
+/// A skew of `t` between them rotates the quadrature channel's phase by
+/// `2π f t`, which leaks every tone into its mirror frequency, so capture with
+/// ADC1 and ADC2 in dual simultaneous mode, with the same sampling time and
+/// matched input filtering, rather than two independently started ADCs.
+/// Each channel still has its own DC offset, which lands in bin 0 unless
+/// it's subtracted first.
+pub fn acquire_iq(i_buf: &[u16], q_buf: &[u16], out: &mut [Complex<f32>]) {
+    assert_eq!(i_buf.len(), q_buf.len());
+    assert_eq!(i_buf.len(), out.len());
+
+    for ((out, &i), &q) in out.iter_mut().zip(i_buf).zip(q_buf) {
+        *out = Complex::new(i as f32, q as f32);
+    }
+}
+
+/// Why a source couldn't fill a buffer with a clean capture
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcqError {
+    /// The transfer failed outright, so nothing in the buffer is any good
+    Transfer,
+    /// The buffer was filled, but samples were dropped along the way
+    Overrun,
+    /// Only the first `received` samples arrived in time
+    Timeout { received: usize },
+}
+
+/// Somewhere raw codes come from, a buffer at a time: the ADC and its DMA
+/// on the board, or canned data in a test
+pub trait SampleSource {
+    /// Fill `buf` with the next capture
+    fn fill(&mut self, buf: &mut [u16]) -> Result<(), AcqError>;
+}
+
+/// How the ADC and its DMA run from one capture to the next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcquisitionMode {
+    /// Each capture starts the ADC and DMA afresh, and they stop once the
+    /// buffer is full, so there are gaps between captures
+    OneShot,
+    /// The ADC never stops, and circular DMA fills two buffers in turn,
+    /// each handed over as it completes. Captures follow on without gaps,
+    /// as long as each is processed in the time it takes to fill the next.
+    Continuous,
+}
+
+/// Which of the DMA controller's requests is served first when several
+/// streams want the bus at once
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaPriority {
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+/// How full a DMA stream's FIFO gets before it's drained to memory. Only
+/// used with the FIFO enabled; in direct mode every transfer is single.
+///
+/// Bursts have to go evenly into the threshold (RM0433's "FIFO threshold
+/// configurations" table), or the stream flags a FIFO error when it's
+/// enabled and doesn't run. With the board's 4-beat bursts, reading 16-bit
+/// samples makes 8-byte bursts, which only fit `Half` and `Full`; packed
+/// 8-bit samples make 4-byte bursts, which fit any of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FifoThreshold {
+    Quarter,
+    Half,
+    ThreeQuarters,
+    Full,
+}
+
+impl FifoThreshold {
+    /// Every DMA1/DMA2 stream's FIFO is four words
+    pub const FIFO_BYTES: usize = 16;
+
+    pub const fn bytes(self) -> usize {
+        match self {
+            FifoThreshold::Quarter => Self::FIFO_BYTES / 4,
+            FifoThreshold::Half => Self::FIFO_BYTES / 2,
+            FifoThreshold::ThreeQuarters => Self::FIFO_BYTES * 3 / 4,
+            FifoThreshold::Full => Self::FIFO_BYTES,
+        }
+    }
+
+    /// Whether bursts of `beats` transfers, `beat_bytes` each, go evenly
+    /// into this threshold
+    pub const fn fits_burst(self, beats: usize, beat_bytes: usize) -> bool {
+        let burst = beats * beat_bytes;
+        burst > 0 && self.bytes().is_multiple_of(burst)
+    }
+}
+
+/// What starts each regular conversion of ADC1 (or ADC2) instead of the
+/// ADC free-running, by its EXTSEL number, RM0433 table 211. ADC3 has a
+/// list of its own, so these don't apply to it.
+///
+/// Any of them can be taken on either edge, but the TRGOs are pulses a
+/// timer clock long, so `TriggerEdge::Both` would convert twice, back to
+/// back, per event; see `supports`. The compare outputs, EXTI11 and the
+/// LPTIM outputs are levels with an edge each way, so converting on both
+/// gives a conversion per edge, e.g. twice a PWM period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExternalTrigger {
+    Tim1Oc1 = 0,
+    Tim1Oc2 = 1,
+    Tim1Oc3 = 2,
+    Tim2Oc2 = 3,
+    Tim3Trgo = 4,
+    Tim4Oc4 = 5,
+    /// PA11, PB11, ... whichever port EXTI line 11 is routed to
+    Exti11 = 6,
+    Tim8Trgo = 7,
+    Tim8Trgo2 = 8,
+    Tim1Trgo = 9,
+    Tim1Trgo2 = 10,
+    Tim2Trgo = 11,
+    Tim4Trgo = 12,
+    Tim6Trgo = 13,
+    Tim15Trgo = 14,
+    Tim3Oc4 = 15,
+    HrtimAdcTrg1 = 16,
+    HrtimAdcTrg3 = 17,
+    Lptim1Out = 18,
+    Lptim2Out = 19,
+    Lptim3Out = 20,
+}
+
+impl ExternalTrigger {
+    /// CFGR.EXTSEL for this source
+    pub const fn extsel(self) -> u32 {
+        self as u32
+    }
+
+    /// Whether this is a timer's TRGO, a pulse rather than a level
+    pub const fn is_pulse(self) -> bool {
+        matches!(
+            self,
+            ExternalTrigger::Tim1Trgo
+                | ExternalTrigger::Tim1Trgo2
+                | ExternalTrigger::Tim2Trgo
+                | ExternalTrigger::Tim3Trgo
+                | ExternalTrigger::Tim4Trgo
+                | ExternalTrigger::Tim6Trgo
+                | ExternalTrigger::Tim8Trgo
+                | ExternalTrigger::Tim8Trgo2
+                | ExternalTrigger::Tim15Trgo
+        )
+    }
+
+    /// Whether converting on `edge` of this source gives a conversion per
+    /// event: every edge does but both edges of a pulse
+    pub const fn supports(self, edge: TriggerEdge) -> bool {
+        !(self.is_pulse() && matches!(edge, TriggerEdge::Both))
+    }
+}
+
+/// Which edges of an `ExternalTrigger` start a conversion
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+impl TriggerEdge {
+    /// CFGR.EXTEN for this edge. 0 is left for software triggering.
+    pub const fn exten(self) -> u32 {
+        match self {
+            TriggerEdge::Rising => 0b01,
+            TriggerEdge::Falling => 0b10,
+            TriggerEdge::Both => 0b11,
+        }
+    }
+}
+
+/// Shortest wait between enabling the ADC and converting with it, in us.
+///
+/// RM0433 §25.4.6 has software wait out the ADC regulator's start-up,
+/// tADCVREG_STUP, 10 us at most in the datasheet (DS12110), before
+/// enabling. The HAL does that once when it powers the ADC up, but the
+/// first conversions after any enable can still be off while the reference
+/// and the sampling capacitor settle, so the same wait is kept after every
+/// enable and can only be made longer.
+pub const MIN_SETTLING_US: u32 = 10;
+
+/// Everything that decides how samples are taken
+#[derive(Clone, Copy, Debug)]
+pub struct AcquisitionConfig {
+    pub adc_clock_hz: u32,
+    pub resolution_bits: u8,
+    pub channel: ChannelConfig,
+    /// Oversampling ratio, 1 for none
+    pub oversampling: u16,
+    /// Conversion trigger rate, or `None` for free-running conversions
+    pub trigger_rate_hz: Option<f32>,
+    /// What triggers each conversion, at `trigger_rate_hz`, or `None` to
+    /// leave it to software
+    pub external_trigger: Option<(ExternalTrigger, TriggerEdge)>,
+    pub mode: AcquisitionMode,
+    pub dma_priority: DmaPriority,
+    pub fifo_threshold: FifoThreshold,
+    /// How long to wait after enabling the ADC, never under
+    /// `MIN_SETTLING_US`
+    pub settling_us: u32,
+    /// Conversions thrown away ahead of each capture
+    pub discard: usize,
+}
+
+impl AcquisitionConfig {
+    pub const fn new(adc_clock_hz: u32, resolution_bits: u8) -> Self {
+        Self {
+            adc_clock_hz,
+            resolution_bits,
+            channel: ChannelConfig::new(SampleTime::T_32_5),
+            oversampling: 1,
+            trigger_rate_hz: None,
+            external_trigger: None,
+            mode: AcquisitionMode::OneShot,
+            // As the HAL's `DmaConfig` defaults
+            dma_priority: DmaPriority::Medium,
+            fifo_threshold: FifoThreshold::Quarter,
+            settling_us: MIN_SETTLING_US,
+            discard: 0,
+        }
+    }
+
+    pub fn channel(mut self, channel: ChannelConfig) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn oversampling(mut self, ratio: u16) -> Self {
+        self.oversampling = ratio;
+        self
+    }
+
+    pub fn trigger_rate_hz(mut self, rate: f32) -> Self {
+        self.trigger_rate_hz = Some(rate);
+        self
+    }
+
+    /// Convert on `edge` of `source` rather than free-running. Set
+    /// `trigger_rate_hz` to the rate it runs at as well, as nothing here
+    /// knows it.
+    pub fn external_trigger(mut self, source: ExternalTrigger, edge: TriggerEdge) -> Self {
+        self.external_trigger = Some((source, edge));
+        self
+    }
+
+    pub fn mode(mut self, mode: AcquisitionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn dma_priority(mut self, priority: DmaPriority) -> Self {
+        self.dma_priority = priority;
+        self
+    }
+
+    /// See `FifoThreshold` for which go with which bursts
+    pub fn fifo_threshold(mut self, threshold: FifoThreshold) -> Self {
+        self.fifo_threshold = threshold;
+        self
+    }
+
+    /// Wait `us` after enabling the ADC, or `MIN_SETTLING_US` if that's
+    /// longer
+    pub fn settling_us(mut self, us: u32) -> Self {
+        self.settling_us = us.max(MIN_SETTLING_US);
+        self
+    }
+
+    /// Convert `n` samples and throw them away before each capture, so
+    /// none of the capture is taken while the ADC is still settling from
+    /// being enabled or idle. A continuous acquisition only discards when
+    /// it starts.
+    pub fn discard_samples(mut self, n: usize) -> Self {
+        self.discard = n;
+        self
+    }
+
+    pub fn max_conversion_rate_hz(&self) -> f32 {
+        max_conversion_rate_hz(
+            self.adc_clock_hz,
+            self.channel.sample_time,
+            self.resolution_bits,
+            self.oversampling,
+        )
+    }
+
+    /// Slowest and fastest free-running rates the sampling times give
+    pub fn rate_range_hz(&self) -> (f32, f32) {
+        let rate = |sample_time| {
+            self.channel(ChannelConfig::new(sample_time))
+                .max_conversion_rate_hz()
+        };
+        (rate(SampleTime::T_810_5), rate(SampleTime::T_1_5))
+    }
+
+    /// Switch to the sampling time whose free-running rate is closest to
+    /// `rate_hz`. Check `max_conversion_rate_hz` for what that came to.
+    pub fn nearest_rate(self, rate_hz: f32) -> Self {
+        let error = |sample_time: &SampleTime| {
+            let rate = self
+                .channel(ChannelConfig::new(*sample_time))
+                .max_conversion_rate_hz();
+            (rate - rate_hz).abs()
+        };
+        let best = SampleTime::ALL
+            .iter()
+            .min_by(|a, b| error(a).total_cmp(&error(b)))
+            .copied()
+            .unwrap_or(self.channel.sample_time);
+        self.channel(ChannelConfig::new(best))
+    }
+
+    /// The rate samples are actually taken at: the trigger's if there is
+    /// one, otherwise `measured_hz` if we have it, otherwise the fastest the
+    /// ADC converts at
+    pub fn effective_rate_hz(&self, measured_hz: Option<f32>) -> f32 {
+        self.trigger_rate_hz
+            .or(measured_hz)
+            .unwrap_or_else(|| self.max_conversion_rate_hz())
+    }
+
+    /// Log the achievable rate, and warn if the trigger asks for more
+    pub fn validate(&self) -> bool {
+        let max_rate = self.max_conversion_rate_hz();
+        info!(
+            "ADC: {} Hz clock, {:?} sampling, max {} S/s",
+            self.adc_clock_hz, self.channel.sample_time, max_rate
+        );
+
+        if let Some((source, edge)) = self.external_trigger {
+            if !source.supports(edge) {
+                warn!(
+                    "{:?} is a pulse, so {:?} edges would convert twice each time",
+                    source, edge
+                );
+                return false;
+            }
+            if self.trigger_rate_hz.is_none() {
+                warn!("{:?} triggers conversions with no rate set for it", source);
+            }
+        }
+
+        match self.trigger_rate_hz {
+            Some(rate) if rate > max_rate => {
+                warn!(
+                    "Trigger at {} Hz is faster than conversions can complete ({} S/s)",
+                    rate, max_rate
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() <= 1e-3 * b
+    }
+
+    #[test]
+    fn fastest_sixteen_bit_conversion() {
+        // 1.5 cycles sampling + 8.5 converting = 10 cycles
+        let rate = max_conversion_rate_hz(36_000_000, SampleTime::T_1_5, 16, 1);
+        assert!(close(rate, 3_600_000.0), "{rate}");
+    }
+
+    #[test]
+    fn resolution_shortens_the_conversion() {
+        // 32.5 + 6.5 = 39 cycles at 12 bit, 32.5 + 4.5 = 37 at 8 bit
+        let rate = max_conversion_rate_hz(1_000_000, SampleTime::T_32_5, 12, 1);
+        assert!(close(rate, 1_000_000.0 / 39.0), "{rate}");
+        let rate = max_conversion_rate_hz(1_000_000, SampleTime::T_32_5, 8, 1);
+        assert!(close(rate, 1_000_000.0 / 37.0), "{rate}");
+    }
+
+    #[test]
+    fn oversampling_divides_the_rate() {
+        let single = max_conversion_rate_hz(6_114_000, SampleTime::T_32_5, 16, 1);
+        let four = max_conversion_rate_hz(6_114_000, SampleTime::T_32_5, 16, 4);
+        assert!(close(four, single / 4.0), "{four}");
+
+        // A ratio of 0 means no oversampling rather than dividing by zero
+        let none = max_conversion_rate_hz(6_114_000, SampleTime::T_32_5, 16, 0);
+        assert_eq!(none, single);
+    }
+
+    #[test]
+    fn config_uses_its_own_settings() {
+        let acq = AcquisitionConfig::new(6_114_000, 16)
+            .channel(ChannelConfig::new(SampleTime::T_810_5))
+            .oversampling(2);
+        let expected = max_conversion_rate_hz(6_114_000, SampleTime::T_810_5, 16, 2);
+        assert_eq!(acq.max_conversion_rate_hz(), expected);
+    }
+
+    #[test]
+    fn one_shot_unless_asked() {
+        let acq = AcquisitionConfig::new(6_114_000, 16);
+        assert_eq!(acq.mode, AcquisitionMode::OneShot);
+        // Kept through the builders that rebuild the channel
+        let acq = acq
+            .mode(AcquisitionMode::Continuous)
+            .nearest_rate(100_000.0);
+        assert_eq!(acq.mode, AcquisitionMode::Continuous);
+    }
+
+    #[test]
+    fn settling_honours_the_minimum() {
+        let acq = AcquisitionConfig::new(6_114_000, 16);
+        assert_eq!((acq.settling_us, acq.discard), (MIN_SETTLING_US, 0));
+
+        let acq = acq.settling_us(1).discard_samples(8);
+        assert_eq!((acq.settling_us, acq.discard), (MIN_SETTLING_US, 8));
+        // Kept through the builders that rebuild the channel
+        let acq = acq.settling_us(250).nearest_rate(100_000.0);
+        assert_eq!((acq.settling_us, acq.discard), (250, 8));
+    }
+
+    #[test]
+    fn rate_from_timestamps() {
+        assert_eq!(measured_rate_hz(1024, 0), None);
+        let rate = measured_rate_hz(1024, 6_800).unwrap();
+        assert!(close(rate, 150_588.2), "{rate}");
+    }
+
+    #[test]
+    fn rate_stats_mean_and_jitter() {
+        let mut stats = RateStats::new();
+        assert_eq!(stats.mean_hz(), None);
+        assert_eq!(stats.jitter_hz(), 0.0);
+
+        stats.push(100_000.0);
+        assert_eq!(stats.mean_hz(), Some(100_000.0));
+        assert_eq!(stats.jitter_hz(), 0.0);
+
+        for rate in [99_000.0, 101_000.0, 100_000.0] {
+            stats.push(rate);
+        }
+        assert_eq!(stats.count(), 4);
+        assert!(close(stats.mean_hz().unwrap(), 100_000.0));
+        // Sample standard deviation of 100k, 99k, 101k, 100k
+        assert!(close(stats.jitter_hz(), 816.5), "{}", stats.jitter_hz());
+    }
+
+    #[test]
+    fn a_soak_passes_with_steady_timing_and_no_errors() {
+        let mut soak = SoakStats::new();
+        assert!(!soak.passed(), "nothing was captured");
+        for i in 0..10_000 {
+            soak.push(Some(10_240 + i % 3));
+        }
+        assert_eq!(soak.captures(), 10_000);
+        assert_eq!(soak.spread_us(), 2);
+        assert!(soak.passed(), "{soak:?}");
+
+        // A single failure fails the lot
+        let mut failed = soak;
+        failed.push(None);
+        assert_eq!(failed.errors(), 1);
+        assert!(!failed.passed());
+
+        // As does creeping more than 1% slower
+        let mut drifted = soak;
+        drifted.push(Some(10_400));
+        assert!(close(drifted.drift(), 160.0 / 10_240.0));
+        assert!(!drifted.passed());
+    }
+
+    #[test]
+    fn mismatch_is_beyond_one_percent() {
+        assert!(!rate_mismatch(100_900.0, 100_000.0));
+        assert!(!rate_mismatch(99_100.0, 100_000.0));
+        assert!(rate_mismatch(101_100.0, 100_000.0));
+        assert!(rate_mismatch(98_900.0, 100_000.0));
+    }
+
+    #[test]
+    fn effective_rate_prefers_the_trigger_then_the_measurement() {
+        let acq = AcquisitionConfig::new(1_000_000, 16);
+        let max = acq.max_conversion_rate_hz();
+
+        assert_eq!(acq.effective_rate_hz(None), max);
+        assert_eq!(acq.effective_rate_hz(Some(12_345.0)), 12_345.0);
+        let triggered = acq.trigger_rate_hz(10_000.0);
+        assert_eq!(triggered.effective_rate_hz(Some(12_345.0)), 10_000.0);
+    }
+
+    #[test]
+    fn nearest_rate_picks_the_closest_sampling_time() {
+        let acq = AcquisitionConfig::new(6_114_000, 16);
+        let (min, max) = acq.rate_range_hz();
+        assert!(close(min, 6_114_000.0 / 819.0), "{min}");
+        assert!(close(max, 6_114_000.0 / 10.0), "{max}");
+
+        // 41 cycles (T_32_5) gives 149 kS/s and 73 (T_64_5) 83.8 kS/s
+        let acq = acq.nearest_rate(130_000.0);
+        assert_eq!(acq.channel.sample_time, SampleTime::T_32_5);
+        let acq = acq.nearest_rate(100_000.0);
+        assert_eq!(acq.channel.sample_time, SampleTime::T_64_5);
+        assert_eq!(
+            acq.nearest_rate(1.0).channel.sample_time,
+            SampleTime::T_810_5
+        );
+        assert_eq!(acq.nearest_rate(1e9).channel.sample_time, SampleTime::T_1_5);
+    }
+
+    #[test]
+    fn iq_pairs_up_the_channels() {
+        let mut out = [Complex::new(0.0, 0.0); 3];
+        acquire_iq(&[1, 2, 3], &[40_000, 0, 65_535], &mut out);
+        assert_eq!(
+            out,
+            [
+                Complex::new(1.0, 40_000.0),
+                Complex::new(2.0, 0.0),
+                Complex::new(3.0, 65_535.0)
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn iq_lengths_must_match() {
+        acquire_iq(&[1, 2], &[1], &mut [Complex::new(0.0, 0.0); 2]);
+    }
+
+    #[test]
+    fn trigger_faster_than_conversions_fails_validation() {
+        let acq = AcquisitionConfig::new(1_000_000, 16);
+        let max = acq.max_conversion_rate_hz();
+
+        assert!(acq.validate());
+        assert!(acq.trigger_rate_hz(max / 2.0).validate());
+        assert!(!acq.trigger_rate_hz(max * 2.0).validate());
+    }
+
+    #[test]
+    fn external_triggers_map_onto_the_register_fields() {
+        assert_eq!(ExternalTrigger::Tim1Oc1.extsel(), 0);
+        assert_eq!(ExternalTrigger::Tim3Trgo.extsel(), 4);
+        assert_eq!(ExternalTrigger::Tim2Trgo.extsel(), 11);
+        assert_eq!(ExternalTrigger::Lptim3Out.extsel(), 20);
+        assert_eq!(TriggerEdge::Rising.exten(), 0b01);
+        assert_eq!(TriggerEdge::Both.exten(), 0b11);
+
+        // A TRGO on both edges would convert twice per update event
+        assert!(ExternalTrigger::Tim2Trgo.supports(TriggerEdge::Rising));
+        assert!(ExternalTrigger::Tim2Trgo.supports(TriggerEdge::Falling));
+        assert!(!ExternalTrigger::Tim2Trgo.supports(TriggerEdge::Both));
+        assert!(ExternalTrigger::Exti11.supports(TriggerEdge::Both));
+
+        let acq = AcquisitionConfig::new(1_000_000, 16).trigger_rate_hz(10_000.0);
+        assert!(acq
+            .external_trigger(ExternalTrigger::Tim3Trgo, TriggerEdge::Rising)
+            .validate());
+        assert!(!acq
+            .external_trigger(ExternalTrigger::Tim3Trgo, TriggerEdge::Both)
+            .validate());
+        assert!(acq
+            .external_trigger(ExternalTrigger::Tim3Oc4, TriggerEdge::Both)
+            .validate());
+    }
+
+    #[test]
+    fn fifo_thresholds_that_fit_a_burst() {
+        let fits = |beats, beat_bytes| {
+            [
+                FifoThreshold::Quarter,
+                FifoThreshold::Half,
+                FifoThreshold::ThreeQuarters,
+                FifoThreshold::Full,
+            ]
+            .map(|threshold| threshold.fits_burst(beats, beat_bytes))
+        };
+        // 4 half-words, and 4 bytes
+        assert_eq!(fits(4, 2), [false, true, false, true]);
+        assert_eq!(fits(4, 1), [true; 4]);
+        // A single word fits anything, and a 16 byte burst only a full FIFO
+        assert_eq!(fits(1, 4), [true; 4]);
+        assert_eq!(fits(4, 4), [false, false, false, true]);
+        assert_eq!(fits(0, 2), [false; 4]);
+
+        let acq = AcquisitionConfig::new(1_000_000, 16)
+            .dma_priority(DmaPriority::VeryHigh)
+            .fifo_threshold(FifoThreshold::Half);
+        assert_eq!(acq.dma_priority, DmaPriority::VeryHigh);
+        assert_eq!(acq.fifo_threshold.bytes(), 8);
+    }
+}
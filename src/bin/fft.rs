@@ -0,0 +1,54 @@
+//! Reads from ADC1 (or ADC3, with the `adc3` feature) and stores the result in a buffer using DMA.
+//! Then, does various operations on the buffer!
+#![no_main]
+#![no_std]
+
+use cortex_m_rt::entry;
+
+#[cfg(not(feature = "source-i2s"))]
+use lab_3::capture_state::{CaptureStateMachine, State};
+use lab_3::dma_buffer;
+#[cfg(not(feature = "source-i2s"))]
+use lab_3::dsp::scaling::TwoPointCalibration;
+#[cfg(not(feature = "source-i2s"))]
+use lab_3::report::session::Session;
+use lab_3::{board, report};
+
+/// Measure a two-point calibration from the next two captures and save it
+/// to backup SRAM: the first with the input grounded, the second with it at
+/// `CALIBRATION_VOLTS`. The log says when to switch.
+const CALIBRATE: bool = false;
+
+/// Reference voltage applied for the second calibration capture
+#[cfg(not(feature = "source-i2s"))]
+const CALIBRATION_VOLTS: f32 = 1.65;
+
+#[entry]
+fn main() -> ! {
+    #[cfg_attr(not(feature = "capture-soak"), allow(unused_mut))]
+    let mut board = board::init(CALIBRATE);
+    #[cfg(feature = "capture-soak")]
+    report::soak(&mut board.source);
+
+    // The FFT's input, padding and all, kept off the stack
+    let fft_buffer = dma_buffer!(".axisram", f32, report::FFT_LEN);
+
+    #[cfg(not(feature = "source-i2s"))]
+    {
+        let two_point = CALIBRATE.then(|| TwoPointCalibration::new(CALIBRATION_VOLTS));
+        let mut session = Session::new(board, fft_buffer, two_point);
+        // `config` starts running, so go straight to the first capture
+        let mut machine = CaptureStateMachine::starting_in(State::Armed);
+        loop {
+            let action = machine.step(session.poll());
+            let outcome = session.act(action);
+            machine.step(outcome);
+            // The console shows a stop as soon as it's asked for, and goes
+            // back to running if it's taken back or started from the button
+            session.follow(&machine);
+        }
+    }
+
+    #[cfg(feature = "source-i2s")]
+    report::mic::run(board, fft_buffer);
+}
@@ -0,0 +1,161 @@
+//! The `fft` pipeline on Embassy, as an alternative to `fft_rtic`: one task
+//! captures with embassy-stm32's async ADC DMA read and takes the spectrum,
+//! another writes it out of USART3 with async DMA, and a two-slot channel
+//! between them lets each run while the other waits. Only the DSP and the
+//! logger are shared with the other binaries, as embassy-stm32 has its own
+//! PAC and drivers in place of stm32h7xx-hal's.
+//!
+//! `memory.x` puts RAM, so statics and the stack, in DTCM, and DMA1 can't
+//! reach DTCM. So everything either DMA touches, the ADC buffer and the line
+//! being written, is a static in `.axisram`, as `dma_buffer!` does for the
+//! other binaries. The data cache is left off, so nothing there needs
+//! cleaning or invalidating.
+//!
+//! Build it on its own, with `--bin fft_embassy --features embassy`.
+#![no_main]
+#![no_std]
+
+use core::fmt::Write;
+
+use embassy_executor::Spawner;
+use embassy_stm32::adc::{Adc, AdcChannel, AnyAdcChannel, Resolution, SampleTime as AdcSampleTime};
+use embassy_stm32::mode::Async;
+use embassy_stm32::peripherals::{ADC1, DMA1_CH0};
+use embassy_stm32::usart::{self, UartTx};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::String;
+use log::{error, info};
+
+use lab_3::acquisition::{max_conversion_rate_hz, SampleTime};
+use lab_3::dsp::pipeline::{self, Peak};
+use lab_3::dsp::scaling::AdcScale;
+use lab_3::logger;
+
+#[cfg(not(feature = "board-nucleo-h743zi2"))]
+compile_error!("fft_embassy only has the Nucleo's pins, build it for board-nucleo-h743zi2");
+
+const SIZE: usize = 1024;
+
+/// PLL2 P, from HSI: 64 MHz / 16 * 64 / 64
+const ADC_CLOCK_HZ: u32 = 4_000_000;
+const ADC_BITS: u8 = 16;
+const ADC_VREF: f32 = 3.3;
+
+/// The same sampling time as the other binaries, in both crates' terms
+const SAMPLE_TIME: SampleTime = SampleTime::T_32_5;
+const ADC_SAMPLE_TIME: AdcSampleTime = AdcSampleTime::CYCLES32_5;
+
+const CONSOLE_BAUD: u32 = 115_200;
+
+/// Longest line `output` writes, an `index,magnitude` pair
+const LINE_LEN: usize = 32;
+
+/// What `capture` hands `output`
+struct Spectrum {
+    magnitudes: [f32; SIZE / 2],
+    peak: Option<Peak>,
+}
+
+/// Two, so `capture` fills one while `output` writes the other
+static SPECTRA: Channel<CriticalSectionRawMutex, Spectrum, 2> = Channel::new();
+
+#[link_section = ".axisram"]
+static mut ADC_BUFFER: [u16; SIZE] = [0; SIZE];
+#[link_section = ".axisram"]
+static mut LINE: [u8; LINE_LEN] = [0; LINE_LEN];
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    logger::init();
+
+    let mut config = embassy_stm32::Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hsi = Some(HSIPrescaler::DIV1);
+        config.rcc.pll2 = Some(Pll {
+            source: PllSource::HSI,
+            prediv: PllPreDiv::DIV16,
+            mul: PllMul::MUL64,
+            divp: Some(PllDiv::DIV64),
+            divq: None,
+            divr: None,
+        });
+        config.rcc.mux.adcsel = mux::Adcsel::PLL2_P;
+    }
+    let p = embassy_stm32::init(config);
+
+    let mut adc = Adc::new(p.ADC1);
+    adc.set_resolution(Resolution::BITS16);
+    // A0 on CN9, as for the other binaries
+    let channel = p.PA3.degrade_adc();
+
+    let mut uart_config = usart::Config::default();
+    uart_config.baudrate = CONSOLE_BAUD;
+    let tx = match UartTx::new(p.USART3, p.PD8, p.DMA1_CH1, uart_config) {
+        Ok(tx) => tx,
+        Err(e) => {
+            error!("Can't set USART3 up: {:?}", e);
+            return;
+        }
+    };
+
+    info!("Embassy pipeline running, {} samples a capture", SIZE);
+    spawner.must_spawn(capture(adc, channel, p.DMA1_CH0));
+    spawner.must_spawn(output(tx));
+}
+
+/// Capture a buffer at a time and take its spectrum, waiting for a free
+/// slot if `output` is two behind
+#[embassy_executor::task]
+async fn capture(mut adc: Adc<'static, ADC1>, mut channel: AnyAdcChannel<ADC1>, mut dma: DMA1_CH0) {
+    // Safety: only this task uses the buffer, and only between reads
+    let raw = unsafe { &mut *core::ptr::addr_of_mut!(ADC_BUFFER) };
+    let scale = AdcScale::new(ADC_BITS, ADC_VREF);
+    let sample_rate_hz = max_conversion_rate_hz(ADC_CLOCK_HZ, SAMPLE_TIME, ADC_BITS, 1);
+    let mut samples = [0.0; SIZE];
+
+    loop {
+        adc.read(
+            &mut dma,
+            [(&mut channel, ADC_SAMPLE_TIME)].into_iter(),
+            &mut raw[..],
+        )
+        .await;
+
+        pipeline::remove_mean(&raw[..], &mut samples);
+        let mut magnitudes = [0.0; SIZE / 2];
+        pipeline::magnitudes(&mut samples, &scale, &mut magnitudes);
+        let peak = pipeline::peak(&magnitudes, sample_rate_hz);
+
+        SPECTRA.send(Spectrum { magnitudes, peak }).await;
+    }
+}
+
+/// Write each spectrum out as `index,magnitude` lines, each line's DMA
+/// awaited rather than paced with a delay
+#[embassy_executor::task]
+async fn output(mut tx: UartTx<'static, Async>) {
+    // Safety: only this task uses the line, and only between writes
+    let line = unsafe { &mut *core::ptr::addr_of_mut!(LINE) };
+
+    loop {
+        let spectrum = SPECTRA.receive().await;
+        if let Some(peak) = spectrum.peak {
+            info!("Peak at bin {} = {} Hz", peak.bin, peak.hz);
+        }
+
+        for (i, magnitude) in spectrum.magnitudes.iter().enumerate() {
+            let mut text: String<LINE_LEN> = String::new();
+            // Always fits, a magnitude is at most 16-bit full scale times
+            // the bins, eight digits before the point
+            let _ = writeln!(text, "{i},{magnitude:.3}");
+            let bytes = &mut line[..text.len()];
+            bytes.copy_from_slice(text.as_bytes());
+            if let Err(e) = tx.write(bytes).await {
+                error!("Writing the spectrum failed: {:?}", e);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! The `fft` pipeline as RTIC tasks, to compare against the bare-metal loop.
+//! DMA runs continuously over a pool of buffers; its interrupt is a hardware
+//! task that hands each finished buffer to `process`, which windows, FFTs and
+//! finds the peak, and `output` sends the result on the console at the
+//! lowest priority. Both handoffs are channels, so the only lock is for the
+//! timing.
+//!
+//! `output` logs how many buffers arrived while it was writing and the
+//! longest gap between them, timed with the cycle counter, so the log shows
+//! whether acquisition ever waited on output. Build it with
+//! `--bin fft_rtic --features rtic`.
+#![no_main]
+#![no_std]
+
+use log::info;
+
+use cortex_m::peripheral::DWT;
+
+use lab_3::board::{Board, SIZE};
+use lab_3::report::tasks::{self, ConsoleOutput, Processor, Spectrum};
+use lab_3::timing::Arrivals;
+use lab_3::{board, utilities};
+
+#[cfg(any(feature = "adc3", feature = "source-i2s"))]
+compile_error!("fft_rtic streams ADC1 continuously, build it without adc3 or source-i2s");
+
+/// Buffers `dma` can get ahead of `process` by, one for each the pool can
+/// queue. Any more would already have been dropped.
+const BUFFER_SLOTS: usize = utilities::dma::POOL_SIZE;
+
+#[rtic::app(device = stm32h7xx_hal::pac, peripherals = true, dispatchers = [SPI1, SPI2])]
+mod app {
+    use super::*;
+    use rtic_sync::channel::{Receiver, Sender};
+    use rtic_sync::make_channel;
+
+    #[shared]
+    struct Shared {
+        // How buffers arrived, from `dma`, while `output` was writing
+        arrivals: Arrivals,
+    }
+
+    #[local]
+    struct Local {
+        buffers: Sender<'static, u32, BUFFER_SLOTS>,
+        spectra: Sender<'static, Spectrum, 1>,
+        processor: Processor,
+        console_output: ConsoleOutput,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        let Board {
+            console,
+            config,
+            mut source,
+            scale,
+            limits,
+            ..
+        } = board::init_with(cx.core, cx.device, false);
+
+        let (buffers, buffers_in) = make_channel!(u32, BUFFER_SLOTS);
+        let (spectra, spectra_in) = make_channel!(Spectrum, 1);
+        process::spawn(buffers_in).ok();
+        output::spawn(spectra_in).ok();
+
+        // The first buffer interrupts once init returns
+        source.start();
+        info!("RTIC pipeline running, {} samples a buffer", SIZE);
+
+        (
+            Shared {
+                arrivals: Arrivals::default(),
+            },
+            Local {
+                buffers,
+                spectra,
+                processor: Processor::new(source, scale),
+                console_output: ConsoleOutput::new(console, config, limits, scale),
+            },
+        )
+    }
+
+    /// Spin rather than sleep, as the cycle counter stops with the core clock
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    /// Hand each finished buffer (or a DMA error) over to `process`
+    #[task(binds = DMA1_STR0, priority = 3, shared = [arrivals], local = [buffers])]
+    fn dma(mut cx: dma::Context) {
+        let at = DWT::cycle_count();
+        utilities::dma::on_interrupt();
+        cx.shared.arrivals.lock(|arrivals| arrivals.buffer(at));
+        // Full means the pool's out too, and process finds out from the
+        // dropped flag when it gets that far
+        let _ = cx.local.buffers.try_send(at);
+    }
+
+    /// Copy out each buffer, window it, FFT it and find the peak
+    #[task(priority = 2, local = [processor, spectra])]
+    async fn process(cx: process::Context, mut buffers: Receiver<'static, u32, BUFFER_SLOTS>) {
+        while let Ok(handed_over_at) = buffers.recv().await {
+            // The capture loop is here, so this is where a stall shows
+            utilities::watchdog::feed();
+            let Some(spectrum) = cx.local.processor.spectrum(handed_over_at) else {
+                continue;
+            };
+            // Output still has the last one, so this one's only counted
+            if cx.local.spectra.try_send(spectrum).is_err() {
+                cx.local.processor.skipped();
+            }
+        }
+    }
+
+    /// Send each spectrum on the console, and report on acquisition during it
+    #[task(priority = 1, shared = [arrivals], local = [console_output])]
+    async fn output(mut cx: output::Context, mut spectra: Receiver<'static, Spectrum, 1>) {
+        while let Ok(spectrum) = spectra.recv().await {
+            if !cx.local.console_output.poll() {
+                continue;
+            }
+            cx.shared.arrivals.lock(|arrivals| arrivals.begin());
+            let output_cycles = cx.local.console_output.send(&spectrum);
+            let (during, longest_gap) = cx.shared.arrivals.lock(|arrivals| arrivals.end());
+            tasks::log_timing(&spectrum, output_cycles, during, longest_gap);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Logs each capture's AC level, as RMS volts and dBFS with a bar, like a
+//! VU meter. No FFT, so it keeps up with back-to-back captures.
+#![no_main]
+#![no_std]
+
+use log::error;
+
+use cortex_m_rt::entry;
+
+#[cfg(feature = "source-i2s")]
+use lab_3::board::FloatSource;
+use lab_3::board::{Board, SIZE};
+use lab_3::dsp::samples::rms;
+#[cfg(not(feature = "source-i2s"))]
+use lab_3::utilities;
+use lab_3::{board, report};
+
+#[entry]
+fn main() -> ! {
+    let Board {
+        mut console,
+        mut config,
+        mut delay,
+        mut source,
+        scale,
+        limits,
+        #[cfg(not(feature = "source-i2s"))]
+        packed,
+        ..
+    } = board::init(false);
+
+    let mut samples = [0.0; SIZE];
+    #[cfg(not(feature = "source-i2s"))]
+    let mut raw = [0u16; SIZE];
+
+    loop {
+        #[cfg(not(feature = "source-i2s"))]
+        let rate_hz = config.rate_hz;
+        report::service_console(&mut console, &mut config, &limits, &mut delay);
+
+        #[cfg(not(feature = "source-i2s"))]
+        let valid = {
+            if config.rate_hz != rate_hz {
+                source.retune(&mut console, &config);
+            }
+            let capture = source.capture(&mut raw);
+            if packed {
+                utilities::dma::unpack_8bit(&mut raw, capture.valid);
+            }
+            let valid = capture.valid;
+            lab_3::dsp::pipeline::remove_mean(&raw[..valid], &mut samples[..valid]);
+            valid
+        };
+        #[cfg(feature = "source-i2s")]
+        let valid = {
+            let valid = source.capture(&mut samples).valid;
+            report::normalize_slice(&mut samples[..valid]);
+            valid
+        };
+
+        if valid == 0 {
+            error!("No samples arrived, no level to show");
+            continue;
+        }
+        report::log_level(rms(&samples[..valid]), &scale);
+
+        #[cfg(feature = "low-power")]
+        source.stop();
+    }
+}
@@ -0,0 +1,105 @@
+//! Spectra of very low frequencies, bins a twentieth of a hertz apart: the
+//! continuous ADC stream decimated to about `OUTPUT_RATE_HZ` by
+//! `lab_3::dsp::slow_stream`, gathered into `LONG_LEN` samples in AXISRAM,
+//! over a minute of it, and Welch averaged in `SEGMENT_LEN` segments. Each
+//! spectrum logs its peak and then every bin as `hz,psd`, with the PSD in
+//! counts²/Hz.
+//!
+//! DMA moves on through the pool from its interrupt while the loop logs
+//! progress, so nothing's lost unless the loop falls behind by more than
+//! the pool holds. A buffer that arrives after a gap, dropped buffers or a
+//! stall, starts the long capture over rather than being spliced on.
+#![no_main]
+#![no_std]
+
+use log::{error, info, warn};
+
+use cortex_m_rt::entry;
+
+use lab_3::board::{Board, SIZE};
+use lab_3::dma_buffer;
+use lab_3::dsp::slow_stream::{self, Gathered, LongCapture, SlowStream};
+use lab_3::{board, report};
+
+/// What the ADC samples at, a whole `slow_stream::MAX_CIC_FACTOR` and 4
+/// of `OUTPUT_RATE_HZ`
+const INPUT_RATE_HZ: f32 = 12_800.0;
+
+/// Roughly the rate out. The exact one comes from the measured input rate.
+const OUTPUT_RATE_HZ: f32 = 200.0;
+
+/// Decimated samples in a spectrum, 82 s at 200 S/s
+const LONG_LEN: usize = 16_384;
+
+/// Welch segments, as long as the FFT goes, with half of each overlapping
+const SEGMENT_LEN: usize = 4_096;
+
+/// Log how far the long capture's got every this many ADC buffers, 8 s
+const PROGRESS_BUFFERS: u32 = 100;
+
+// Pool buffers are pushed whole, so must be whole CIC blocks
+const _: () = assert!(SIZE % slow_stream::MAX_CIC_FACTOR == 0);
+
+#[entry]
+fn main() -> ! {
+    let Board {
+        mut console,
+        mut config,
+        mut delay,
+        mut source,
+        limits,
+        ..
+    } = board::init(false);
+
+    config.rate_hz = Some(INPUT_RATE_HZ);
+    source.retune(&mut console, &config);
+
+    let long = dma_buffer!(".axisram", f32, LONG_LEN);
+    let mut psd = [0.0; SEGMENT_LEN / 2 + 1];
+    let mut raw = [0u16; SIZE];
+    let mut gathering = LongCapture::new(SlowStream::new(INPUT_RATE_HZ, OUTPUT_RATE_HZ));
+    info!(
+        "Decimating by {} ({} in the CIC), {} samples to a spectrum",
+        gathering.stream().factor(),
+        gathering.stream().cic_factor(),
+        LONG_LEN
+    );
+
+    loop {
+        let rate_hz = config.rate_hz;
+        report::service_console(&mut console, &mut config, &limits, &mut delay);
+        if config.rate_hz != rate_hz {
+            source.retune(&mut console, &config);
+            let input_rate_hz = config.rate_hz.unwrap_or(INPUT_RATE_HZ);
+            gathering = LongCapture::new(SlowStream::new(input_rate_hz, OUTPUT_RATE_HZ));
+        }
+
+        let capture = source.capture(&mut raw);
+        if !capture.complete() {
+            error!("A partial capture, starting the long capture over");
+            gathering.reset();
+            continue;
+        }
+        let (at_us, sample_rate_hz) = (capture.captured_at_us, capture.sample_rate_hz);
+        match gathering.push(&raw, at_us, sample_rate_hz, long) {
+            Gathered::Gap { dropped } => {
+                warn!("A gap in the stream {} samples in, starting over", dropped)
+            }
+            Gathered::Filling(filled) if gathering.buffers().is_multiple_of(PROGRESS_BUFFERS) => {
+                info!(
+                    "{}/{} samples, {}%",
+                    filled,
+                    LONG_LEN,
+                    100 * filled / LONG_LEN
+                )
+            }
+            Gathered::Filling(_) => {}
+            Gathered::Full => {
+                let rate_hz = gathering.stream().output_rate_hz(sample_rate_hz);
+                report::log_welch_spectrum(long, SEGMENT_LEN, &mut psd, rate_hz);
+                // Logging it held the loop up far longer than the pool lasts
+                gathering.reset();
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Streams every capture's raw ADC codes over the console as `protocol`
+//! frames, with no processing on the board. The host does the rest.
+#![no_main]
+#![no_std]
+// Only the mic's error path is left when it's the source
+#![cfg_attr(
+    feature = "source-i2s",
+    allow(dead_code, unused_imports, unused_mut, unused_variables)
+)]
+
+use log::error;
+
+use cortex_m_rt::entry;
+
+use lab_3::board::{Board, SIZE};
+use lab_3::config::{Output, OutputMode, Outputs};
+use lab_3::{board, report, utilities};
+
+#[entry]
+fn main() -> ! {
+    let Board {
+        mut console,
+        mut config,
+        mut delay,
+        mut source,
+        scale,
+        limits,
+        packed,
+        ..
+    } = board::init(false);
+
+    // Frames carry the resolution and whether each capture is trusted, so
+    // the host can decide what to keep
+    config.mode = OutputMode::Raw;
+    config.output = Outputs::only(Output::Frames);
+
+    #[cfg(not(feature = "source-i2s"))]
+    {
+        let mut raw = [0u16; SIZE];
+
+        loop {
+            let rate_hz = config.rate_hz;
+            report::service_console(&mut console, &mut config, &limits, &mut delay);
+            if config.rate_hz != rate_hz {
+                source.retune(&mut console, &config);
+            }
+
+            let capture = source.capture(&mut raw);
+            if capture.valid == 0 {
+                error!("No samples arrived, nothing to send");
+                continue;
+            }
+            if packed {
+                utilities::dma::unpack_8bit(&mut raw, capture.valid);
+            }
+            report::send_frames(&mut console, &capture, &scale, &[], &raw[..capture.valid]);
+
+            #[cfg(feature = "low-power")]
+            source.stop();
+        }
+    }
+
+    // Frames carry 16-bit codes, which the mic's 24-bit samples aren't
+    #[cfg(feature = "source-i2s")]
+    {
+        error!("stream_raw needs an ADC source, build it without `source-i2s`");
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+}
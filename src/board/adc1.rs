@@ -0,0 +1,432 @@
+//! ADC1 as a `SampleSource`, converting into a DMA1 buffer in AXISRAM
+//! with retries, and with `dac-chirp` playing a sweep in step with it.
+
+use log::{error, info, warn};
+
+use cortex_m::peripheral::SCB;
+use stm32h7xx_hal::dma::{
+    dma::{DmaConfig, Stream0},
+    DBTransfer, PeripheralToMemory, Transfer,
+};
+use stm32h7xx_hal::{adc, pac};
+
+#[cfg(feature = "capture-soak")]
+use crate::acquisition::SoakStats;
+use crate::acquisition::{AcqError, AcquisitionConfig, AcquisitionMode, RateStats, SampleSource};
+use crate::dsp::scaling::{AdcScale, OffsetDrift};
+use crate::pool::Reader;
+
+#[cfg(feature = "low-power")]
+use super::STOP_SECONDS;
+use super::{target, INJECTED_REFERENCE, MAX_CAPTURE_ATTEMPTS, SIZE, WAIT_MODE};
+#[cfg(feature = "dac-chirp")]
+use super::{CHIRP_END_HZ, CHIRP_MS, CHIRP_START_HZ};
+use crate::utilities::{
+    self,
+    dma::{CaptureError, POOL_SIZE},
+};
+
+type Parts = (
+    Stream0<pac::DMA1>,
+    adc::Adc<pac::ADC1, adc::Enabled>,
+    &'static mut [u16],
+);
+
+type PooledTransfer = Transfer<
+    Stream0<pac::DMA1>,
+    adc::Adc<pac::ADC1, adc::Enabled>,
+    PeripheralToMemory,
+    &'static mut [u16],
+    DBTransfer,
+>;
+
+/// ADC1 converting into its DMA1 buffer, a capture per `fill`, which
+/// retries from a fresh stream and ADC if DMA reports an error. `fill`
+/// copies the capture out of the DMA buffer, into a `SIZE` long buffer.
+///
+/// In `AcquisitionMode::Continuous` the first `fill` leaves DMA running in
+/// double-buffer mode over the pool from `dma::init_pool`, and every `fill`
+/// takes the oldest buffer the interrupt queued.
+pub struct Adc1Source {
+    /// Idle between captures, a `Transfer` owns them during one
+    pub(super) parts: Option<Parts>,
+    /// The double-buffered transfer, while a continuous acquisition is
+    /// running, and the one-shot buffer it leaves aside meanwhile
+    pub(super) running: Option<PooledTransfer>,
+    pub(super) parked: Option<&'static mut [u16]>,
+    /// The pool's reading half, in `AcquisitionMode::Continuous`
+    pub(super) reader: Option<Reader<'static, POOL_SIZE>>,
+    pub(super) channel: target::Adc1In,
+    pub(super) dma_config: DmaConfig,
+    pub(super) packed: bool,
+    pub(super) acq: AcquisitionConfig,
+    pub(super) timeout: u32,
+    /// For the timeout, and retuning
+    pub(super) sys_ck_hz: u32,
+    /// The sample rate, as measured over the captures so far
+    pub(super) stats: RateStats,
+    pub(super) scb: SCB,
+    /// When the last capture started converting, and when DMA finished it
+    pub(super) started_at: u64,
+    pub(super) captured_at: u64,
+    /// CRC of what the last capture left in the DMA buffer
+    pub(super) buffer_crc: u32,
+    /// ADC3, only for the die temperature, and its scale
+    pub(super) thermometer: adc::Adc<pac::ADC3, adc::Enabled>,
+    pub(super) sensor_scale: AdcScale,
+    /// What's taken off each capture as the die warms up
+    pub(super) drift: OffsetDrift,
+    /// Sweeps in step with each capture
+    #[cfg(feature = "dac-chirp")]
+    pub(super) stimulus: (utilities::dac::Dac, utilities::dac::DacTimer),
+}
+
+impl SampleSource for Adc1Source {
+    fn fill(&mut self, buf: &mut [u16]) -> Result<(), AcqError> {
+        if self.acq.mode == AcquisitionMode::Continuous {
+            return self.fill_continuous(buf);
+        }
+
+        // If the ADC never finishes, we carry on with however much arrived
+        let mut attempt = 1;
+        let result = loop {
+            match self.run_once() {
+                Ok(_) => break Ok(()),
+                Err(e) => {
+                    error!(
+                        "Capture attempt {}/{} failed: {:?}",
+                        attempt, MAX_CAPTURE_ATTEMPTS, e
+                    );
+                    if attempt == MAX_CAPTURE_ATTEMPTS {
+                        break Err(match e {
+                            CaptureError::Timeout { received } => AcqError::Timeout { received },
+                            CaptureError::Overrun => AcqError::Overrun,
+                            CaptureError::Dma(_) => AcqError::Transfer,
+                        });
+                    }
+                    attempt += 1;
+                }
+            }
+        };
+
+        let valid = match result {
+            Err(AcqError::Timeout { received }) => received,
+            Err(AcqError::Transfer) => 0,
+            Ok(()) | Err(AcqError::Overrun) => SIZE,
+        };
+        let (_, _, buffer) = self.parts.as_ref().expect("the ADC is capturing");
+        // process_capture checks the copy against this
+        self.buffer_crc = utilities::crc::crc32_samples(&buffer[..valid]);
+        buf[..valid].copy_from_slice(&buffer[..valid]);
+        result
+    }
+}
+
+impl Adc1Source {
+    /// Take one one-shot capture into the DMA buffer, and hand it back:
+    /// program the stream afresh, start the ADC converting, wait for the
+    /// transfer and take the stream and ADC back out of it. `started_at`
+    /// and `captured_at` time it. On an error the stream and ADC are reset
+    /// for the next call, and the buffer holds whatever arrived, so this
+    /// can go on being called for as long as there are captures to take.
+    /// `fill` is this with retries.
+    pub fn run_once(&mut self) -> Result<&[u16; SIZE], CaptureError> {
+        let (stream, mut adc1, buffer) = self.parts.take().expect("the ADC is already capturing");
+
+        let sample_time = utilities::adc::hal_sample_time(self.acq.channel.sample_time);
+        adc1.set_sample_time(sample_time);
+        utilities::adc::discard_conversions(&mut adc1, &mut self.channel, self.acq.discard);
+
+        let mut transfer: Transfer<_, _, _, _, _> =
+            Transfer::init(stream, adc1, buffer, None, self.dma_config);
+        if self.packed {
+            utilities::dma::set_byte_packing();
+        }
+
+        info!("About to start transfer...    ");
+
+        // Armed afresh for each capture, so a retry gets the whole sweep
+        #[cfg(feature = "dac-chirp")]
+        let chirp = {
+            let (dac, timer) = &mut self.stimulus;
+            match utilities::dac::play_chirp(dac, timer, CHIRP_START_HZ, CHIRP_END_HZ, CHIRP_MS) {
+                Ok(chirp) => Some(chirp),
+                Err(e) => {
+                    error!("Can't play the chirp: {:?}", e);
+                    None
+                }
+            }
+        };
+
+        let channel = &mut self.channel;
+        let trigger = self.acq.external_trigger;
+        let mut started_at = 0;
+        transfer.start(|adc| {
+            // This closure runs right after enabling the stream
+
+            // The sweep's first code is a DAC tick off, so it goes first
+            #[cfg(feature = "dac-chirp")]
+            if let Some(chirp) = &chirp {
+                chirp.start();
+            }
+
+            // Start a one-shot conversion for the length of this transfer
+            utilities::adc::start_conversion_dma(adc, channel, adc::AdcDmaMode::OneShot, trigger);
+            started_at = utilities::monotonic::now_us();
+            if INJECTED_REFERENCE {
+                utilities::adc::start_injected();
+            }
+        });
+        self.started_at = started_at;
+        #[cfg(feature = "dac-chirp")]
+        if let Some(chirp) = &chirp {
+            info!(
+                "Chirp starts {} samples in",
+                chirp.lag_samples(self.acq.effective_rate_hz(None))
+            );
+        }
+
+        // Wait for transfer to complete, or fail
+        let result = utilities::dma::wait(WAIT_MODE, &mut self.scb, SIZE, self.timeout);
+        self.captured_at = utilities::monotonic::now_us();
+        // Whatever's left of the sweep isn't being captured
+        #[cfg(feature = "dac-chirp")]
+        drop(chirp);
+
+        // Take everything back out of the transfer, which disables the stream
+        utilities::adc::stop_conversions();
+        let (stream, mut adc1, buffer, _) = transfer.free();
+
+        // A complete transfer is still no good if the ADC dropped samples
+        let result = result.and_then(|()| {
+            if utilities::adc::check_overrun() {
+                Err(CaptureError::Overrun)
+            } else {
+                Ok(())
+            }
+        });
+
+        if let Err(e) = result {
+            utilities::dma::log_state();
+            if let CaptureError::Timeout { .. } = e {
+                utilities::adc::log_registers();
+            }
+            // Tear down, so the next capture starts with a clean stream and ADC
+            utilities::dma::clear_flags();
+            adc1 = adc1.disable().enable();
+            utilities::adc::settle(self.acq.settling_us, self.sys_ck_hz);
+        }
+
+        self.parts = Some((stream, adc1, buffer));
+        let (_, _, buffer) = self.parts.as_ref().expect("just put back");
+        result.map(|()| <&[u16; SIZE]>::try_from(&buffer[..]).expect("the DMA buffer is SIZE long"))
+    }
+
+    /// With `capture-soak`, take `captures` one-shot captures back to back
+    /// with `run_once`, without retrying any, and log PASS if none failed
+    /// and how long they took didn't drift, see `SoakStats`
+    #[cfg(feature = "capture-soak")]
+    #[must_use]
+    pub fn soak(&mut self, captures: u32) -> bool {
+        let mut stats = SoakStats::new();
+        for i in 0..captures {
+            utilities::watchdog::feed();
+            let elapsed = match self.run_once().map(drop) {
+                Ok(()) => Some(self.captured_at - self.started_at),
+                Err(e) => {
+                    error!("Soak capture {} failed: {:?}", i, e);
+                    None
+                }
+            };
+            stats.push(elapsed);
+        }
+
+        let spread = stats.spread_us();
+        if stats.passed() {
+            info!(
+                "Capture soak: PASS, {} captures, none failed, {} us apart at most",
+                stats.captures(),
+                spread
+            );
+            true
+        } else {
+            error!(
+                "Capture soak: FAIL, {} of {} captures failed, {} us apart at most ({}%)",
+                stats.errors(),
+                stats.captures(),
+                spread,
+                stats.drift() * 100.0
+            );
+            false
+        }
+    }
+
+    /// `fill` for `AcquisitionMode::Continuous`: start DMA if it isn't
+    /// running, then copy out the oldest buffer queued and hand it back. An
+    /// error stops it, to start over on the next `fill`, and giving up is as
+    /// for one-shot captures.
+    fn fill_continuous(&mut self, buf: &mut [u16]) -> Result<(), AcqError> {
+        let mut attempt = 1;
+        loop {
+            if self.running.is_none() {
+                self.start_pooled();
+            }
+
+            let reader = self.reader.as_mut().expect("continuous without a pool");
+            let result = utilities::dma::next_buffer(reader, self.timeout);
+            let result = result.and_then(|filled| {
+                if utilities::adc::check_overrun() {
+                    reader.release(filled.token);
+                    Err(CaptureError::Overrun)
+                } else {
+                    Ok(filled)
+                }
+            });
+            match result {
+                Ok(filled) => {
+                    // Timed from the previous buffer, as conversions never
+                    // stop, unless some were dropped in between
+                    self.started_at = if filled.flags.after_drop {
+                        warn!("Continuous capture fell behind, buffers were dropped");
+                        filled.captured_at_us
+                    } else {
+                        self.captured_at
+                    };
+                    self.captured_at = filled.captured_at_us;
+
+                    let samples = utilities::dma::pool_samples(&filled.token);
+                    self.buffer_crc = utilities::crc::crc32_samples(samples);
+                    buf[..SIZE].copy_from_slice(samples);
+                    reader.release(filled.token);
+                    break Ok(());
+                }
+                Err(e) => {
+                    error!(
+                        "Capture attempt {}/{} failed: {:?}",
+                        attempt, MAX_CAPTURE_ATTEMPTS, e
+                    );
+                    utilities::dma::log_state();
+                    // The ADC stops converting on an overrun, so start over
+                    // whatever went wrong
+                    self.halt();
+                    if attempt == MAX_CAPTURE_ATTEMPTS {
+                        self.buffer_crc = utilities::crc::crc32_samples(&[]);
+                        break Err(match e {
+                            CaptureError::Timeout { received } => AcqError::Timeout { received },
+                            CaptureError::Overrun => AcqError::Overrun,
+                            CaptureError::Dma(_) => AcqError::Transfer,
+                        });
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Set DMA converting into the pool's two armed buffers, with the
+    /// interrupt moving it on to free ones as each fills
+    fn start_pooled(&mut self) {
+        let (stream, mut adc1, buffer) = self.parts.take().expect("the ADC is already capturing");
+        self.parked = Some(buffer);
+        let sample_time = utilities::adc::hal_sample_time(self.acq.channel.sample_time);
+        adc1.set_sample_time(sample_time);
+        utilities::adc::discard_conversions(&mut adc1, &mut self.channel, self.acq.discard);
+        let reader = self.reader.as_mut().expect("continuous without a pool");
+        utilities::dma::start_continuous(reader);
+
+        let config = self
+            .dma_config
+            .double_buffer(true)
+            .transfer_complete_interrupt(true)
+            .transfer_error_interrupt(true)
+            .direct_mode_error_interrupt(true);
+        // Safety: these go with the transfer in `halt`
+        let (first, second) = unsafe { utilities::dma::armed_buffers() };
+        let mut transfer: PooledTransfer =
+            Transfer::init(stream, adc1, first, Some(second), config);
+
+        info!("Starting continuous transfer...");
+        let channel = &mut self.channel;
+        let trigger = self.acq.external_trigger;
+        let mut started_at = 0;
+        transfer.start(|adc| {
+            utilities::adc::start_conversion_dma(adc, channel, adc::AdcDmaMode::Circular, trigger);
+            started_at = utilities::monotonic::now_us();
+        });
+        // The first buffer is timed from here
+        self.captured_at = started_at;
+        self.running = Some(transfer);
+    }
+
+    /// Start a continuous acquisition ahead of the first `fill`, for taking
+    /// each buffer as its interrupt arrives rather than waiting for it
+    pub fn start(&mut self) {
+        if self.running.is_none() {
+            self.start_pooled();
+        }
+    }
+
+    /// Stop a continuous acquisition, if one's running, leaving the stream
+    /// and ADC idle for the next `fill` or a retune
+    pub(super) fn halt(&mut self) {
+        let Some(transfer) = self.running.take() else {
+            return;
+        };
+        utilities::dma::stop_continuous();
+        utilities::adc::stop_conversions();
+        // The pool's buffers stay with its tokens, so only the stream and
+        // ADC come back
+        let (stream, adc1, _, _) = transfer.free();
+        utilities::dma::clear_flags();
+        let buffer = self
+            .parked
+            .take()
+            .expect("a running transfer parks the buffer");
+        self.parts = Some((stream, adc1.disable().enable(), buffer));
+        utilities::adc::settle(self.acq.settling_us, self.sys_ck_hz);
+    }
+
+    /// The die temperature, read on ADC3 between captures, or `None` if the
+    /// conversion failed
+    pub(super) fn die_degc(&mut self) -> Option<f32> {
+        utilities::adc::read_die_degc(&mut self.thermometer, &self.sensor_scale)
+    }
+
+    /// How much of a capture `fill` left to carry on with, and whether it's
+    /// to be trusted. Only DMA failing on every attempt is fatal.
+    pub(super) fn outcome(result: Result<(), AcqError>) -> (usize, bool) {
+        match result {
+            Ok(()) => (SIZE, true),
+            Err(AcqError::Timeout { received }) => {
+                warn!(
+                    "Continuing with a partial buffer, {}/{} samples",
+                    received, SIZE
+                );
+                (received, true)
+            }
+            Err(AcqError::Overrun) => {
+                warn!("Continuing with an overrun buffer, results invalid");
+                (SIZE, false)
+            }
+            Err(AcqError::Transfer) => {
+                panic!(
+                    "DMA capture failed {} times, giving up",
+                    MAX_CAPTURE_ATTEMPTS
+                )
+            }
+        }
+    }
+
+    /// Sit in Stop for `STOP_SECONDS`. The ADC's kernel clock stops in Stop,
+    /// so it's brought back up from disabled. DMA is reprogrammed by the
+    /// next `Transfer::init`.
+    #[cfg(feature = "low-power")]
+    pub fn stop(&mut self) {
+        self.halt();
+        let (stream, adc1, buffer) = self.parts.take().expect("the ADC is capturing");
+        let disabled = adc1.disable();
+        utilities::low_power::stop_for(&mut self.scb, STOP_SECONDS);
+        self.parts = Some((stream, disabled.enable(), buffer));
+        utilities::adc::settle(self.acq.settling_us, self.sys_ck_hz);
+    }
+}
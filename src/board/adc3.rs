@@ -0,0 +1,149 @@
+//! ADC3 as a `SampleSource`, converting into a BDMA buffer in SRAM4. Both
+//! live in the D3 domain, so this keeps working in low-power configurations
+//! where DMA1 doesn't.
+
+use log::{info, warn};
+
+use cortex_m::peripheral::SCB;
+use stm32h7xx_hal::dma::{
+    bdma::{BdmaConfig, Stream0},
+    Transfer,
+};
+use stm32h7xx_hal::{adc, pac};
+
+use crate::acquisition::{AcqError, AcquisitionConfig, RateStats, SampleSource};
+use crate::dsp::scaling::{AdcScale, OffsetDrift};
+
+#[cfg(feature = "low-power")]
+use super::STOP_SECONDS;
+use super::{target, SIZE};
+use crate::utilities::{self, dma::CaptureError};
+
+/// ADC3 converting into its BDMA buffer, a capture per `fill`. There's no
+/// retrying, a BDMA error is fatal. `fill` copies the capture out of the
+/// BDMA buffer, into a `SIZE` long buffer.
+pub struct Adc3Source {
+    /// Idle between captures, a `Transfer` owns them during one
+    pub(super) parts: Option<(
+        Stream0<pac::BDMA>,
+        adc::Adc<pac::ADC3, adc::Enabled>,
+        &'static mut [u16],
+    )>,
+    pub(super) channel: target::Adc3In,
+    pub(super) dma_config: BdmaConfig,
+    pub(super) acq: AcquisitionConfig,
+    pub(super) timeout: u32,
+    /// For the timeout, and retuning
+    pub(super) sys_ck_hz: u32,
+    /// The sample rate, as measured over the captures so far
+    pub(super) stats: RateStats,
+    pub(super) scb: SCB,
+    /// When the last capture started converting, and when BDMA finished it
+    pub(super) started_at: u64,
+    pub(super) captured_at: u64,
+    /// CRC of what the last capture left in the BDMA buffer
+    pub(super) buffer_crc: u32,
+    /// The capture's scale, for reading the die temperature with
+    pub(super) sensor_scale: AdcScale,
+    /// What's taken off each capture as the die warms up
+    pub(super) drift: OffsetDrift,
+}
+
+impl SampleSource for Adc3Source {
+    fn fill(&mut self, buf: &mut [u16]) -> Result<(), AcqError> {
+        let (stream, mut adc3, buffer) = self.parts.take().expect("the ADC is already capturing");
+        let sample_time = utilities::adc::hal_sample_time(self.acq.channel.sample_time);
+        adc3.set_sample_time(sample_time);
+        utilities::adc::discard_conversions(&mut adc3, &mut self.channel, self.acq.discard);
+
+        let mut transfer: Transfer<_, _, _, _, _> =
+            Transfer::init(stream, adc3, buffer, None, self.dma_config);
+
+        info!("About to start ADC3 transfer...");
+
+        let channel = &mut self.channel;
+        let mut started_at = 0;
+        transfer.start(|adc| {
+            adc.start_conversion_dma(channel, adc::AdcDmaMode::OneShot);
+            started_at = utilities::monotonic::now_us();
+        });
+        self.started_at = started_at;
+
+        let result = utilities::bdma::wait_for_interrupt(SIZE, self.timeout);
+        self.captured_at = utilities::monotonic::now_us();
+        utilities::adc::stop_conversions_adc3();
+        let (stream, adc3, buffer, _) = transfer.free();
+
+        // As on ADC1, a complete buffer is no good if samples were dropped
+        let result = result.and_then(|()| {
+            if utilities::adc::check_overrun_adc3() {
+                Err(CaptureError::Overrun)
+            } else {
+                Ok(())
+            }
+        });
+        let (result, valid) = match result {
+            Ok(()) => (Ok(()), SIZE),
+            Err(CaptureError::Timeout { received }) => {
+                (Err(AcqError::Timeout { received }), received)
+            }
+            Err(CaptureError::Overrun) => (Err(AcqError::Overrun), SIZE),
+            Err(e) => panic!("ADC3 capture failed: {:?}", e),
+        };
+
+        // process_capture checks nothing has touched the samples since
+        self.buffer_crc = utilities::crc::crc32_samples(&buffer[..valid]);
+        buf[..valid].copy_from_slice(&buffer[..valid]);
+
+        self.parts = Some((stream, adc3, buffer));
+        result
+    }
+}
+
+impl Adc3Source {
+    /// How much of a capture `fill` left to carry on with, and whether it's
+    /// to be trusted
+    pub(super) fn outcome(result: Result<(), AcqError>) -> (usize, bool) {
+        match result {
+            Ok(()) => (SIZE, true),
+            Err(AcqError::Timeout { received }) => {
+                warn!(
+                    "ADC3 capture timed out, continuing with {}/{} samples",
+                    received, SIZE
+                );
+                (received, true)
+            }
+            Err(AcqError::Overrun) => {
+                warn!("ADC3 overran, results invalid");
+                (SIZE, false)
+            }
+            // `fill` panics on those itself
+            Err(AcqError::Transfer) => unreachable!(),
+        }
+    }
+
+    /// The battery voltage on VBAT, read between captures, or `None` if
+    /// the conversion failed
+    pub fn vbat_volts(&mut self, scale: &AdcScale) -> Option<f32> {
+        let (_, adc3, _) = self.parts.as_mut().expect("the ADC is capturing");
+        utilities::adc::read_vbat_volts(adc3, scale)
+    }
+
+    /// The die temperature, read between captures, or `None` if the
+    /// conversion failed
+    pub(super) fn die_degc(&mut self) -> Option<f32> {
+        let (_, adc3, _) = self.parts.as_mut().expect("the ADC is capturing");
+        utilities::adc::read_die_degc(adc3, &self.sensor_scale)
+    }
+
+    /// Sit in Stop for `STOP_SECONDS`, bringing the ADC back up after, as for
+    /// ADC1
+    #[cfg(feature = "low-power")]
+    pub fn stop(&mut self) {
+        let (stream, adc3, buffer) = self.parts.take().expect("the ADC is capturing");
+        let disabled = adc3.disable();
+        utilities::low_power::stop_for(&mut self.scb, STOP_SECONDS);
+        self.parts = Some((stream, disabled.enable(), buffer));
+        utilities::adc::settle(self.acq.settling_us, self.sys_ck_hz);
+    }
+}
@@ -0,0 +1,112 @@
+//! MCUDev DevEBox STM32H743VIT6: inputs on the headers, the D2 LED, K1,
+//! a 25 MHz crystal and USART1 on PA9/PA10 for a USB serial adapter, as
+//! there's no ST-LINK on board. It has no Ethernet PHY either.
+
+#[cfg(not(feature = "differential-input"))]
+use stm32h7xx_hal::gpio::PA0;
+use stm32h7xx_hal::gpio::{Alternate, Analog, PA10, PA6, PA9, PC0};
+#[cfg(feature = "differential-input")]
+use stm32h7xx_hal::gpio::{PA4, PA5};
+use stm32h7xx_hal::pac::{self, interrupt, Interrupt};
+use stm32h7xx_hal::pwr::Pwr;
+use stm32h7xx_hal::rcc::rec;
+use stm32h7xx_hal::time::Hertz;
+
+use super::{Button, Led, Supply};
+use crate::utilities;
+
+#[cfg(feature = "ethernet")]
+compile_error!("The DevEBox H743 has no Ethernet PHY, build it without ethernet");
+
+/// ADC1's input (ADC1 INP16)
+#[cfg(not(feature = "differential-input"))]
+pub type Adc1In = PA0<Analog>;
+/// ADC1's input with `differential-input`, the positive of its pair (ADC1
+/// INP18), as PA0's negative is the LED's PA1
+#[cfg(feature = "differential-input")]
+pub type Adc1In = PA4<Analog>;
+/// The pair's negative (ADC1 INN18)
+#[cfg(feature = "differential-input")]
+pub type Adc1Negative = PA5<Analog>;
+/// ADC1's injected reference (ADC1 INP3)
+pub type Adc1Reference = PA6<Analog>;
+/// ADC3's input (ADC3 INP10)
+pub type Adc3In = PC0<Analog>;
+
+/// D2, which sinks into PA1
+pub const LED_COUNT: usize = 1;
+pub const LED_ACTIVE_LOW: bool = true;
+/// K1 shorts PE3 to ground, held up by the internal pull-up
+pub const BUTTON_ACTIVE_LOW: bool = true;
+
+/// The board's crystal
+pub const HSE: Hertz = Hertz::MHz(25);
+pub const HSE_BYPASS: bool = false;
+
+/// Wire a 3.3 V USB serial adapter to PA9 (TX) and PA10 (RX)
+pub type VcpUsart = pac::USART1;
+pub type VcpRec = rec::Usart1;
+pub type VcpPins = (PA9<Alternate<7>>, PA10<Alternate<7>>);
+pub const VCP_NAME: &str = "USART1";
+pub const VCP_INTERRUPT: Interrupt = Interrupt::USART1;
+
+/// What `board_pins!` takes out of the GPIO ports
+pub struct Pins {
+    #[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+    pub adc1_in: Adc1In,
+    #[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+    pub adc1_reference: Adc1Reference,
+    #[cfg(feature = "differential-input")]
+    pub adc1_negative: Adc1Negative,
+    #[cfg(feature = "adc3")]
+    pub adc3_in: Adc3In,
+    pub leds: [Led; LED_COUNT],
+    pub button: Button,
+    pub vcp: VcpPins,
+}
+
+/// Take the board's pins out of the split GPIO ports
+macro_rules! board_pins {
+    ($gpioa:ident, $gpiob:ident, $gpioc:ident, $gpiod:ident, $gpioe:ident) => {
+        target::Pins {
+            #[cfg(all(
+                not(feature = "adc3"),
+                not(feature = "source-i2s"),
+                not(feature = "differential-input")
+            ))]
+            adc1_in: $gpioa.pa0.into_analog(),
+            #[cfg(feature = "differential-input")]
+            adc1_in: $gpioa.pa4.into_analog(),
+            #[cfg(feature = "differential-input")]
+            adc1_negative: $gpioa.pa5.into_analog(),
+            #[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+            adc1_reference: $gpioa.pa6.into_analog(),
+            #[cfg(feature = "adc3")]
+            adc3_in: $gpioc.pc0.into_analog(),
+            leds: [$gpioa.pa1.into_push_pull_output().erase()],
+            button: $gpioe.pe3.into_pull_up_input().erase(),
+            vcp: ($gpioa.pa9.into_alternate(), $gpioa.pa10.into_alternate()),
+        }
+    };
+}
+
+/// The VCP's UART and its clock record
+macro_rules! vcp_usart {
+    ($dp:ident, $ccdr:ident) => {
+        ($dp.USART1, $ccdr.peripheral.USART1)
+    };
+}
+
+/// The H743 has no SMPS, so the core runs from the LDO
+pub const SUPPLY: Supply = Supply::Ldo;
+
+/// Set PWR up for `SUPPLY`. The LDO is how it comes out of reset, so
+/// there's nothing to do.
+pub fn apply_supply(pwr: Pwr) -> Pwr {
+    pwr
+}
+
+#[interrupt]
+fn USART1() {
+    utilities::console::on_receive();
+}
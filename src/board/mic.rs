@@ -0,0 +1,40 @@
+//! The I2S mic as a source of captures. Its samples are 24 bits, so it
+//! fills floats rather than being a `SampleSource`.
+
+use log::error;
+
+use super::{CaptureInfo, SIZE};
+use crate::utilities;
+
+/// Like a `SampleSource`, for somewhere whose samples don't fit in 16-bit
+/// codes. It fills a whole buffer with them in its own counts, ready for
+/// `analyze`, and says what it knows about the capture.
+pub trait FloatSource {
+    fn capture(&mut self, out: &mut [f32; SIZE]) -> CaptureInfo;
+}
+
+impl FloatSource for utilities::sai::Microphone {
+    fn capture(&mut self, out: &mut [f32; SIZE]) -> CaptureInfo {
+        let (valid, trusted, captured_at_us) = match self.read(out) {
+            Ok((trusted, captured_at)) => (SIZE, trusted, captured_at),
+            // Nothing's converted from a failed capture
+            Err(e) => {
+                error!("I2S capture failed: {:?}", e);
+                (0, false, utilities::monotonic::now_us())
+            }
+        };
+        let mut crc = utilities::crc::Digest::new();
+        for sample in &out[..valid] {
+            crc.update(&sample.to_le_bytes());
+        }
+        CaptureInfo {
+            valid,
+            trusted,
+            captured_at_us,
+            sample_rate_hz: self.sample_rate_hz(),
+            buffer_crc: crc.finish(),
+            die_degc: None,
+            offset_correction: 0,
+        }
+    }
+}
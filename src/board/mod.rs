@@ -0,0 +1,973 @@
+//! Bringing the board up, for every binary: power, clocks, the console and
+//! whatever else the features ask for, and the capture source, ADC1, ADC3
+//! or the I2S mic, ready to capture from.
+//!
+//! `init` hands it all back as a `Board`, and what's done with the captures
+//! is up to the binary.
+//!
+//! Which pins, clocks and UART that takes differs between boards, and a
+//! `board-*` feature picks the module that says, as `target`. Everything
+//! else here is the same on every board.
+
+use log::{info, warn, LevelFilter};
+
+use cortex_m::peripheral::SCB;
+use stm32h7xx_hal::{adc, delay::Delay, pac, prelude::*};
+
+#[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+use stm32h7xx_hal::dma::{
+    config::BurstMode,
+    dma::{DmaConfig, StreamsTuple},
+};
+
+use stm32h7xx_hal::gpio::{ErasedPin, Input, Output};
+#[cfg(feature = "debug-clocks")]
+use stm32h7xx_hal::gpio::Speed;
+
+#[cfg(feature = "adc3")]
+use stm32h7xx_hal::dma::bdma::{BdmaConfig, StreamsTuple as BdmaStreamsTuple};
+
+#[cfg(not(feature = "source-i2s"))]
+use crate::acquisition::SampleSource;
+use crate::acquisition::{
+    self, AcquisitionConfig, AcquisitionMode, ChannelConfig, DmaPriority, ExternalTrigger,
+    FifoThreshold, RateStats, SampleTime, TriggerEdge,
+};
+use crate::config::{Config, Limits};
+#[cfg(not(feature = "source-i2s"))]
+use crate::dsp::scaling::OffsetDrift;
+use crate::dsp::scaling::{self, AdcScale, Calibration};
+#[cfg(feature = "source-i2s")]
+use crate::i2s::Slot;
+
+use crate::utilities;
+#[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+use crate::utilities::adc::InjectedTrigger;
+use crate::utilities::console::Console;
+use crate::utilities::dma::WaitMode;
+use crate::utilities::rtc::DateTime;
+
+#[cfg(not(any(feature = "board-nucleo-h743zi2", feature = "board-devebox-h743")))]
+compile_error!("Select a board with the board-nucleo-h743zi2 or board-devebox-h743 feature");
+
+#[cfg(all(feature = "board-nucleo-h743zi2", feature = "board-devebox-h743"))]
+compile_error!(
+    "Select only one board feature. The Nucleo is the default, so build for the DevEBox with \
+     --no-default-features --features board-devebox-h743"
+);
+
+#[cfg(feature = "board-nucleo-h743zi2")]
+#[macro_use]
+pub mod nucleo_h743zi2;
+#[cfg(feature = "board-nucleo-h743zi2")]
+pub use nucleo_h743zi2 as target;
+#[cfg(feature = "board-devebox-h743")]
+#[macro_use]
+pub mod devebox_h743;
+#[cfg(feature = "board-devebox-h743")]
+pub use devebox_h743 as target;
+
+#[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+mod adc1;
+#[cfg(feature = "adc3")]
+mod adc3;
+#[cfg(feature = "source-i2s")]
+mod mic;
+mod power;
+
+#[cfg(feature = "source-i2s")]
+pub use mic::FloatSource;
+pub use power::{configure_power, PowerConfigured, Supply};
+
+#[cfg(all(feature = "adc3", feature = "source-i2s"))]
+compile_error!("adc3 and source-i2s are both sample sources, pick one");
+
+#[cfg(all(feature = "dac-chirp", any(feature = "adc3", feature = "source-i2s")))]
+compile_error!("dac-chirp plays in step with ADC1's captures, and needs its DMA1");
+
+#[cfg(all(
+    feature = "differential-input",
+    any(feature = "adc3", feature = "source-i2s")
+))]
+compile_error!("differential-input is ADC1's, build it without adc3 or source-i2s");
+
+#[cfg(all(feature = "differential-input", feature = "dac-chirp"))]
+compile_error!("differential-input takes PA4, which dac-chirp plays out of");
+
+#[cfg(all(
+    feature = "capture-soak",
+    any(feature = "adc3", feature = "source-i2s")
+))]
+compile_error!("capture-soak is ADC1's one-shot captures, build it without adc3 or source-i2s");
+
+pub const SIZE: usize = 1024;
+
+/// What to log from boot, `Warn` to leave out the spectrum dump and the
+/// lines every capture logs. The console's `log` changes it while running,
+/// and `no-logging` compiles logging out altogether.
+const LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// How many times to retry a capture that DMA reports an error for
+pub const MAX_CAPTURE_ATTEMPTS: u32 = 3;
+
+/// ADC resolution. Everything downstream scales from what the ADC is set to.
+pub const ADC_RESOLUTION: adc::Resolution = adc::Resolution::SixteenBit;
+
+/// ADC reference voltage, VREF+ on the Nucleo is tied to 3.3V
+pub const ADC_VREF: f32 = 3.3;
+
+/// How ADC1's input is wired. With `differential-input` it's the pair
+/// `target::Adc1In` and `target::Adc1Negative`, reading -VREF to VREF.
+pub const ADC_INPUT: scaling::Input = if cfg!(feature = "differential-input") {
+    scaling::Input::Differential
+} else {
+    scaling::Input::SingleEnded
+};
+
+/// At 8 bits, read the ADC bytewise and pack two samples per half-word,
+/// halving the DMA bus traffic for very high sample rates
+const PACK_8BIT_SAMPLES: bool = false;
+
+/// ADC1's DMA stream priority, and its FIFO threshold for when the FIFO is
+/// on, which is only for packed samples. ADC3's BDMA has no FIFO, and
+/// ignores both.
+const DMA_PRIORITY: DmaPriority = DmaPriority::Medium;
+const FIFO_THRESHOLD: FifoThreshold = FifoThreshold::Quarter;
+/// Beats in each of ADC1's DMA bursts from the peripheral
+const DMA_BURST_BEATS: usize = 4;
+
+// Packed samples are read a byte at a time, so each burst is four bytes
+const _: () = assert!(
+    !PACK_8BIT_SAMPLES || FIFO_THRESHOLD.fits_burst(DMA_BURST_BEATS, 1),
+    "FIFO_THRESHOLD doesn't take whole bursts, so the stream would never run"
+);
+
+/// One-shot captures a buffer per `fill`. Continuous leaves ADC1's DMA
+/// running over a pool of buffers and queues each as it fills, so there's no
+/// gap between captures as long as processing keeps up on average; a slow
+/// one only drops buffers once the pool runs out. `fft_rtic` takes the
+/// buffers from its DMA task, so `rtic` is always continuous, and
+/// `slow_fft` decimates an unbroken stream, so `slow-stream` is too.
+#[cfg(not(any(feature = "rtic", feature = "slow-stream")))]
+const ACQUISITION_MODE: AcquisitionMode = AcquisitionMode::OneShot;
+#[cfg(any(feature = "rtic", feature = "slow-stream"))]
+const ACQUISITION_MODE: AcquisitionMode = AcquisitionMode::Continuous;
+
+// Continuous is only wired up for ADC1 streaming on its own
+const _: () = assert!(
+    !matches!(ACQUISITION_MODE, AcquisitionMode::Continuous)
+        || !(cfg!(feature = "adc3")
+            || cfg!(feature = "source-i2s")
+            || cfg!(feature = "dac-chirp")
+            || PACK_8BIT_SAMPLES
+            || INJECTED_REFERENCE),
+    "Continuous acquisition is ADC1 only, without dac-chirp, packing or the injected reference"
+);
+
+/// Sampling time on the input channel
+#[cfg(not(feature = "overrun-stress"))]
+const SAMPLE_TIME: SampleTime = SampleTime::T_32_5;
+#[cfg(feature = "overrun-stress")]
+const SAMPLE_TIME: SampleTime = SampleTime::T_1_5;
+
+/// How long to let the ADC settle after enabling it, in us. Anything under
+/// `acquisition::MIN_SETTLING_US` is raised to it.
+const SETTLING_US: u32 = acquisition::MIN_SETTLING_US;
+
+/// Conversions to throw away ahead of each capture, for absolute
+/// measurements that can't have the first few samples off
+const DISCARD_SAMPLES: usize = 0;
+
+/// Pace ADC1's conversions by a timer or a pin instead of free-running, one
+/// per trigger: the source, its edge, and the rate it runs at, which the
+/// capture timeout and the bins go by. Setting up the source is up to you,
+/// and it has to be running before a capture starts, e.g.
+/// `Some((ExternalTrigger::Tim2Trgo, TriggerEdge::Rising, 48_000.0))` with
+/// TIM2 sending TRGO on update. See `utilities::adc::set_trigger`.
+const EXTERNAL_TRIGGER: Option<(ExternalTrigger, TriggerEdge, f32)> = None;
+
+// ADC3's triggers are numbered differently, and the mic has none
+const _: () = assert!(
+    EXTERNAL_TRIGGER.is_none() || !(cfg!(feature = "adc3") || cfg!(feature = "source-i2s")),
+    "External triggers are only mapped for ADC1"
+);
+
+/// Convert a reference on the board's `Adc1Reference` pin as an injected
+/// channel once per capture, preempting the streamed samples rather than
+/// joining their scan
+pub const INJECTED_REFERENCE: bool = false;
+
+/// How many times longer than expected a capture may take before we give up
+const CAPTURE_TIMEOUT_FACTOR: f32 = 4.0;
+
+/// Spin or sleep while DMA fills the buffer. Interrupt frees up the CPU, and
+/// LowPower also clock-gates it to save energy on battery.
+const WAIT_MODE: WaitMode = WaitMode::BusyWait;
+
+/// With `debug-clocks`, divide the clock on MCO2 by this much. It's raised
+/// if need be to keep MCO2 under what the pin can drive.
+#[cfg(feature = "debug-clocks")]
+const MCO2_DIVIDER: u32 = 1;
+
+/// With `swo`, the baud to set the probe's SWO viewer to
+#[cfg(feature = "swo")]
+const SWO_BAUD: u32 = 2_000_000;
+
+/// Time to move the input between the calibration captures
+pub const CALIBRATION_PAUSE_MS: u32 = 10_000;
+
+/// What to set the RTC calendar to on a cold boot, when the backup domain
+/// has lost it. Set this to roughly now before flashing.
+const RTC_INITIAL_TIME: DateTime = DateTime {
+    year: 2024,
+    month: 1,
+    day: 1,
+    hour: 0,
+    minute: 0,
+    second: 0,
+    millisecond: 0,
+};
+
+/// With `source-i2s`, the rate to clock the mic at, and which slot its L/R
+/// pin puts it in
+#[cfg(feature = "source-i2s")]
+const MIC_SAMPLE_RATE_HZ: u32 = 48_000;
+#[cfg(feature = "source-i2s")]
+const MIC_SLOT: Slot = Slot::Left;
+
+/// With `low-power`, how long to sit in Stop between captures
+#[cfg(feature = "low-power")]
+pub const STOP_SECONDS: u16 = 10;
+
+/// With `dac-chirp`, the sweep played out of PA4 with each capture
+#[cfg(feature = "dac-chirp")]
+const CHIRP_START_HZ: f32 = 100.0;
+#[cfg(feature = "dac-chirp")]
+const CHIRP_END_HZ: f32 = 10_000.0;
+#[cfg(feature = "dac-chirp")]
+const CHIRP_MS: u32 = 100;
+
+/// Reset if the main loop hasn't come round in this long. That's a capture
+/// and all its retries at the slowest rate, plus saving it. The watchdog
+/// keeps counting in Stop, so with `low-power` it has to outlast that too.
+#[cfg(not(feature = "low-power"))]
+const WATCHDOG_MS: u32 = 8_000;
+#[cfg(feature = "low-power")]
+const WATCHDOG_MS: u32 = 8_000 + STOP_SECONDS as u32 * 1000;
+
+/// Where captures come from, as the features pick
+#[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+pub type Source = adc1::Adc1Source;
+#[cfg(feature = "adc3")]
+pub type Source = adc3::Adc3Source;
+#[cfg(feature = "source-i2s")]
+pub type Source = utilities::sai::Microphone;
+
+/// A user LED, see `set_led`
+pub type Led = ErasedPin<Output>;
+
+/// The user button, see `button_pressed`
+pub type Button = ErasedPin<Input>;
+
+/// What the capture loop learned about a finished capture
+pub struct CaptureInfo {
+    /// Only this many samples at the front of the buffer arrived
+    pub valid: usize,
+    /// An untrusted capture is processed but its spectrum isn't logged
+    pub trusted: bool,
+    /// Monotonic time the DMA finished
+    pub captured_at_us: u64,
+    /// Rate to turn bins into frequencies with
+    pub sample_rate_hz: f32,
+    /// CRC of the valid samples, taken as soon as DMA was stopped
+    pub buffer_crc: u32,
+    /// The die temperature just after, where the source has a sensor
+    pub die_degc: Option<f32>,
+    /// Counts to take off every sample for the offset's drift since it
+    /// was calibrated, see `OffsetDrift`
+    pub offset_correction: i32,
+}
+
+impl CaptureInfo {
+    /// Every sample arrived, and none were lost on the way
+    pub fn complete(&self) -> bool {
+        self.valid == SIZE && self.trusted
+    }
+}
+
+/// Everything `init` set up
+pub struct Board {
+    pub console: Console,
+    pub config: Config,
+    pub delay: Delay,
+    pub source: Source,
+    pub scale: AdcScale,
+    /// What the console may set
+    pub limits: Limits,
+    /// Whether 8-bit samples arrive two to a half-word, see `PACK_8BIT_SAMPLES`
+    pub packed: bool,
+    /// Off to start with
+    pub leds: [Led; target::LED_COUNT],
+    pub button: Button,
+    #[cfg(feature = "can")]
+    pub can: Option<utilities::can::CanBus>,
+    #[cfg(feature = "sd-card")]
+    pub sd: utilities::sd::SdLogger,
+    #[cfg(feature = "qspi-flash")]
+    pub flash: utilities::qspi::Flash,
+}
+
+/// Bring the board up and set up the capture source. With `calibrating`,
+/// there's time to ground the input before the ADC starts; the log says.
+pub fn init(calibrating: bool) -> Board {
+    let cp = cortex_m::Peripherals::take().unwrap();
+    let dp = pac::Peripherals::take().unwrap();
+    init_with(cp, dp, calibrating)
+}
+
+/// `init`, with peripherals something else took first, like RTIC's `init`
+pub fn init_with(mut cp: cortex_m::Peripherals, dp: pac::Peripherals, calibrating: bool) -> Board {
+    // Start up core systems!
+    crate::logger::init_with_level(LOG_LEVEL);
+    if utilities::reset_cause::init().is_watchdog() {
+        warn!("The watchdog reset the board, the last run hung");
+    }
+    #[cfg_attr(not(feature = "flash-store-test"), allow(unused_mut))]
+    let mut scb = cp.SCB;
+
+    // The cycle counter times out stuck captures
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+
+    // Constrain and Freeze power
+    info!("Setup PWR...                  ");
+    let power = configure_power(dp.PWR.constrain(), &dp.SYSCFG);
+    // Short of 480 MHz if the core couldn't get to VOS0 for it
+    let sys_ck = utilities::clocks::sys_ck(power.vos0);
+
+    // Constrain and Freeze clock
+    // Fun fact: if you do this wrong, it won't let you compile!
+    info!("Setup RCC...                  ");
+    let rcc = utilities::clocks::select_source(dp.RCC.constrain());
+
+    #[cfg(not(feature = "overrun-stress"))]
+    let pll2_p = 4096.kHz();
+    // Run the ADC kernel clock as fast as it goes to provoke overruns
+    #[cfg(feature = "overrun-stress")]
+    let pll2_p = 36.MHz();
+
+    let rcc = rcc.sys_ck(sys_ck).pll2_p_ck(pll2_p);
+
+    // Put the ADC kernel clock on MCO2 so it can be checked with a scope
+    #[cfg(feature = "debug-clocks")]
+    let mco2_prescaler = utilities::clocks::mco_prescaler(pll2_p.raw(), MCO2_DIVIDER);
+    #[cfg(feature = "debug-clocks")]
+    let rcc = rcc.mco2_from_pll2_p_ck(pll2_p / mco2_prescaler);
+
+    // SDMMC1's and FDCAN's kernel clock is PLL1 Q, which is otherwise left off
+    #[cfg(any(feature = "sd-card", feature = "can"))]
+    let rcc = rcc.pll1_q_ck(sys_ck / 2);
+
+    // SAI1's kernel clock is PLL3 P, 49.152 MHz divides to 48 kHz frames
+    #[cfg(feature = "source-i2s")]
+    let rcc = rcc.pll3_p_ck(49_152.kHz());
+
+    // SWO is clocked from PLL1 R, which is otherwise left off
+    #[cfg(feature = "swo")]
+    let rcc = rcc.pll1_r_ck(sys_ck / 2);
+
+    let ccdr = rcc.freeze(power.config, &dp.SYSCFG);
+
+    utilities::clocks::init(&ccdr.clocks);
+    #[cfg(feature = "swo")]
+    utilities::swo::init(&mut cp.ITM, &ccdr.clocks, SWO_BAUD);
+    utilities::monotonic::init(dp.TIM5, ccdr.peripheral.TIM5, &ccdr.clocks);
+    utilities::watchdog::init(dp.IWDG, WATCHDOG_MS);
+
+    // Wall clock time, which carries on through resets in the backup domain
+    let rtc_clock = utilities::rtc::init();
+    if !utilities::rtc::is_calendar_set() {
+        warn!("RTC calendar lost, setting it to {}", RTC_INITIAL_TIME);
+        utilities::rtc::set_date_time(&RTC_INITIAL_TIME);
+    }
+    info!("RTC on {:?}", rtc_clock);
+
+    // Every board has its pins on ports A to E, and the features take theirs
+    // from what's left, so not every build uses every port
+    #[allow(unused_variables)]
+    let (gpioa, gpiob, gpioc, gpiod, gpioe) = (
+        dp.GPIOA.split(ccdr.peripheral.GPIOA),
+        dp.GPIOB.split(ccdr.peripheral.GPIOB),
+        dp.GPIOC.split(ccdr.peripheral.GPIOC),
+        dp.GPIOD.split(ccdr.peripheral.GPIOD),
+        dp.GPIOE.split(ccdr.peripheral.GPIOE),
+    );
+    let pins = board_pins!(gpioa, gpiob, gpioc, gpiod, gpioe);
+    let mut leds = pins.leds;
+    for led in &mut leds {
+        set_led(led, false);
+    }
+
+    // Dropping the pin leaves it on MCO2
+    #[cfg(feature = "debug-clocks")]
+    {
+        let _ = gpioc.pc9.into_alternate::<0>().speed(Speed::VeryHigh);
+        info!(
+            "MCO2 on PC9: expect {} Hz (PLL2 P / {})",
+            ccdr.clocks.pll2_p_ck().unwrap().raw() / mco2_prescaler,
+            mco2_prescaler
+        );
+    }
+    let mut delay = Delay::new(cp.SYST, ccdr.clocks);
+    let sys_ck_hz = ccdr.clocks.sys_ck().raw();
+
+    // Settings can be changed from a terminal on the board's COM port
+    let (usart, usart_rec) = vcp_usart!(dp, ccdr);
+    #[cfg_attr(not(any(feature = "usb", feature = "ethernet")), allow(unused_mut))]
+    let mut console = utilities::console::init(usart, pins.vcp, usart_rec, &ccdr.clocks);
+
+    // Each capture's results go out on the CAN bus too
+    #[cfg(feature = "can")]
+    let can = utilities::can::init(
+        dp.FDCAN1,
+        (gpiod.pd0, gpiod.pd1),
+        ccdr.peripheral.FDCAN,
+        &ccdr.clocks,
+    );
+
+    // Noise for dither
+    utilities::rng::init(dp.RNG, ccdr.peripheral.RNG, &ccdr.clocks);
+
+    #[cfg(feature = "buzzer")]
+    utilities::buzzer::init(dp.TIM3, gpioc.pc6, ccdr.peripheral.TIM3, &ccdr.clocks);
+
+    // Frames go over USB instead of the console
+    #[cfg(feature = "usb")]
+    console.frames_over_usb(crate::transport::usb::init(
+        (dp.OTG2_HS_GLOBAL, dp.OTG2_HS_DEVICE, dp.OTG2_HS_PWRCLK),
+        (gpioa.pa11, gpioa.pa12),
+        ccdr.peripheral.USB2OTG,
+        dp.CRS,
+        ccdr.peripheral.CRS,
+        &ccdr.clocks,
+    ));
+
+    // Or as UDP datagrams
+    #[cfg(feature = "ethernet")]
+    console.frames_over_udp({
+        let gpiog = dp.GPIOG.split(ccdr.peripheral.GPIOG);
+        crate::transport::udp::init(
+            (dp.ETHERNET_MAC, dp.ETHERNET_MTL, dp.ETHERNET_DMA),
+            (
+                gpioa.pa1, gpioa.pa2, gpioc.pc1, gpioa.pa7, gpioc.pc4, gpioc.pc5, gpiog.pg11,
+                gpiog.pg13, gpiob.pb13,
+            ),
+            ccdr.peripheral.ETH1MAC,
+            &ccdr.clocks,
+        )
+    });
+
+    #[cfg(feature = "sd-card")]
+    let sd = utilities::sd::init(
+        dp.SDMMC1,
+        (gpioc.pc12, gpiod.pd2, gpioc.pc8, gpioc.pc9, gpioc.pc10, gpioc.pc11),
+        ccdr.peripheral.SDMMC1,
+        &ccdr.clocks,
+    );
+
+    // Lists what's stored as it starts
+    #[cfg(feature = "qspi-flash")]
+    let flash = utilities::qspi::init(
+        dp.QUADSPI,
+        (gpiob.pb2, gpiob.pb6, gpiod.pd11, gpiod.pd12, gpioe.pe2, gpiod.pd13),
+        ccdr.peripheral.QSPI,
+        &ccdr.clocks,
+    );
+    #[cfg(feature = "qspi-flash")]
+    let stored_slots = flash.slot_count();
+    #[cfg(not(feature = "qspi-flash"))]
+    let stored_slots = 0;
+
+    // Setup ADC
+    #[cfg(not(feature = "overrun-stress"))]
+    let adc_clock = 6114.kHz();
+    #[cfg(feature = "overrun-stress")]
+    let adc_clock = 36.MHz();
+
+    // Packing is only wired up for the DMA1 path
+    let packed = PACK_8BIT_SAMPLES
+        && utilities::adc::resolution_bits(ADC_RESOLUTION) == 8
+        && cfg!(not(feature = "adc3"));
+
+    // Checksums for protocol frames and the saved settings
+    utilities::crc::init(dp.CRC, ccdr.peripheral.CRC);
+
+    // Settings from the last `save`, kept in internal flash across power
+    // cycles, or the defaults
+    let saved = utilities::internal_flash::load_settings();
+    let config = saved.map_or_else(Config::new, |s| s.config);
+
+    // Per-board ADC correction, kept in backup SRAM across resets, which
+    // is newer than any saved to flash if it's there
+    utilities::backup::enable();
+    // What the last run was doing, if it hung or faulted
+    utilities::flight_recorder::recover(utilities::reset_cause::cause());
+    let calibration = match utilities::backup::load_calibration() {
+        Some(cal) => {
+            info!("Using stored calibration: {:?}", cal);
+            cal
+        }
+        None => match saved
+            .map(|s| s.calibration)
+            .filter(Calibration::is_plausible)
+        {
+            Some(cal) => {
+                info!("Using saved calibration: {:?}", cal);
+                cal
+            }
+            None => {
+                info!("No stored calibration, using raw counts");
+                Calibration::default()
+            }
+        },
+    };
+    // And how the offset drifts as the die warms, for the ADCs
+    #[cfg(not(feature = "source-i2s"))]
+    let drift = match utilities::backup::load_drift() {
+        Some(drift) => {
+            info!("Using stored offset drift: {:?}", drift);
+            drift
+        }
+        None => match saved.map(|s| s.drift).filter(OffsetDrift::is_plausible) {
+            Some(drift) => {
+                info!("Using saved offset drift: {:?}", drift);
+                drift
+            }
+            None => {
+                info!("No stored offset drift, send `cal` to measure it");
+                OffsetDrift::NONE
+            }
+        },
+    };
+
+    // Each save writes both slots over, so only with nothing worth keeping
+    #[cfg(feature = "flash-store-test")]
+    {
+        let settings = crate::config_store::Settings {
+            config,
+            calibration,
+            #[cfg(not(feature = "source-i2s"))]
+            drift,
+            #[cfg(feature = "source-i2s")]
+            drift: Default::default(),
+        };
+        utilities::internal_flash::corruption_test(&settings, &mut scb);
+    }
+
+    // MDMA for moving buffers around
+    ccdr.peripheral.MDMA.enable();
+
+    #[cfg(feature = "low-power")]
+    utilities::low_power::init();
+
+    // There's nothing to calibrate on the mic
+    #[cfg(not(feature = "source-i2s"))]
+    if calibrating {
+        info!(
+            "Calibrating: ground the input within {} s",
+            CALIBRATION_PAUSE_MS / 1000
+        );
+        #[cfg(feature = "differential-input")]
+        info!("Both of the pair, and keep the negative grounded for the reference");
+        utilities::watchdog::delay_ms(&mut delay, CALIBRATION_PAUSE_MS);
+    }
+
+    // Capture with ADC1 and DMA1 into AXISRAM
+    #[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+    let (source, scale, limits) = {
+        // Shenanigans to link in a buffer from the .axisram section
+        #[cfg(not(feature = "dma-error-test"))]
+        let adc_buffer: &'static mut [u16; SIZE] =
+            dma_buffer!(".axisram", u16, SIZE);
+
+        // DMA1 can't reach DTCM, so a buffer there makes every transfer fail.
+        // This exists purely to prove the error and retry path works.
+        #[cfg(feature = "dma-error-test")]
+        let adc_buffer: &'static mut [u16; SIZE] = {
+            static mut DTCM_BUFFER: [u16; SIZE] = [0; SIZE];
+            warn!("dma-error-test: capturing into DTCM, expect DMA errors");
+            unsafe { &mut *core::ptr::addr_of_mut!(DTCM_BUFFER) }
+        };
+
+        let adc1 = adc::Adc::adc1(
+            dp.ADC1,
+            adc_clock,
+            &mut delay,
+            ccdr.peripheral.ADC12,
+            &ccdr.clocks,
+        );
+        // Only while it's still disabled
+        #[cfg(feature = "differential-input")]
+        let adc1 = utilities::adc::into_differential(adc1, &pins.adc1_in, &pins.adc1_negative);
+        let mut adc1 = adc1.enable();
+        let scale = utilities::adc::set_resolution_scaled(&mut adc1, ADC_RESOLUTION, ADC_VREF)
+            .with_input(ADC_INPUT)
+            .with_calibration(calibration);
+        let (acq, timeout) =
+            acquisition_for(adc1.clock_frequency().raw(), sys_ck_hz, config.rate_hz);
+        utilities::adc::settle(acq.settling_us, sys_ck_hz);
+        let limits = limits_for(&acq, &scale, stored_slots);
+
+        // ADC3 is otherwise unused here, so it reads the die temperature
+        let mut thermometer = adc::Adc::adc3(
+            dp.ADC3,
+            adc_clock,
+            &mut delay,
+            ccdr.peripheral.ADC3,
+            &ccdr.clocks,
+        )
+        .enable();
+        thermometer.set_resolution(adc::Resolution::SixteenBit);
+        let sensor_scale = AdcScale::new(16, ADC_VREF);
+
+        let channel = pins.adc1_in;
+
+        let mut reference = pins.adc1_reference;
+        if INJECTED_REFERENCE {
+            utilities::adc::configure_injected(
+                &mut adc1,
+                &mut reference,
+                SampleTime::T_64_5,
+                InjectedTrigger::Software,
+            );
+        }
+
+        // 4-beat bursts can be used, see `DMA_BURST_BEATS`
+        #[cfg(not(feature = "overrun-stress"))]
+        let dma_config = DmaConfig::default()
+            .memory_increment(true)
+            .priority(utilities::dma::hal_priority(acq.dma_priority))
+            .fifo_enable(packed)
+            .fifo_threshold(utilities::dma::hal_fifo_threshold(acq.fifo_threshold))
+            .peripheral_burst(BurstMode::Burst4);
+
+        // Single transfers and the fastest conversions we can get, so DMA falls
+        // behind and the overrun path gets exercised
+        #[cfg(feature = "overrun-stress")]
+        let dma_config = {
+            warn!("overrun-stress: expect ADC overruns");
+            DmaConfig::default().memory_increment(true)
+        };
+
+        // Setup the DMA transfer on stream 0
+        let streams = StreamsTuple::new(dp.DMA1, ccdr.peripheral.DMA1);
+        #[cfg(feature = "dac-chirp")]
+        let stimulus = utilities::dac::init(
+            dp.DAC,
+            gpioa.pa4.into_analog(),
+            ccdr.peripheral.DAC12,
+            streams.1,
+            dp.TIM6,
+            ccdr.peripheral.TIM6,
+            &ccdr.clocks,
+            &mut delay,
+        );
+        // Only continuous acquisition takes its buffers from the pool
+        let reader = match ACQUISITION_MODE {
+            AcquisitionMode::OneShot => None,
+            AcquisitionMode::Continuous => Some(utilities::dma::init_pool(dma_buffer!(
+                ".axisram",
+                [u16; SIZE],
+                utilities::dma::POOL_SIZE
+            ))),
+        };
+        let source = adc1::Adc1Source {
+            parts: Some((streams.0, adc1, adc_buffer)),
+            running: None,
+            parked: None,
+            reader,
+            channel,
+            dma_config,
+            packed,
+            acq,
+            timeout,
+            sys_ck_hz,
+            stats: RateStats::new(),
+            scb,
+            started_at: 0,
+            captured_at: 0,
+            buffer_crc: 0,
+            thermometer,
+            sensor_scale,
+            drift,
+            #[cfg(feature = "dac-chirp")]
+            stimulus,
+        };
+        (source, scale, limits)
+    };
+
+    // Capture with ADC3 and BDMA into SRAM4. Both live in the D3 domain, so
+    // this keeps working in low-power configurations where DMA1 doesn't.
+    #[cfg(feature = "adc3")]
+    let (source, scale, limits) = {
+        const _: () = assert!(
+            SIZE * core::mem::size_of::<u16>() <= utilities::buffer::SRAM4_BYTES,
+            "SIZE is too large for a capture buffer in SRAM4"
+        );
+        let adc_buffer: &'static mut [u16; SIZE] = dma_buffer!(".sram4", u16, SIZE);
+
+        let mut adc3 = adc::Adc::adc3(
+            dp.ADC3,
+            adc_clock,
+            &mut delay,
+            ccdr.peripheral.ADC3,
+            &ccdr.clocks,
+        )
+        .enable();
+        let scale = utilities::adc::set_resolution_scaled(&mut adc3, ADC_RESOLUTION, ADC_VREF)
+            .with_calibration(calibration);
+        let (acq, timeout) =
+            acquisition_for(adc3.clock_frequency().raw(), sys_ck_hz, config.rate_hz);
+        utilities::adc::settle(acq.settling_us, sys_ck_hz);
+        let limits = limits_for(&acq, &scale, stored_slots);
+
+        let channel = pins.adc3_in;
+
+        let dma_config = BdmaConfig::default().memory_increment(true);
+
+        // Setup the BDMA transfer on channel 0
+        let streams = BdmaStreamsTuple::new(dp.BDMA, ccdr.peripheral.BDMA);
+        let mut source = adc3::Adc3Source {
+            parts: Some((streams.0, adc3, adc_buffer)),
+            channel,
+            dma_config,
+            acq,
+            timeout,
+            sys_ck_hz,
+            stats: RateStats::new(),
+            scb,
+            started_at: 0,
+            captured_at: 0,
+            buffer_crc: 0,
+            sensor_scale: scale,
+            drift,
+        };
+        match source.vbat_volts(&scale) {
+            Some(volts) => info!("VBAT: {} V", volts),
+            None => warn!("VBAT: conversion failed"),
+        }
+        (source, scale, limits)
+    };
+
+    // Capture from an I2S mic on SAI1, with DMA2 into AXISRAM
+    #[cfg(feature = "source-i2s")]
+    let (source, scale, limits) = {
+        let mic = utilities::sai::init(
+            dp.SAI1,
+            dp.DMA2,
+            (gpioe.pe5, gpioe.pe4, gpioe.pe6),
+            ccdr.peripheral.SAI1,
+            ccdr.peripheral.DMA2,
+            &ccdr.clocks,
+            MIC_SAMPLE_RATE_HZ,
+            MIC_SLOT,
+        );
+        // Signed 24-bit counts, which aren't volts of anything
+        let scale = AdcScale::new(24, 1.0);
+        // The rate is the mic's, and with no volts there's no trigger level.
+        // Stored captures came from the ADC, so none are offered.
+        let limits = Limits {
+            min_rate_hz: mic.sample_rate_hz(),
+            max_rate_hz: mic.sample_rate_hz(),
+            min_volts: 0.0,
+            full_scale_volts: 0.0,
+            stored_slots: 0,
+        };
+        (mic, scale, limits)
+    };
+
+    Board {
+        console,
+        config,
+        delay,
+        source,
+        scale,
+        limits,
+        packed,
+        leds,
+        button: pins.button,
+        #[cfg(feature = "can")]
+        can,
+        #[cfg(feature = "sd-card")]
+        sd,
+        #[cfg(feature = "qspi-flash")]
+        flash,
+    }
+}
+
+/// Light or put out a user LED, whichever level that takes on this board
+pub fn set_led(led: &mut Led, on: bool) {
+    if on != target::LED_ACTIVE_LOW {
+        led.set_high();
+    } else {
+        led.set_low();
+    }
+}
+
+/// Whether the user button is held down
+pub fn button_pressed(button: &Button) -> bool {
+    button.is_high() != target::BUTTON_ACTIVE_LOW
+}
+
+/// Acquisition settings for an ADC the HAL has clocked at `adc_clock_hz`,
+/// sampling as close to `rate_hz` as it can if that's set, and the capture
+/// timeout that goes with them
+fn acquisition_for(
+    adc_clock_hz: u32,
+    sys_ck_hz: u32,
+    rate_hz: Option<f32>,
+) -> (AcquisitionConfig, u32) {
+    let mut acq =
+        AcquisitionConfig::new(adc_clock_hz, utilities::adc::resolution_bits(ADC_RESOLUTION))
+            .channel(ChannelConfig::new(SAMPLE_TIME))
+            .mode(ACQUISITION_MODE)
+            .dma_priority(DMA_PRIORITY)
+            .fifo_threshold(FIFO_THRESHOLD)
+            .settling_us(SETTLING_US)
+            .discard_samples(DISCARD_SAMPLES);
+    if let Some(hz) = rate_hz {
+        acq = acq.nearest_rate(hz);
+    }
+    if let Some((source, edge, trigger_hz)) = EXTERNAL_TRIGGER {
+        acq = acq.external_trigger(source, edge).trigger_rate_hz(trigger_hz);
+    }
+    acq.validate();
+
+    // Derived from the clock the ADC actually got, so it's as accurate as the
+    // clock source and nothing downstream assumes a nominal rate. With an
+    // external trigger, it's the trigger's, as conversions wait for it.
+    let sample_rate_hz = acq.effective_rate_hz(None);
+    info!(
+        "Sample rate: {} Hz from {:?}",
+        sample_rate_hz,
+        utilities::clocks::source()
+    );
+
+    let timeout = utilities::dma::timeout_cycles(
+        SIZE,
+        sample_rate_hz,
+        sys_ck_hz,
+        CAPTURE_TIMEOUT_FACTOR,
+    );
+    (acq, timeout)
+}
+
+/// What the console may set, from the ADC's clock and scale and how many
+/// flash slots there are to dump
+fn limits_for(acq: &AcquisitionConfig, scale: &AdcScale, stored_slots: u8) -> Limits {
+    let (min_rate_hz, max_rate_hz) = acq.rate_range_hz();
+    Limits {
+        min_rate_hz,
+        max_rate_hz,
+        min_volts: scale.min_volts(),
+        full_scale_volts: scale.full_scale_volts(),
+        stored_slots,
+    }
+}
+
+/// Fold a capture's timing into the sample rate measurement and log it
+/// against the configured rate. `elapsed_us` is from starting the transfer
+/// to DMA completing, only for a capture that converted the whole buffer.
+/// Returns the rate to turn bins into frequencies with.
+fn track_sample_rate(
+    stats: &mut RateStats,
+    acq: &AcquisitionConfig,
+    elapsed_us: Option<u64>,
+) -> f32 {
+    let configured = acq.trigger_rate_hz.unwrap_or_else(|| acq.max_conversion_rate_hz());
+
+    if let Some(measured) = elapsed_us.and_then(|us| acquisition::measured_rate_hz(SIZE, us)) {
+        stats.push(measured);
+        info!(
+            "Sample rate: configured {} Hz, measured {} Hz (mean {} Hz, jitter {} Hz over {})",
+            configured,
+            measured,
+            stats.mean_hz().unwrap_or(measured),
+            stats.jitter_hz(),
+            stats.count()
+        );
+        if acquisition::rate_mismatch(measured, configured) {
+            warn!(
+                "Measured sample rate is more than {}% off the configured rate",
+                acquisition::RATE_TOLERANCE * 100.0
+            );
+        }
+    }
+
+    acq.effective_rate_hz(stats.mean_hz())
+}
+
+/// The ADC sources share these, and only one of them is ever built, so they
+/// share a type too
+#[cfg(not(feature = "source-i2s"))]
+impl Source {
+    /// Capture into `raw`, and say how it went. A partial or overrun capture
+    /// is carried on with, and only a complete one is timed.
+    pub fn capture(&mut self, raw: &mut [u16; SIZE]) -> CaptureInfo {
+        let result = self.fill(raw);
+        let (valid, trusted) = Self::outcome(result);
+        let elapsed = (valid == SIZE && trusted).then(|| self.captured_at - self.started_at);
+        let die_degc = self.die_degc();
+        if die_degc.is_none() {
+            warn!("Die temperature conversion failed, not correcting the offset");
+        }
+        CaptureInfo {
+            valid,
+            trusted,
+            captured_at_us: self.captured_at,
+            sample_rate_hz: track_sample_rate(&mut self.stats, &self.acq, elapsed),
+            buffer_crc: self.buffer_crc,
+            die_degc,
+            // Without a temperature there's nothing to correct for
+            offset_correction: die_degc.map_or(0, |degc| self.drift.correction_counts(degc)),
+        }
+    }
+
+    /// Start correcting captures for `drift`, e.g. `OffsetDrift::NONE`
+    /// while measuring it
+    pub fn set_drift(&mut self, drift: OffsetDrift) {
+        self.drift = drift;
+    }
+
+    /// What captures are being corrected for, to save
+    pub fn drift(&self) -> OffsetDrift {
+        self.drift
+    }
+
+    /// Pick up a new rate from the console: rebuild the acquisition around
+    /// it, and start measuring afresh as the old measurements are of another
+    /// rate
+    pub fn retune(&mut self, console: &mut Console, config: &Config) {
+        // A running continuous acquisition restarts at the new rate
+        #[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+        self.halt();
+        (self.acq, self.timeout) =
+            acquisition_for(self.acq.adc_clock_hz, self.sys_ck_hz, config.rate_hz);
+        self.stats = RateStats::new();
+        console.reply(format_args!(
+            "rate {} S/s with {:?} sampling",
+            self.acq.max_conversion_rate_hz(),
+            self.acq.channel.sample_time
+        ));
+    }
+
+    /// How many captures the sample rate's been measured over
+    pub fn rate_captures(&self) -> u32 {
+        self.stats.count()
+    }
+
+    /// For MDMA, and sleeping between captures
+    pub fn scb(&mut self) -> &mut SCB {
+        &mut self.scb
+    }
+}
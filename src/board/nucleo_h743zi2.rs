@@ -0,0 +1,112 @@
+//! NUCLEO-H743ZI2: the analog inputs on the Arduino headers, LD1-LD3, B1,
+//! the ST-LINK's 8 MHz MCO as HSE and its virtual COM port on USART3.
+
+#[cfg(not(feature = "differential-input"))]
+use stm32h7xx_hal::gpio::PA3;
+use stm32h7xx_hal::gpio::{Alternate, Analog, PA6, PC0, PD8, PD9};
+#[cfg(feature = "differential-input")]
+use stm32h7xx_hal::gpio::{PA4, PA5};
+use stm32h7xx_hal::pac::{self, interrupt, Interrupt};
+use stm32h7xx_hal::pwr::Pwr;
+use stm32h7xx_hal::rcc::rec;
+use stm32h7xx_hal::time::Hertz;
+
+use super::{Button, Led, Supply};
+use crate::utilities;
+
+/// ADC1's input, A0 on CN9 (ADC1 INP15)
+#[cfg(not(feature = "differential-input"))]
+pub type Adc1In = PA3<Analog>;
+/// ADC1's input with `differential-input`, the positive of its pair, on
+/// CN7 (ADC1 INP18), as PA3 has no negative to go with it
+#[cfg(feature = "differential-input")]
+pub type Adc1In = PA4<Analog>;
+/// The pair's negative (ADC1 INN18)
+#[cfg(feature = "differential-input")]
+pub type Adc1Negative = PA5<Analog>;
+/// ADC1's injected reference, D12 on CN7 (ADC1 INP3)
+pub type Adc1Reference = PA6<Analog>;
+/// ADC3's input, A1 on CN9 (ADC3 INP10)
+pub type Adc3In = PC0<Analog>;
+
+/// LD1 (green), LD2 (yellow) and LD3 (red)
+pub const LED_COUNT: usize = 3;
+pub const LED_ACTIVE_LOW: bool = false;
+/// B1 pulls PC13 up to VDD when pressed
+pub const BUTTON_ACTIVE_LOW: bool = false;
+
+/// The ST-LINK supplies 8 MHz on MCO, so HSE is bypassed
+pub const HSE: Hertz = Hertz::MHz(8);
+pub const HSE_BYPASS: bool = true;
+
+/// The ST-LINK's virtual COM port
+pub type VcpUsart = pac::USART3;
+pub type VcpRec = rec::Usart3;
+pub type VcpPins = (PD8<Alternate<7>>, PD9<Alternate<7>>);
+pub const VCP_NAME: &str = "USART3";
+pub const VCP_INTERRUPT: Interrupt = Interrupt::USART3;
+
+/// What `board_pins!` takes out of the GPIO ports
+pub struct Pins {
+    #[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+    pub adc1_in: Adc1In,
+    #[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+    pub adc1_reference: Adc1Reference,
+    #[cfg(feature = "differential-input")]
+    pub adc1_negative: Adc1Negative,
+    #[cfg(feature = "adc3")]
+    pub adc3_in: Adc3In,
+    pub leds: [Led; LED_COUNT],
+    pub button: Button,
+    pub vcp: VcpPins,
+}
+
+/// Take the board's pins out of the split GPIO ports
+macro_rules! board_pins {
+    ($gpioa:ident, $gpiob:ident, $gpioc:ident, $gpiod:ident, $gpioe:ident) => {
+        target::Pins {
+            #[cfg(all(
+                not(feature = "adc3"),
+                not(feature = "source-i2s"),
+                not(feature = "differential-input")
+            ))]
+            adc1_in: $gpioa.pa3.into_analog(),
+            #[cfg(feature = "differential-input")]
+            adc1_in: $gpioa.pa4.into_analog(),
+            #[cfg(feature = "differential-input")]
+            adc1_negative: $gpioa.pa5.into_analog(),
+            #[cfg(all(not(feature = "adc3"), not(feature = "source-i2s")))]
+            adc1_reference: $gpioa.pa6.into_analog(),
+            #[cfg(feature = "adc3")]
+            adc3_in: $gpioc.pc0.into_analog(),
+            leds: [
+                $gpiob.pb0.into_push_pull_output().erase(),
+                $gpioe.pe1.into_push_pull_output().erase(),
+                $gpiob.pb14.into_push_pull_output().erase(),
+            ],
+            button: $gpioc.pc13.into_input().erase(),
+            vcp: ($gpiod.pd8.into_alternate(), $gpiod.pd9.into_alternate()),
+        }
+    };
+}
+
+/// The VCP's UART and its clock record
+macro_rules! vcp_usart {
+    ($dp:ident, $ccdr:ident) => {
+        ($dp.USART3, $ccdr.peripheral.USART3)
+    };
+}
+
+/// The H743 has no SMPS, so the core runs from the LDO
+pub const SUPPLY: Supply = Supply::Ldo;
+
+/// Set PWR up for `SUPPLY`. The LDO is how it comes out of reset, so
+/// there's nothing to do.
+pub fn apply_supply(pwr: Pwr) -> Pwr {
+    pwr
+}
+
+#[interrupt]
+fn USART3() {
+    utilities::console::on_receive();
+}
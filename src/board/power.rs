@@ -0,0 +1,79 @@
+//! The core's supply and voltage scaling, which `init` has to settle before
+//! the clocks. The supply is a property of the board, and VOS0 is only
+//! asked for by the 480 MHz profile.
+
+use log::{info, warn};
+
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::pwr::{PowerConfiguration, Pwr};
+
+use super::target;
+
+/// DBGMCU_IDC's REV_ID for revision V silicon, the only one with VOS0
+const REV_ID_V: u32 = 0x2003;
+
+const SYSCFG_PWRCR_ODEN: u32 = 1 << 0;
+
+/// How the board supplies the core
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Supply {
+    /// The internal LDO, as out of reset. VOS0 is only available on it.
+    Ldo,
+    /// VCORE driven from outside, with the LDO off
+    Bypass,
+}
+
+/// What `configure_power` applied, ready for the RCC's `freeze`
+pub struct PowerConfigured {
+    pub config: PowerConfiguration,
+    /// The core is at VOS0, so 480 MHz is allowed
+    pub vos0: bool,
+}
+
+/// Apply the board's supply configuration, and VOS0 if the clock profile
+/// wants it, and log what that came to. VOS0 needs the LDO and revision V
+/// silicon; without either this warns and leaves the core at VOS1, and
+/// `PowerConfigured::vos0` tells the clocks to slow down to suit rather
+/// than the RCC failing to freeze.
+#[cfg_attr(not(feature = "clock-480"), allow(unused_variables))]
+pub fn configure_power(pwr: Pwr, syscfg: &pac::SYSCFG) -> PowerConfigured {
+    let pwr = target::apply_supply(pwr);
+
+    let vos0_possible = target::SUPPLY == Supply::Ldo && revision_id() == REV_ID_V;
+    let vos0 = cfg!(feature = "clock-480") && vos0_possible;
+    if cfg!(feature = "clock-480") && !vos0_possible {
+        warn!(
+            "480 MHz needs VOS0, which a {:?} supply on silicon revision {:#06x} can't \
+             give, so running slower",
+            target::SUPPLY,
+            revision_id()
+        );
+    }
+
+    #[cfg(feature = "clock-480")]
+    let pwr = if vos0 { pwr.vos0(syscfg) } else { pwr };
+    let config = pwr.freeze();
+
+    info!("Power: {:?} supply, VOS{}", target::SUPPLY, vos_level());
+    PowerConfigured { config, vos0 }
+}
+
+fn revision_id() -> u32 {
+    // Safety: read only
+    let dbgmcu = unsafe { &*pac::DBGMCU::ptr() };
+    dbgmcu.idc.read().bits() >> 16
+}
+
+/// The voltage scale PWR and SYSCFG have the core at, 0 to 3
+fn vos_level() -> u8 {
+    // Safety: read only
+    let (pwr, syscfg) = unsafe { (&*pac::PWR::ptr(), &*pac::SYSCFG::ptr()) };
+    if syscfg.pwrcr.read().bits() & SYSCFG_PWRCR_ODEN != 0 {
+        return 0;
+    }
+    match (pwr.d3cr.read().bits() >> 14) & 0b11 {
+        0b11 => 1,
+        0b10 => 2,
+        _ => 3,
+    }
+}
@@ -0,0 +1,190 @@
+//! What goes on the CAN bus: bit timings for the FDCAN kernel clock, and the
+//! 8-byte payloads a capture's results are packed into.
+//!
+//! Every field is little-endian. A peak message is
+//!
+//! ```text
+//! 0..4  peak frequency, u32 mHz
+//! 4..6  peak level, i16 centi-dBFS (-12.34 dBFS is -1234)
+//! 6     capture counter, u8, wrapping
+//! 7     flags, bit 0 set if the capture is trusted
+//! ```
+//!
+//! and a bands message is four i16 centi-dBFS levels, the first message the
+//! lowest four bands. Slots past the last band hold `NO_LEVEL`, as does a
+//! band with no power, whose level in dB is minus infinity.
+
+/// Centi-dBFS for a level that isn't there
+pub const NO_LEVEL: i16 = i16::MIN;
+
+/// Band levels per bands message
+pub const BANDS_PER_MESSAGE: usize = 4;
+
+/// Nominal bit timing, in the fields FDCAN's NBTP register takes. A bit is
+/// `1 + seg1 + seg2` time quanta of `prescaler` kernel clocks each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitTiming {
+    pub prescaler: u16,
+    pub seg1: u8,
+    pub seg2: u8,
+    pub sync_jump_width: u8,
+}
+
+impl BitTiming {
+    /// Fraction of the bit at which it's sampled
+    pub fn sample_point(&self) -> f32 {
+        (1 + self.seg1 as u32) as f32 / self.quanta() as f32
+    }
+
+    fn quanta(&self) -> u32 {
+        1 + self.seg1 as u32 + self.seg2 as u32
+    }
+}
+
+/// A timing that makes `bitrate` exactly from `kernel_hz`, sampling close
+/// to 87.5% of the way through the bit as CANopen recommends. Prefers more
+/// quanta per bit, for a finer sample point. `None` if no prescaler divides
+/// evenly.
+pub fn bit_timing(kernel_hz: u32, bitrate: u32) -> Option<BitTiming> {
+    if bitrate == 0 {
+        return None;
+    }
+    (8..=25u32).rev().find_map(|quanta| {
+        let clocks = bitrate.checked_mul(quanta)?;
+        if !kernel_hz.is_multiple_of(clocks) {
+            return None;
+        }
+        let prescaler = kernel_hz / clocks;
+        if !(1..=512).contains(&prescaler) {
+            return None;
+        }
+        let seg2 = (quanta + 4) / 8;
+        Some(BitTiming {
+            prescaler: prescaler as u16,
+            seg1: (quanta - 1 - seg2) as u8,
+            seg2: seg2 as u8,
+            sync_jump_width: seg2 as u8,
+        })
+    })
+}
+
+/// A level in dB as centi-dB, saturating at the ends of `i16`. Minus
+/// infinity and NaN, which an empty band gives, are `NO_LEVEL`.
+pub fn centi_db(db: f32) -> i16 {
+    if db.is_nan() {
+        return NO_LEVEL;
+    }
+    // `as` saturates, so -inf lands on i16::MIN, which is NO_LEVEL
+    let centi = db * 100.0;
+    let rounded = if centi < 0.0 {
+        centi - 0.5
+    } else {
+        centi + 0.5
+    };
+    rounded as i16
+}
+
+/// The peak message for a capture
+pub fn peak_payload(peak_hz: f32, level_dbfs: f32, counter: u8, trusted: bool) -> [u8; 8] {
+    // Negative or NaN frequencies saturate to 0
+    let millihertz = (peak_hz * 1000.0 + 0.5) as u32;
+
+    let mut payload = [0; 8];
+    payload[0..4].copy_from_slice(&millihertz.to_le_bytes());
+    payload[4..6].copy_from_slice(&centi_db(level_dbfs).to_le_bytes());
+    payload[6] = counter;
+    payload[7] = trusted as u8;
+    payload
+}
+
+/// Band levels in dBFS, packed `BANDS_PER_MESSAGE` to a message
+pub fn band_payloads(levels_dbfs: &[f32]) -> impl Iterator<Item = [u8; 8]> + '_ {
+    levels_dbfs.chunks(BANDS_PER_MESSAGE).map(|levels| {
+        let mut payload = [0; 8];
+        for (i, out) in payload.chunks_exact_mut(2).enumerate() {
+            let level = levels.get(i).map_or(NO_LEVEL, |&db| centi_db(db));
+            out.copy_from_slice(&level.to_le_bytes());
+        }
+        payload
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timings_divide_the_kernel_clock_exactly() {
+        // SYS_CK / 2 for each clock profile
+        for kernel_hz in [48_000_000, 100_000_000, 200_000_000, 240_000_000] {
+            let timing = bit_timing(kernel_hz, 500_000).unwrap();
+            let bit_clocks = timing.prescaler as u32 * timing.quanta();
+            assert_eq!(kernel_hz / bit_clocks, 500_000, "{kernel_hz}");
+            assert_eq!(kernel_hz % bit_clocks, 0, "{kernel_hz}");
+            let sample_point = timing.sample_point();
+            assert!((0.8..=0.9).contains(&sample_point), "{timing:?}");
+        }
+
+        assert_eq!(
+            bit_timing(48_000_000, 500_000),
+            Some(BitTiming {
+                prescaler: 4,
+                seg1: 20,
+                seg2: 3,
+                sync_jump_width: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn unreachable_bitrates_have_no_timing() {
+        // Not a whole number of clocks per bit
+        assert_eq!(bit_timing(7_000_001, 500_000), None);
+        assert_eq!(bit_timing(48_000_000, 0), None);
+        // Would need a prescaler over 512
+        assert_eq!(bit_timing(240_000_000, 10_000), None);
+    }
+
+    #[test]
+    fn levels_round_and_saturate() {
+        assert_eq!(centi_db(-12.34), -1234);
+        assert_eq!(centi_db(-12.345_6), -1235);
+        assert_eq!(centi_db(0.004), 0);
+        assert_eq!(centi_db(400.0), i16::MAX);
+        assert_eq!(centi_db(f32::NEG_INFINITY), NO_LEVEL);
+        assert_eq!(centi_db(f32::NAN), NO_LEVEL);
+    }
+
+    #[test]
+    fn peak_payload_layout() {
+        let payload = peak_payload(1_000.25, -6.02, 7, true);
+        assert_eq!(
+            u32::from_le_bytes(payload[0..4].try_into().unwrap()),
+            1_000_250
+        );
+        assert_eq!(i16::from_le_bytes([payload[4], payload[5]]), -602);
+        assert_eq!(payload[6], 7);
+        assert_eq!(payload[7], 1);
+
+        assert_eq!(peak_payload(-1.0, 0.0, 0, false)[0..4], [0; 4]);
+    }
+
+    #[test]
+    fn bands_are_packed_four_to_a_message() {
+        let levels = [-1.0, -2.0, -3.0, -4.0, -5.0, f32::NEG_INFINITY];
+        let payloads: Vec<[u8; 8]> = band_payloads(&levels).collect();
+        assert_eq!(payloads.len(), 2);
+
+        let level =
+            |payload: &[u8; 8], i: usize| i16::from_le_bytes([payload[2 * i], payload[2 * i + 1]]);
+        assert_eq!(level(&payloads[0], 0), -100);
+        assert_eq!(level(&payloads[0], 3), -400);
+        assert_eq!(level(&payloads[1], 0), -500);
+        // An empty band, then slots past the end
+        assert_eq!(level(&payloads[1], 1), NO_LEVEL);
+        assert_eq!(level(&payloads[1], 2), NO_LEVEL);
+        assert_eq!(level(&payloads[1], 3), NO_LEVEL);
+
+        assert_eq!(band_payloads(&[]).count(), 0);
+    }
+}
@@ -0,0 +1,263 @@
+//! The capture loop's control flow as a state machine, so the console, the
+//! button and the capture's own outcome all feed it the same way.
+//!
+//! ```text
+//! Idle --Start/Button--> Armed --Armed--> Capturing --Captured--> Processing
+//!                                             |                      |
+//!                                       CaptureFailed            Processed
+//!                                             v                      v
+//!                                           Error                Outputting
+//!
+//! Error --Recovered-->, Processing --Skipped--> and Outputting --OutputDone-->
+//! go back to Armed for the next capture, or to Idle if a stop came in.
+//! Armed --Stop/Button--> Idle, as nothing's been started.
+//! ```
+//!
+//! Each state has exactly one `Action` for the loop to carry out, and the
+//! loop reports how it went as the next `Event`. An event that means
+//! nothing in the current state leaves it where it is, so there's no
+//! transition that can't happen. A stop part way through a capture lets it
+//! finish first, the way the console's `stop` always has.
+
+use log::debug;
+
+/// Where the capture loop is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Stopped, waiting to be started
+    Idle,
+    /// Started, with settings to apply before the next capture
+    Armed,
+    Capturing,
+    Processing,
+    /// Keeping the result: storage, calibration and the status
+    Outputting,
+    /// The capture failed, and the source needs starting over
+    Error,
+}
+
+/// Something the loop, the console or the button reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// `start` on the console
+    Start,
+    /// `stop` on the console, or the loop deciding it's done
+    Stop,
+    /// The button, which starts when stopped and stops otherwise
+    Button,
+    /// The settings are applied and the source is ready
+    Armed,
+    Captured,
+    /// The source gave up, every attempt failed
+    CaptureFailed,
+    Processed,
+    /// Processed, but nothing to keep: no samples, or not triggered
+    Skipped,
+    OutputDone,
+    /// The source has been started over after an error
+    Recovered,
+}
+
+/// What the loop should do next, one for each `State`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Wait on the console and the button
+    Wait,
+    /// Apply the settings, e.g. retune to a new rate, then report `Armed`
+    Arm,
+    /// Capture, then report `Captured` or `CaptureFailed`
+    Capture,
+    /// Process the capture, then report `Processed` or `Skipped`
+    Process,
+    /// Keep the result, then report `OutputDone`
+    Output,
+    /// Start the source over, then report `Recovered`
+    Recover,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaptureStateMachine {
+    state: State,
+    /// A stop arrived mid-capture, so go idle once it's finished with
+    stop_pending: bool,
+}
+
+impl CaptureStateMachine {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Idle,
+            stop_pending: false,
+        }
+    }
+
+    /// Start in `state`, e.g. `Armed` to capture straight after boot
+    pub const fn starting_in(state: State) -> Self {
+        Self {
+            state,
+            stop_pending: false,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Whether it'll carry on to another capture, rather than being idle or
+    /// stopping after this one
+    pub fn running(&self) -> bool {
+        self.state != State::Idle && !self.stop_pending
+    }
+
+    /// Take `events` in order, and say what to do in the state they lead to
+    pub fn step(&mut self, events: impl IntoIterator<Item = Event>) -> Action {
+        for event in events {
+            let next = self.transition(event);
+            if next != self.state {
+                debug!(
+                    "Capture state {:?} -> {:?} on {:?}",
+                    self.state, next, event
+                );
+            }
+            self.state = next;
+        }
+        self.action()
+    }
+
+    pub fn action(&self) -> Action {
+        match self.state {
+            State::Idle => Action::Wait,
+            State::Armed => Action::Arm,
+            State::Capturing => Action::Capture,
+            State::Processing => Action::Process,
+            State::Outputting => Action::Output,
+            State::Error => Action::Recover,
+        }
+    }
+
+    fn transition(&mut self, event: Event) -> State {
+        use Event::*;
+
+        match (self.state, event) {
+            (State::Idle, Start | Button) => {
+                self.stop_pending = false;
+                State::Armed
+            }
+            // Nothing's started yet, so a stop takes effect straight away
+            (State::Armed, Stop | Button) => State::Idle,
+            (State::Armed, Armed) => State::Capturing,
+
+            // A start after a stop mid-capture takes the stop back
+            (State::Capturing | State::Processing | State::Outputting, Start) => {
+                self.stop_pending = false;
+                self.state
+            }
+            (State::Capturing | State::Processing | State::Outputting, Stop | Button) => {
+                self.stop_pending = true;
+                self.state
+            }
+            (State::Capturing, Captured) => State::Processing,
+            (State::Capturing, CaptureFailed) => State::Error,
+            (State::Processing, Processed) => State::Outputting,
+            (State::Processing, Skipped) | (State::Outputting, OutputDone) => self.finished(),
+
+            (State::Error, Stop | Button) => {
+                self.stop_pending = true;
+                State::Error
+            }
+            (State::Error, Recovered) => self.finished(),
+
+            (state, _) => state,
+        }
+    }
+
+    /// Where a capture ends up, ready for another unless stopped
+    fn finished(&mut self) -> State {
+        if core::mem::take(&mut self.stop_pending) {
+            State::Idle
+        } else {
+            State::Armed
+        }
+    }
+}
+
+impl Default for CaptureStateMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::*;
+
+    #[test]
+    fn a_capture_goes_round_the_loop() {
+        let mut machine = CaptureStateMachine::new();
+        assert_eq!(machine.action(), Action::Wait);
+        assert_eq!(machine.step([Start]), Action::Arm);
+        assert_eq!(machine.step([Armed]), Action::Capture);
+        assert_eq!(machine.step([Captured]), Action::Process);
+        assert_eq!(machine.step([Processed]), Action::Output);
+        // Still running, so straight on to the next
+        assert_eq!(machine.step([OutputDone]), Action::Arm);
+
+        // An untriggered capture has nothing to output
+        assert_eq!(machine.step([Armed, Captured, Skipped]), Action::Arm);
+        assert_eq!(machine.state(), State::Armed);
+    }
+
+    #[test]
+    fn a_stop_mid_capture_waits_for_it_to_finish() {
+        let mut machine = CaptureStateMachine::starting_in(State::Armed);
+        assert_eq!(machine.step([Armed, Stop]), Action::Capture);
+        assert!(!machine.running());
+        assert_eq!(machine.step([Captured, Processed]), Action::Output);
+        assert_eq!(machine.step([OutputDone]), Action::Wait);
+
+        // Taken back by a start before it finished
+        machine.step([Start, Armed, Button, Start, Captured, Skipped]);
+        assert_eq!(machine.state(), State::Armed);
+        assert!(machine.running());
+
+        // Not yet capturing, so it stops at once
+        assert_eq!(machine.step([Button]), Action::Wait);
+        assert_eq!(machine.step([Button]), Action::Arm);
+    }
+
+    #[test]
+    fn errors_recover_to_armed_or_idle() {
+        let mut machine = CaptureStateMachine::starting_in(State::Armed);
+        assert_eq!(machine.step([Armed, CaptureFailed]), Action::Recover);
+        // Nothing but recovering gets it out
+        assert_eq!(machine.step([Start, Captured, OutputDone]), Action::Recover);
+        assert_eq!(machine.step([Recovered]), Action::Arm);
+
+        assert_eq!(machine.step([Armed, CaptureFailed, Stop]), Action::Recover);
+        assert_eq!(machine.step([Recovered]), Action::Wait);
+    }
+
+    #[test]
+    fn out_of_place_events_change_nothing() {
+        let mut machine = CaptureStateMachine::new();
+        for event in [
+            Stop,
+            Armed,
+            Captured,
+            CaptureFailed,
+            Processed,
+            Skipped,
+            OutputDone,
+            Recovered,
+        ] {
+            assert_eq!(machine.step([event]), Action::Wait);
+        }
+
+        let mut machine = CaptureStateMachine::starting_in(State::Processing);
+        assert_eq!(
+            machine.step([Armed, Captured, CaptureFailed, Recovered]),
+            Action::Process
+        );
+        assert_eq!(machine.step([OutputDone]), Action::Process);
+    }
+}
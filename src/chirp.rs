@@ -0,0 +1,140 @@
+//! Linear frequency sweeps, as tables of 12-bit DAC codes, for driving a
+//! system with a known stimulus and capturing its response.
+
+use micromath::F32Ext;
+
+/// Largest 12-bit DAC code
+pub const DAC_MAX: u16 = 4095;
+
+/// The code the DAC idles at between sweeps, so a sweep starts and ends on it
+pub const DAC_MID: u16 = DAC_MAX / 2 + 1;
+
+/// Frequency a sweep from `start_hz` to `end_hz` over `duration_s` is at
+/// `t_s` seconds in
+pub fn linear_chirp_hz(start_hz: f32, end_hz: f32, duration_s: f32, t_s: f32) -> f32 {
+    start_hz + (end_hz - start_hz) * t_s / duration_s
+}
+
+/// Fill `table` with a sine sweeping linearly from `start_hz` to `end_hz`
+/// over its length, at `sample_rate_hz`. `amplitude` is a fraction of half
+/// the DAC's range, about `DAC_MID`. The phase starts at zero, so the first
+/// code is `DAC_MID`, and the last code is `DAC_MID` too, so the output
+/// doesn't step as the sweep stops.
+pub fn fill_linear_chirp(
+    table: &mut [u16],
+    sample_rate_hz: f32,
+    start_hz: f32,
+    end_hz: f32,
+    amplitude: f32,
+) {
+    fill_linear_chirp_dithered(table, sample_rate_hz, start_hz, end_hz, amplitude, || 0.0);
+}
+
+/// `fill_linear_chirp`, with `dither()` codes added to each before it's
+/// rounded, so the DAC's quantization error doesn't repeat with the sweep.
+/// The last code is still `DAC_MID`, but the first is only near it.
+pub fn fill_linear_chirp_dithered(
+    table: &mut [u16],
+    sample_rate_hz: f32,
+    start_hz: f32,
+    end_hz: f32,
+    amplitude: f32,
+    mut dither: impl FnMut() -> f32,
+) {
+    let Some(last) = table.len().checked_sub(1) else {
+        return;
+    };
+    let duration_s = table.len() as f32 / sample_rate_hz;
+    let peak = amplitude.clamp(0.0, 1.0) * (DAC_MAX - DAC_MID) as f32;
+
+    // In cycles, kept below 1 so there's no precision lost over a long sweep
+    let mut phase = 0.0f32;
+    for (i, code) in table[..last].iter_mut().enumerate() {
+        let wave = (2.0 * core::f32::consts::PI * phase).sin();
+        let exact = DAC_MID as f32 + peak * wave + dither();
+        *code = ((exact + 0.5) as u16).min(DAC_MAX);
+
+        let t_s = i as f32 / sample_rate_hz;
+        phase += linear_chirp_hz(start_hz, end_hz, duration_s, t_s) / sample_rate_hz;
+        phase -= phase.floor();
+    }
+    table[last] = DAC_MID;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rising crossings of mid-scale in `codes`
+    fn crossings(codes: &[u16]) -> usize {
+        codes
+            .windows(2)
+            .filter(|w| w[0] < DAC_MID && w[1] >= DAC_MID)
+            .count()
+    }
+
+    #[test]
+    fn sweep_starts_and_ends_at_mid_scale() {
+        let mut table = [0; 1000];
+        fill_linear_chirp(&mut table, 100_000.0, 1_000.0, 5_000.0, 0.9);
+        assert_eq!(table[0], DAC_MID);
+        assert_eq!(table[999], DAC_MID);
+
+        let max = *table.iter().max().unwrap();
+        let min = *table.iter().min().unwrap();
+        let peak = 0.9 * (DAC_MAX - DAC_MID) as f32;
+        assert!((max as f32 - (DAC_MID as f32 + peak)).abs() < 5.0, "{max}");
+        assert!((min as f32 - (DAC_MID as f32 - peak)).abs() < 5.0, "{min}");
+    }
+
+    #[test]
+    fn frequency_rises_linearly() {
+        // 100 ms from 1 kHz to 5 kHz at 200 kS/s
+        let mut table = [0; 20_000];
+        fill_linear_chirp(&mut table, 200_000.0, 1_000.0, 5_000.0, 1.0);
+
+        // The mean frequency is 3 kHz, so 300 cycles in all
+        let total = crossings(&table);
+        assert!((299..=301).contains(&total), "{total}");
+
+        // The first and last 10 ms average 1.2 kHz and 4.8 kHz
+        let first = crossings(&table[..2_000]);
+        let last = crossings(&table[18_000..]);
+        assert!((11..=13).contains(&first), "{first}");
+        assert!((47..=49).contains(&last), "{last}");
+    }
+
+    #[test]
+    fn amplitude_is_clamped_to_the_dac() {
+        let mut table = [0; 500];
+        fill_linear_chirp(&mut table, 10_000.0, 500.0, 500.0, 3.0);
+        assert!(table.iter().all(|&code| code <= DAC_MAX));
+    }
+
+    #[test]
+    fn dither_moves_codes_by_at_most_its_size() {
+        let mut plain = [0; 400];
+        let mut dithered = [0; 400];
+        fill_linear_chirp(&mut plain, 10_000.0, 200.0, 800.0, 1.0);
+        let mut flip = 0.4;
+        fill_linear_chirp_dithered(&mut dithered, 10_000.0, 200.0, 800.0, 1.0, || {
+            flip = -flip;
+            flip
+        });
+        assert_eq!(dithered[399], DAC_MID);
+        assert!(plain
+            .iter()
+            .zip(&dithered)
+            .all(|(&a, &b)| a.abs_diff(b) <= 1));
+        assert_ne!(plain, dithered);
+        assert!(dithered.iter().all(|&code| code <= DAC_MAX));
+    }
+
+    #[test]
+    fn instantaneous_frequency() {
+        assert_eq!(linear_chirp_hz(100.0, 1_100.0, 2.0, 0.0), 100.0);
+        assert_eq!(linear_chirp_hz(100.0, 1_100.0, 2.0, 1.0), 600.0);
+        assert_eq!(linear_chirp_hz(100.0, 1_100.0, 2.0, 2.0), 1_100.0);
+        fill_linear_chirp(&mut [], 1.0, 1.0, 1.0, 1.0);
+    }
+}
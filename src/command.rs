@@ -0,0 +1,346 @@
+//! The line-based commands that change capture settings at runtime.
+//!
+//! One command per line, words separated by spaces:
+//!
+//! ```text
+//! rate 48000          sample as close to 48 kS/s as the ADC can
+//! trig 1.2 rising     only process captures that cross 1.2 V upwards
+//! trig off            process every capture
+//! mode spectrum       log the FFT magnitudes (or `raw`, `waveform`, `bands`,
+//!                     `envelope`, `mel` or `dither`)
+//! output frames       send them as protocol frames instead (or `log`, `card`)
+//! output log,card     any of those at once, comma separated
+//! notch 900 1100      filter 900 Hz to 1.1 kHz out and log the difference
+//! notch off           stop filtering
+//! start               capture continuously
+//! stop                stop after the current capture
+//! dump 3              send the capture stored in flash slot 3 as frames
+//! cal                 measure how the ADC offset drifts as the board warms
+//! save                keep the settings and calibration in flash for next boot
+//! log debug           log everything at debug and above (or off, error, ...)
+//! log spectrum off    stop logging the spectrum dump, whatever the level
+//! log reset           back to info, with no per-module levels
+//! ```
+
+use core::fmt;
+use core::str::FromStr;
+
+use log::LevelFilter;
+
+use crate::config::{Output, OutputMode, Outputs};
+use crate::trigger::{Edge, LevelTrigger};
+
+/// A parsed command, not yet checked against what the hardware can do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Rate(f32),
+    /// Trigger level in volts, or `None` to trigger on every capture
+    Trigger(Option<LevelTrigger>),
+    Mode(OutputMode),
+    Output(Outputs),
+    Start,
+    Stop,
+    /// Send the capture in a flash slot
+    Dump(u8),
+    Log(LogSetting),
+    /// A band to filter out of the spectrum, `(low, high)` Hz, or `None`
+    Notch(Option<(f32, f32)>),
+    /// Measure the offset drift with the input grounded
+    Calibrate,
+    /// Save the settings to flash
+    Save,
+}
+
+/// A change to what gets logged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSetting {
+    /// The level for everything without a level of its own
+    All(LevelFilter),
+    /// The level for one module, above or below the rest
+    Module(ModuleName, LevelFilter),
+    Reset,
+}
+
+/// Longest module name `log` takes a level for
+pub const MODULE_NAME_MAX: usize = 16;
+
+/// A module to give its own log level, matched against each `::` separated
+/// part of a record's target, so `report` covers everything `fft::report`
+/// logs and `spectrum` the spectrum dump, which logs to that target
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ModuleName {
+    bytes: [u8; MODULE_NAME_MAX],
+    len: u8,
+}
+
+impl ModuleName {
+    /// `None` if it's empty or longer than `MODULE_NAME_MAX`
+    pub fn new(name: &str) -> Option<Self> {
+        if name.is_empty() || name.len() > MODULE_NAME_MAX {
+            return None;
+        }
+        let mut bytes = [0; MODULE_NAME_MAX];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Self {
+            bytes,
+            len: name.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever copied whole from a `&str`
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+
+    /// Whether a record logged to `target` is from this module
+    pub fn matches(&self, target: &str) -> bool {
+        target.split("::").any(|part| part == self.as_str())
+    }
+}
+
+impl fmt::Debug for ModuleName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Why a line isn't a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    UnknownCommand,
+    MissingArgument,
+    ExtraArgument,
+    BadNumber,
+    BadSlot,
+    BadEdge,
+    BadMode,
+    BadOutput,
+    BadLevel,
+    BadModule,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseError::Empty => "empty line",
+            ParseError::UnknownCommand => concat!(
+                "unknown command, try rate, trig, mode, output, start, stop, dump, log, notch, ",
+                "cal or save"
+            ),
+            ParseError::MissingArgument => "missing argument",
+            ParseError::ExtraArgument => "too many arguments",
+            ParseError::BadNumber => "not a number",
+            ParseError::BadSlot => "slot must be a whole number",
+            ParseError::BadEdge => "edge must be rising or falling",
+            ParseError::BadMode => {
+                "mode must be spectrum, raw, waveform, bands, envelope, mel or dither"
+            }
+            ParseError::BadOutput => "output must be log, frames or card, or several with commas",
+            ParseError::BadLevel => "level must be off, error, warn, info, debug or trace",
+            ParseError::BadModule => "module names are at most 16 bytes",
+        })
+    }
+}
+
+impl FromStr for Command {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, ParseError> {
+        let mut words = line.split_ascii_whitespace();
+        let name = words.next().ok_or(ParseError::Empty)?;
+        let mut argument = || words.next().ok_or(ParseError::MissingArgument);
+
+        let command = match name {
+            "rate" => Command::Rate(number(argument()?)?),
+            "trig" => match argument()? {
+                "off" => Command::Trigger(None),
+                level => {
+                    let level = number(level)?;
+                    let edge = match argument()? {
+                        "rising" => Edge::Rising,
+                        "falling" => Edge::Falling,
+                        _ => return Err(ParseError::BadEdge),
+                    };
+                    Command::Trigger(Some(LevelTrigger::new(level, edge)))
+                }
+            },
+            "mode" => Command::Mode(match argument()? {
+                "spectrum" => OutputMode::Spectrum,
+                "raw" => OutputMode::Raw,
+                "waveform" => OutputMode::Waveform,
+                "bands" => OutputMode::Bands,
+                "envelope" => OutputMode::Envelope,
+                "mel" => OutputMode::Mel,
+                "dither" => OutputMode::Dither,
+                _ => return Err(ParseError::BadMode),
+            }),
+            "output" => Command::Output(outputs(argument()?)?),
+            "start" => Command::Start,
+            "stop" => Command::Stop,
+            "dump" => Command::Dump(argument()?.parse().map_err(|_| ParseError::BadSlot)?),
+            "log" => Command::Log(match argument()? {
+                "reset" => LogSetting::Reset,
+                word => match word.parse() {
+                    Ok(level) => LogSetting::All(level),
+                    Err(_) => {
+                        let module = ModuleName::new(word).ok_or(ParseError::BadModule)?;
+                        let level = argument()?.parse().map_err(|_| ParseError::BadLevel)?;
+                        LogSetting::Module(module, level)
+                    }
+                },
+            }),
+            "notch" => Command::Notch(match argument()? {
+                "off" => None,
+                low => Some((number(low)?, number(argument()?)?)),
+            }),
+            "cal" => Command::Calibrate,
+            "save" => Command::Save,
+            _ => return Err(ParseError::UnknownCommand),
+        };
+
+        match words.next() {
+            Some(_) => Err(ParseError::ExtraArgument),
+            None => Ok(command),
+        }
+    }
+}
+
+/// One or more outputs, comma separated, like `log,card`
+fn outputs(word: &str) -> Result<Outputs, ParseError> {
+    word.split(',').try_fold(Outputs::NONE, |outputs, name| {
+        let output = match name {
+            "log" => Output::Log,
+            "frames" => Output::Frames,
+            "card" => Output::Card,
+            _ => return Err(ParseError::BadOutput),
+        };
+        Ok(outputs.with(output))
+    })
+}
+
+/// A finite number, NaN and infinity are never a sensible setting
+fn number(word: &str) -> Result<f32, ParseError> {
+    word.parse::<f32>()
+        .ok()
+        .filter(|n| n.is_finite())
+        .ok_or(ParseError::BadNumber)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_command() {
+        assert_eq!("rate 48000".parse(), Ok(Command::Rate(48_000.0)));
+        assert_eq!(
+            "trig 1.2 rising".parse(),
+            Ok(Command::Trigger(Some(LevelTrigger::new(1.2, Edge::Rising))))
+        );
+        assert_eq!(
+            "trig 0.5 falling".parse(),
+            Ok(Command::Trigger(Some(LevelTrigger::new(
+                0.5,
+                Edge::Falling
+            ))))
+        );
+        assert_eq!("trig off".parse(), Ok(Command::Trigger(None)));
+        assert_eq!(
+            "mode spectrum".parse(),
+            Ok(Command::Mode(OutputMode::Spectrum))
+        );
+        assert_eq!("mode raw".parse(), Ok(Command::Mode(OutputMode::Raw)));
+        assert_eq!(
+            "mode waveform".parse(),
+            Ok(Command::Mode(OutputMode::Waveform))
+        );
+        assert_eq!("mode bands".parse(), Ok(Command::Mode(OutputMode::Bands)));
+        assert_eq!(
+            "mode envelope".parse(),
+            Ok(Command::Mode(OutputMode::Envelope))
+        );
+        assert_eq!("mode mel".parse(), Ok(Command::Mode(OutputMode::Mel)));
+        assert_eq!("mode dither".parse(), Ok(Command::Mode(OutputMode::Dither)));
+        assert_eq!(
+            "output log".parse(),
+            Ok(Command::Output(Outputs::only(Output::Log)))
+        );
+        assert_eq!(
+            "output frames".parse(),
+            Ok(Command::Output(Outputs::only(Output::Frames)))
+        );
+        assert_eq!(
+            "output log,card".parse(),
+            Ok(Command::Output(
+                Outputs::only(Output::Log).with(Output::Card)
+            ))
+        );
+        assert_eq!("start".parse(), Ok(Command::Start));
+        assert_eq!(" stop \r".parse(), Ok(Command::Stop));
+        assert_eq!("dump 3".parse(), Ok(Command::Dump(3)));
+        assert_eq!(
+            "log debug".parse(),
+            Ok(Command::Log(LogSetting::All(LevelFilter::Debug)))
+        );
+        assert_eq!(
+            "log spectrum off".parse(),
+            Ok(Command::Log(LogSetting::Module(
+                ModuleName::new("spectrum").unwrap(),
+                LevelFilter::Off
+            )))
+        );
+        assert_eq!("log reset".parse(), Ok(Command::Log(LogSetting::Reset)));
+        assert_eq!(
+            "notch 900 1100".parse(),
+            Ok(Command::Notch(Some((900.0, 1_100.0))))
+        );
+        assert_eq!("notch off".parse(), Ok(Command::Notch(None)));
+        assert_eq!("cal".parse(), Ok(Command::Calibrate));
+        assert_eq!("save".parse(), Ok(Command::Save));
+    }
+
+    #[test]
+    fn module_names_match_whole_parts_of_a_target() {
+        let report = ModuleName::new("report").unwrap();
+        assert_eq!(report.as_str(), "report");
+        assert!(report.matches("fft::report"));
+        assert!(report.matches("report"));
+        assert!(!report.matches("fft::reports"));
+        assert!(!report.matches("fft::utilities::dma"));
+
+        assert_eq!(ModuleName::new(""), None);
+        assert_eq!(ModuleName::new("seventeen_bytes__"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let parse = |line: &str| line.parse::<Command>();
+
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        assert_eq!(parse("go"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("rate"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("rate fast"), Err(ParseError::BadNumber));
+        assert_eq!(parse("rate inf"), Err(ParseError::BadNumber));
+        assert_eq!(parse("trig 1.2"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("trig 1.2 up"), Err(ParseError::BadEdge));
+        assert_eq!(parse("mode fft"), Err(ParseError::BadMode));
+        assert_eq!(parse("output usb"), Err(ParseError::BadOutput));
+        assert_eq!(parse("output log,"), Err(ParseError::BadOutput));
+        assert_eq!(parse("output log card"), Err(ParseError::ExtraArgument));
+        assert_eq!(parse("start now"), Err(ParseError::ExtraArgument));
+        assert_eq!(parse("dump"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("dump 1.5"), Err(ParseError::BadSlot));
+        assert_eq!(parse("dump -1"), Err(ParseError::BadSlot));
+        assert_eq!(parse("log"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("log report"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("log report loud"), Err(ParseError::BadLevel));
+        assert_eq!(
+            parse("log a_much_too_long_name info"),
+            Err(ParseError::BadModule)
+        );
+        assert_eq!(parse("log info now"), Err(ParseError::ExtraArgument));
+        assert_eq!(parse("notch 900"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("notch 900 high"), Err(ParseError::BadNumber));
+    }
+}
@@ -0,0 +1,391 @@
+//! Capture settings that can change at runtime, and checking changes to
+//! them against what the hardware can do.
+
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::Command;
+use crate::trigger::LevelTrigger;
+
+/// What to log for each capture
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputMode {
+    /// FFT magnitudes
+    Spectrum,
+    /// The samples themselves, from the trigger point if there is one
+    Raw,
+    /// The same samples centred on mid-scale, signed and timed, as CSV for
+    /// a spreadsheet to plot
+    Waveform,
+    /// Power in octave bands
+    Bands,
+    /// The depth and frequency of amplitude modulation, from the envelope
+    Envelope,
+    /// Log mel band energies, for speech features
+    Mel,
+    /// How far the spurs are below the peak with the capture quantized
+    /// coarser again, with and without dither
+    Dither,
+}
+
+/// Somewhere a capture's output can go
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Output {
+    /// As text, through the logger
+    Log,
+    /// As `protocol` frames on the console. Octave bands, the envelope and
+    /// the dither comparison are only ever logged.
+    Frames,
+    /// The samples as a WAV file on the SD card, in builds with one
+    Card,
+}
+
+impl Output {
+    pub const ALL: [Output; 3] = [Output::Log, Output::Frames, Output::Card];
+}
+
+/// Where each capture's output goes, any number of `Output`s at once
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Outputs(u8);
+
+impl Outputs {
+    pub const NONE: Self = Self(0);
+
+    pub const fn only(output: Output) -> Self {
+        Self::NONE.with(output)
+    }
+
+    pub const fn with(self, output: Output) -> Self {
+        Self(self.0 | 1 << output as u8)
+    }
+
+    pub const fn contains(self, output: Output) -> bool {
+        self.0 & 1 << output as u8 != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Those in the set, in `Output::ALL`'s order
+    pub fn iter(self) -> impl Iterator<Item = Output> {
+        Output::ALL.into_iter().filter(move |&o| self.contains(o))
+    }
+}
+
+/// The range a setting is allowed in, from the ADC's configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub min_rate_hz: f32,
+    pub max_rate_hz: f32,
+    /// The input's range, below 0 V only for a differential one
+    pub min_volts: f32,
+    pub full_scale_volts: f32,
+    /// Flash slots captures are stored in, 0 without flash storage
+    pub stored_slots: u8,
+}
+
+/// Why a command was refused
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigError {
+    RateOutOfRange { min_hz: f32, max_hz: f32 },
+    LevelOutOfRange { min_volts: f32, max_volts: f32 },
+    NoSuchSlot { slots: u8 },
+    BadBand { max_hz: f32 },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::RateOutOfRange { min_hz, max_hz } => {
+                write!(f, "rate must be between {} and {} Hz", min_hz, max_hz)
+            }
+            ConfigError::LevelOutOfRange {
+                min_volts,
+                max_volts,
+            } => {
+                write!(
+                    f,
+                    "trigger level must be between {} and {} V",
+                    min_volts, max_volts
+                )
+            }
+            ConfigError::NoSuchSlot { slots: 0 } => f.write_str("no captures are stored"),
+            ConfigError::NoSuchSlot { slots } => {
+                write!(f, "slot must be between 0 and {}", slots - 1)
+            }
+            ConfigError::BadBand { max_hz } => {
+                write!(
+                    f,
+                    "notch must be from low to high Hz, between 0 and {} Hz",
+                    max_hz
+                )
+            }
+        }
+    }
+}
+
+/// Settings the capture loop reads at the start of each acquisition.
+///
+/// `save` writes them to flash through `config_store`, all but what only
+/// lasts until it's acted on: the requests waiting to be taken, and
+/// `running`, as every boot starts out running.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    /// Whether to keep capturing
+    #[serde(skip, default = "starts_running")]
+    pub running: bool,
+    /// Sample rate to get as close to as possible, or `None` for the
+    /// build's sampling time
+    pub rate_hz: Option<f32>,
+    /// Only process captures that cross this level, in volts
+    pub trigger: Option<LevelTrigger>,
+    pub mode: OutputMode,
+    pub output: Outputs,
+    /// A stored capture to send before the next acquisition, taken by
+    /// whatever sends it
+    #[serde(skip)]
+    pub dump: Option<u8>,
+    /// Filter this band, `(low, high)` Hz, out of each spectrum to show
+    /// what it took out of the signal
+    pub notch: Option<(f32, f32)>,
+    /// Measure the ADC offset's drift with temperature, taken by whatever
+    /// runs the calibration
+    #[serde(skip)]
+    pub calibrate: bool,
+    /// Save these settings and the calibration to flash, taken by whatever
+    /// saves them
+    #[serde(skip)]
+    pub save: bool,
+}
+
+const fn starts_running() -> bool {
+    Config::new().running
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            running: true,
+            rate_hz: None,
+            trigger: None,
+            mode: OutputMode::Spectrum,
+            // Saving to the card, where there is one, whatever's logged
+            output: Outputs::only(Output::Log).with(Output::Card),
+            dump: None,
+            notch: None,
+            calibrate: false,
+            save: false,
+        }
+    }
+
+    /// Apply `command` if its values are within `limits`, otherwise leave
+    /// the settings as they were
+    pub fn apply(&mut self, command: Command, limits: &Limits) -> Result<(), ConfigError> {
+        match command {
+            Command::Rate(hz) => {
+                if !(limits.min_rate_hz..=limits.max_rate_hz).contains(&hz) {
+                    return Err(ConfigError::RateOutOfRange {
+                        min_hz: limits.min_rate_hz,
+                        max_hz: limits.max_rate_hz,
+                    });
+                }
+                self.rate_hz = Some(hz);
+            }
+            Command::Trigger(trigger) => {
+                if let Some(t) = trigger {
+                    if !(limits.min_volts..=limits.full_scale_volts).contains(&t.level) {
+                        return Err(ConfigError::LevelOutOfRange {
+                            min_volts: limits.min_volts,
+                            max_volts: limits.full_scale_volts,
+                        });
+                    }
+                }
+                self.trigger = trigger;
+            }
+            Command::Mode(mode) => self.mode = mode,
+            Command::Output(output) => self.output = output,
+            Command::Start => self.running = true,
+            Command::Stop => self.running = false,
+            Command::Dump(slot) => {
+                if slot >= limits.stored_slots {
+                    return Err(ConfigError::NoSuchSlot {
+                        slots: limits.stored_slots,
+                    });
+                }
+                self.dump = Some(slot);
+            }
+            // The logger's to apply, it isn't a capture setting
+            Command::Log(_) => {}
+            Command::Notch(band) => {
+                // Nyquist at the fastest rate, as the rate can change after
+                let max_hz = limits.max_rate_hz / 2.0;
+                if let Some((low, high)) = band {
+                    if !(0.0 <= low && low <= high && high <= max_hz) {
+                        return Err(ConfigError::BadBand { max_hz });
+                    }
+                }
+                self.notch = band;
+            }
+            // It takes captures, so keeps them coming
+            Command::Calibrate => {
+                self.calibrate = true;
+                self.running = true;
+            }
+            Command::Save => self.save = true,
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::Edge;
+
+    const LIMITS: Limits = Limits {
+        min_rate_hz: 4_000.0,
+        max_rate_hz: 600_000.0,
+        min_volts: 0.0,
+        full_scale_volts: 3.3,
+        stored_slots: 4,
+    };
+
+    #[test]
+    fn outputs_are_a_set() {
+        let outputs = Outputs::only(Output::Frames).with(Output::Card);
+        assert!(outputs.contains(Output::Card) && !outputs.contains(Output::Log));
+        assert_eq!(outputs.with(Output::Card), outputs);
+        assert_eq!(
+            outputs.with(Output::Log).iter().collect::<Vec<_>>(),
+            Output::ALL
+        );
+        assert!(Outputs::NONE.is_empty() && Outputs::NONE.iter().next().is_none());
+    }
+
+    #[test]
+    fn applies_valid_commands() {
+        let mut config = Config::new();
+        let trigger = LevelTrigger::new(1.2, Edge::Rising);
+
+        config.apply(Command::Rate(48_000.0), &LIMITS).unwrap();
+        config
+            .apply(Command::Trigger(Some(trigger)), &LIMITS)
+            .unwrap();
+        config
+            .apply(Command::Mode(OutputMode::Raw), &LIMITS)
+            .unwrap();
+        config
+            .apply(Command::Output(Outputs::only(Output::Frames)), &LIMITS)
+            .unwrap();
+        config.apply(Command::Stop, &LIMITS).unwrap();
+        config.apply(Command::Dump(3), &LIMITS).unwrap();
+        config
+            .apply(Command::Notch(Some((900.0, 1_100.0))), &LIMITS)
+            .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                running: false,
+                rate_hz: Some(48_000.0),
+                trigger: Some(trigger),
+                mode: OutputMode::Raw,
+                output: Outputs::only(Output::Frames),
+                dump: Some(3),
+                notch: Some((900.0, 1_100.0)),
+                calibrate: false,
+                save: false,
+            }
+        );
+
+        config.apply(Command::Trigger(None), &LIMITS).unwrap();
+        config.apply(Command::Start, &LIMITS).unwrap();
+        assert_eq!(config.trigger, None);
+        assert!(config.running);
+
+        config.apply(Command::Stop, &LIMITS).unwrap();
+        config.apply(Command::Calibrate, &LIMITS).unwrap();
+        assert!(config.calibrate && config.running);
+
+        config.apply(Command::Save, &LIMITS).unwrap();
+        assert!(config.save);
+    }
+
+    #[test]
+    fn refuses_out_of_range_values_and_keeps_the_old_ones() {
+        let mut config = Config::new();
+        config.apply(Command::Rate(48_000.0), &LIMITS).unwrap();
+
+        for hz in [1_000.0, 1_000_000.0] {
+            assert!(matches!(
+                config.apply(Command::Rate(hz), &LIMITS),
+                Err(ConfigError::RateOutOfRange { .. })
+            ));
+        }
+        for level in [-0.1, 3.4] {
+            let trigger = Some(LevelTrigger::new(level, Edge::Falling));
+            assert_eq!(
+                config.apply(Command::Trigger(trigger), &LIMITS),
+                Err(ConfigError::LevelOutOfRange {
+                    min_volts: 0.0,
+                    max_volts: 3.3
+                })
+            );
+        }
+
+        assert_eq!(
+            config.apply(Command::Dump(4), &LIMITS),
+            Err(ConfigError::NoSuchSlot { slots: 4 })
+        );
+        let no_flash = Limits {
+            stored_slots: 0,
+            ..LIMITS
+        };
+        assert_eq!(
+            config.apply(Command::Dump(0), &no_flash),
+            Err(ConfigError::NoSuchSlot { slots: 0 })
+        );
+
+        for band in [(-1.0, 100.0), (1_100.0, 900.0), (900.0, 300_001.0)] {
+            assert_eq!(
+                config.apply(Command::Notch(Some(band)), &LIMITS),
+                Err(ConfigError::BadBand { max_hz: 300_000.0 })
+            );
+        }
+
+        assert_eq!(config.rate_hz, Some(48_000.0));
+        assert_eq!(config.trigger, None);
+        assert_eq!(config.dump, None);
+        assert_eq!(config.notch, None);
+    }
+
+    #[test]
+    fn differential_levels_go_below_zero() {
+        let differential = Limits {
+            min_volts: -3.3,
+            ..LIMITS
+        };
+        let mut config = Config::new();
+        let trigger = Some(LevelTrigger::new(-0.1, Edge::Falling));
+        assert_eq!(
+            config.apply(Command::Trigger(trigger), &differential),
+            Ok(())
+        );
+        let trigger = Some(LevelTrigger::new(-3.4, Edge::Falling));
+        assert_eq!(
+            config.apply(Command::Trigger(trigger), &differential),
+            Err(ConfigError::LevelOutOfRange {
+                min_volts: -3.3,
+                max_volts: 3.3
+            })
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! How the settings are laid out in internal flash, so they survive power
+//! being removed, unlike the calibration in backup SRAM.
+//!
+//! There are two slots, each a `RECORD_LEN` record: a header, the
+//! `Settings` serialized with postcard, and a CRC-32 of both.
+//!
+//! ```text
+//! magic "CFG1" | sequence u32 | len u16 | VERSION | 0 | postcard(Settings) | crc32
+//! ```
+//!
+//! Saves alternate between the slots, each with a sequence number one past
+//! the last, and loading takes the newest slot that checks out. A save cut
+//! short by a reset or a brownout only ever spoils the slot being written,
+//! so the one before it is still there to fall back to. Erased flash reads
+//! as all ones, which never has the magic number.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::dsp::scaling::{Calibration, OffsetDrift};
+
+/// Bytes in a slot, four of the H7's 32-byte flash words
+pub const RECORD_LEN: usize = 128;
+
+/// Bytes in front of the settings
+const HEADER_LEN: usize = 12;
+
+/// Most bytes the serialized settings can take
+pub const MAX_PAYLOAD_LEN: usize = RECORD_LEN - HEADER_LEN - 4;
+
+/// Bumped whenever `Settings` changes shape. A slot of another version is
+/// ignored rather than misread, so an update starts from the defaults.
+pub const VERSION: u8 = 2;
+
+/// "CFG1" read as little-endian, at the start of every written slot
+const MAGIC: u32 = u32::from_le_bytes(*b"CFG1");
+
+/// Everything that's saved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub config: Config,
+    pub calibration: Calibration,
+    pub drift: OffsetDrift,
+}
+
+/// A slot that checked out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    /// Counts up with each save, so the newest slot is the one with the
+    /// highest, allowing for wrapping
+    pub sequence: u32,
+    pub settings: Settings,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreError {
+    /// The settings serialize to more than `MAX_PAYLOAD_LEN` bytes
+    TooLong,
+}
+
+impl Record {
+    /// The slot as stored, with a CRC-32 from `crc`. Whatever follows the
+    /// CRC stays erased.
+    pub fn to_bytes(&self, crc: impl FnOnce(&[u8]) -> u32) -> Result<[u8; RECORD_LEN], StoreError> {
+        let mut bytes = [0xFF; RECORD_LEN];
+        let len = postcard::to_slice(
+            &self.settings,
+            &mut bytes[HEADER_LEN..HEADER_LEN + MAX_PAYLOAD_LEN],
+        )
+        .map_err(|_| StoreError::TooLong)?
+        .len();
+
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..10].copy_from_slice(&(len as u16).to_le_bytes());
+        bytes[10] = VERSION;
+        bytes[11] = 0;
+        let end = HEADER_LEN + len;
+        let checksum = crc(&bytes[..end]);
+        bytes[end..end + 4].copy_from_slice(&checksum.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Read a slot back. `None` for an empty or damaged slot, or one from
+    /// another `VERSION`.
+    pub fn from_bytes(bytes: &[u8; RECORD_LEN], crc: impl FnOnce(&[u8]) -> u32) -> Option<Self> {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        let len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        if u32_at(0) != MAGIC || bytes[10] != VERSION || len > MAX_PAYLOAD_LEN {
+            return None;
+        }
+        let end = HEADER_LEN + len;
+        if crc(&bytes[..end]) != u32_at(end) {
+            return None;
+        }
+        let settings = postcard::from_bytes(&bytes[HEADER_LEN..end]).ok()?;
+        Some(Self {
+            sequence: u32_at(4),
+            settings,
+        })
+    }
+}
+
+/// Whether a slot has never been written since it was erased, to tell an
+/// empty slot from a damaged one
+pub fn is_erased(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&b| b == 0xFF)
+}
+
+/// The newest of the slots that checked out and which slot it's in, or
+/// `None` if none did
+pub fn newest(records: &[Option<Record>]) -> Option<(usize, Record)> {
+    records
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, record)| record.map(|r| (slot, r)))
+        .reduce(|newest, candidate| {
+            if is_newer(candidate.1.sequence, newest.1.sequence) {
+                candidate
+            } else {
+                newest
+            }
+        })
+}
+
+/// Slot to save to next and the sequence number to give it: the one after
+/// the newest, so that one's kept, or the first if nothing's saved
+pub fn next_slot(records: &[Option<Record>]) -> (usize, u32) {
+    match newest(records) {
+        Some((slot, record)) => ((slot + 1) % records.len(), record.sequence.wrapping_add(1)),
+        None => (0, 1),
+    }
+}
+
+/// Whether sequence `a` came after `b`, if they're less than half the
+/// range apart, as saves only ever count up by one
+fn is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutputMode;
+    use crate::protocol::crc32::crc32;
+    use crate::trigger::{Edge, LevelTrigger};
+
+    fn settings() -> Settings {
+        let mut config = Config::new();
+        config.rate_hz = Some(48_000.0);
+        config.trigger = Some(LevelTrigger::new(1.2, Edge::Falling));
+        config.mode = OutputMode::Mel;
+        config.notch = Some((900.0, 1_100.0));
+        Settings {
+            config,
+            calibration: Calibration {
+                offset: 31.5,
+                gain: 1.002,
+            },
+            drift: OffsetDrift {
+                reference_degc: 31.0,
+                reference_offset: 31.5,
+                counts_per_degc: 0.4,
+            },
+        }
+    }
+
+    fn stored(sequence: u32) -> Option<Record> {
+        Some(Record {
+            sequence,
+            settings: settings(),
+        })
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let record = stored(7).unwrap();
+        let bytes = record.to_bytes(crc32).unwrap();
+        assert_eq!(&bytes[..4], b"CFG1");
+        assert_eq!(Record::from_bytes(&bytes, crc32), Some(record));
+
+        // Only settings are saved, not requests still waiting to be taken
+        let mut pending = record;
+        pending.settings.config.dump = Some(2);
+        pending.settings.config.calibrate = true;
+        pending.settings.config.save = true;
+        pending.settings.config.running = false;
+        let bytes = pending.to_bytes(crc32).unwrap();
+        assert_eq!(Record::from_bytes(&bytes, crc32), Some(record));
+    }
+
+    #[test]
+    fn the_newest_good_slot_wins() {
+        assert_eq!(next_slot(&[None, None]), (0, 1));
+        assert_eq!(next_slot(&[stored(1), None]), (1, 2));
+        assert_eq!(next_slot(&[stored(3), stored(2)]), (1, 4));
+        assert_eq!(newest(&[stored(3), stored(4)]).unwrap().0, 1);
+        // Past the wrap, 0 is newer than u32::MAX
+        assert_eq!(next_slot(&[stored(u32::MAX), stored(0)]), (0, 1));
+    }
+
+    #[test]
+    fn a_corrupted_slot_falls_back_to_the_older_one() {
+        let erased = [0xFF; RECORD_LEN];
+        assert!(is_erased(&erased));
+        assert_eq!(Record::from_bytes(&erased, crc32), None);
+
+        let older = stored(5).unwrap().to_bytes(crc32).unwrap();
+        let mut newer = stored(6).unwrap().to_bytes(crc32).unwrap();
+        // A save cut short, or garbage written over it on purpose
+        newer[HEADER_LEN + 3] ^= 0x5A;
+        assert!(!is_erased(&newer));
+
+        let records = [
+            Record::from_bytes(&older, crc32),
+            Record::from_bytes(&newer, crc32),
+        ];
+        assert_eq!(records[1], None);
+        assert_eq!(newest(&records), Some((0, stored(5).unwrap())));
+        // And the next save goes over the damaged slot, keeping the good one
+        assert_eq!(next_slot(&records), (1, 6));
+
+        // Another version's settings aren't read as this one's
+        let mut other_version = older;
+        other_version[10] = VERSION + 1;
+        assert_eq!(Record::from_bytes(&other_version, crc32), None);
+        assert_eq!(newest(&[None, None]), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! The real cepstrum, for finding the pitch of harmonic signals and the
+//! formants of voiced ones.
+
+use micromath::F32Ext;
+
+use super::fft;
+
+/// Magnitudes below this are taken as this, so an empty bin's log is large
+/// and negative rather than infinite
+const MAGNITUDE_FLOOR: f32 = 1e-10;
+
+/// The real cepstrum of `samples`, the inverse FFT of its log-magnitude
+/// spectrum, into `out`. `out[q]` is at a quefrency of `q` samples, so a
+/// harmonic series with a fundamental period of `P` samples gives a peak at
+/// `out[P]`, wherever the energy is among its harmonics; the low quefrencies
+/// are the spectral envelope, the formants. Up to `N / 2` quefrencies are
+/// written, as the cepstrum is symmetric, and `samples` is left as scratch.
+///
+/// microfft only has forward transforms, so the inverse is worked out as
+/// `fft::icfft` does: `IFFT(X) = conj(FFT(conj(X))) / N`. A log-magnitude
+/// spectrum is real, so both conjugates do nothing, and it's even, so the
+/// result is real and a real FFT of the whole log spectrum is enough.
+/// Panics if `N` isn't a supported FFT length.
+pub fn real_cepstrum<const N: usize>(samples: &mut [f32; N], out: &mut [f32]) {
+    let half = N / 2;
+    fft::rfft(samples);
+
+    // microfft leaves the spectrum in `samples` as re, im pairs, and bin 0
+    // holds the Nyquist bin's real part as its imaginary part. Bin `k` is at
+    // `2k`, so writing each log magnitude to `k` only ever overwrites bins
+    // already read.
+    let nyquist = log_magnitude(samples[1].abs());
+    samples[0] = log_magnitude(samples[0].abs());
+    for k in 1..half {
+        let (re, im) = (samples[2 * k], samples[2 * k + 1]);
+        samples[k] = log_magnitude((re * re + im * im).sqrt());
+    }
+    // The negative frequencies mirror the positive ones
+    samples[half] = nyquist;
+    for k in 1..half {
+        samples[N - k] = samples[k];
+    }
+
+    let cepstrum = fft::rfft(samples);
+    let scale = 1.0 / N as f32;
+    for (q, c) in out.iter_mut().take(half).enumerate() {
+        *c = cepstrum[q].re * scale;
+    }
+}
+
+fn log_magnitude(magnitude: f32) -> f32 {
+    magnitude.max(MAGNITUDE_FLOOR).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_harmonic_series_peaks_at_its_period() {
+        // A fundamental at bin 8 of 256, so a period of 32 samples, with
+        // harmonics falling off
+        const N: usize = 256;
+        let mut samples: [f32; N] = core::array::from_fn(|n| {
+            (1..=8)
+                .map(|h| {
+                    let phase = 2.0 * core::f32::consts::PI * (8 * h * n) as f32 / N as f32;
+                    phase.cos() / h as f32
+                })
+                .sum()
+        });
+        let mut cepstrum = [0.0; N / 2];
+        real_cepstrum(&mut samples, &mut cepstrum);
+
+        // Past the envelope at the lowest quefrencies
+        let peak = (4..N / 2)
+            .max_by(|&a, &b| cepstrum[a].total_cmp(&cepstrum[b]))
+            .unwrap();
+        assert_eq!(peak, 32);
+    }
+
+    #[test]
+    fn an_impulse_has_a_flat_spectrum_and_no_cepstrum() {
+        let mut samples = [0.0; 64];
+        samples[0] = 1.0;
+        let mut cepstrum = [1.0; 40];
+        real_cepstrum(&mut samples, &mut cepstrum);
+
+        // log(1) everywhere is zero, and only half is written
+        assert!(cepstrum[..32].iter().all(|c| c.abs() < 1e-5));
+        assert_eq!(cepstrum[32..], [1.0; 8]);
+    }
+}
@@ -0,0 +1,187 @@
+//! Cross-correlation, for estimating the delay between two channels, and
+//! autocorrelation, for a signal's period.
+
+/// Largest lag `cross_correlation` will search. The cost is
+/// `(2 * max_lag + 1) * len` multiply-adds, so this keeps it bounded.
+pub const MAX_LAG: usize = 256;
+
+/// Correlate `a` against `b` for every lag in `-max_lag..=max_lag`.
+///
+/// `out[max_lag + lag]` is the sum of `a[n] * b[n + lag]` over the samples
+/// where both exist, so a positive peak lag means `b` is a delayed copy of
+/// `a`. The sums aren't normalized by the overlap, which slightly favours
+/// small lags; remove DC first or the mean dominates everything.
+pub fn cross_correlation(a: &[f32], b: &[f32], max_lag: usize, out: &mut [f32]) {
+    assert!(max_lag <= MAX_LAG, "max_lag is larger than MAX_LAG");
+    assert_eq!(out.len(), 2 * max_lag + 1);
+
+    let len = a.len().min(b.len());
+
+    for (k, r) in out.iter_mut().enumerate() {
+        let lag = k as isize - max_lag as isize;
+        let (a, b) = if lag >= 0 {
+            let lag = lag as usize;
+            (
+                &a[..len.saturating_sub(lag)],
+                b.get(lag..len).unwrap_or(&[]),
+            )
+        } else {
+            let lag = (-lag) as usize;
+            (
+                a.get(lag..len).unwrap_or(&[]),
+                &b[..len.saturating_sub(lag)],
+            )
+        };
+
+        *r = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    }
+}
+
+/// Lag of the largest value in a `cross_correlation` output
+pub fn argmax_lag(out: &[f32]) -> i32 {
+    let max_lag = (out.len() / 2) as i32;
+
+    let peak = out
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (i, &r)| {
+            if r > best.1 {
+                (i, r)
+            } else {
+                best
+            }
+        })
+        .0;
+
+    peak as i32 - max_lag
+}
+
+/// How alike a signal has to be to itself a period later for
+/// `autocorr_pitch` to call it periodic, out of 1
+pub const MIN_CLARITY: f32 = 0.8;
+
+/// How close to the best a shorter period has to come to be taken instead,
+/// as a signal is as alike two periods on as one
+const FIRST_PEAK_FRACTION: f32 = 0.9;
+
+/// The fundamental frequency of `samples`, from the lag where they're most
+/// like themselves, searched over periods from `1 / max_hz` to `1 / min_hz`.
+/// Unlike the FFT's peak, this isn't led astray by harmonics stronger than
+/// the fundamental. Returns `None` for noise, or anything else that's never
+/// `MIN_CLARITY` alike, and for silence.
+///
+/// Each lag is scored with the normalized autocorrelation
+/// `2 Σ x[n] x[n + lag] / Σ (x[n]² + x[n + lag]²)`, 1 for a perfect repeat
+/// and unaffected by the shrinking overlap. The first peak nearly as good
+/// as the best is the period, as the best may be a multiple of it; the peak
+/// lag is refined with a parabola through its neighbours. Take the mean off
+/// first, as DC correlates with itself at every lag.
+///
+/// This is the direct O(N · lags) sum: about `2 · len · lags` multiply-adds
+/// with the search taken twice, once to find the best score and once to
+/// find the first peak near it, though that usually stops early. `analyze`
+/// logs what it costs with the cycle counter. Lags past half of `samples`
+/// overlap too little to trust, so aren't searched.
+pub fn autocorr_pitch(
+    samples: &[f32],
+    sample_rate_hz: f32,
+    min_hz: f32,
+    max_hz: f32,
+) -> Option<f32> {
+    let len = samples.len();
+    let min_lag = ((sample_rate_hz / max_hz) as usize).max(2);
+    let max_lag = ((sample_rate_hz / min_hz) as usize + 1).min(len / 2);
+    if min_lag >= max_lag {
+        return None;
+    }
+
+    let score = |lag: usize| normalized_autocorrelation(samples, lag);
+    let is_peak = |lag: usize, r: f32| score(lag - 1) < r && r >= score(lag + 1);
+
+    let best = (min_lag..=max_lag)
+        .map(score)
+        .fold(f32::NEG_INFINITY, f32::max);
+    if best < MIN_CLARITY {
+        return None;
+    }
+
+    let lag = (min_lag..=max_lag).find(|&lag| {
+        let r = score(lag);
+        r >= FIRST_PEAK_FRACTION * best && is_peak(lag, r)
+    })?;
+
+    let (left, centre, right) = (score(lag - 1), score(lag), score(lag + 1));
+    let curvature = left - 2.0 * centre + right;
+    let offset = if curvature == 0.0 {
+        0.0
+    } else {
+        (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
+    };
+    Some(sample_rate_hz / (lag as f32 + offset))
+}
+
+/// `2 Σ x[n] x[n + lag] / Σ (x[n]² + x[n + lag]²)`, 0 where there's nothing
+fn normalized_autocorrelation(samples: &[f32], lag: usize) -> f32 {
+    let Some(overlap) = samples.len().checked_sub(lag) else {
+        return 0.0;
+    };
+    let (mut product, mut energy) = (0.0, 0.0);
+    for (x, y) in samples[..overlap].iter().zip(&samples[lag..]) {
+        product += x * y;
+        energy += x * x + y * y;
+    }
+    if energy == 0.0 {
+        0.0
+    } else {
+        2.0 * product / energy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::PI;
+
+    const RATE: f32 = 10_240.0;
+
+    /// A fundamental of `hz` with a second harmonic over three times as
+    /// strong and a third, like a voice or a reed
+    fn harmonic_rich(hz: f32) -> [f32; 1024] {
+        core::array::from_fn(|n| {
+            let phase = 2.0 * PI * hz * n as f32 / RATE;
+            0.3 * phase.sin() + (2.0 * phase).sin() + 0.6 * (3.0 * phase + 1.0).sin()
+        })
+    }
+
+    #[test]
+    fn finds_the_fundamental_under_stronger_harmonics() {
+        for hz in [110.0, 220.0, 333.3, 440.0] {
+            let pitch = autocorr_pitch(&harmonic_rich(hz), RATE, 50.0, 1_000.0).unwrap();
+            assert!((pitch / hz - 1.0).abs() < 0.005, "{hz} Hz came out {pitch}");
+        }
+
+        // A sawtooth has every harmonic
+        let saw: [f32; 1024] = core::array::from_fn(|n| {
+            let cycles = 150.0 * n as f32 / RATE;
+            cycles - (cycles as i32) as f32 - 0.5
+        });
+        let pitch = autocorr_pitch(&saw, RATE, 50.0, 1_000.0).unwrap();
+        assert!((pitch / 150.0 - 1.0).abs() < 0.005, "{pitch}");
+    }
+
+    #[test]
+    fn noise_and_silence_have_no_pitch() {
+        let mut seed = 1u32;
+        let noise: [f32; 1024] = core::array::from_fn(|_| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+        });
+        assert_eq!(autocorr_pitch(&noise, RATE, 50.0, 1_000.0), None);
+        assert_eq!(autocorr_pitch(&[0.0; 1024], RATE, 50.0, 1_000.0), None);
+
+        // Nothing to search, the range is shorter than a lag
+        let tone = harmonic_rich(200.0);
+        assert_eq!(autocorr_pitch(&tone, RATE, 5_000.0, 5_100.0), None);
+        assert_eq!(autocorr_pitch(&tone[..8], RATE, 50.0, 1_000.0), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! Dither, noise added ahead of quantizing so the quantization error stops
+//! following the signal. Without it a quiet or slowly varying signal gets
+//! the same error every cycle, which is harmonics of it: idle tones, lines
+//! in the spectrum that aren't in the input.
+//!
+//! The noise comes from whatever random source there is, through
+//! `uniform_pm`, so this is the same on the board's RNG and in tests.
+
+use micromath::F32Ext;
+
+/// What's done with the dither around quantizing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DitherMode {
+    /// Quantize the signal as it is
+    Off,
+    /// Add triangular dither two LSBs wide and leave it in, which removes
+    /// the idle tones for a noise floor about 5 dB higher
+    Additive,
+    /// Add rectangular dither an LSB wide and take the same values off
+    /// again after quantizing, which removes the idle tones and leaves the
+    /// noise floor where it was
+    Subtractive,
+}
+
+/// A uniform value in `-amplitude..amplitude` from 32 random bits. Only the
+/// top 24 are used, as many as an `f32` holds.
+pub fn uniform_pm(bits: u32, amplitude: f32) -> f32 {
+    let unit = (bits >> 8) as f32 / (1 << 24) as f32;
+    (2.0 * unit - 1.0) * amplitude
+}
+
+/// Round `samples` to multiples of `step`, as an ADC with LSBs that size
+/// would, with `mode`'s dither. `noise` returns uniform values in
+/// `-step / 2..step / 2`, e.g. `uniform_pm` of random bits.
+pub fn requantize(
+    samples: &mut [f32],
+    step: f32,
+    mode: DitherMode,
+    mut noise: impl FnMut() -> f32,
+) {
+    for sample in samples {
+        *sample = match mode {
+            DitherMode::Off => quantize(*sample, step),
+            // The sum of two uniform values is triangular
+            DitherMode::Additive => quantize(*sample + noise() + noise(), step),
+            DitherMode::Subtractive => {
+                let dither = noise();
+                quantize(*sample + dither, step) - dither
+            }
+        };
+    }
+}
+
+/// The multiple of `step` nearest `x`
+fn quantize(x: f32, step: f32) -> f32 {
+    (x / step).round() * step
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::TAU;
+
+    const LEN: usize = 1024;
+    /// Whole cycles in the capture, so the harmonics fall on bins
+    const CYCLES: usize = 16;
+
+    /// The same pseudo-random bits every run
+    fn xorshift(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    /// Amplitude of the component `cycles` times round the capture
+    fn amplitude_at(samples: &[f32], cycles: usize) -> f32 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, &x) in samples.iter().enumerate() {
+            let phase = TAU * (cycles * i % samples.len()) as f32 / samples.len() as f32;
+            re += x * phase.cos();
+            im += x * phase.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / samples.len() as f32
+    }
+
+    /// A sine a couple of LSBs high, quantized with `mode`: its amplitude,
+    /// and the worst of its odd harmonics, where the idle tones are
+    fn quantized_sine(mode: DitherMode) -> (f32, f32) {
+        let mut samples = [0.0; LEN];
+        for (i, x) in samples.iter_mut().enumerate() {
+            *x = 2.3 * (TAU * (CYCLES * i) as f32 / LEN as f32 + 0.3).sin();
+        }
+        let mut state = 0x1234_5678;
+        requantize(&mut samples, 1.0, mode, || {
+            uniform_pm(xorshift(&mut state), 0.5)
+        });
+
+        let spur = (3..16)
+            .step_by(2)
+            .map(|harmonic| amplitude_at(&samples, harmonic * CYCLES))
+            .fold(0.0, f32::max);
+        (amplitude_at(&samples, CYCLES), spur)
+    }
+
+    #[test]
+    fn uniform_values_cover_the_range() {
+        assert_eq!(uniform_pm(0, 0.5), -0.5);
+        assert!((uniform_pm(u32::MAX, 0.5) - 0.5).abs() < 1e-6);
+        assert_eq!(uniform_pm(1 << 31, 2.0), 0.0);
+
+        let mut samples = [0.26, -0.74, 1.5];
+        requantize(&mut samples, 0.5, DitherMode::Off, || unreachable!());
+        assert_eq!(samples, [0.5, -0.5, 1.5]);
+    }
+
+    #[test]
+    fn dither_breaks_up_the_idle_tones() {
+        let (plain, plain_spur) = quantized_sine(DitherMode::Off);
+        // Quantizing a signal this small gets its amplitude wrong as well
+        assert!((plain - 2.3).abs() > 0.1, "{plain}");
+        assert!(plain_spur > 0.1, "{plain_spur}");
+
+        for mode in [DitherMode::Additive, DitherMode::Subtractive] {
+            let (amplitude, spur) = quantized_sine(mode);
+            assert!((amplitude - 2.3).abs() < 0.05, "{mode:?}: {amplitude}");
+            assert!(spur < plain_spur / 2.0, "{mode:?}: {spur} vs {plain_spur}");
+        }
+    }
+}
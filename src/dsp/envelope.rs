@@ -0,0 +1,198 @@
+//! Amplitude tracking in the time domain.
+
+use micromath::F32Ext;
+
+use super::filter::Biquad;
+
+/// Peak envelope follower: rectifies the signal, then smooths it with one
+/// time constant while the level rises and another while it falls.
+///
+/// With attack much shorter than a period of the signal, and release much
+/// longer, the envelope of a steady sine sits just under its amplitude. A
+/// longer attack averages more, pulling it down towards the rectified
+/// mean of 2/π times the amplitude.
+#[derive(Clone, Copy, Debug)]
+pub struct EnvelopeFollower {
+    attack: f32,
+    release: f32,
+    envelope: f32,
+}
+
+impl EnvelopeFollower {
+    /// Time constants are how long it takes to cover 63% of a step
+    pub fn new(attack_ms: f32, release_ms: f32, sample_rate_hz: f32) -> Self {
+        Self {
+            attack: coefficient(attack_ms, sample_rate_hz),
+            release: coefficient(release_ms, sample_rate_hz),
+            envelope: 0.0,
+        }
+    }
+
+    pub fn envelope(&self) -> f32 {
+        self.envelope
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = 0.0;
+    }
+
+    /// Track a single sample, returning the envelope after it
+    #[inline]
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        let level = x.abs();
+        let coefficient = if level > self.envelope {
+            self.attack
+        } else {
+            self.release
+        };
+        self.envelope = level + coefficient * (self.envelope - level);
+        self.envelope
+    }
+
+    /// Replace each sample of `buf` with the envelope at that point
+    pub fn process(&mut self, buf: &mut [f32]) {
+        buf.iter_mut().for_each(|f| *f = self.process_sample(*f));
+    }
+
+    /// Write the envelope of `input` into `output`, which must be as long
+    pub fn process_into(&mut self, input: &[f32], output: &mut [f32]) {
+        assert_eq!(input.len(), output.len());
+        for (out, &x) in output.iter_mut().zip(input) {
+            *out = self.process_sample(x);
+        }
+    }
+}
+
+/// The envelope of an amplitude modulated carrier, in place: rectified, then
+/// smoothed by `lowpass`, whose cutoff should be well above the modulation
+/// and well below the carrier. A sine's rectified mean is 2/π of its
+/// amplitude, so that's what the envelope is in, which modulation depth
+/// doesn't mind.
+///
+/// `samples` have to be centred on zero, e.g. after `normalize_slice`, as
+/// rectifying folds the carrier up about zero: codes with their mid-scale
+/// offset left on are all one side of it, and come back barely changed.
+/// The filter is settled on the rectified mean before it starts, so the
+/// envelope doesn't ramp up from zero over the first few milliseconds.
+pub fn envelope(samples: &mut [f32], lowpass: &mut Biquad) {
+    if samples.is_empty() {
+        return;
+    }
+    samples.iter_mut().for_each(|x| *x = x.abs());
+    let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+    lowpass.settle(mean);
+    lowpass.process_block(samples);
+}
+
+/// How deeply `envelope` is modulated, `(max - min) / (max + min)`: 0 for a
+/// steady carrier, 1 where it's modulated down to nothing. Returns `None`
+/// without a carrier to measure against, an empty or all-zero envelope.
+/// The lowpass can undershoot below zero after a deep trough, which is
+/// taken as zero, as no rectified signal goes below it.
+pub fn modulation_depth(envelope: &[f32]) -> Option<f32> {
+    let (min, max) = envelope
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &x| {
+            (min.min(x), max.max(x))
+        });
+    let min = min.max(0.0);
+    (max > 0.0).then(|| (max - min) / (max + min))
+}
+
+/// One-pole smoothing coefficient for a time constant. Zero time follows
+/// the input immediately.
+fn coefficient(time_ms: f32, sample_rate_hz: f32) -> f32 {
+    let samples = time_ms * 1e-3 * sample_rate_hz;
+    if samples > 0.0 {
+        (-1.0 / samples).exp()
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::PI;
+
+    const RATE: f32 = 48_000.0;
+
+    fn sine(amplitude: f32, hz: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| amplitude * (2.0 * PI * hz * n as f32 / RATE).sin())
+            .collect()
+    }
+
+    #[test]
+    fn envelope_of_a_sine_converges_to_its_amplitude() {
+        let mut follower = EnvelopeFollower::new(0.05, 50.0, RATE);
+        let mut buf = sine(0.8, 1_000.0, 24_000);
+        follower.process(&mut buf);
+
+        // Past a few release times, only the ripple between peaks is left
+        for &envelope in &buf[12_000..] {
+            assert!((envelope - 0.8).abs() < 0.02, "{envelope}");
+        }
+    }
+
+    #[test]
+    fn attack_and_release_rates() {
+        let mut follower = EnvelopeFollower::new(1.0, 10.0, RATE);
+
+        // One attack time into a step, 63% of the way there
+        let mut step = vec![1.0; 48];
+        follower.process(&mut step);
+        assert!(
+            (step[47] - (1.0 - (-1.0f32).exp())).abs() < 0.01,
+            "{}",
+            step[47]
+        );
+
+        // And one release time after it ends, 37% of what's left
+        let start = follower.envelope();
+        let mut silence = vec![0.0; 480];
+        follower.process(&mut silence);
+        let expected = start * (-1.0f32).exp();
+        assert!((silence[479] - expected).abs() < 0.01, "{}", silence[479]);
+    }
+
+    #[test]
+    fn depth_of_an_am_carrier() {
+        // A 10 kHz carrier modulated at 100 Hz, a tenth of a second of it
+        for depth in [0.0, 0.3, 0.8] {
+            let mut samples: Vec<f32> = (0..4_800)
+                .map(|n| {
+                    let t = n as f32 / RATE;
+                    (1.0 + depth * (2.0 * PI * 100.0 * t).cos()) * (2.0 * PI * 10_000.0 * t).sin()
+                })
+                .collect();
+            envelope(&mut samples, &mut Biquad::lowpass(1_000.0, RATE, 0.707));
+
+            let measured = modulation_depth(&samples).unwrap();
+            assert!((measured - depth).abs() < 0.05, "{depth}: {measured}");
+            // Of a carrier of amplitude 1, on average
+            let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+            assert!((mean - 2.0 / PI).abs() < 0.02, "{mean}");
+        }
+    }
+
+    #[test]
+    fn no_depth_without_a_carrier() {
+        let mut silence = [0.0; 64];
+        envelope(&mut silence, &mut Biquad::lowpass(1_000.0, RATE, 0.707));
+        assert_eq!(modulation_depth(&silence), None);
+        assert_eq!(modulation_depth(&[]), None);
+
+        // An undershoot below zero is as deep as it goes
+        assert_eq!(modulation_depth(&[1.0, -0.1, 0.5]), Some(1.0));
+    }
+
+    #[test]
+    fn zero_times_follow_the_rectified_input() {
+        let mut follower = EnvelopeFollower::new(0.0, 0.0, RATE);
+        let input = [0.5, -2.0, 1.0, 0.0];
+        let mut output = [0.0; 4];
+        follower.process_into(&input, &mut output);
+        assert_eq!(output, [0.5, 2.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,526 @@
+//! FFTs of any size microfft supports, picked at runtime from the length.
+
+use microfft::Complex32;
+use micromath::F32Ext;
+
+/// Largest transform microfft is built with (its default `size-4096` feature)
+pub const MAX_FFT_LEN: usize = 4096;
+
+/// Whether `len` is a real FFT size microfft provides
+pub const fn is_supported_len(len: usize) -> bool {
+    len.is_power_of_two() && len >= 2 && len <= MAX_FFT_LEN
+}
+
+/// Real FFT of `buf`, in place, dispatching to `microfft::real::rfft_*`.
+///
+/// Like microfft, this returns `len / 2` bins with the Nyquist bin's real
+/// part packed into the imaginary part of bin 0.
+/// Panics if the length isn't supported, see `is_supported_len`.
+pub fn rfft(buf: &mut [f32]) -> &mut [Complex32] {
+    macro_rules! dispatch {
+        ($($n:literal => $f:ident),*) => {
+            match buf.len() {
+                $($n => microfft::real::$f(buf.try_into().unwrap()),)*
+                len => panic!("no {} point real FFT", len),
+            }
+        };
+    }
+
+    dispatch!(
+        2 => rfft_2,
+        4 => rfft_4,
+        8 => rfft_8,
+        16 => rfft_16,
+        32 => rfft_32,
+        64 => rfft_64,
+        128 => rfft_128,
+        256 => rfft_256,
+        512 => rfft_512,
+        1024 => rfft_1024,
+        2048 => rfft_2048,
+        4096 => rfft_4096
+    )
+}
+
+/// Complex FFT of `buf`, in place, dispatching to `microfft::complex::cfft_*`.
+///
+/// All `len` bins come back, in the usual order: DC, the positive
+/// frequencies, then the negative ones from the most negative up. For IQ
+/// data, e.g. from `acquisition::acquire_iq`, use `fftshift` or
+/// `two_sided_magnitudes` to put them in frequency order.
+/// Panics if the length isn't supported, see `is_supported_len`.
+pub fn cfft(buf: &mut [Complex32]) -> &mut [Complex32] {
+    macro_rules! dispatch {
+        ($($n:literal => $f:ident),*) => {
+            match buf.len() {
+                $($n => microfft::complex::$f(buf.try_into().unwrap()),)*
+                len => panic!("no {} point complex FFT", len),
+            }
+        };
+    }
+
+    dispatch!(
+        2 => cfft_2,
+        4 => cfft_4,
+        8 => cfft_8,
+        16 => cfft_16,
+        32 => cfft_32,
+        64 => cfft_64,
+        128 => cfft_128,
+        256 => cfft_256,
+        512 => cfft_512,
+        1024 => cfft_1024,
+        2048 => cfft_2048,
+        4096 => cfft_4096
+    )
+}
+
+/// `cfft` for a buffer whose length is fixed, e.g. a whole `SIZE` capture,
+/// giving the array back. An unsupported `N` fails to build rather than
+/// panicking.
+pub fn complex_fft<const N: usize>(buf: &mut [Complex32; N]) -> &mut [Complex32; N] {
+    const { assert!(is_supported_len(N), "no complex FFT of that length") };
+    cfft(buf).try_into().unwrap()
+}
+
+/// Inverse complex FFT of `buf`, in place, scaled by `1 / len` so it undoes
+/// `cfft`. microfft only has forward transforms, but conjugating turns one
+/// into the other: `IFFT(X) = conj(FFT(conj(X))) / N`, so this conjugates,
+/// transforms forward and conjugates back.
+/// Panics if the length isn't supported, see `is_supported_len`.
+pub fn icfft(buf: &mut [Complex32]) -> &mut [Complex32] {
+    buf.iter_mut().for_each(|x| *x = x.conj());
+    let out = cfft(buf);
+    let scale = 1.0 / out.len() as f32;
+    out.iter_mut().for_each(|x| *x = x.conj() * scale);
+    out
+}
+
+/// Inverse real FFT of a packed `rfft` spectrum, so `irfft(rfft(x))` is `x`
+/// again, as microfft has no inverse of its own. The spectrum's expanded to
+/// all `N` bins in `scratch`, by `expand_to_full`, and inverted there by
+/// `icfft`, which leaves the signal in the real parts. The first
+/// `out.len()` samples go in `out`, e.g. to leave zero padding off.
+/// Panics unless `scratch` is twice `spectrum`, a supported length, and
+/// `out` is no longer.
+pub fn irfft(spectrum: &[Complex32], scratch: &mut [Complex32], out: &mut [f32]) {
+    assert_eq!(
+        spectrum.len() * 2,
+        scratch.len(),
+        "the scratch holds every bin"
+    );
+    assert!(out.len() <= scratch.len(), "there are only N samples");
+    expand_to_full(spectrum, scratch);
+    let signal = icfft(scratch);
+    for (sample, x) in out.iter_mut().zip(signal.iter()) {
+        *sample = x.re;
+    }
+}
+
+/// Zero every bin of a packed `rfft` spectrum whose center is from `low_hz`
+/// to `high_hz`, to take the band out of the signal `irfft` gives back. DC
+/// and Nyquist share bin 0, so each is only zeroed if it's in the band.
+pub fn notch(spectrum: &mut [Complex32], low_hz: f32, high_hz: f32, sample_rate_hz: f32) {
+    let half = spectrum.len();
+    for k in 0..=half {
+        if !(low_hz..=high_hz).contains(&bin_to_hz(k, sample_rate_hz, half * 2)) {
+            continue;
+        }
+        match k {
+            0 => spectrum[0].re = 0.0,
+            k if k == half => spectrum[0].im = 0.0,
+            k => spectrum[k] = Complex32::new(0.0, 0.0),
+        }
+    }
+}
+
+/// Rebuild all `full_out.len()` bins of a real signal's spectrum from the
+/// one-sided half `rfft` gives, for tools that expect N bins. A real
+/// signal's spectrum is conjugate symmetric, `X[N - k] = conj(X[k])`, so the
+/// negative frequencies mirror the positive ones.
+///
+/// `one_sided` is either `rfft`'s `N / 2` bins, with the Nyquist bin's real
+/// part packed into bin 0's imaginary part, or `N / 2 + 1` bins with
+/// Nyquist as the last. DC and Nyquist are their own mirror images, so
+/// they're real, and any imaginary part they have is dropped.
+/// Panics unless `full_out.len()` is even and `one_sided` is one of those
+/// lengths.
+pub fn expand_to_full(one_sided: &[Complex32], full_out: &mut [Complex32]) {
+    let n = full_out.len();
+    let half = n / 2;
+    assert!(
+        n >= 2 && n.is_multiple_of(2),
+        "a full spectrum needs an even number of bins"
+    );
+    let (dc, nyquist) = match one_sided.len() {
+        len if len == half => (one_sided[0].re, one_sided[0].im),
+        len if len == half + 1 => (one_sided[0].re, one_sided[half].re),
+        len => panic!("{} bins is no half of a {} point spectrum", len, n),
+    };
+
+    full_out[0] = Complex32::new(dc, 0.0);
+    full_out[half] = Complex32::new(nyquist, 0.0);
+    for k in 1..half {
+        full_out[k] = one_sided[k];
+        full_out[n - k] = one_sided[k].conj();
+    }
+}
+
+/// Reorder a full FFT so bins run from the most negative frequency to the
+/// most positive, with DC at index `len / 2`. Like numpy's `fftshift`, this
+/// works for odd lengths too, where there's one fewer negative bin than
+/// positive ones rather than a shared Nyquist bin.
+pub fn fftshift<T>(bins: &mut [T]) {
+    let len = bins.len();
+    bins.rotate_right(len / 2);
+}
+
+/// Undo `fftshift`. Only different from it for odd lengths.
+pub fn ifftshift<T>(bins: &mut [T]) {
+    let len = bins.len();
+    bins.rotate_left(len / 2);
+}
+
+/// Frequency of index `i` of an `fftshift`ed `fft_len` point spectrum,
+/// negative left of DC
+pub fn shifted_bin_to_hz(i: usize, sample_rate_hz: f32, fft_len: usize) -> f32 {
+    (i as f32 - (fft_len / 2) as f32) * bin_width_hz(sample_rate_hz, fft_len)
+}
+
+/// Magnitudes of a full complex spectrum in frequency order, negative
+/// frequencies on the left, as `out[i]` is at `shifted_bin_to_hz(i, ..)`.
+/// Panics if the lengths differ.
+pub fn two_sided_magnitudes(spectrum: &[Complex32], out: &mut [f32]) {
+    assert_eq!(
+        spectrum.len(),
+        out.len(),
+        "one magnitude for each bin is needed"
+    );
+    for (m, bin) in out.iter_mut().zip(spectrum) {
+        *m = bin.norm_sqr().sqrt();
+    }
+    fftshift(out);
+}
+
+/// Magnitude and phase of every bin in one pass, for when the phase matters,
+/// as it does comparing two channels. Phases are in radians, from -π to π.
+/// Panics unless both outputs are as long as `spectrum`.
+pub fn magnitude_phase(spectrum: &[Complex32], mag_out: &mut [f32], phase_out: &mut [f32]) {
+    assert_eq!(
+        spectrum.len(),
+        mag_out.len(),
+        "one magnitude for each bin is needed"
+    );
+    assert_eq!(
+        spectrum.len(),
+        phase_out.len(),
+        "one phase for each bin is needed"
+    );
+    for ((bin, m), p) in spectrum.iter().zip(mag_out).zip(phase_out) {
+        *m = bin.norm_sqr().sqrt();
+        *p = bin.im.atan2(bin.re);
+    }
+}
+
+/// Undo the wrapping at ±π of phases from `magnitude_phase`, in place, by
+/// adding or taking off whole turns so no step from one bin to the next is
+/// more than π. The first phase is left as it is. Do this before
+/// differentiating the phase, for group delay, or each wrap is a spike.
+/// A true step of more than π between bins can't be told from a wrap, so
+/// the bins have to be close enough together for the phase to change less.
+pub fn unwrap_phase(phase: &mut [f32]) {
+    use core::f32::consts::{PI, TAU};
+
+    let Some((&mut first, rest)) = phase.split_first_mut() else {
+        return;
+    };
+    let mut previous = first;
+    let mut turns = 0.0;
+    for p in rest {
+        let wrapped = *p;
+        let mut step = wrapped - previous;
+        while step > PI {
+            step -= TAU;
+            turns -= TAU;
+        }
+        while step < -PI {
+            step += TAU;
+            turns += TAU;
+        }
+        previous = wrapped;
+        *p = wrapped + turns;
+    }
+}
+
+/// Width of one bin of an `fft_len` point transform, in Hz
+pub fn bin_width_hz(sample_rate_hz: f32, fft_len: usize) -> f32 {
+    sample_rate_hz / fft_len as f32
+}
+
+/// Center frequency of `bin`
+pub fn bin_to_hz(bin: usize, sample_rate_hz: f32, fft_len: usize) -> f32 {
+    bin as f32 * bin_width_hz(sample_rate_hz, fft_len)
+}
+
+/// Bin whose center is nearest `hz`. Negative frequencies round to bin 0,
+/// and nothing clamps at Nyquist, so check the result against the spectrum.
+pub fn hz_to_bin(hz: f32, sample_rate_hz: f32, fft_len: usize) -> usize {
+    // `as` saturates, so anything below zero (or NaN) becomes 0
+    (hz / bin_width_hz(sample_rate_hz, fft_len) + 0.5) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fftshift_even_and_odd() {
+        let mut even = [0, 1, 2, 3, -4, -3, -2, -1];
+        fftshift(&mut even);
+        assert_eq!(even, [-4, -3, -2, -1, 0, 1, 2, 3]);
+        ifftshift(&mut even);
+        assert_eq!(even, [0, 1, 2, 3, -4, -3, -2, -1]);
+
+        // No Nyquist bin, so one more positive frequency than negative
+        let mut odd = [0, 1, 2, -2, -1];
+        fftshift(&mut odd);
+        assert_eq!(odd, [-2, -1, 0, 1, 2]);
+        ifftshift(&mut odd);
+        assert_eq!(odd, [0, 1, 2, -2, -1]);
+
+        let mut one = [0];
+        fftshift(&mut one);
+        assert_eq!(one, [0]);
+        fftshift::<i32>(&mut []);
+    }
+
+    #[test]
+    fn shifted_frequencies_are_centred_on_dc() {
+        assert_eq!(shifted_bin_to_hz(0, 8.0, 8), -4.0);
+        assert_eq!(shifted_bin_to_hz(4, 8.0, 8), 0.0);
+        assert_eq!(shifted_bin_to_hz(7, 8.0, 8), 3.0);
+        assert_eq!(shifted_bin_to_hz(0, 5.0, 5), -2.0);
+        assert_eq!(shifted_bin_to_hz(2, 5.0, 5), 0.0);
+        assert_eq!(shifted_bin_to_hz(4, 5.0, 5), 2.0);
+    }
+
+    #[test]
+    fn negative_tones_land_left_of_dc() {
+        // e^(-j 2π 3n/N) is a tone at -3 bins, which a real FFT can't tell
+        // from +3
+        const N: usize = 64;
+        let mut buf = [Complex32::new(0.0, 0.0); N];
+        for (n, x) in buf.iter_mut().enumerate() {
+            let phase = -2.0 * core::f32::consts::PI * 3.0 * n as f32 / N as f32;
+            *x = Complex32::new(phase.cos(), phase.sin());
+        }
+
+        let mut magnitudes = [0.0; N];
+        two_sided_magnitudes(cfft(&mut buf), &mut magnitudes);
+
+        let peak = magnitudes
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap()
+            .0;
+        assert_eq!(shifted_bin_to_hz(peak, N as f32, N), -3.0);
+        assert!((magnitudes[peak] - N as f32).abs() < 1e-3);
+    }
+
+    #[test]
+    fn complex_fft_matches_cfft() {
+        let input: [Complex32; 16] =
+            core::array::from_fn(|n| Complex32::new(n as f32, (n % 3) as f32 - 1.0));
+        let mut expected = input;
+        cfft(&mut expected);
+        let mut buf = input;
+        assert_eq!(complex_fft(&mut buf), &expected);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn magnitude_and_phase_of_each_quadrant() {
+        use core::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+        let spectrum = [
+            Complex32::new(3.0, 4.0),
+            Complex32::new(0.0, 2.0),
+            Complex32::new(-1.0, -1.0),
+            Complex32::new(-2.0, 0.0),
+        ];
+        let mut mag = [0.0; 4];
+        let mut phase = [0.0; 4];
+        magnitude_phase(&spectrum, &mut mag, &mut phase);
+
+        let expected_mag = [5.0, 2.0, 2.0f32.sqrt(), 2.0];
+        let expected_phase = [(4.0f32).atan2(3.0), FRAC_PI_2, -3.0 * FRAC_PI_4, PI];
+        for i in 0..4 {
+            assert!((mag[i] - expected_mag[i]).abs() < 1e-5, "{i}: {}", mag[i]);
+            assert!(
+                (phase[i] - expected_phase[i]).abs() < 1e-5,
+                "{i}: {}",
+                phase[i]
+            );
+        }
+    }
+
+    #[test]
+    fn unwrapping_restores_a_linear_phase() {
+        // A delay's phase falls steadily, and an upward ramp steps by most
+        // of a half turn
+        for slope in [-0.7, 0.3, 2.9] {
+            let linear: [f32; 64] = core::array::from_fn(|k| 0.5 + slope * k as f32);
+            let mut phase = linear.map(|p| p.sin().atan2(p.cos()));
+            assert!(phase.iter().all(|p| p.abs() <= core::f32::consts::PI));
+
+            unwrap_phase(&mut phase);
+            for (k, (p, expected)) in phase.iter().zip(&linear).enumerate() {
+                assert!(
+                    (p - expected).abs() < 1e-3,
+                    "{slope} at {k}: {p} != {expected}"
+                );
+            }
+        }
+
+        unwrap_phase(&mut []);
+        let mut one = [3.0];
+        unwrap_phase(&mut one);
+        assert_eq!(one, [3.0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn magnitude_phase_needs_matching_lengths() {
+        let spectrum = [Complex32::new(1.0, 0.0); 4];
+        magnitude_phase(&spectrum, &mut [0.0; 4], &mut [0.0; 3]);
+    }
+
+    #[test]
+    fn inverse_undoes_the_forward_transform() {
+        let original: [Complex32; 16] =
+            core::array::from_fn(|n| Complex32::new(n as f32, (n * n % 7) as f32 - 3.0));
+        let mut buf = original;
+        cfft(&mut buf);
+        icfft(&mut buf);
+        for (x, y) in buf.iter().zip(&original) {
+            assert!((*x - *y).norm_sqr() < 1e-8, "{x:?} != {y:?}");
+        }
+    }
+
+    #[test]
+    fn expanding_a_real_fft_matches_the_complex_one() {
+        const N: usize = 16;
+        let signal: [f32; N] = core::array::from_fn(|n| {
+            let t = n as f32 / N as f32;
+            // DC, an odd bin and Nyquist, so each part of the mirror is used
+            1.5 + (2.0 * core::f32::consts::PI * 3.0 * t).sin()
+                + if n % 2 == 0 { 0.5 } else { -0.5 }
+        });
+        let mut expected: [Complex32; N] = core::array::from_fn(|n| Complex32::new(signal[n], 0.0));
+        cfft(&mut expected);
+
+        let mut real = signal;
+        let packed = rfft(&mut real);
+        let mut full = [Complex32::new(0.0, 0.0); N];
+        expand_to_full(packed, &mut full);
+        for (k, (x, y)) in full.iter().zip(&expected).enumerate() {
+            assert!((*x - *y).norm_sqr() < 1e-8, "{k}: {x:?} != {y:?}");
+        }
+
+        // The same, with Nyquist unpacked into a bin of its own
+        let mut unpacked = [Complex32::new(0.0, 0.0); N / 2 + 1];
+        unpacked[..N / 2].copy_from_slice(packed);
+        unpacked[0].im = 0.0;
+        unpacked[N / 2] = Complex32::new(packed[0].im, 0.0);
+        let mut full = [Complex32::new(0.0, 0.0); N];
+        expand_to_full(&unpacked, &mut full);
+        for (x, y) in full.iter().zip(&expected) {
+            assert!((*x - *y).norm_sqr() < 1e-8, "{x:?} != {y:?}");
+        }
+    }
+
+    /// Noise from a fixed seed, to the same buffer every run
+    fn noise<const N: usize>(mut seed: u32) -> [f32; N] {
+        core::array::from_fn(|_| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+        })
+    }
+
+    /// `‖a - b‖ / ‖b‖`
+    fn relative_error(a: &[f32], b: &[f32]) -> f32 {
+        let error: f32 = a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum();
+        let norm: f32 = b.iter().map(|y| y * y).sum();
+        (error / norm).sqrt()
+    }
+
+    #[test]
+    fn inverse_real_fft_round_trips() {
+        const N: usize = 1024;
+        let original: [f32; N] = noise(7);
+        let mut buf = original;
+        let spectrum = rfft(&mut buf);
+        let mut scratch = [Complex32::new(0.0, 0.0); N];
+        let mut out = [0.0; N];
+        irfft(spectrum, &mut scratch, &mut out);
+        let error = relative_error(&out, &original);
+        assert!(error < 1e-3, "{error}");
+
+        // Only the DC and Nyquist terms, which are packed into one bin
+        let original: [f32; 8] =
+            core::array::from_fn(|n| 2.0 + if n % 2 == 0 { 1.0 } else { -1.0 });
+        let mut buf = original;
+        let spectrum = rfft(&mut buf);
+        assert_eq!((spectrum[0].re, spectrum[0].im), (16.0, 8.0));
+        let mut scratch = [Complex32::new(0.0, 0.0); 8];
+        let mut out = [0.0; 8];
+        irfft(spectrum, &mut scratch, &mut out);
+        assert!(relative_error(&out, &original) < 1e-6, "{out:?}");
+
+        // Just the start, as for a padded transform
+        let mut start = [0.0; 3];
+        irfft(spectrum, &mut scratch, &mut start);
+        assert!(relative_error(&start, &original[..3]) < 1e-6, "{start:?}");
+    }
+
+    #[test]
+    fn notching_a_band_takes_its_tone_out() {
+        // Bins 10 and 50 at 1 Hz a bin, with DC under them
+        const N: usize = 256;
+        let tone =
+            |bin: f32, n: usize| (2.0 * core::f32::consts::PI * bin * n as f32 / N as f32).sin();
+        let kept: [f32; N] = core::array::from_fn(|n| 0.5 + tone(10.0, n));
+        let mut buf: [f32; N] = core::array::from_fn(|n| kept[n] + tone(50.0, n));
+
+        let spectrum = rfft(&mut buf);
+        notch(spectrum, 45.0, 55.0, N as f32);
+        let mut scratch = [Complex32::new(0.0, 0.0); N];
+        let mut out = [0.0; N];
+        irfft(spectrum, &mut scratch, &mut out);
+        let error = relative_error(&out, &kept);
+        assert!(error < 1e-3, "{error}");
+
+        // DC and Nyquist only go if they're in the band
+        let mut packed = [Complex32::new(3.0, 4.0), Complex32::new(1.0, 1.0)];
+        notch(&mut packed, 0.0, 0.5, 4.0);
+        assert_eq!(packed[0], Complex32::new(0.0, 4.0));
+        notch(&mut packed, 1.5, 2.0, 4.0);
+        assert_eq!(packed, [Complex32::new(0.0, 0.0), Complex32::new(1.0, 1.0)]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn expanding_needs_half_the_bins() {
+        expand_to_full(
+            &[Complex32::new(0.0, 0.0); 3],
+            &mut [Complex32::new(0.0, 0.0); 8],
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn unsupported_complex_lengths_panic() {
+        cfft(&mut [Complex32::new(0.0, 0.0); 3]);
+    }
+}
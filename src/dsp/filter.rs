@@ -0,0 +1,549 @@
+//! Time-domain filters and resampling.
+
+use core::f32::consts::PI;
+use micromath::F32Ext;
+
+/// A direct form I second order IIR section.
+/// Coefficients are normalized so that `a0 == 1`.
+#[derive(Clone, Copy, Debug)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    /// Build a filter from already-normalized coefficients
+    pub const fn new(b0: f32, b1: f32, b2: f32, a1: f32, a2: f32) -> Self {
+        Self {
+            b0,
+            b1,
+            b2,
+            a1,
+            a2,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    /// Butterworth-style lowpass from the RBJ audio EQ cookbook.
+    /// A `q` of `0.707` gives a maximally flat passband.
+    pub fn lowpass(cutoff_hz: f32, sample_rate_hz: f32, q: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff_hz / sample_rate_hz;
+        let (sin, cos) = (w0.sin(), w0.cos());
+        let alpha = sin / (2.0 * q);
+        let a0 = 1.0 + alpha;
+
+        Self::new(
+            (1.0 - cos) / 2.0 / a0,
+            (1.0 - cos) / a0,
+            (1.0 - cos) / 2.0 / a0,
+            -2.0 * cos / a0,
+            (1.0 - alpha) / a0,
+        )
+    }
+
+    /// Clear the filter history, keeping the coefficients
+    pub fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+        self.y1 = 0.0;
+        self.y2 = 0.0;
+    }
+
+    /// Set the history as if the input had always been `x`, so a filter
+    /// started part way into a signal doesn't ring up from zero first
+    pub fn settle(&mut self, x: f32) {
+        // The DC gain, unless the filter has a pole at DC and no steady state
+        let gain = (self.b0 + self.b1 + self.b2) / (1.0 + self.a1 + self.a2);
+        let y = if gain.is_finite() { gain * x } else { 0.0 };
+        self.x1 = x;
+        self.x2 = x;
+        self.y1 = y;
+        self.y2 = y;
+    }
+
+    /// Filter a single sample
+    #[inline]
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+
+        y
+    }
+
+    /// Filter a buffer in place
+    pub fn process_block(&mut self, buf: &mut [f32]) {
+        buf.iter_mut().for_each(|f| *f = self.process(*f));
+    }
+}
+
+/// A finite impulse response filter with `TAPS` coefficients.
+///
+/// The coefficients designed here are symmetric, which makes the filter
+/// linear phase: every frequency is delayed by the same
+/// `(TAPS - 1) / 2` samples (see `group_delay`). Subtract that when lining
+/// filtered data up against unfiltered data, e.g. in a cross-correlation.
+#[derive(Clone, Copy, Debug)]
+pub struct Fir<const TAPS: usize> {
+    coefficients: [f32; TAPS],
+    /// Past inputs, as a ring with `head` the next slot to write
+    history: [f32; TAPS],
+    head: usize,
+}
+
+impl<const TAPS: usize> Fir<TAPS> {
+    pub const fn new(coefficients: [f32; TAPS]) -> Self {
+        Self {
+            coefficients,
+            history: [0.0; TAPS],
+            head: 0,
+        }
+    }
+
+    /// Windowed-sinc lowpass with a Hamming window, normalized to unity
+    /// gain at DC. More taps give a sharper transition; an odd count keeps
+    /// the delay a whole number of samples.
+    pub fn lowpass(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        let fc = cutoff_hz / sample_rate_hz;
+        let middle = (TAPS as f32 - 1.0) / 2.0;
+
+        let mut coefficients = [0.0; TAPS];
+        for (i, c) in coefficients.iter_mut().enumerate() {
+            let t = i as f32 - middle;
+            let sinc = if t == 0.0 {
+                2.0 * fc
+            } else {
+                (2.0 * PI * fc * t).sin() / (PI * t)
+            };
+            let hamming = if TAPS > 1 {
+                0.54 - 0.46 * (2.0 * PI * i as f32 / (TAPS as f32 - 1.0)).cos()
+            } else {
+                1.0
+            };
+            *c = sinc * hamming;
+        }
+
+        let sum: f32 = coefficients.iter().sum();
+        coefficients.iter_mut().for_each(|c| *c /= sum);
+
+        Self::new(coefficients)
+    }
+
+    /// Delay through the filter, in samples, for symmetric coefficients
+    pub fn group_delay(&self) -> f32 {
+        (TAPS as f32 - 1.0) / 2.0
+    }
+
+    /// Clear the filter history, keeping the coefficients
+    pub fn reset(&mut self) {
+        self.history = [0.0; TAPS];
+        self.head = 0;
+    }
+
+    /// Filter a single sample
+    #[inline]
+    pub fn process(&mut self, x: f32) -> f32 {
+        if TAPS == 0 {
+            return 0.0;
+        }
+
+        self.history[self.head] = x;
+
+        // Walk back from the newest input, which meets coefficients[0]
+        let (to_head, after_head) = self.history.split_at(self.head + 1);
+        let y = to_head
+            .iter()
+            .rev()
+            .chain(after_head.iter().rev())
+            .zip(self.coefficients.iter())
+            .map(|(x, c)| x * c)
+            .sum();
+
+        self.head = (self.head + 1) % TAPS;
+        y
+    }
+
+    /// Filter a buffer in place, carrying the history over between calls
+    pub fn process_block(&mut self, buf: &mut [f32]) {
+        buf.iter_mut().for_each(|f| *f = self.process(*f));
+    }
+}
+
+/// Low-pass filter `input` and then keep every `factor`th sample.
+///
+/// The filter is what makes this decimation rather than plain downsampling:
+/// anything above the new Nyquist frequency (`sample_rate / (2 * factor)`) that
+/// isn't removed first folds back into the output spectrum as an alias. Design
+/// `filter` with a cutoff comfortably below that, e.g.
+/// `Biquad::lowpass(0.4 * sample_rate / factor as f32, sample_rate, 0.707)`.
+///
+/// The filter state carries over between calls, but the decimation phase
+/// doesn't: each call keeps samples `factor - 1`, `2 * factor - 1`, ... of
+/// its own input. `input` must therefore be a whole number of `factor`
+/// samples long, and then consecutive buffers of a stream decimate as one,
+/// without a transient or a skipped sample at each boundary.
+pub fn decimate(input: &[f32], factor: usize, output: &mut [f32], filter: &mut Biquad) {
+    assert!(factor > 0, "decimation factor must be non-zero");
+    assert!(
+        input.len().is_multiple_of(factor),
+        "input must be a multiple of the decimation factor"
+    );
+    assert_eq!(output.len(), input.len() / factor);
+
+    let mut out = output.iter_mut();
+    for (i, x) in input.iter().enumerate() {
+        // Every input sample goes through the filter to keep its state correct
+        let y = filter.process(*x);
+        if i % factor == factor - 1 {
+            if let Some(slot) = out.next() {
+                *slot = y;
+            }
+        }
+    }
+}
+
+/// Replace each sample with the median of the `W` samples centred on it.
+///
+/// Unlike a moving average, a single spike can't drag the output with it, so
+/// this removes impulse noise while keeping edges sharp. At the ends the
+/// window is clamped to the buffer, so the first and last `W / 2` samples
+/// use a smaller window, and of one with an even count the upper of the two
+/// middle samples.
+///
+/// `W` must be odd. The window is sorted with an insertion sort each time,
+/// which is fine for the small widths (up to ~9) this is meant for.
+pub fn median_filter<const W: usize>(input: &[f32], output: &mut [f32]) {
+    assert!(W % 2 == 1, "median window must be odd");
+    assert_eq!(input.len(), output.len());
+
+    let half = W / 2;
+    let mut window = [0.0f32; W];
+
+    for (i, out) in output.iter_mut().enumerate() {
+        let lo = i.saturating_sub(half);
+        let hi = (i + half + 1).min(input.len());
+        let n = hi - lo;
+
+        window[..n].copy_from_slice(&input[lo..hi]);
+
+        // Insertion sort. NaNs compare false, so they just stay put.
+        for j in 1..n {
+            let mut k = j;
+            while k > 0 && window[k - 1] > window[k] {
+                window.swap(k - 1, k);
+                k -= 1;
+            }
+        }
+
+        *out = window[n / 2];
+    }
+}
+
+/// A cascaded integrator-comb decimator: `STAGES` integrators at the input
+/// rate, then `STAGES` combs at the output rate, with no multiplies at all.
+/// That makes it the cheap way to bring a very fast stream, like the
+/// interleaved ADCs, down by a large factor.
+///
+/// Each stage is a moving sum over `factor` samples, so the response is a
+/// sinc to the power `STAGES`: nulls at every multiple of the output rate,
+/// where the aliases would fold onto DC, but a droop across the passband.
+/// More stages push the aliases down further. Follow it with a short FIR to
+/// flatten the droop and do the last factor of 2 or so if that matters.
+///
+/// The sums grow: the DC gain is `factor^STAGES` (see `gain`), which takes
+/// `STAGES * log2(factor)` more bits than the input has (see `bit_growth`).
+/// The registers are `i32`s that wrap, which is harmless as the combs
+/// subtract the wraps back out, provided the output itself fits. With
+/// unsigned 16-bit input that means `16 + bit_growth(factor) <= 31`, which
+/// `process` checks, e.g. 3 stages decimating by up to 32. Divide the output
+/// by `gain(factor)` to get back to ADC counts, for a power of two `factor`
+/// simply a right shift by `bit_growth(factor)`.
+#[derive(Clone, Copy, Debug)]
+pub struct Cic<const STAGES: usize> {
+    integrators: [i32; STAGES],
+    /// Each comb's previous input, a differential delay of one output
+    combs: [i32; STAGES],
+}
+
+impl<const STAGES: usize> Cic<STAGES> {
+    pub const fn new() -> Self {
+        Self {
+            integrators: [0; STAGES],
+            combs: [0; STAGES],
+        }
+    }
+
+    /// DC gain when decimating by `factor`
+    pub fn gain(factor: usize) -> u64 {
+        (factor as u64).pow(STAGES as u32)
+    }
+
+    /// Bits the output needs on top of the input's, rounded up for a
+    /// `factor` that isn't a power of two
+    pub fn bit_growth(factor: usize) -> u32 {
+        STAGES as u32 * factor.next_power_of_two().ilog2()
+    }
+
+    /// Clear the integrators and combs
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Decimate `input` by `factor` into `output`, which must be
+    /// `input.len() / factor` long. As with `decimate`, the state carries
+    /// over between calls but the phase doesn't, so give it whole multiples
+    /// of `factor` samples and a stream comes out as if it went in at once.
+    /// Keep `factor` the same between calls, or `reset` when changing it.
+    pub fn process(&mut self, input: &[u16], factor: usize, output: &mut [i32]) {
+        assert!(factor > 0, "decimation factor must be non-zero");
+        assert!(
+            16 + Self::bit_growth(factor) <= 31,
+            "{} stages decimating by {} overflow an i32",
+            STAGES,
+            factor
+        );
+        assert!(
+            input.len().is_multiple_of(factor),
+            "input must be a multiple of the decimation factor"
+        );
+        assert_eq!(output.len(), input.len() / factor);
+
+        for (block, out) in input.chunks_exact(factor).zip(output.iter_mut()) {
+            for &x in block {
+                let mut sum = x as i32;
+                for integrator in self.integrators.iter_mut() {
+                    *integrator = integrator.wrapping_add(sum);
+                    sum = *integrator;
+                }
+            }
+
+            let mut y = self.integrators.last().copied().unwrap_or(0);
+            for previous in self.combs.iter_mut() {
+                let x = y;
+                y = x.wrapping_sub(*previous);
+                *previous = x;
+            }
+            *out = y;
+        }
+    }
+}
+
+impl<const STAGES: usize> Default for Cic<STAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 48_000.0;
+
+    fn sine(hz: f32, i: usize) -> f32 {
+        (2.0 * PI * hz * i as f32 / RATE).sin()
+    }
+
+    /// Amplitude a unit sine at `hz` comes out of `filter` at, once it's
+    /// settled
+    fn gain_at(hz: f32, mut filter: impl FnMut(f32) -> f32) -> f32 {
+        (0..8_000)
+            .map(|i| filter(sine(hz, i)))
+            .skip(6_000)
+            .fold(0.0, |peak, y: f32| peak.max(y.abs()))
+    }
+
+    #[test]
+    fn lowpass_passes_dc_and_is_3db_down_at_cutoff() {
+        let mut dc = Biquad::lowpass(1_000.0, RATE, 0.707);
+        let settled = (0..2_000).map(|_| dc.process(1.0)).last().unwrap();
+        assert!((settled - 1.0).abs() < 1e-3, "{settled}");
+
+        let mut at_cutoff = Biquad::lowpass(1_000.0, RATE, 0.707);
+        let gain = gain_at(1_000.0, |x| at_cutoff.process(x));
+        assert!((gain - 0.707).abs() < 0.01, "{gain}");
+        // Second order, so 40 dB down a decade up
+        let mut above = Biquad::lowpass(1_000.0, RATE, 0.707);
+        let gain = gain_at(10_000.0, |x| above.process(x));
+        assert!(gain < 0.012, "{gain}");
+    }
+
+    #[test]
+    fn a_settled_filter_has_no_transient() {
+        let mut filter = Biquad::lowpass(1_000.0, RATE, 0.707);
+        filter.settle(2.5);
+        for _ in 0..100 {
+            let y = filter.process(2.5);
+            assert!((y - 2.5).abs() < 1e-4, "{y}");
+        }
+    }
+
+    #[test]
+    fn decimation_keeps_the_last_of_each_period() {
+        let through = &mut Biquad::new(1.0, 0.0, 0.0, 0.0, 0.0);
+        let ramp: [f32; 8] = core::array::from_fn(|i| i as f32);
+        let mut output = [0.0; 2];
+        decimate(&ramp, 4, &mut output, through);
+        assert_eq!(output, [3.0, 7.0]);
+    }
+
+    #[test]
+    fn decimation_knocks_down_what_would_alias() {
+        // Down by 8 to 6 kS/s, whose Nyquist is 3 kHz
+        let factor = 8;
+        let run = |hz: f32| {
+            let input: [f32; 8_000] = core::array::from_fn(|i| sine(hz, i));
+            let mut output = [0.0; 1_000];
+            let mut filter = Biquad::lowpass(0.4 * RATE / factor as f32, RATE, 0.707);
+            decimate(&input, factor, &mut output, &mut filter);
+            output[500..]
+                .iter()
+                .fold(0.0, |peak: f32, y| peak.max(y.abs()))
+        };
+        assert!(run(300.0) > 0.95);
+        // 20 kHz would fold down to 2 kHz
+        assert!(run(20_000.0) < 0.02, "{}", run(20_000.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "multiple of the decimation factor")]
+    fn decimation_needs_whole_periods() {
+        let mut filter = Biquad::lowpass(1_000.0, RATE, 0.707);
+        decimate(&[0.0; 10], 4, &mut [0.0; 2], &mut filter);
+    }
+
+    #[test]
+    fn fir_lowpass_taps_are_symmetric_with_unity_dc_gain() {
+        let fir = Fir::<31>::lowpass(4_000.0, RATE);
+        let sum: f32 = fir.coefficients.iter().sum();
+        assert!((sum - 1.0).abs() < 1e-6, "{sum}");
+        for (a, b) in fir.coefficients.iter().zip(fir.coefficients.iter().rev()) {
+            assert!((a - b).abs() < 1e-6, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn fir_impulse_response_peaks_at_the_group_delay() {
+        let mut fir = Fir::<31>::lowpass(4_000.0, RATE);
+        assert_eq!(fir.group_delay(), 15.0);
+        let response: [f32; 31] =
+            core::array::from_fn(|i| fir.process(if i == 0 { 1.0 } else { 0.0 }));
+        let peak = response
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap()
+            .0;
+        assert_eq!(peak as f32, fir.group_delay());
+        // And the response is the taps themselves
+        assert_eq!(response, fir.coefficients);
+    }
+
+    #[test]
+    fn fir_lowpass_stops_above_its_transition() {
+        let mut fir = Fir::<31>::lowpass(4_000.0, RATE);
+        let gain = gain_at(500.0, |x| fir.process(x));
+        assert!((gain - 1.0).abs() < 0.01, "{gain}");
+        // The Hamming window's sidelobes are over 50 dB down
+        fir.reset();
+        let gain = gain_at(12_000.0, |x| fir.process(x));
+        assert!(gain < 0.005, "{gain}");
+    }
+
+    #[test]
+    fn median_removes_a_spike_and_keeps_an_edge() {
+        let mut output = [0.0; 8];
+        median_filter::<3>(&[1.0, 1.0, 1.0, 50.0, 1.0, 1.0, 1.0, 1.0], &mut output);
+        assert_eq!(output, [1.0; 8]);
+
+        let step = [0.0, 0.0, 0.0, 0.0, 5.0, 5.0, 5.0, 5.0];
+        median_filter::<5>(&step, &mut output);
+        assert_eq!(output, step);
+    }
+
+    #[test]
+    fn median_windows_shrink_at_the_ends() {
+        let input = [9.0, 1.0, 2.0, 3.0, 4.0, 8.0, 7.0];
+        let mut output = [0.0; 7];
+        median_filter::<5>(&input, &mut output);
+        // 9, 1, 2 then 9, 1, 2, 3 at the start, whose upper middle is 3,
+        // and 3, 4, 8, 7 then 4, 8, 7 at the end
+        assert_eq!(output[..2], [2.0, 3.0]);
+        assert_eq!(output[5..], [7.0, 7.0]);
+        assert_eq!(output[2..5], [3.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "odd")]
+    fn median_windows_are_odd() {
+        median_filter::<4>(&[0.0; 8], &mut [0.0; 8]);
+    }
+
+    #[test]
+    fn one_stage_sums_each_block() {
+        let input: [u16; 12] = [1, 2, 3, 4, 10, 20, 30, 40, 0, 0, 0, 65535];
+        let mut output = [0; 3];
+        Cic::<1>::new().process(&input, 4, &mut output);
+        assert_eq!(output, [10, 100, 65535]);
+    }
+
+    #[test]
+    fn dc_settles_to_the_gain() {
+        const FACTOR: usize = 32;
+        let input = [u16::MAX; FACTOR * 8];
+        let mut output = [0; 8];
+        Cic::<3>::new().process(&input, FACTOR, &mut output);
+
+        // The gain takes the full 31 bits, yet nothing overflows, and after
+        // a transient as long as the filter the output's flat
+        let gain = Cic::<3>::gain(FACTOR);
+        assert_eq!(Cic::<3>::bit_growth(FACTOR), 15);
+        assert!(output[..2].iter().all(|&y| (y as u64) < gain * 65535));
+        assert!(output[3..].iter().all(|&y| y as u64 == gain * 65535));
+        assert_eq!(output[7] >> Cic::<3>::bit_growth(FACTOR), 65535);
+    }
+
+    #[test]
+    fn streams_across_calls() {
+        let input: [u16; 64] = core::array::from_fn(|i| (i * i * 37 % 4096) as u16);
+
+        let mut whole = [0; 8];
+        Cic::<4>::new().process(&input, 8, &mut whole);
+
+        let mut cic = Cic::<4>::new();
+        let mut parts = [0; 8];
+        let (first, second) = parts.split_at_mut(3);
+        cic.process(&input[..24], 8, first);
+        cic.process(&input[24..], 8, second);
+        assert_eq!(parts, whole);
+    }
+
+    #[test]
+    fn growth_rounds_up() {
+        assert_eq!(Cic::<3>::gain(10), 1000);
+        assert_eq!(Cic::<3>::bit_growth(10), 12);
+        assert_eq!(Cic::<2>::bit_growth(1), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_much_growth_panics() {
+        let mut output = [0; 1];
+        Cic::<4>::new().process(&[0; 16], 16, &mut output);
+    }
+}
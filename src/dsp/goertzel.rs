@@ -0,0 +1,281 @@
+//! Single-frequency DFTs with the Goertzel recurrence, and a detector for
+//! frequency-shift signalling built on them.
+//!
+//! Where an FFT works out every bin, Goertzel works out one, at any
+//! frequency rather than only on a bin, for a multiply and two adds a
+//! sample. For a handful of tones that's far less work than an FFT.
+
+use micromath::F32Ext;
+
+/// Least share of a block's energy the strongest tone has to have for
+/// `ToneDetector::classify` to say it's there. A block that straddles a
+/// change of tone splits its energy, and falls short until about 70% of
+/// it is the one tone.
+pub const MIN_TONE_SHARE: f32 = 0.5;
+
+/// The DFT of a block of samples at one frequency
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Goertzel {
+    /// 2 cos ω, for ω the frequency in radians a sample
+    coefficient: f32,
+}
+
+impl Goertzel {
+    pub fn new(hz: f32, sample_rate_hz: f32) -> Self {
+        let omega = 2.0 * core::f32::consts::PI * hz / sample_rate_hz;
+        Self {
+            coefficient: 2.0 * omega.cos(),
+        }
+    }
+
+    /// The squared magnitude of `samples`' DFT at the frequency. A sine of
+    /// amplitude A over N samples comes to (A N / 2)², near enough, for a
+    /// block of more than a few cycles.
+    pub fn power(&self, samples: &[f32]) -> f32 {
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for &x in samples {
+            let s0 = x + self.coefficient * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+        s1 * s1 + s2 * s2 - self.coefficient * s1 * s2
+    }
+}
+
+/// How the signal a `ToneDetector` listens to is cut into blocks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockConfig {
+    pub sample_rate_hz: f32,
+    /// Samples in each block `classify` is given
+    pub len: usize,
+    /// Symbols a second, how often the signal can change tone
+    pub symbol_rate_hz: f32,
+}
+
+impl BlockConfig {
+    /// Longest block that always fits wholly inside a symbol, half of one
+    pub fn max_len(&self) -> usize {
+        (self.sample_rate_hz / self.symbol_rate_hz / 2.0) as usize
+    }
+
+    /// Closest two tones can be and still be told apart in a block, about
+    /// a bin of an FFT as long
+    pub fn min_spacing_hz(&self) -> f32 {
+        self.sample_rate_hz / self.len as f32
+    }
+}
+
+/// Why a `ToneDetector` can't listen for a set of tones
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneError {
+    /// The block's longer than half a symbol
+    BlockTooLong { max_len: usize },
+    /// Two of the tones are closer than a block tells apart
+    TonesTooClose { min_spacing_hz: f32 },
+    /// A tone isn't between 0 Hz and Nyquist
+    ToneOutOfRange { hz: f32 },
+}
+
+/// Which of `TONES` frequencies is in each block of a signal, for decoding
+/// frequency-shift keying: a tone for each symbol, held for a symbol's time.
+///
+/// The block length is a trade between the two ends of that. A block tells
+/// tones apart down to about `sample_rate_hz / len`, so longer blocks let
+/// them be closer. But the blocks don't line up with the symbols, and a
+/// block that straddles a change of tone is no use, so for each symbol to
+/// have a block wholly inside it, however they line up, a block can be at
+/// most half a symbol. Together, the tones have to be at least twice the
+/// symbol rate apart: 300 baud needs 600 Hz between tones, whatever the
+/// sample rate. `new` checks both.
+#[derive(Clone, Copy, Debug)]
+pub struct ToneDetector<const TONES: usize> {
+    tones: [Goertzel; TONES],
+    len: usize,
+    /// Each tone's share of the last block's energy
+    shares: [f32; TONES],
+}
+
+impl<const TONES: usize> ToneDetector<TONES> {
+    /// Listen for `tones_hz` in blocks as `config` has them. Panics on an
+    /// empty block.
+    pub fn new(tones_hz: [f32; TONES], config: BlockConfig) -> Result<Self, ToneError> {
+        assert!(config.len > 0, "blocks need samples");
+        let nyquist_hz = config.sample_rate_hz / 2.0;
+        if let Some(&hz) = tones_hz.iter().find(|&&hz| !(hz > 0.0 && hz < nyquist_hz)) {
+            return Err(ToneError::ToneOutOfRange { hz });
+        }
+        if config.len > config.max_len() {
+            return Err(ToneError::BlockTooLong {
+                max_len: config.max_len(),
+            });
+        }
+        let min_spacing_hz = config.min_spacing_hz();
+        for (i, a) in tones_hz.iter().enumerate() {
+            if tones_hz[i + 1..]
+                .iter()
+                .any(|b| (a - b).abs() < min_spacing_hz)
+            {
+                return Err(ToneError::TonesTooClose { min_spacing_hz });
+            }
+        }
+
+        Ok(Self {
+            tones: tones_hz.map(|hz| Goertzel::new(hz, config.sample_rate_hz)),
+            len: config.len,
+            shares: [0.0; TONES],
+        })
+    }
+
+    /// The index in `tones_hz` of the tone in `samples`, the next block, if
+    /// one has at least `MIN_TONE_SHARE` of its energy. `None` for silence,
+    /// another frequency, or a block across a change of tone. The samples
+    /// want their mean taking off first, DC counts against every tone.
+    /// Panics unless the block's as long as `BlockConfig::len`.
+    pub fn classify(&mut self, samples: &[f32]) -> Option<usize> {
+        assert_eq!(samples.len(), self.len, "a block at a time");
+        let energy: f32 = samples.iter().map(|x| x * x).sum();
+        // A sine that's all of the block comes to a share of 1
+        let scale = if energy > 0.0 {
+            2.0 / (self.len as f32 * energy)
+        } else {
+            0.0
+        };
+        for (share, tone) in self.shares.iter_mut().zip(&self.tones) {
+            *share = tone.power(samples) * scale;
+        }
+
+        let (strongest, &share) = self
+            .shares
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        (share >= MIN_TONE_SHARE).then_some(strongest)
+    }
+
+    /// Each tone's share of the energy in the last block classified, from 0
+    /// to about 1, for seeing how close a call it was
+    pub fn shares(&self) -> &[f32; TONES] {
+        &self.shares
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 48_000.0;
+    /// Bell 202's tones, at a quarter of its rate
+    const TONES: [f32; 2] = [1_200.0, 2_200.0];
+    const SYMBOL_LEN: usize = 160;
+    const CONFIG: BlockConfig = BlockConfig {
+        sample_rate_hz: RATE,
+        len: SYMBOL_LEN / 2,
+        symbol_rate_hz: 300.0,
+    };
+
+    /// A block of a sine
+    fn sine(hz: f32, amplitude: f32) -> [f32; 80] {
+        core::array::from_fn(|i| {
+            amplitude * (2.0 * core::f32::consts::PI * hz * i as f32 / RATE).sin()
+        })
+    }
+
+    #[test]
+    fn goertzel_matches_the_dft_at_its_frequency() {
+        // 1.2 kHz is two whole cycles in 80 samples
+        let block = sine(1_200.0, 3.0);
+        let power = Goertzel::new(1_200.0, RATE).power(&block);
+        assert!((power / (3.0 * 80.0 / 2.0_f32).powi(2) - 1.0).abs() < 1e-3);
+        // Off a bin too, as an FFT couldn't
+        let block = sine(1_500.0, 3.0);
+        let power = Goertzel::new(1_500.0, RATE).power(&block);
+        assert!((power / (3.0 * 80.0 / 2.0_f32).powi(2) - 1.0).abs() < 0.05);
+        // And nothing at a frequency that isn't there
+        assert!(Goertzel::new(2_400.0, RATE).power(&sine(1_200.0, 3.0)) < 1e-3);
+    }
+
+    #[test]
+    fn classifies_each_tone_and_nothing_else() {
+        let mut detector = ToneDetector::new(TONES, CONFIG).unwrap();
+        assert_eq!(detector.classify(&sine(1_200.0, 500.0)), Some(0));
+        assert_eq!(detector.classify(&sine(2_200.0, 0.01)), Some(1));
+        assert!(detector.shares()[1] > 0.95, "{:?}", detector.shares());
+
+        // Between the two, silence, and both at once
+        assert_eq!(detector.classify(&sine(1_700.0, 500.0)), None);
+        assert_eq!(detector.classify(&[0.0; 80]), None);
+        let low = sine(1_200.0, 500.0);
+        let both: [f32; 80] = core::array::from_fn(|i| low[i] + sine(2_200.0, 500.0)[i]);
+        assert_eq!(detector.classify(&both), None);
+    }
+
+    #[test]
+    fn decodes_symbols_whichever_way_the_blocks_line_up() {
+        let symbols = [0, 1, 1, 0, 1, 0, 0, 0, 1, 1, 0, 1];
+        // Continuous phase, as a modem sends it
+        let mut signal = [0.0; 12 * SYMBOL_LEN];
+        let mut phase = 0.0_f32;
+        for (i, sample) in signal.iter_mut().enumerate() {
+            *sample = 1_000.0 * phase.sin();
+            phase += 2.0 * core::f32::consts::PI * TONES[symbols[i / SYMBOL_LEN]] / RATE;
+        }
+
+        for offset in [0, 1, 37, 79] {
+            let mut detector = ToneDetector::new(TONES, CONFIG).unwrap();
+            let mut heard = [false; 12];
+            for (b, block) in signal[offset..].chunks_exact(CONFIG.len).enumerate() {
+                let start = offset + b * CONFIG.len;
+                let (first, last) = (start / SYMBOL_LEN, (start + CONFIG.len - 1) / SYMBOL_LEN);
+                match detector.classify(block) {
+                    // Never a tone that wasn't sent while the block was
+                    Some(tone) => assert!(
+                        tone == symbols[first] || tone == symbols[last],
+                        "block at {}: {}",
+                        start,
+                        tone
+                    ),
+                    None => assert_ne!(first, last, "block at {} was all one symbol", start),
+                }
+                if first == last {
+                    heard[first] = true;
+                }
+            }
+            // Every symbol but one the offset cut into had a block to itself
+            assert!(
+                heard[1..].iter().all(|&h| h),
+                "offset {}: {:?}",
+                offset,
+                heard
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_blocks_that_cant_keep_up_or_tell_the_tones_apart() {
+        // Bell 103's tones are only 200 Hz apart, which takes 240-sample
+        // blocks, too long for 300 baud's 160-sample symbols
+        let bell_103 = [1_070.0, 1_270.0];
+        let bell_103_config = BlockConfig { len: 240, ..CONFIG };
+        assert_eq!(
+            ToneDetector::new(bell_103, bell_103_config).unwrap_err(),
+            ToneError::BlockTooLong { max_len: 80 }
+        );
+        assert_eq!(
+            ToneDetector::new(bell_103, CONFIG).unwrap_err(),
+            ToneError::TonesTooClose {
+                min_spacing_hz: 600.0
+            }
+        );
+        // At 100 baud they fit
+        let slow = BlockConfig {
+            symbol_rate_hz: 100.0,
+            ..bell_103_config
+        };
+        assert!(ToneDetector::new(bell_103, slow).is_ok());
+
+        assert_eq!(
+            ToneDetector::new([1_200.0, 24_000.0], CONFIG).unwrap_err(),
+            ToneError::ToneOutOfRange { hz: 24_000.0 }
+        );
+    }
+}
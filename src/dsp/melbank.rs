@@ -0,0 +1,216 @@
+//! Mel-scale filter banks, for band energies spaced the way pitch is heard
+//! rather than evenly in Hz, as keyword spotting and MFCCs start from.
+//!
+//! The scale and the filters follow HTK: `mel = 2595 log10(1 + hz / 700)`,
+//! band edges evenly spaced in mel, and triangles in Hz that peak at 1 in
+//! the middle of each band, with no normalization to equal area.
+
+use micromath::F32Ext;
+
+/// Floor under band energies before taking their log, so an empty band
+/// comes out very negative rather than minus infinity
+pub const LOG_FLOOR: f32 = 1e-10;
+
+/// Hz on HTK's mel scale
+pub fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+/// The inverse of `hz_to_mel`
+pub fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10.0f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// `BANDS` triangular filters over the power spectrum of an FFT, evenly
+/// spaced in mel from `low_hz` to `high_hz`. Each band rises from the
+/// centre of the one below to its own and falls to the centre of the one
+/// above, the bottom and top bands starting and ending at the limits.
+/// Built once for a sample rate and FFT length, as only the centres are
+/// kept and each weight is worked out as it's applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MelBank<const BANDS: usize> {
+    centres_hz: [f32; BANDS],
+    low_hz: f32,
+    high_hz: f32,
+    bin_hz: f32,
+}
+
+impl<const BANDS: usize> MelBank<BANDS> {
+    /// Bands for the spectrum of `fft_len` samples at `sample_rate_hz`,
+    /// from `low_hz` to `high_hz`, which should be no more than Nyquist
+    pub fn new(sample_rate_hz: f32, fft_len: usize, low_hz: f32, high_hz: f32) -> Self {
+        let low_mel = hz_to_mel(low_hz);
+        let step = (hz_to_mel(high_hz) - low_mel) / (BANDS + 1) as f32;
+        let mut centres_hz = [0.0; BANDS];
+        for (i, centre) in centres_hz.iter_mut().enumerate() {
+            *centre = mel_to_hz(low_mel + (i + 1) as f32 * step);
+        }
+
+        Self {
+            centres_hz,
+            low_hz,
+            high_hz,
+            bin_hz: sample_rate_hz / fft_len as f32,
+        }
+    }
+
+    /// Where each band peaks
+    pub fn centres_hz(&self) -> &[f32; BANDS] {
+        &self.centres_hz
+    }
+
+    /// Each band's energy: the sum of `power`, one value per bin from DC
+    /// up, under its triangle. Bins past the end of `power` count as zero.
+    pub fn apply(&self, power: &[f32]) -> [f32; BANDS] {
+        let mut energies = [0.0; BANDS];
+        for (band, energy) in energies.iter_mut().enumerate() {
+            let (lower, centre, upper) = self.edges(band);
+            let first = (lower / self.bin_hz).ceil() as usize;
+            let last = ((upper / self.bin_hz) as usize).min(power.len().saturating_sub(1));
+
+            for (k, &p) in power.iter().enumerate().take(last + 1).skip(first) {
+                let hz = k as f32 * self.bin_hz;
+                let weight = if hz <= centre {
+                    (hz - lower) / (centre - lower)
+                } else {
+                    (upper - hz) / (upper - centre)
+                };
+                // Rounding can put a bin at an edge just outside it
+                *energy += weight.max(0.0) * p;
+            }
+        }
+        energies
+    }
+
+    /// The start, peak and end of `band`, in Hz
+    fn edges(&self, band: usize) -> (f32, f32, f32) {
+        let lower = match band {
+            0 => self.low_hz,
+            _ => self.centres_hz[band - 1],
+        };
+        let upper = self
+            .centres_hz
+            .get(band + 1)
+            .copied()
+            .unwrap_or(self.high_hz);
+        (lower, self.centres_hz[band], upper)
+    }
+}
+
+/// Take the natural log of each energy, as HTK's filter bank output does,
+/// no lower than `LOG_FLOOR`'s
+pub fn log_compress(energies: &mut [f32]) {
+    for energy in energies {
+        *energy = energy.max(LOG_FLOOR).ln();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE_HZ: f32 = 16_000.0;
+    const FFT_LEN: usize = 512;
+
+    /// HTK band centres for 8 bands from 0 Hz to Nyquist at 16 kHz, in
+    /// double precision
+    const CENTRES_HZ: [f64; 8] = [
+        226.190_659_687,
+        525.470_197_275,
+        921.455_786_345,
+        1_445.396_006_298,
+        2_138.636_774_806,
+        3_055.884_095_815,
+        4_269.521_097_733,
+        5_875.320_034_057,
+    ];
+
+    fn close(actual: f32, expected: f64, tolerance: f64) -> bool {
+        ((actual as f64 - expected) / expected).abs() < tolerance
+    }
+
+    #[test]
+    fn the_scale_matches_htk() {
+        // 1 kHz is about 1000 mel, by design
+        assert!((hz_to_mel(1_000.0) - 999.985_5).abs() < 1e-3);
+        assert_eq!(hz_to_mel(0.0), 0.0);
+        for hz in [50.0, 440.0, 4_000.0, 20_000.0] {
+            assert!((mel_to_hz(hz_to_mel(hz)) - hz).abs() < hz * 1e-5, "{hz}");
+        }
+
+        let bank: MelBank<8> = MelBank::new(RATE_HZ, FFT_LEN, 0.0, RATE_HZ / 2.0);
+        for (&actual, &expected) in bank.centres_hz().iter().zip(&CENTRES_HZ) {
+            assert!(close(actual, expected, 1e-5), "{actual} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn energies_match_the_reference_weights() {
+        let bank: MelBank<8> = MelBank::new(RATE_HZ, FFT_LEN, 0.0, RATE_HZ / 2.0);
+
+        // A flat spectrum sums each band's weights, and a ramp weights
+        // them by bin, both from the same filters built in double precision
+        let flat = [1.0; FFT_LEN / 2 + 1];
+        let weights = [
+            8.393_390_742,
+            11.129_752_231,
+            14.713_084_741,
+            19.478_002_200,
+            25.765_175_105,
+            34.096_365_897,
+            45.107_922_395,
+            59.689_770_607,
+        ];
+        for (&actual, &expected) in bank.apply(&flat).iter().zip(&weights) {
+            assert!(close(actual, expected, 1e-4), "{actual} vs {expected}");
+        }
+
+        let mut ramp = [0.0; FFT_LEN / 2 + 1];
+        for (k, p) in ramp.iter_mut().enumerate() {
+            *p = k as f32;
+        }
+        let weighted = [
+            67.376_717_738,
+            198.661_151_764,
+            453.946_155_179,
+            936.075_567_542,
+            1_824.833_690_797,
+            3_442.008_800_007,
+            6_351.478_870_305,
+            11_552.525_842_817,
+        ];
+        for (&actual, &expected) in bank.apply(&ramp).iter().zip(&weighted) {
+            assert!(close(actual, expected, 1e-4), "{actual} vs {expected}");
+        }
+
+        // A spectrum that stops short of the top band leaves it empty
+        assert_eq!(bank.apply(&flat[..100])[7], 0.0);
+    }
+
+    #[test]
+    fn a_band_limited_bank_and_log_compression() {
+        // The narrower range speech features often use
+        let bank: MelBank<4> = MelBank::new(RATE_HZ, FFT_LEN, 300.0, 3_400.0);
+        let centres = [
+            626.040_447_566,
+            1_058.383_268_582,
+            1_631.687_336_463,
+            2_391.911_719_228,
+        ];
+        for (&actual, &expected) in bank.centres_hz().iter().zip(&centres) {
+            assert!(close(actual, expected, 1e-5), "{actual} vs {expected}");
+        }
+
+        // Nothing outside the range gets in
+        let mut power = [0.0; FFT_LEN / 2 + 1];
+        power[..9].fill(1.0);
+        power[109..].fill(1.0);
+        assert_eq!(bank.apply(&power), [0.0; 4]);
+
+        let mut energies = [core::f32::consts::E, 1.0, 0.0];
+        log_compress(&mut energies);
+        assert!((energies[0] - 1.0).abs() < 1e-6);
+        assert_eq!(energies[1], 0.0);
+        assert_eq!(energies[2], LOG_FLOOR.ln());
+    }
+}
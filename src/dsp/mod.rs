@@ -0,0 +1,25 @@
+//! Signal processing routines that operate on captured buffers.
+//! Everything in here is plain `no_std` math with no hardware access.
+
+pub mod cepstrum;
+pub mod correlation;
+pub mod dither;
+pub mod envelope;
+pub mod fft;
+pub mod filter;
+pub mod goertzel;
+pub mod melbank;
+pub mod peak_hold;
+pub mod pipeline;
+pub mod psd;
+pub mod samples;
+pub mod scaling;
+pub mod slow_stream;
+pub mod smoothing;
+pub mod spectrogram;
+pub mod spectrum;
+pub mod transfer;
+pub mod window;
+
+pub use correlation::autocorr_pitch;
+pub use envelope::envelope;
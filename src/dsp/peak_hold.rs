@@ -0,0 +1,91 @@
+//! Per-bin peak hold, like a spectrum analyzer's falling peak markers.
+
+/// How much a held peak falls each frame it isn't topped up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decay {
+    /// Fall by this much magnitude per frame, down to zero
+    Linear(f32),
+    /// Scale by this factor per frame, between 0 (no hold) and 1 (hold
+    /// forever)
+    Multiplicative(f32),
+}
+
+impl Decay {
+    fn apply(self, held: f32) -> f32 {
+        match self {
+            Decay::Linear(step) => (held - step).max(0.0),
+            Decay::Multiplicative(factor) => held * factor,
+        }
+    }
+}
+
+/// The maximum of each of `BINS` bins over recent frames, decaying between
+/// them so a peak that's gone drifts back down to the live spectrum.
+#[derive(Clone, Debug)]
+pub struct PeakHold<const BINS: usize> {
+    held: [f32; BINS],
+    decay: Decay,
+}
+
+impl<const BINS: usize> PeakHold<BINS> {
+    pub const fn new(decay: Decay) -> Self {
+        Self {
+            held: [0.0; BINS],
+            decay,
+        }
+    }
+
+    /// Decay the held peaks by a frame, then raise any that `magnitudes`
+    /// tops. Extra bins are ignored and missing ones only decay.
+    pub fn update(&mut self, magnitudes: &[f32]) {
+        for (i, held) in self.held.iter_mut().enumerate() {
+            let decayed = self.decay.apply(*held);
+            *held = magnitudes.get(i).map_or(decayed, |&m| decayed.max(m));
+        }
+    }
+
+    /// The held peaks, what to display or log instead of the spectrum
+    pub fn held(&self) -> &[f32; BINS] {
+        &self.held
+    }
+
+    pub fn set_decay(&mut self, decay: Decay) {
+        self.decay = decay;
+    }
+
+    pub fn reset(&mut self) {
+        self.held = [0.0; BINS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_the_maximum_and_decays_linearly() {
+        let mut hold: PeakHold<3> = PeakHold::new(Decay::Linear(0.25));
+        hold.update(&[1.0, 0.5, 0.0]);
+        hold.update(&[0.0, 0.75, 0.0]);
+        assert_eq!(hold.held(), &[0.75, 0.75, 0.0]);
+
+        // Never falls below zero, or below the live spectrum
+        for _ in 0..4 {
+            hold.update(&[0.0, 0.0, 0.1]);
+        }
+        assert_eq!(hold.held(), &[0.0, 0.0, 0.1]);
+    }
+
+    #[test]
+    fn multiplicative_decay() {
+        let mut hold: PeakHold<2> = PeakHold::new(Decay::Multiplicative(0.5));
+        hold.update(&[8.0, 2.0]);
+        hold.update(&[0.0]);
+        hold.update(&[0.0]);
+        // The missing second bin decays all the same
+        assert_eq!(hold.held(), &[2.0, 0.5]);
+
+        hold.reset();
+        assert_eq!(hold.held(), &[0.0, 0.0]);
+    }
+}
@@ -0,0 +1,324 @@
+//! What every capture goes through on its way to a spectrum, from its
+//! `SampleSource` onwards. Nothing here knows where the samples came from,
+//! so the whole thing can be run on canned data.
+
+use micromath::F32Ext;
+
+use super::fft::{self, bin_width_hz};
+use super::scaling::AdcScale;
+use super::spectrum::{find_peak_bin, interpolate_peak};
+use super::window::{apply_window, WindowType};
+use crate::acquisition::{AcqError, SampleSource};
+
+/// The strongest bin in a spectrum, past DC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Peak {
+    pub bin: usize,
+    /// Interpolated between bins, see `spectrum::interpolate_peak`
+    pub hz: f32,
+    pub magnitude: f32,
+}
+
+/// What `analyze_capture` makes of a capture
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Analysis {
+    /// In raw codes
+    pub mean: f32,
+    pub peak: Option<Peak>,
+    /// The cycle counter once the capture was in, to line captures up
+    /// against each other with `timing::elapsed_cycles`
+    pub timestamp_cycles: u32,
+}
+
+/// Codes as samples with their mean taken off, so DC doesn't swamp the
+/// spectrum. Returns the mean, in codes. Panics unless the lengths match.
+pub fn remove_mean(raw: &[u16], samples: &mut [f32]) -> f32 {
+    assert_eq!(raw.len(), samples.len(), "one sample for each code");
+    if raw.is_empty() {
+        return 0.0;
+    }
+
+    let sum = raw.iter().fold(0u32, |acc, &code| acc + code as u32);
+    let mean = sum as f32 / raw.len() as f32;
+    for (sample, &code) in samples.iter_mut().zip(raw) {
+        *sample = code as f32 - mean;
+    }
+    mean
+}
+
+/// Copy `samples` into the start of `padded` with `window` applied, and
+/// zero the rest, to FFT to a finer-looking spectrum. Only the samples are
+/// windowed, so the padding stays exactly zero. Padding interpolates between
+/// the bins, it doesn't resolve tones any closer together than `samples`
+/// alone would. Panics if `padded` is shorter than `samples`.
+pub fn zero_pad(samples: &[f32], window: WindowType, padded: &mut [f32]) {
+    assert!(
+        padded.len() >= samples.len(),
+        "padding can't be shorter than the samples"
+    );
+    let (captured, padding) = padded.split_at_mut(samples.len());
+    captured.copy_from_slice(samples);
+    apply_window(captured, window);
+    padding.fill(0.0);
+}
+
+/// FFT `samples` in place, and put the magnitudes of the first half of the
+/// bins in `magnitudes` in 16-bit counts, whatever the resolution, so they
+/// compare directly. Panics unless there are half as many magnitudes as
+/// samples, or if the FFT length isn't supported.
+pub fn magnitudes(samples: &mut [f32], scale: &AdcScale, magnitudes: &mut [f32]) {
+    assert_eq!(
+        samples.len() / 2,
+        magnitudes.len(),
+        "one magnitude for each bin"
+    );
+
+    let spectrum = fft::rfft(samples);
+    for (m, value) in magnitudes.iter_mut().zip(spectrum.iter()) {
+        *m = scale.to_16bit_counts(value.norm_sqr().sqrt());
+    }
+}
+
+/// Take the mean off `N` samples, window them and FFT them, into all
+/// `N / 2 + 1` magnitudes from DC to Nyquist in 16-bit counts, unpacking
+/// what `magnitudes` leaves packed. The transform works in `samples`, so
+/// they're left as scratch. One function for every length, so 512 and
+/// 2048 point pipelines come from the same code as the 1024 point one.
+///
+/// The bins would be returned as `[f32; N / 2 + 1]`, but stable Rust can't
+/// do arithmetic on a const parameter in a type (that's the unstable
+/// `generic_const_exprs`), so their count is a parameter of its own. The
+/// caller's type fixes it, and a count that isn't `N / 2 + 1`, or an `N`
+/// with no FFT, fails to compile rather than to run:
+///
+/// ```ignore
+/// let bins: [f32; 257] = pipeline::process(&mut samples_512, WindowType::Hann, &scale);
+/// ```
+pub fn process<const N: usize, const BINS: usize>(
+    samples: &mut [f32; N],
+    window: WindowType,
+    scale: &AdcScale,
+) -> [f32; BINS] {
+    const {
+        assert!(fft::is_supported_len(N), "no real FFT of N points");
+        assert!(BINS == N / 2 + 1, "N points have N / 2 + 1 bins");
+    }
+
+    let mean = samples.iter().sum::<f32>() / N as f32;
+    samples.iter_mut().for_each(|x| *x -= mean);
+    apply_window(samples, window);
+    let spectrum = fft::rfft(samples);
+
+    // DC and Nyquist are both real, and share the first bin
+    let mut bins = [0.0; BINS];
+    bins[0] = scale.to_16bit_counts(spectrum[0].re.abs());
+    bins[N / 2] = scale.to_16bit_counts(spectrum[0].im.abs());
+    for (bin, value) in bins[1..N / 2].iter_mut().zip(&spectrum[1..]) {
+        *bin = scale.to_16bit_counts(value.norm_sqr().sqrt());
+    }
+    bins
+}
+
+/// The peak of `magnitudes`, from a real FFT of samples at `sample_rate_hz`.
+/// The bins are as wide as the FFT was long, padding and all.
+pub fn peak(magnitudes: &[f32], sample_rate_hz: f32) -> Option<Peak> {
+    find_peak_bin(magnitudes).map(|bin| Peak {
+        bin,
+        hz: interpolate_peak(magnitudes, bin) * bin_width_hz(sample_rate_hz, magnitudes.len() * 2),
+        magnitude: magnitudes[bin],
+    })
+}
+
+/// Capture from `source` into `raw`, and take its spectrum into
+/// `magnitudes`, with `samples` as scratch for the FFT. All of `raw` is
+/// used, so a partial capture is an error, not a spectrum. `now_cycles`
+/// reads the cycle counter, for the analysis's timestamp.
+pub fn analyze_capture<S: SampleSource>(
+    source: &mut S,
+    raw: &mut [u16],
+    samples: &mut [f32],
+    scale: &AdcScale,
+    sample_rate_hz: f32,
+    magnitudes_out: &mut [f32],
+    now_cycles: impl FnOnce() -> u32,
+) -> Result<Analysis, AcqError> {
+    source.fill(raw)?;
+    let timestamp_cycles = now_cycles();
+    let mean = remove_mean(raw, samples);
+    magnitudes(samples, scale, magnitudes_out);
+    Ok(Analysis {
+        mean,
+        peak: peak(magnitudes_out, sample_rate_hz),
+        timestamp_cycles,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A source playing back canned captures, one per fill
+    struct Canned<'a> {
+        captures: &'a [Result<&'a [u16], AcqError>],
+        next: usize,
+    }
+
+    impl SampleSource for Canned<'_> {
+        fn fill(&mut self, buf: &mut [u16]) -> Result<(), AcqError> {
+            let capture = self.captures[self.next];
+            self.next += 1;
+            buf.copy_from_slice(capture?);
+            Ok(())
+        }
+    }
+
+    fn tone(bin: f32, amplitude: f32) -> [u16; 1024] {
+        core::array::from_fn(|i| {
+            let phase = 2.0 * core::f32::consts::PI * bin * i as f32 / 1024.0;
+            (32_768.0 + amplitude * phase.sin()) as u16
+        })
+    }
+
+    #[test]
+    fn a_canned_tone_comes_out_at_its_frequency() {
+        // Bin 100 at 10.24 kS/s is 1 kHz
+        let capture = tone(100.0, 10_000.0);
+        let captures = [Ok(&capture[..])];
+        let mut source = Canned {
+            captures: &captures,
+            next: 0,
+        };
+        let mut raw = [0; 1024];
+        let mut samples = [0.0; 1024];
+        let mut mags = [0.0; 512];
+        let analysis = analyze_capture(
+            &mut source,
+            &mut raw,
+            &mut samples,
+            &AdcScale::new(16, 3.3),
+            10_240.0,
+            &mut mags,
+            || 0xFFFF_FFF0,
+        )
+        .unwrap();
+
+        assert!((analysis.mean - 32_768.0).abs() < 1.0, "{analysis:?}");
+        assert_eq!(analysis.timestamp_cycles, 0xFFFF_FFF0);
+        let peak = analysis.peak.unwrap();
+        assert_eq!(peak.bin, 100);
+        assert!((peak.hz - 1_000.0).abs() < 1.0, "{peak:?}");
+        // A sine of amplitude A lands as A·N/2
+        assert!((peak.magnitude / (10_000.0 * 512.0) - 1.0).abs() < 0.01);
+        // With the mean off there's next to nothing at DC
+        assert!(mags[0] < peak.magnitude * 1e-3);
+    }
+
+    #[test]
+    fn source_errors_come_straight_through() {
+        let captures = [
+            Err(AcqError::Overrun),
+            Err(AcqError::Timeout { received: 7 }),
+        ];
+        let mut source = Canned {
+            captures: &captures,
+            next: 0,
+        };
+        let mut analyze = || {
+            analyze_capture(
+                &mut source,
+                &mut [0; 1024],
+                &mut [0.0; 1024],
+                &AdcScale::new(16, 3.3),
+                10_240.0,
+                &mut [0.0; 512],
+                || unreachable!("a failed capture has no timestamp"),
+            )
+        };
+        assert_eq!(analyze(), Err(AcqError::Overrun));
+        assert_eq!(analyze(), Err(AcqError::Timeout { received: 7 }));
+    }
+
+    #[test]
+    fn padding_interpolates_without_moving_the_peak() {
+        // Between bins 100 and 101, where the unpadded bins are coarsest
+        let capture = tone(100.4, 10_000.0);
+        let mut samples = [0.0; 1024];
+        remove_mean(&capture, &mut samples);
+        let scale = AdcScale::new(16, 3.3);
+
+        let mut unpadded = samples;
+        let mut mags = [0.0; 512];
+        magnitudes(&mut unpadded, &scale, &mut mags);
+        let unpadded = peak(&mags, 10_240.0).unwrap();
+
+        let mut padded = [1.0; 4096];
+        zero_pad(&samples, WindowType::Rectangular, &mut padded);
+        assert!(padded[1024..].iter().all(|&x| x == 0.0));
+        let mut padded_mags = [0.0; 2048];
+        magnitudes(&mut padded, &scale, &mut padded_mags);
+        let padded = peak(&padded_mags, 10_240.0).unwrap();
+
+        // Four bins to each of the capture's, at the same frequencies, and
+        // the parabola nearer the truth through bins closer together
+        assert_eq!(padded.bin, 402);
+        assert!((padded.hz - 1_004.0).abs() < 0.1, "{padded:?}");
+        assert!((padded.hz - 1_004.0).abs() < (unpadded.hz - 1_004.0).abs());
+        // A bin nearer the tone catches more of it, but padding adds no
+        // energy, so it's never above A·N/2
+        assert!(padded.magnitude > unpadded.magnitude);
+        assert!(padded.magnitude < 10_000.0 * 512.0);
+    }
+
+    #[test]
+    fn process_takes_any_supported_length() {
+        let scale = AdcScale::new(16, 3.3);
+
+        // Bin 32 of 512 and bin 100 of 2048, on top of an offset that the
+        // mean takes off
+        let mut short: [f32; 512] = core::array::from_fn(|i| {
+            let phase = 2.0 * core::f32::consts::PI * 32.0 * i as f32 / 512.0;
+            500.0 + 1_000.0 * phase.cos()
+        });
+        let bins: [f32; 257] = process(&mut short, WindowType::Rectangular, &scale);
+        assert_eq!(peak(&bins, 512.0).map(|p| p.bin), Some(32));
+        assert!((bins[32] / (1_000.0 * 256.0) - 1.0).abs() < 1e-3);
+        assert!(bins[0] < 1.0);
+
+        let mut long: [f32; 2048] = core::array::from_fn(|i| {
+            let phase = 2.0 * core::f32::consts::PI * 100.0 * i as f32 / 2048.0;
+            1_000.0 * phase.sin()
+        });
+        let bins: [f32; 1025] = process(&mut long, WindowType::Rectangular, &scale);
+        assert_eq!(peak(&bins, 2048.0).map(|p| p.bin), Some(100));
+        assert!((bins[100] / (1_000.0 * 1024.0) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn process_unpacks_nyquist() {
+        // Alternating, so all of it's at Nyquist
+        let mut samples: [f32; 8] = core::array::from_fn(|i| if i % 2 == 0 { 3.0 } else { -3.0 });
+        let bins: [f32; 5] = process(
+            &mut samples,
+            WindowType::Rectangular,
+            &AdcScale::new(16, 3.3),
+        );
+        assert!((bins[4] - 24.0).abs() < 1e-4, "{bins:?}");
+        assert!(bins[..4].iter().all(|&m| m < 1e-4), "{bins:?}");
+    }
+
+    #[test]
+    fn only_the_samples_are_windowed() {
+        let mut padded = [7.0; 8];
+        zero_pad(&[1.0; 4], WindowType::Hann, &mut padded);
+        assert_eq!(padded[..4], [0.0, 0.5, 1.0, 0.5]);
+        assert_eq!(padded[4..], [0.0; 4]);
+    }
+
+    #[test]
+    fn mean_is_taken_off() {
+        let mut samples = [0.0; 4];
+        assert_eq!(remove_mean(&[1, 2, 3, 6], &mut samples), 3.0);
+        assert_eq!(samples, [-2.0, -1.0, 0.0, 3.0]);
+        assert_eq!(remove_mean(&[], &mut []), 0.0);
+    }
+}
@@ -0,0 +1,155 @@
+//! Power spectral density estimates.
+
+use super::fft::{self, MAX_FFT_LEN};
+use super::window::{apply_window, window_power, WindowType};
+
+/// Welch's method: split `samples` into overlapping segments of
+/// `segment_len`, window and FFT each one, and average their power spectra.
+///
+/// Averaging K segments cuts the variance of the estimate by about K, at the
+/// cost of the frequency resolution of a `segment_len` FFT rather than one
+/// over the whole buffer. 50% overlap with a Hann window is the usual choice.
+///
+/// `out` gets the one-sided PSD in units²/Hz, `segment_len / 2 + 1` bins
+/// from DC to Nyquist. Samples past the last whole segment are ignored.
+///
+/// `segment_len` must be a size microfft provides, a power of two up to
+/// `MAX_FFT_LEN`, as each segment goes through `fft::rfft`. The segment
+/// is copied to a `MAX_FFT_LEN` scratch buffer on the stack (16K).
+pub fn welch_psd(
+    samples: &[f32],
+    segment_len: usize,
+    overlap: usize,
+    window: WindowType,
+    out: &mut [f32],
+    sample_rate_hz: f32,
+) {
+    assert!(
+        fft::is_supported_len(segment_len),
+        "segment length must be a microfft size"
+    );
+    assert!(overlap < segment_len, "overlap must be less than a segment");
+    assert!(samples.len() >= segment_len, "need at least one segment");
+    assert_eq!(out.len(), segment_len / 2 + 1);
+
+    let step = segment_len - overlap;
+    let segments = welch_segments(samples.len(), segment_len, overlap);
+    let mut scratch = [0.0f32; MAX_FFT_LEN];
+
+    out.iter_mut().for_each(|f| *f = 0.0);
+
+    for segment in samples.windows(segment_len).step_by(step).take(segments) {
+        let buf = &mut scratch[..segment_len];
+        buf.copy_from_slice(segment);
+        apply_window(buf, window);
+
+        let spectrum = fft::rfft(buf);
+
+        // DC and Nyquist come packed together in bin 0
+        out[0] += spectrum[0].re * spectrum[0].re;
+        out[segment_len / 2] += spectrum[0].im * spectrum[0].im;
+        for (o, bin) in out[1..segment_len / 2].iter_mut().zip(&spectrum[1..]) {
+            *o += bin.norm_sqr();
+        }
+    }
+
+    // Average, and scale so the PSD integrates to the signal's power. Every
+    // bin except DC and Nyquist is doubled to fold in negative frequencies.
+    let scale = 1.0 / (segments as f32 * sample_rate_hz * window_power(window, segment_len));
+    let last = out.len() - 1;
+    for (k, o) in out.iter_mut().enumerate() {
+        *o *= if k == 0 || k == last {
+            scale
+        } else {
+            2.0 * scale
+        };
+    }
+}
+
+/// How many whole segments of `segment_len`, each `overlap` into the last,
+/// `welch_psd` averages over `len` samples. 0 if there isn't room for one.
+/// Panics unless `overlap` is less than a segment.
+pub fn welch_segments(len: usize, segment_len: usize, overlap: usize) -> usize {
+    assert!(overlap < segment_len, "overlap must be less than a segment");
+    match len.checked_sub(segment_len) {
+        Some(spare) => spare / (segment_len - overlap) + 1,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 1_000.0;
+
+    /// Uniform noise from a fixed seed, of variance 1/12
+    fn noise<const N: usize>(mut seed: u32) -> [f32; N] {
+        core::array::from_fn(|_| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+        })
+    }
+
+    /// The PSD's integral, its power
+    fn integral(psd: &[f32], segment_len: usize) -> f32 {
+        psd.iter().sum::<f32>() * RATE / segment_len as f32
+    }
+
+    #[test]
+    fn white_noise_integrates_to_its_variance() {
+        let samples: [f32; 1024] = noise(7);
+        let mut psd = [0.0; 65];
+        // Unwindowed and without overlap, it's Parseval's theorem exactly
+        welch_psd(&samples, 128, 0, WindowType::Rectangular, &mut psd, RATE);
+        let power = samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32;
+        assert!((integral(&psd, 128) / power - 1.0).abs() < 1e-3);
+
+        // Under a window it's an estimate, of variance 1/12
+        welch_psd(&samples, 128, 64, WindowType::Hann, &mut psd, RATE);
+        let variance = integral(&psd, 128);
+        assert!((variance * 12.0 - 1.0).abs() < 0.1, "{variance}");
+    }
+
+    #[test]
+    fn a_sines_power_is_all_in_its_bin() {
+        // Bin 8 of 64, a whole number of cycles in every segment
+        let samples: [f32; 512] = core::array::from_fn(|i| {
+            2.0 * (2.0 * core::f32::consts::PI * 8.0 * i as f32 / 64.0).sin()
+        });
+        let mut psd = [0.0; 33];
+        welch_psd(&samples, 64, 32, WindowType::Rectangular, &mut psd, RATE);
+        let bin_hz = RATE / 64.0;
+        // A² / 2, doubled in from the negative frequencies
+        assert!((psd[8] * bin_hz - 2.0).abs() < 1e-3, "{}", psd[8] * bin_hz);
+        let elsewhere: f32 = psd
+            .iter()
+            .enumerate()
+            .filter(|&(k, _)| k != 8)
+            .map(|(_, p)| p)
+            .sum();
+        assert!(elsewhere * bin_hz < 1e-3, "{elsewhere}");
+    }
+
+    #[test]
+    fn segments_step_by_the_overlap() {
+        assert_eq!(welch_segments(1024, 256, 0), 4);
+        assert_eq!(welch_segments(1024, 256, 128), 7);
+        // What's left past the last whole segment is ignored
+        assert_eq!(welch_segments(1000, 256, 0), 3);
+        assert_eq!(welch_segments(256, 256, 128), 1);
+        assert_eq!(welch_segments(255, 256, 0), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "microfft size")]
+    fn segments_have_to_be_an_fft_size() {
+        welch_psd(&[0.0; 256], 100, 0, WindowType::Hann, &mut [0.0; 51], RATE);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlap")]
+    fn segments_cant_overlap_entirely() {
+        welch_psd(&[0.0; 256], 64, 64, WindowType::Hann, &mut [0.0; 33], RATE);
+    }
+}
@@ -0,0 +1,372 @@
+//! Element-wise operations on whole sample buffers.
+
+use micromath::F32Ext;
+
+/// A type samples can be held in, for `mean` and `normalize`: `f32` on
+/// the board, `f64` for checking against on the host, and `i16` (Q15) and
+/// `i32` for fixed point. Sums are taken in `Acc`, which for the integers
+/// is an `i64`, so no buffer that fits in memory can overflow it.
+pub trait Sample: Copy {
+    type Acc: Copy;
+    const ZERO: Self::Acc;
+
+    fn accumulate(sum: Self::Acc, sample: Self) -> Self::Acc;
+
+    /// `sum / len`, the mean of `len` samples. The integers round to the
+    /// nearest, halves away from zero, and have a mean of 0 with no
+    /// samples; the floats divide as they are, so get NaN.
+    fn mean_of(sum: Self::Acc, len: usize) -> Self;
+
+    /// `self - mean`. The integers saturate, as a sample near one end less
+    /// a mean near the other doesn't fit.
+    fn less(self, mean: Self) -> Self;
+}
+
+impl Sample for f32 {
+    type Acc = f32;
+    const ZERO: f32 = 0.0;
+
+    fn accumulate(sum: f32, sample: f32) -> f32 {
+        sum + sample
+    }
+
+    fn mean_of(sum: f32, len: usize) -> f32 {
+        sum / len as f32
+    }
+
+    fn less(self, mean: f32) -> f32 {
+        self - mean
+    }
+}
+
+impl Sample for f64 {
+    type Acc = f64;
+    const ZERO: f64 = 0.0;
+
+    fn accumulate(sum: f64, sample: f64) -> f64 {
+        sum + sample
+    }
+
+    fn mean_of(sum: f64, len: usize) -> f64 {
+        sum / len as f64
+    }
+
+    fn less(self, mean: f64) -> f64 {
+        self - mean
+    }
+}
+
+macro_rules! integer_sample {
+    ($($t:ty),*) => {$(
+        impl Sample for $t {
+            type Acc = i64;
+            const ZERO: i64 = 0;
+
+            fn accumulate(sum: i64, sample: $t) -> i64 {
+                sum + sample as i64
+            }
+
+            fn mean_of(sum: i64, len: usize) -> $t {
+                // Between the smallest and largest sample, so it fits
+                rounded_div(sum, len) as $t
+            }
+
+            fn less(self, mean: $t) -> $t {
+                self.saturating_sub(mean)
+            }
+        }
+    )*};
+}
+
+integer_sample!(i16, i32);
+
+/// `sum / len` to the nearest, halves away from zero, and 0 for no `len`
+fn rounded_div(sum: i64, len: usize) -> i64 {
+    if len == 0 {
+        return 0;
+    }
+    let len = len as i64;
+    if sum >= 0 {
+        (sum + len / 2) / len
+    } else {
+        (sum - len / 2) / len
+    }
+}
+
+/// The mean of `samples`, rounded as `Sample::mean_of` says
+pub fn mean<S: Sample>(samples: &[S]) -> S {
+    let sum = samples
+        .iter()
+        .fold(S::ZERO, |sum, &x| S::accumulate(sum, x));
+    S::mean_of(sum, samples.len())
+}
+
+/// Take the mean off `samples` in place, and return it. For `f32` this is
+/// the same fold and loop `normalize_slice` always was.
+pub fn normalize<S: Sample>(samples: &mut [S]) -> S {
+    let mean = mean(samples);
+    samples.iter_mut().for_each(|x| *x = x.less(mean));
+    mean
+}
+
+/// Saturate every sample into `[min, max]`.
+///
+/// Use before converting filtered data back to integer codes, where an out
+/// of range value would otherwise wrap. NaN isn't in any range, so it's
+/// mapped to `min` rather than being passed along. Panics if `min` is above
+/// `max`, or either is NaN.
+pub fn clamp_slice(slice: &mut [f32], min: f32, max: f32) {
+    assert!(min <= max, "clamping needs min <= max, neither NaN");
+
+    slice.iter_mut().for_each(|f| {
+        *f = if f.is_nan() { min } else { f.clamp(min, max) };
+    });
+}
+
+/// Convert codes to samples in `[-1, 1]` around mid-scale, for a bipolar
+/// signal biased to the middle of the range, so the FFT gets properly signed
+/// input without taking the mean off first. `full_scale` is the largest code
+/// at the configured resolution, see `AdcScale::full_scale_code`, and maps
+/// to 1 as code 0 maps to -1; codes above it saturate there. Mid-scale is
+/// rounded up, so there's a code fewer above it than below, and the top
+/// half's steps are that much wider. Converts as many as both slices have
+/// room for.
+pub fn center_samples(raw: &[u16], out: &mut [f32], full_scale: u16) {
+    // As `AdcScale::mid_scale`, so a full scale of 4095 centres on 2048
+    let mid = (full_scale as u32).div_ceil(2);
+    let below = mid.max(1) as f32;
+    let above = (full_scale as u32 - mid).max(1) as f32;
+    for (sample, &code) in out.iter_mut().zip(raw) {
+        let code = code.min(full_scale) as u32;
+        *sample = if code >= mid {
+            (code - mid) as f32 / above
+        } else {
+            -((mid - code) as f32 / below)
+        };
+    }
+}
+
+/// Count how many of `samples` fall in each of `bins`, which split the codes
+/// `min_code..=max_code` into equal ranges. Counts add to what's already in
+/// `bins`, so several captures can go into one histogram.
+///
+/// With a bin per code, a code that never comes up or a pattern every
+/// power of two codes points at a stuck or weak bit, and the spread of the
+/// counts is the DNL. Codes outside the range go in the end bins, so the
+/// total still matches the number of samples. Panics if `min_code` is above
+/// `max_code`, as a range the wrong way round is a bug in the caller.
+pub fn histogram(samples: &[u16], bins: &mut [u32], min_code: u16, max_code: u16) {
+    assert!(
+        min_code <= max_code,
+        "histogram range needs min_code <= max_code"
+    );
+    if bins.is_empty() {
+        return;
+    }
+
+    let span = max_code as u64 - min_code as u64 + 1;
+    let last = bins.len() - 1;
+    for &code in samples {
+        let offset = code.clamp(min_code, max_code) - min_code;
+        let bin = (offset as u64 * bins.len() as u64 / span) as usize;
+        bins[bin.min(last)] += 1;
+    }
+}
+
+/// Root mean square of `samples`, or 0 for none. With the mean taken off
+/// first, it's the AC level a true-RMS meter would read.
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum_of_squares = samples.iter().map(|x| x * x).sum::<f32>();
+    (sum_of_squares / samples.len() as f32).sqrt()
+}
+
+/// Reduce `input` to `(min, max)` pairs for `columns` pixel columns of a
+/// waveform display, splitting it into that many groups as evenly as whole
+/// samples allow. Unlike keeping every nth sample, a spike anywhere in a
+/// group still reaches the screen.
+///
+/// With more columns than samples, each column shows the sample it falls
+/// on, so a short buffer is stretched rather than failing; with no samples
+/// at all every column is `(0.0, 0.0)`. Only the first `columns` of `out`
+/// are written.
+pub fn minmax_decimate(input: &[f32], columns: usize, out: &mut [(f32, f32)]) {
+    assert!(out.len() >= columns, "out must have room for every column");
+    if input.is_empty() {
+        out[..columns].fill((0.0, 0.0));
+        return;
+    }
+
+    for (column, pair) in out[..columns].iter_mut().enumerate() {
+        let start = column * input.len() / columns;
+        let end = ((column + 1) * input.len() / columns).max(start + 1);
+        *pair = input[start..end]
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &x| {
+                (min.min(x), max.max(x))
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_bin_per_code() {
+        let mut bins = [0; 4];
+        histogram(&[100, 101, 101, 103, 103, 103], &mut bins, 100, 103);
+        assert_eq!(bins, [1, 2, 0, 3]);
+
+        // Counts accumulate
+        histogram(&[102], &mut bins, 100, 103);
+        assert_eq!(bins, [1, 2, 1, 3]);
+    }
+
+    #[test]
+    fn codes_share_wider_bins() {
+        let mut bins = [0; 4];
+        let samples: [u16; 16] = core::array::from_fn(|i| i as u16 * 4096);
+        histogram(&samples, &mut bins, 0, u16::MAX);
+        assert_eq!(bins, [4; 4]);
+    }
+
+    #[test]
+    fn out_of_range_codes_go_in_the_end_bins() {
+        let mut bins = [0; 3];
+        histogram(&[0, 9, 10, 11, 12, 13, 65535], &mut bins, 10, 12);
+        assert_eq!(bins, [3, 1, 3]);
+
+        histogram(&[5], &mut [], 0, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "min_code <= max_code")]
+    fn histogram_ranges_go_upwards() {
+        histogram(&[5], &mut [0; 4], 10, 0);
+    }
+
+    #[test]
+    fn clamping_saturates_at_both_ends() {
+        let mut samples = [-5.0, -1.0, 0.5, 1.0, 7.0, f32::INFINITY, f32::NEG_INFINITY];
+        clamp_slice(&mut samples, -1.0, 1.0);
+        assert_eq!(samples, [-1.0, -1.0, 0.5, 1.0, 1.0, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn clamping_maps_nan_to_min() {
+        let mut samples = [f32::NAN, 2.0];
+        clamp_slice(&mut samples, 0.0, 4_095.0);
+        assert_eq!(samples, [0.0, 2.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "min <= max")]
+    fn clamping_refuses_bounds_the_wrong_way_round() {
+        clamp_slice(&mut [0.0], 1.0, -1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "min <= max")]
+    fn clamping_refuses_a_nan_bound() {
+        clamp_slice(&mut [0.0], f32::NAN, 1.0);
+    }
+
+    #[test]
+    fn centres_on_mid_scale() {
+        let mut out = [0.0; 5];
+        center_samples(&[0, 1024, 2048, 3072, 4095], &mut out, 4095);
+        assert_eq!(out, [-1.0, -0.5, 0.0, 1024.0 / 2047.0, 1.0]);
+
+        // At 16 bits the same swing covers the same range
+        center_samples(&[0, 16384, 32768, 65535], &mut out, u16::MAX);
+        assert_eq!(out[..4], [-1.0, -0.5, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn codes_past_full_scale_saturate() {
+        let mut out = [0.0; 2];
+        center_samples(&[4096, u16::MAX], &mut out, 4095);
+        assert_eq!(out, [1.0; 2]);
+
+        // Only as many as both have
+        let mut short = [9.0; 1];
+        center_samples(&[0, 0], &mut short, 4095);
+        assert_eq!(short, [-1.0]);
+    }
+
+    #[test]
+    fn rms_of_a_sine_and_dc() {
+        let sine: [f32; 1000] = core::array::from_fn(|i| {
+            3.0 * (2.0 * core::f32::consts::PI * 10.0 * i as f32 / 1000.0).sin()
+        });
+        assert!((rms(&sine) - 3.0 / core::f32::consts::SQRT_2).abs() < 1e-3);
+        assert_eq!(rms(&[-2.0; 8]), 2.0);
+        assert_eq!(rms(&[]), 0.0);
+    }
+
+    #[test]
+    fn integer_means_round_half_away_from_zero() {
+        assert_eq!(mean(&[1i16, 2]), 2);
+        assert_eq!(mean(&[-1i16, -2]), -2);
+        assert_eq!(mean(&[1i32, 1, 2]), 1);
+        assert_eq!(mean::<i16>(&[]), 0);
+        assert!(mean::<f32>(&[]).is_nan());
+
+        // Full scale either way doesn't overflow the sum, and what doesn't
+        // fit after the mean's off saturates
+        let mut q15 = [i16::MIN, i16::MAX, i16::MAX];
+        assert_eq!(normalize(&mut q15), 10_922);
+        assert_eq!(q15, [i16::MIN, 21_845, 21_845]);
+    }
+
+    #[test]
+    fn integer_and_float_paths_agree() {
+        let codes: [i32; 64] = core::array::from_fn(|i| (i as i32 * 7_919) % 2_000 - 700);
+        let mut fixed = codes;
+        let mut float: [f32; 64] = core::array::from_fn(|i| codes[i] as f32);
+        let mut wide: [f64; 64] = core::array::from_fn(|i| codes[i] as f64);
+        let q15_mean = normalize(&mut codes.map(|c| c as i16));
+
+        let exact = normalize(&mut wide);
+        assert!((normalize(&mut float) as f64 - exact).abs() < 1e-3);
+        // The integer mean is the float one rounded, so every sample is
+        // within half a code of it
+        assert_eq!(normalize(&mut fixed), exact.round() as i32);
+        assert_eq!(q15_mean as i32, exact.round() as i32);
+        for ((&f, &x), &w) in fixed.iter().zip(&float).zip(&wide) {
+            assert!((f as f64 - w).abs() <= 0.5, "{f} vs {w}");
+            assert!((x as f64 - w).abs() < 1e-3, "{x} vs {w}");
+        }
+    }
+
+    #[test]
+    fn minmax_keeps_spikes() {
+        let mut input = [0.0; 100];
+        input[37] = 5.0;
+        input[80] = -2.0;
+        let mut out = [(9.0, 9.0); 5];
+        minmax_decimate(&input, 4, &mut out);
+        assert_eq!(out[..4], [(0.0, 0.0), (0.0, 5.0), (0.0, 0.0), (-2.0, 0.0)]);
+        // Past `columns` is left alone
+        assert_eq!(out[4], (9.0, 9.0));
+
+        // Uneven groups still cover every sample
+        let ramp: [f32; 10] = core::array::from_fn(|i| i as f32);
+        minmax_decimate(&ramp, 3, &mut out);
+        assert_eq!(out[..3], [(0.0, 2.0), (3.0, 5.0), (6.0, 9.0)]);
+    }
+
+    #[test]
+    fn minmax_with_more_columns_than_samples() {
+        let mut out = [(9.0, 9.0); 4];
+        minmax_decimate(&[1.0, 2.0], 4, &mut out);
+        assert_eq!(out, [(1.0, 1.0), (1.0, 1.0), (2.0, 2.0), (2.0, 2.0)]);
+
+        minmax_decimate(&[], 4, &mut out);
+        assert_eq!(out, [(0.0, 0.0); 4]);
+        minmax_decimate(&[1.0], 0, &mut []);
+    }
+}
@@ -0,0 +1,577 @@
+//! Conversions between raw ADC counts and physical or normalized units.
+//!
+//! Everything here takes the full scale from an `AdcScale` rather than
+//! assuming 16 bits, so changing the ADC resolution only means building a
+//! different `AdcScale`.
+
+use micromath::F32Ext;
+use serde::{Deserialize, Serialize};
+
+/// Per-board offset and gain correction for the ADC.
+///
+/// A corrected count is `(count - offset) * gain`, so the identity is an
+/// offset of 0 and a gain of 1. Counts here are from the code 0 V should
+/// read as, `AdcScale::zero_count`, so the offset is only the ADC's error.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Counts the ADC reads past `zero_count` with its input at 0 V
+    pub offset: f32,
+    /// Ideal counts per measured count, after removing the offset
+    pub gain: f32,
+}
+
+impl Calibration {
+    pub const IDENTITY: Self = Self {
+        offset: 0.0,
+        gain: 1.0,
+    };
+
+    /// Two-point calibration from the counts read with the input grounded
+    /// and with it at `reference_volts`. For a differential input that's
+    /// both of the pair grounded, then the reference across them.
+    pub fn from_two_points(
+        zero_counts: f32,
+        reference_counts: f32,
+        reference_volts: f32,
+        scale: &AdcScale,
+    ) -> Self {
+        let ideal = reference_volts * scale.max_count() as f32 / scale.span_volts();
+
+        Self {
+            offset: zero_counts - scale.zero_count() as f32,
+            gain: ideal / (reference_counts - zero_counts),
+        }
+    }
+
+    /// Whether this could be a real calibration, rather than garbage
+    pub fn is_plausible(&self) -> bool {
+        self.offset.is_finite() && self.gain.is_finite() && self.gain > 0.0
+    }
+
+    pub fn apply(&self, counts: f32) -> f32 {
+        (counts - self.offset) * self.gain
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Collects the two readings `Calibration::from_two_points` needs, one at
+/// a time: first with the input grounded, then at `reference_volts`
+#[derive(Clone, Copy, Debug)]
+pub struct TwoPointCalibration {
+    reference_volts: f32,
+    zero_counts: Option<f32>,
+}
+
+impl TwoPointCalibration {
+    pub const fn new(reference_volts: f32) -> Self {
+        Self {
+            reference_volts,
+            zero_counts: None,
+        }
+    }
+
+    pub const fn reference_volts(&self) -> f32 {
+        self.reference_volts
+    }
+
+    /// Whether the grounded reading has been taken, so the next one should
+    /// be at the reference
+    pub const fn wants_reference(&self) -> bool {
+        self.zero_counts.is_some()
+    }
+
+    /// Feed the next reading, in raw counts. Returns the calibration once
+    /// both are in, and starts over after that.
+    pub fn feed(&mut self, counts: f32, scale: &AdcScale) -> Option<Calibration> {
+        match self.zero_counts.take() {
+            None => {
+                self.zero_counts = Some(counts);
+                None
+            }
+            Some(zero) => Some(Calibration::from_two_points(
+                zero,
+                counts,
+                self.reference_volts,
+                scale,
+            )),
+        }
+    }
+}
+
+/// Smallest change in die temperature a drift calibration fits its line
+/// over, so a few tenths of a degree of sensor noise can't set the slope
+pub const MIN_DRIFT_SPAN_DEGC: f32 = 5.0;
+
+/// How the ADC's offset moves with die temperature, as a line through the
+/// offsets read with the input grounded at two temperatures.
+///
+/// It corrects relative to `reference_degc`, so a `Calibration` taken at
+/// about that temperature still takes the offset itself out, and this only
+/// what it's drifted by since.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OffsetDrift {
+    pub reference_degc: f32,
+    /// Counts the ADC read at `reference_degc`
+    pub reference_offset: f32,
+    /// Counts the offset rises by per degree
+    pub counts_per_degc: f32,
+}
+
+impl OffsetDrift {
+    /// No correction at any temperature
+    pub const NONE: Self = Self {
+        reference_degc: 0.0,
+        reference_offset: 0.0,
+        counts_per_degc: 0.0,
+    };
+
+    /// The line through `(degc, offset counts)` read at two temperatures,
+    /// from the first, or `None` if they're under `MIN_DRIFT_SPAN_DEGC`
+    /// apart
+    pub fn from_two_points(first: (f32, f32), second: (f32, f32)) -> Option<Self> {
+        let span = second.0 - first.0;
+        (span.abs() >= MIN_DRIFT_SPAN_DEGC).then(|| Self {
+            reference_degc: first.0,
+            reference_offset: first.1,
+            counts_per_degc: (second.1 - first.1) / span,
+        })
+    }
+
+    /// Whether this could be a real fit, rather than garbage
+    pub fn is_plausible(&self) -> bool {
+        self.reference_degc.is_finite()
+            && self.reference_offset.is_finite()
+            && self.counts_per_degc.is_finite()
+    }
+
+    /// Counts to take off each raw sample at `degc`, whole like the samples
+    pub fn correction_counts(&self, degc: f32) -> i32 {
+        (self.counts_per_degc * (degc - self.reference_degc)).round() as i32
+    }
+}
+
+impl Default for OffsetDrift {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// `code` less `correction` counts, kept within `0..=max_count`
+pub fn correct_offset(code: u16, correction: i32, max_count: u32) -> u16 {
+    (code as i32 - correction).clamp(0, max_count as i32) as u16
+}
+
+/// Collects the offsets `OffsetDrift::from_two_points` needs as the board
+/// warms up: the first reading, then every one after until one's far
+/// enough from it in temperature
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DriftCalibration {
+    first: Option<(f32, f32)>,
+    readings: u32,
+}
+
+impl DriftCalibration {
+    pub const fn new() -> Self {
+        Self {
+            first: None,
+            readings: 0,
+        }
+    }
+
+    /// The first reading, `(degc, offset counts)`, once it's in
+    pub const fn first(&self) -> Option<(f32, f32)> {
+        self.first
+    }
+
+    /// Readings fed since the first, that one included
+    pub const fn readings(&self) -> u32 {
+        self.readings
+    }
+
+    /// Feed the offset read at `degc`. Returns the fit once a reading is
+    /// far enough from the first, and starts over after that.
+    pub fn feed(&mut self, degc: f32, offset: f32) -> Option<OffsetDrift> {
+        self.readings += 1;
+        let Some(first) = self.first else {
+            self.first = Some((degc, offset));
+            return None;
+        };
+        let drift = OffsetDrift::from_two_points(first, (degc, offset))?;
+        *self = Self::new();
+        Some(drift)
+    }
+}
+
+/// How the ADC's input is wired, which decides what its codes mean
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    /// One pin against VREF-, from 0 V at code 0 to VREF at full scale
+    SingleEnded,
+    /// The difference between a pair of pins, 0 V at mid-scale, from -VREF
+    /// at code 0 to VREF at full scale. It rejects whatever the pair has in
+    /// common, at half the resolution per volt.
+    Differential,
+}
+
+/// The ADC's full scale, as a resolution and reference voltage, plus how
+/// the input's wired and any calibration to correct counts with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdcScale {
+    bits: u8,
+    vref: f32,
+    input: Input,
+    calibration: Calibration,
+}
+
+impl AdcScale {
+    /// A single-ended input, unless it's made `with_input`
+    pub const fn new(bits: u8, vref: f32) -> Self {
+        Self {
+            bits,
+            vref,
+            input: Input::SingleEnded,
+            calibration: Calibration::IDENTITY,
+        }
+    }
+
+    pub const fn with_input(mut self, input: Input) -> Self {
+        self.input = input;
+        self
+    }
+
+    pub const fn input(&self) -> Input {
+        self.input
+    }
+
+    /// Correct counts with `calibration` in `counts_to_volts` and
+    /// `volts_to_counts`
+    pub const fn with_calibration(mut self, calibration: Calibration) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    pub const fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Largest code the ADC can produce
+    pub const fn max_count(&self) -> u32 {
+        (1 << self.bits) - 1
+    }
+
+    /// `max_count` as a code, saturating for resolutions past 16 bits, for
+    /// `samples::center_samples`
+    pub const fn full_scale_code(&self) -> u16 {
+        if self.bits >= 16 {
+            u16::MAX
+        } else {
+            self.max_count() as u16
+        }
+    }
+
+    /// Code at the middle of the range, the zero of a bipolar signal
+    pub const fn mid_scale(&self) -> u32 {
+        1 << (self.bits - 1)
+    }
+
+    /// The reference, and so the most the input reads
+    pub const fn full_scale_volts(&self) -> f32 {
+        self.vref
+    }
+
+    /// The least the input reads, 0 V single-ended or -VREF differential
+    pub fn min_volts(&self) -> f32 {
+        match self.input {
+            Input::SingleEnded => 0.0,
+            Input::Differential => -self.vref,
+        }
+    }
+
+    /// Volts from code 0 to full scale
+    pub fn span_volts(&self) -> f32 {
+        self.vref - self.min_volts()
+    }
+
+    /// The code 0 V reads as on an ideal ADC
+    pub const fn zero_count(&self) -> u32 {
+        match self.input {
+            Input::SingleEnded => 0,
+            Input::Differential => self.mid_scale(),
+        }
+    }
+
+    /// Convert a count (or a float derived from counts, e.g. a mean) to
+    /// volts, correcting it with the calibration
+    pub fn counts_to_volts(&self, counts: f32) -> f32 {
+        let from_zero = counts - self.zero_count() as f32;
+        self.calibration.apply(from_zero) * self.span_volts() / self.max_count() as f32
+    }
+
+    /// The raw count that `counts_to_volts` would turn into `volts`
+    pub fn volts_to_counts(&self, volts: f32) -> f32 {
+        let corrected = volts * self.max_count() as f32 / self.span_volts();
+        corrected / self.calibration.gain + self.calibration.offset + self.zero_count() as f32
+    }
+
+    /// Rescale a difference of counts, like an FFT magnitude, to what a
+    /// 16-bit ADC would have read, with the calibration gain applied. The
+    /// offset cancels out of differences, so it isn't.
+    pub fn to_16bit_counts(&self, counts: f32) -> f32 {
+        counts * self.calibration.gain * u16::MAX as f32 / self.max_count() as f32
+    }
+
+    /// Whether a sample sits at either rail, meaning the input was clipped
+    pub fn is_clipped(&self, count: u16) -> bool {
+        count == 0 || count as u32 >= self.max_count()
+    }
+
+    /// Center a count around mid-scale and scale it to Q15, so a full scale
+    /// swing at any resolution covers the whole `i16` range
+    pub fn to_q15(&self, count: u16) -> i16 {
+        let centred = count as i32 - self.mid_scale() as i32;
+        let shifted = if self.bits <= 16 {
+            centred << (16 - self.bits)
+        } else {
+            centred >> (self.bits - 16)
+        };
+
+        shifted.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+
+    /// FFT magnitude of a full scale sine in an `fft_len` point real FFT,
+    /// i.e. the 0 dBFS reference. A sine of amplitude A lands as A·N/2 in its bin.
+    pub fn dbfs_reference(&self, fft_len: usize) -> f32 {
+        (self.max_count() as f32 / 2.0) * fft_len as f32 / 2.0
+    }
+
+    /// Convert an FFT magnitude to dB relative to full scale
+    pub fn magnitude_to_dbfs(&self, magnitude: f32, fft_len: usize) -> f32 {
+        20.0 * (magnitude / self.dbfs_reference(fft_len)).log10()
+    }
+
+    /// Convert an RMS level in counts to dB relative to full scale. As for
+    /// magnitudes, a full scale sine is 0 dBFS, and its RMS is half the
+    /// range over √2.
+    pub fn rms_to_dbfs(&self, rms_counts: f32) -> f32 {
+        let full_scale_rms = self.max_count() as f32 / 2.0 / core::f32::consts::SQRT_2;
+        20.0 * (rms_counts / full_scale_rms).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESOLUTIONS: [u8; 3] = [8, 12, 16];
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() <= 1e-4 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn full_scale_codes() {
+        assert_eq!(AdcScale::new(8, 3.3).max_count(), 255);
+        assert_eq!(AdcScale::new(12, 3.3).max_count(), 4095);
+        assert_eq!(AdcScale::new(16, 3.3).max_count(), 65535);
+
+        assert_eq!(AdcScale::new(8, 3.3).mid_scale(), 128);
+        assert_eq!(AdcScale::new(12, 3.3).mid_scale(), 2048);
+        assert_eq!(AdcScale::new(16, 3.3).mid_scale(), 32768);
+
+        assert_eq!(AdcScale::new(12, 3.3).full_scale_code(), 4095);
+        assert_eq!(AdcScale::new(16, 3.3).full_scale_code(), u16::MAX);
+    }
+
+    #[test]
+    fn q15_spans_i16_at_every_resolution() {
+        for bits in RESOLUTIONS {
+            let scale = AdcScale::new(bits, 3.3);
+            let max = scale.max_count() as u16;
+
+            assert_eq!(scale.to_q15(scale.mid_scale() as u16), 0, "{bits} bit");
+            assert_eq!(scale.to_q15(0), i16::MIN, "{bits} bit");
+            // The top code is one LSB short of mid-scale + half range
+            assert_eq!(
+                scale.to_q15(max),
+                i16::MAX - ((1 << (16 - bits)) - 1),
+                "{bits} bit"
+            );
+        }
+    }
+
+    #[test]
+    fn clipping_is_at_the_rails_only() {
+        for bits in RESOLUTIONS {
+            let scale = AdcScale::new(bits, 3.3);
+            let max = scale.max_count() as u16;
+
+            assert!(scale.is_clipped(0), "{bits} bit");
+            assert!(scale.is_clipped(max), "{bits} bit");
+            assert!(!scale.is_clipped(1), "{bits} bit");
+            assert!(!scale.is_clipped(max - 1), "{bits} bit");
+            assert!(!scale.is_clipped(scale.mid_scale() as u16), "{bits} bit");
+        }
+    }
+
+    #[test]
+    fn volts_at_every_resolution() {
+        for bits in RESOLUTIONS {
+            let scale = AdcScale::new(bits, 3.3);
+            let max = scale.max_count() as f32;
+
+            assert!(close(scale.counts_to_volts(0.0), 0.0), "{bits} bit");
+            assert!(close(scale.counts_to_volts(max), 3.3), "{bits} bit");
+            assert!(close(scale.counts_to_volts(max / 2.0), 1.65), "{bits} bit");
+            assert!(close(scale.volts_to_counts(3.3), max), "{bits} bit");
+            assert!(close(scale.volts_to_counts(0.0), 0.0), "{bits} bit");
+        }
+    }
+
+    #[test]
+    fn differential_volts_centre_on_mid_scale() {
+        for bits in RESOLUTIONS {
+            let scale = AdcScale::new(bits, 3.3).with_input(Input::Differential);
+            let max = scale.max_count() as f32;
+            let mid = scale.mid_scale() as f32;
+
+            assert_eq!((scale.min_volts(), scale.span_volts()), (-3.3, 6.6));
+            assert!(close(scale.counts_to_volts(mid), 0.0), "{bits} bit");
+            assert!(
+                close(scale.counts_to_volts(0.0), -6.6 * mid / max),
+                "{bits} bit"
+            );
+            assert!(
+                close(scale.counts_to_volts(max), 6.6 * (max - mid) / max),
+                "{bits} bit"
+            );
+            assert!(close(scale.volts_to_counts(0.0), mid), "{bits} bit");
+            assert!(
+                close(scale.volts_to_counts(-1.0), mid - max / 6.6),
+                "{bits} bit"
+            );
+        }
+    }
+
+    #[test]
+    fn magnitudes_match_across_resolutions() {
+        for bits in RESOLUTIONS {
+            let scale = AdcScale::new(bits, 3.3);
+            let full_scale = scale.max_count() as f32;
+
+            assert!(
+                close(scale.to_16bit_counts(full_scale), 65535.0),
+                "{bits} bit"
+            );
+            assert!(close(scale.to_16bit_counts(0.0), 0.0), "{bits} bit");
+        }
+
+        let scale = AdcScale::new(12, 3.3).with_calibration(Calibration {
+            offset: 100.0,
+            gain: 2.0,
+        });
+        assert!(close(scale.to_16bit_counts(4095.0), 131070.0));
+    }
+
+    #[test]
+    fn full_scale_sine_rms_is_zero_dbfs() {
+        for bits in RESOLUTIONS {
+            let scale = AdcScale::new(bits, 3.3);
+            let amplitude = scale.max_count() as f32 / 2.0;
+            let rms = amplitude / core::f32::consts::SQRT_2;
+            assert!(scale.rms_to_dbfs(rms).abs() < 1e-3, "{bits} bit");
+            // Half the amplitude is 6 dB down
+            assert!(close(scale.rms_to_dbfs(rms / 2.0), -6.0206), "{bits} bit");
+        }
+    }
+
+    #[test]
+    fn two_point_calibration_corrects_both_points() {
+        for bits in RESOLUTIONS {
+            let scale = AdcScale::new(bits, 3.3);
+            let max = scale.max_count() as f32;
+            let mut run = TwoPointCalibration::new(1.65);
+
+            assert!(!run.wants_reference());
+            assert_eq!(run.feed(max * 0.01, &scale), None);
+            assert!(run.wants_reference());
+            let cal = run.feed(max * 0.52, &scale).unwrap();
+            assert!(!run.wants_reference());
+
+            let scale = scale.with_calibration(cal);
+            assert!(cal.is_plausible(), "{bits} bit");
+            assert!(close(scale.counts_to_volts(max * 0.01), 0.0), "{bits} bit");
+            assert!(close(scale.counts_to_volts(max * 0.52), 1.65), "{bits} bit");
+        }
+    }
+
+    #[test]
+    fn two_point_calibration_corrects_a_differential_pair() {
+        let scale = AdcScale::new(16, 3.3).with_input(Input::Differential);
+        // Reading 40 counts high, and with a gain 2% low
+        let zero = 32_768.0 + 40.0;
+        let volt = zero + 0.98 * 65_535.0 / 6.6;
+        let mut run = TwoPointCalibration::new(1.0);
+        run.feed(zero, &scale);
+        let cal = run.feed(volt, &scale).unwrap();
+        assert!(close(cal.offset, 40.0));
+
+        let scale = scale.with_calibration(cal);
+        assert!(close(scale.counts_to_volts(zero), 0.0));
+        assert!(close(scale.counts_to_volts(volt), 1.0));
+        // Below zero is as good as above it
+        assert!(close(scale.counts_to_volts(2.0 * zero - volt), -1.0));
+        assert!(close(scale.volts_to_counts(-1.0), 2.0 * zero - volt));
+    }
+
+    #[test]
+    fn offset_drift_follows_temperature() {
+        let mut run = DriftCalibration::new();
+        assert_eq!(run.feed(25.0, 40.0), None);
+        // Too close to the first to fit a slope to
+        assert_eq!(run.feed(28.0, 41.0), None);
+        assert_eq!(run.first(), Some((25.0, 40.0)));
+        assert_eq!(run.readings(), 2);
+        let drift = run.feed(45.0, 46.0).unwrap();
+        assert_eq!((run.first(), run.readings()), (None, 0));
+
+        assert!(drift.is_plausible());
+        assert!(close(drift.counts_per_degc, 0.3));
+        assert_eq!(drift.correction_counts(25.0), 0);
+        assert_eq!(drift.correction_counts(45.0), 6);
+        // Cooler than the reference is corrected the other way
+        assert_eq!(drift.correction_counts(15.0), -3);
+        assert_eq!(OffsetDrift::NONE.correction_counts(80.0), 0);
+
+        assert_eq!(correct_offset(1_000, 6, 4_095), 994);
+        assert_eq!(correct_offset(2, 6, 4_095), 0);
+        assert_eq!(correct_offset(4_094, -3, 4_095), 4_095);
+    }
+
+    #[test]
+    fn calibration_round_trips() {
+        let calibration = Calibration {
+            offset: 12.0,
+            gain: 1.01,
+        };
+
+        for bits in RESOLUTIONS {
+            let scale = AdcScale::new(bits, 3.3).with_calibration(calibration);
+            let max = scale.max_count() as f32;
+
+            assert!(close(scale.counts_to_volts(12.0), 0.0), "{bits} bit");
+            for counts in [12.0, max / 3.0, max] {
+                let volts = scale.counts_to_volts(counts);
+                assert!(close(scale.volts_to_counts(volts), counts), "{bits} bit");
+            }
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! The continuous ADC stream brought down to a few hundred samples a
+//! second, for looking at very low frequencies: minutes of it fit in one
+//! long buffer, so its spectrum has bins a fraction of a hertz apart.
+//!
+//! It's done in two stages. A `Cic` takes the full rate down by a power of
+//! two, up to `MAX_CIC_FACTOR`, with no multiplies, and a `Fir` lowpass at
+//! `FIR_CUTOFF` of the new Nyquist does the rest, keeping one sample in
+//! however many it takes, which needn't be a power of two.
+//!
+//! The CIC's gain is divided straight back out, so the output is in ADC
+//! counts, but its droop isn't corrected: the response falls away towards
+//! the top of the band, by about 0.4 dB at `FIR_CUTOFF` when the CIC
+//! decimates by 16 and the FIR by 4. Levels near the output's Nyquist read a
+//! little low, but frequencies are unaffected, and the bins are exact: the
+//! output rate is `output_rate_hz`, the input's over the whole factor,
+//! rather than whatever was asked for.
+
+use super::filter::{Cic, Fir};
+
+/// Largest factor the CIC stage takes, so any push a whole number of
+/// pool buffers long is a whole number of its blocks
+pub const MAX_CIC_FACTOR: usize = 16;
+
+/// Integrators and combs in the CIC
+const CIC_STAGES: usize = 3;
+
+/// Taps in the second stage's lowpass. Enough for its transition to be
+/// narrower than the gap between `FIR_CUTOFF` and the first alias.
+pub const FIR_TAPS: usize = 63;
+
+/// Where the second stage cuts off, as a fraction of the output's Nyquist
+pub const FIR_CUTOFF: f32 = 0.8;
+
+/// CIC outputs worked out at a time, bounding the stack they take
+const CIC_CHUNK: usize = 32;
+
+/// A buffer arriving more than this many buffers' time after the last
+/// means some were lost in between, see `is_continuous`
+pub const GAP_FACTOR: f32 = 1.5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SlowStream {
+    cic: Cic<CIC_STAGES>,
+    cic_factor: usize,
+    fir: Fir<FIR_TAPS>,
+    fir_factor: usize,
+    /// FIR outputs since the last one kept, carried between pushes
+    phase: usize,
+    /// Outputs still to throw away while the filters fill
+    settling: usize,
+}
+
+impl SlowStream {
+    /// A stream from `input_rate_hz` down to as near `target_rate_hz` as a
+    /// whole factor gets. The CIC takes the largest power of two in the
+    /// factor it can, while leaving the FIR at least 2, so there's a proper
+    /// lowpass ahead of the last step down.
+    pub fn new(input_rate_hz: f32, target_rate_hz: f32) -> Self {
+        let factor = ((input_rate_hz / target_rate_hz + 0.5) as usize).max(1);
+        let mut cic_factor = 1;
+        while cic_factor * 2 <= MAX_CIC_FACTOR
+            && factor.is_multiple_of(cic_factor * 2)
+            && factor / (cic_factor * 2) >= 2
+        {
+            cic_factor *= 2;
+        }
+        let fir_factor = factor / cic_factor;
+
+        let fir_rate_hz = input_rate_hz / cic_factor as f32;
+        let cutoff_hz = FIR_CUTOFF * fir_rate_hz / fir_factor as f32 / 2.0;
+        Self {
+            cic: Cic::new(),
+            cic_factor,
+            fir: Fir::lowpass(cutoff_hz, fir_rate_hz),
+            fir_factor,
+            phase: 0,
+            settling: Self::settling_outputs(fir_factor),
+        }
+    }
+
+    /// The whole decimation factor
+    pub fn factor(&self) -> usize {
+        self.cic_factor * self.fir_factor
+    }
+
+    /// The factor the CIC takes, which every push must be a multiple of
+    pub fn cic_factor(&self) -> usize {
+        self.cic_factor
+    }
+
+    /// The rate out, for an input at `input_rate_hz`. Give it the rate the
+    /// input was actually sampled at, as measured, and the bins of a
+    /// spectrum of the output are exactly where they say.
+    pub fn output_rate_hz(&self, input_rate_hz: f32) -> f32 {
+        input_rate_hz / self.factor() as f32
+    }
+
+    /// Start over, for a stream that isn't carrying on from the last push
+    pub fn reset(&mut self) {
+        self.cic.reset();
+        self.fir.reset();
+        self.phase = 0;
+        self.settling = Self::settling_outputs(self.fir_factor);
+    }
+
+    /// Decimate `input`, the next samples of the stream, into the front of
+    /// `out`, returning how many went in. Anything past the end of `out`
+    /// is dropped. The first few outputs after a `new` or a `reset` are
+    /// thrown away while the filters fill, so the start's no step up from
+    /// zero. Panics unless `input` is a whole number of `cic_factor`s.
+    pub fn push(&mut self, input: &[u16], out: &mut [f32]) -> usize {
+        assert!(
+            input.len().is_multiple_of(self.cic_factor),
+            "input must be a multiple of the CIC's factor"
+        );
+
+        let gain = Cic::<CIC_STAGES>::gain(self.cic_factor) as f32;
+        let mut written = 0;
+        let mut cic_out = [0; CIC_CHUNK];
+        for block in input.chunks(CIC_CHUNK * self.cic_factor) {
+            let cic_out = &mut cic_out[..block.len() / self.cic_factor];
+            self.cic.process(block, self.cic_factor, cic_out);
+            for &y in cic_out.iter() {
+                let y = self.fir.process(y as f32 / gain);
+                self.phase += 1;
+                if self.phase < self.fir_factor {
+                    continue;
+                }
+                self.phase = 0;
+                if self.settling > 0 {
+                    self.settling -= 1;
+                } else if let Some(slot) = out.get_mut(written) {
+                    *slot = y;
+                    written += 1;
+                }
+            }
+        }
+        written
+    }
+
+    /// Outputs it takes the CIC and the FIR to fill with real samples
+    fn settling_outputs(fir_factor: usize) -> usize {
+        (CIC_STAGES + FIR_TAPS).div_ceil(fir_factor)
+    }
+}
+
+/// Whether a buffer of `samples` at `sample_rate_hz` that finished at
+/// `at_us` follows straight on from one that finished at `previous_us`,
+/// rather than after a gap where buffers were lost, by `GAP_FACTOR`
+pub fn is_continuous(previous_us: u64, at_us: u64, samples: usize, sample_rate_hz: f32) -> bool {
+    let buffer_us = samples as f32 / sample_rate_hz * 1e6;
+    (at_us.saturating_sub(previous_us) as f32) < buffer_us * GAP_FACTOR
+}
+
+/// What a buffer pushed onto a `LongCapture` came to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gathered {
+    /// More wanted, with this many samples in so far
+    Filling(usize),
+    /// It came after a gap, which lost the `dropped` samples gathered
+    /// before it, and the long buffer started over with it
+    Gap { dropped: usize },
+    /// The long buffer's full
+    Full,
+}
+
+/// A `SlowStream` gathered into one long buffer, buffer by buffer, started
+/// over whenever a buffer doesn't follow straight on from the last
+#[derive(Clone, Copy, Debug)]
+pub struct LongCapture {
+    stream: SlowStream,
+    filled: usize,
+    buffers: u32,
+    previous_us: Option<u64>,
+}
+
+impl LongCapture {
+    pub fn new(stream: SlowStream) -> Self {
+        Self {
+            stream,
+            filled: 0,
+            buffers: 0,
+            previous_us: None,
+        }
+    }
+
+    pub fn stream(&self) -> &SlowStream {
+        &self.stream
+    }
+
+    /// Buffers pushed since the long buffer last started over
+    pub fn buffers(&self) -> u32 {
+        self.buffers
+    }
+
+    /// Start over with nothing gathered, after a partial buffer or once a
+    /// full long buffer's been used
+    pub fn reset(&mut self) {
+        self.stream.reset();
+        self.filled = 0;
+        self.buffers = 0;
+        self.previous_us = None;
+    }
+
+    /// Decimate `input`, a buffer at `sample_rate_hz` that finished at
+    /// `at_us`, onto the end of what's in `long` so far. `long` wants to be
+    /// longer than a buffer decimates to, or a gap could fill it at once
+    /// and it'd read `Gap` rather than `Full`.
+    pub fn push(
+        &mut self,
+        input: &[u16],
+        at_us: u64,
+        sample_rate_hz: f32,
+        long: &mut [f32],
+    ) -> Gathered {
+        let continuous = self
+            .previous_us
+            .is_none_or(|previous| is_continuous(previous, at_us, input.len(), sample_rate_hz));
+        let dropped = self.filled;
+        if !continuous {
+            self.stream.reset();
+            self.filled = 0;
+            self.buffers = 0;
+        }
+        self.previous_us = Some(at_us);
+
+        self.filled += self.stream.push(input, &mut long[self.filled..]);
+        self.buffers += 1;
+        if !continuous {
+            Gathered::Gap { dropped }
+        } else if self.filled < long.len() {
+            Gathered::Filling(self.filled)
+        } else {
+            Gathered::Full
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::TAU;
+
+    const INPUT_HZ: f32 = 12_800.0;
+
+    /// Amplitude of `hz` in `samples` at `rate_hz`, for a whole number of
+    /// cycles
+    fn amplitude_at(samples: &[f32], hz: f32, rate_hz: f32) -> f32 {
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (i, &x) in samples.iter().enumerate() {
+            let phase = (TAU as f64) * hz as f64 * i as f64 / rate_hz as f64;
+            re += x as f64 * phase.cos();
+            im += x as f64 * phase.sin();
+        }
+        (2.0 * (re * re + im * im).sqrt() / samples.len() as f64) as f32
+    }
+
+    /// 20 s of a 5 Hz tone and a 1030 Hz one, which would alias to 30 Hz
+    fn input() -> Vec<u16> {
+        (0..20 * INPUT_HZ as usize)
+            .map(|i| {
+                let t = i as f32 / INPUT_HZ;
+                (32_768.0 + 8_000.0 * (TAU * 5.0 * t).sin() + 8_000.0 * (TAU * 1_030.0 * t).sin())
+                    as u16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn factors_split_between_the_stages() {
+        for (input_hz, cic, fir) in [
+            (12_800.0, 16, 4),
+            (48_000.0, 16, 15),
+            (10_000.0, 2, 25),
+            (400.0, 1, 2),
+            (100.0, 1, 1),
+        ] {
+            let stream = SlowStream::new(input_hz, 200.0);
+            assert_eq!(
+                (stream.cic_factor(), stream.factor() / cic),
+                (cic, fir),
+                "{input_hz}"
+            );
+        }
+        // The rate out's the one measured over the factor, not the target
+        let stream = SlowStream::new(12_800.0, 200.0);
+        assert_eq!(stream.output_rate_hz(12_790.0), 12_790.0 / 64.0);
+    }
+
+    #[test]
+    fn tones_come_through_and_aliases_dont() {
+        let mut stream = SlowStream::new(INPUT_HZ, 200.0);
+        let rate_hz = stream.output_rate_hz(INPUT_HZ);
+        let mut out = vec![0.0; 5_000];
+        let n = stream.push(&input(), &mut out);
+        // Less what was thrown away settling, 17 outputs
+        assert_eq!(n, 20 * 200 - 17);
+
+        // A whole number of cycles of both, in counts about the mean
+        let out = &out[n - 3_800..n];
+        let mean = out.iter().sum::<f32>() / out.len() as f32;
+        assert!((mean - 32_768.0).abs() < 1.0, "{mean}");
+        let tone = amplitude_at(out, 5.0, rate_hz);
+        assert!((tone / 8_000.0 - 1.0).abs() < 0.01, "{tone}");
+        // Where 1030 Hz would land, 60 dB down or more
+        let alias = amplitude_at(out, 30.0, rate_hz);
+        assert!(alias < 8.0, "{alias}");
+    }
+
+    #[test]
+    fn a_stream_in_buffers_is_the_same_as_in_one() {
+        let input = input();
+        let mut whole = SlowStream::new(INPUT_HZ, 200.0);
+        let mut expected = vec![0.0; 5_000];
+        let n = whole.push(&input, &mut expected);
+
+        let mut buffered = SlowStream::new(INPUT_HZ, 200.0);
+        let mut out = vec![0.0; 5_000];
+        let mut filled = 0;
+        for buffer in input.chunks(1_024) {
+            filled += buffered.push(buffer, &mut out[filled..]);
+        }
+        assert_eq!(filled, n);
+        assert_eq!(out, expected);
+
+        // And starting over settles again
+        buffered.reset();
+        assert_eq!(buffered.push(&input[..1_024 * 16], &mut out), 256 - 17);
+        // Room runs out quietly
+        assert_eq!(buffered.push(&input[..1_024 * 16], &mut out[..10]), 10);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pushes_must_be_whole_cic_blocks() {
+        SlowStream::new(INPUT_HZ, 200.0).push(&[0; 100], &mut [0.0; 10]);
+    }
+
+    #[test]
+    fn long_captures_fill_and_start_over_after_a_gap() {
+        let input = input();
+        // 1024 samples decimate to 16, less 17 settling at the start
+        let mut capture = LongCapture::new(SlowStream::new(INPUT_HZ, 200.0));
+        let mut long = vec![0.0; 100];
+        let mut at_us = 0;
+        let mut push = |capture: &mut LongCapture, buffer, gap_us: u64| {
+            at_us += 80_000 + gap_us;
+            capture.push(buffer, at_us, INPUT_HZ, &mut long)
+        };
+        let buffers: Vec<_> = input.chunks(1_024).collect();
+        assert_eq!(push(&mut capture, buffers[0], 0), Gathered::Filling(0));
+        assert_eq!(push(&mut capture, buffers[1], 0), Gathered::Filling(15));
+        assert_eq!(push(&mut capture, buffers[2], 0), Gathered::Filling(31));
+
+        // Three buffers lost
+        assert_eq!(
+            push(&mut capture, buffers[6], 240_000),
+            Gathered::Gap { dropped: 31 }
+        );
+        assert_eq!(capture.buffers(), 1);
+        for buffer in &buffers[7..13] {
+            assert!(matches!(
+                push(&mut capture, buffer, 0),
+                Gathered::Filling(_)
+            ));
+        }
+        assert_eq!(push(&mut capture, buffers[13], 0), Gathered::Full);
+
+        // Nothing to follow on from after a reset, however long it's been
+        capture.reset();
+        assert_eq!(
+            push(&mut capture, buffers[14], 1_000_000),
+            Gathered::Filling(0)
+        );
+    }
+
+    #[test]
+    fn gaps_between_buffers_are_spotted() {
+        // 1024 samples at 12.8 kS/s is 80 ms a buffer
+        assert!(is_continuous(1_000_000, 1_080_000, 1_024, INPUT_HZ));
+        assert!(is_continuous(1_000_000, 1_100_000, 1_024, INPUT_HZ));
+        assert!(!is_continuous(1_000_000, 1_160_000, 1_024, INPUT_HZ));
+        assert!(!is_continuous(1_000_000, 1_400_000, 1_024, INPUT_HZ));
+    }
+}
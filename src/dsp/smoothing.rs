@@ -0,0 +1,86 @@
+//! Per-bin exponential smoothing, for a steadier spectrum to look at.
+
+/// An exponential moving average of each of `BINS` bins over the frames so
+/// far, which settles a jittery spectrum for display. Unlike Welch
+/// averaging it keeps one frame of state, however long it averages over.
+#[derive(Clone, Debug)]
+pub struct SpectrumSmoother<const BINS: usize> {
+    smoothed: [f32; BINS],
+    /// Whether a frame has come in yet, which the average starts from
+    primed: bool,
+}
+
+impl<const BINS: usize> SpectrumSmoother<BINS> {
+    pub const fn new() -> Self {
+        Self {
+            smoothed: [0.0; BINS],
+            primed: false,
+        }
+    }
+
+    /// Blend `magnitudes` in, `alpha * new + (1 - alpha) * smoothed` for
+    /// each bin, so an `alpha` of 1 doesn't smooth at all and a smaller one
+    /// averages over about `1 / alpha` frames. The first frame is taken as
+    /// it is, rather than ramping up from zero. Extra bins are ignored and
+    /// missing ones keep their value.
+    pub fn update(&mut self, magnitudes: &[f32], alpha: f32) {
+        let alpha = if self.primed { alpha } else { 1.0 };
+        for (smoothed, &m) in self.smoothed.iter_mut().zip(magnitudes) {
+            *smoothed += alpha * (m - *smoothed);
+        }
+        self.primed = true;
+    }
+
+    /// The smoothed spectrum, what to display or log instead of the frame
+    pub fn smoothed(&self) -> &[f32; BINS] {
+        &self.smoothed
+    }
+
+    /// Start over, from the next frame
+    pub fn reset(&mut self) {
+        self.smoothed = [0.0; BINS];
+        self.primed = false;
+    }
+}
+
+impl<const BINS: usize> Default for SpectrumSmoother<BINS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_from_the_first_frame() {
+        let mut smoother: SpectrumSmoother<2> = SpectrumSmoother::new();
+        smoother.update(&[4.0, 8.0], 0.25);
+        assert_eq!(smoother.smoothed(), &[4.0, 8.0]);
+
+        smoother.update(&[8.0, 0.0], 0.25);
+        assert_eq!(smoother.smoothed(), &[5.0, 6.0]);
+        // The missing second bin is left as it was
+        smoother.update(&[5.0], 0.5);
+        assert_eq!(smoother.smoothed(), &[5.0, 6.0]);
+
+        smoother.reset();
+        smoother.update(&[1.0, 2.0, 3.0], 0.25);
+        assert_eq!(smoother.smoothed(), &[1.0, 2.0]);
+    }
+
+    #[test]
+    fn settles_on_a_steady_spectrum() {
+        let mut smoother: SpectrumSmoother<1> = SpectrumSmoother::new();
+        smoother.update(&[0.0], 0.1);
+        for _ in 0..100 {
+            smoother.update(&[1.0], 0.1);
+        }
+        assert!((smoother.smoothed()[0] - 1.0).abs() < 1e-4);
+
+        // Without smoothing it's just the latest frame
+        smoother.update(&[3.0], 1.0);
+        assert_eq!(smoother.smoothed(), &[3.0]);
+    }
+}
@@ -0,0 +1,64 @@
+//! A fixed size waterfall of the most recent magnitude spectra.
+
+/// Ring buffer of the last `FRAMES` magnitude spectra, `BINS` bins each.
+/// Lives entirely in the struct, so put it in a static or a link-section
+/// buffer if it's large.
+pub struct Spectrogram<const BINS: usize, const FRAMES: usize> {
+    columns: [[f32; BINS]; FRAMES],
+    /// Slot the next push goes into
+    next: usize,
+    /// How many slots hold real data, up to FRAMES
+    filled: usize,
+}
+
+impl<const BINS: usize, const FRAMES: usize> Spectrogram<BINS, FRAMES> {
+    pub const fn new() -> Self {
+        assert!(FRAMES > 0, "a spectrogram needs at least one frame");
+        Self {
+            columns: [[0.0; BINS]; FRAMES],
+            next: 0,
+            filled: 0,
+        }
+    }
+
+    /// Add a spectrum as the newest column, dropping the oldest once full.
+    /// Extra bins are ignored and missing ones are zeroed.
+    pub fn push(&mut self, magnitudes: &[f32]) {
+        let column = &mut self.columns[self.next];
+        let n = magnitudes.len().min(BINS);
+
+        column[..n].copy_from_slice(&magnitudes[..n]);
+        column[n..].iter_mut().for_each(|f| *f = 0.0);
+
+        self.next = (self.next + 1) % FRAMES;
+        self.filled = (self.filled + 1).min(FRAMES);
+    }
+
+    /// Number of columns currently held
+    pub fn len(&self) -> usize {
+        self.filled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filled == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.next = 0;
+        self.filled = 0;
+    }
+
+    /// Columns from oldest to newest
+    pub fn columns(&self) -> impl Iterator<Item = &[f32; BINS]> + '_ {
+        // Once full, the oldest column is the one about to be overwritten
+        let start = if self.filled == FRAMES { self.next } else { 0 };
+
+        (0..self.filled).map(move |i| &self.columns[(start + i) % FRAMES])
+    }
+}
+
+impl<const BINS: usize, const FRAMES: usize> Default for Spectrogram<BINS, FRAMES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
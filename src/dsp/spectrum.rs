@@ -0,0 +1,396 @@
+//! Measurements taken from a magnitude spectrum.
+
+use micromath::F32Ext;
+
+use super::fft::{bin_to_hz, hz_to_bin};
+
+/// The A-weighting poles from IEC 61672-1, in Hz
+const A_POLE_1_HZ: f32 = 20.598_997;
+const A_POLE_2_HZ: f32 = 107.652_65;
+const A_POLE_3_HZ: f32 = 737.862_23;
+const A_POLE_4_HZ: f32 = 12_194.217;
+
+/// The standard's +2.00 dB normalization, which brings 1 kHz to 0 dB
+const A_1KHZ_GAIN: f32 = 1.258_925_4;
+
+/// How many times the noise floor `find_peaks` takes a bin to stand above,
+/// without a threshold of its own: 12 dB
+pub const PEAK_THRESHOLD_FACTOR: f32 = 4.0;
+
+/// Total power in the bins whose center frequency is within
+/// `[low_hz, high_hz]`, the sum of their squared magnitudes.
+///
+/// Band edges are clamped to the bins in `magnitudes`, and a band that
+/// contains no bin center has no power.
+pub fn band_power(
+    magnitudes: &[f32],
+    low_hz: f32,
+    high_hz: f32,
+    sample_rate_hz: f32,
+    fft_len: usize,
+) -> f32 {
+    let Some(last_bin) = magnitudes.len().checked_sub(1) else {
+        return 0.0;
+    };
+
+    // hz_to_bin rounds, so step inwards off a nearest bin outside the band
+    let mut low = hz_to_bin(low_hz, sample_rate_hz, fft_len);
+    if bin_to_hz(low, sample_rate_hz, fft_len) < low_hz {
+        low += 1;
+    }
+    let mut high = hz_to_bin(high_hz, sample_rate_hz, fft_len).min(last_bin);
+    if bin_to_hz(high, sample_rate_hz, fft_len) > high_hz {
+        match high.checked_sub(1) {
+            Some(h) => high = h,
+            None => return 0.0,
+        }
+    }
+
+    if low > high {
+        return 0.0;
+    }
+    magnitudes[low..=high].iter().map(|m| m * m).sum()
+}
+
+/// Bin with the largest magnitude, skipping bin 0 as DC dominates any
+/// unnormalized capture. `None` if there's nothing past DC.
+pub fn find_peak_bin(magnitudes: &[f32]) -> Option<usize> {
+    magnitudes
+        .iter()
+        .enumerate()
+        .skip(1)
+        .fold(None, |best: Option<(usize, f32)>, (i, &m)| match best {
+            Some((_, b)) if b >= m => best,
+            _ => Some((i, m)),
+        })
+        .map(|(i, _)| i)
+}
+
+/// Fractional bin of the true peak near `peak_bin`, from a parabola through
+/// it and its two neighbours. Multiplied by the bin width this resolves a
+/// pure tone far more finely than the bins do.
+///
+/// A peak at either end of `magnitudes` has no neighbour on one side, so is
+/// returned as it is, as is a flat top the parabola can't be fitted to.
+pub fn interpolate_peak(magnitudes: &[f32], peak_bin: usize) -> f32 {
+    if peak_bin == 0 || peak_bin + 1 >= magnitudes.len() {
+        return peak_bin as f32;
+    }
+
+    let (left, centre, right) = (
+        magnitudes[peak_bin - 1],
+        magnitudes[peak_bin],
+        magnitudes[peak_bin + 1],
+    );
+    let curvature = left - 2.0 * centre + right;
+    if curvature == 0.0 {
+        return peak_bin as f32;
+    }
+
+    // The vertex is within half a bin for a real peak, clamp in case it isn't
+    let offset = (0.5 * (left - right) / curvature).clamp(-0.5, 0.5);
+    peak_bin as f32 + offset
+}
+
+/// The background level of a spectrum: the median of its bins past DC,
+/// which a few strong peaks can't drag up the way they would a mean. 0 for
+/// a spectrum with nothing past DC.
+///
+/// The median is picked out with a partial selection rather than a sort,
+/// in `scratch`, which the bins past DC are copied into, so `magnitudes`
+/// is left as it is. Panics if `scratch` is shorter than those bins. NaNs
+/// sort above everything.
+pub fn noise_floor(magnitudes: &[f32], scratch: &mut [f32]) -> f32 {
+    let bins = magnitudes.get(1..).unwrap_or(&[]);
+    if bins.is_empty() {
+        return 0.0;
+    }
+    assert!(
+        scratch.len() >= bins.len(),
+        "scratch needs room for every bin past DC"
+    );
+
+    let scratch = &mut scratch[..bins.len()];
+    scratch.copy_from_slice(bins);
+    *scratch
+        .select_nth_unstable_by(bins.len() / 2, f32::total_cmp)
+        .1
+}
+
+/// Local maxima past DC above `threshold`, e.g. `PEAK_THRESHOLD_FACTOR`
+/// times the `noise_floor`, into `peaks` in bin order. Returns how many
+/// went in; past the end of `peaks` any more are dropped. A flat top
+/// counts once, at its first bin. Each bin is looked at once or twice, a
+/// flat top's skipped past as a whole, so it's linear in the bins.
+pub fn find_peaks(magnitudes: &[f32], threshold: f32, peaks: &mut [usize]) -> usize {
+    let mut found = 0;
+    let mut i = 1;
+    while i < magnitudes.len() {
+        let m = magnitudes[i];
+        // Only the first bin of a flat run can be rising into it
+        let rising = i == 1 || magnitudes[i - 1] < m;
+        if !(m > threshold && rising) {
+            i += 1;
+            continue;
+        }
+        // Past the end of a flat top, to see it doesn't go on rising
+        let end = magnitudes[i..]
+            .iter()
+            .position(|&next| next != m)
+            .map_or(magnitudes.len(), |run| i + run);
+        if magnitudes.get(end).is_none_or(|&next| next < m) {
+            let Some(slot) = peaks.get_mut(found) else {
+                break;
+            };
+            *slot = i;
+            found += 1;
+        }
+        i = end;
+    }
+    found
+}
+
+/// Spurious-free dynamic range: how far the strongest bin away from the
+/// peak is below it, in dB. Bins within `guard` of the peak are its own
+/// leakage, and are skipped along with DC. `None` without a peak, or with
+/// nothing but zeros outside the guard.
+pub fn spur_free_range_db(magnitudes: &[f32], guard: usize) -> Option<f32> {
+    let peak = find_peak_bin(magnitudes)?;
+    let spur = magnitudes
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|&(i, _)| i.abs_diff(peak) > guard)
+        .map(|(_, &m)| m)
+        .fold(0.0, f32::max);
+    (spur > 0.0).then(|| 20.0 * (magnitudes[peak] / spur).log10())
+}
+
+/// Weight each bin of `magnitudes` by the IEC 61672 A-weighting response
+/// at its center frequency, into `out`, which must be as long.
+///
+/// The response is normalized to unity at 1 kHz, as the standard defines,
+/// so a weighted level is in the same reference as the magnitudes were:
+/// through `band_power` and `AdcScale::magnitude_to_dbfs` that's dB(A)
+/// relative to full scale. Levels in dBA SPL need the mic's sensitivity to
+/// turn full scale into 20 µPa.
+pub fn a_weighting(magnitudes: &[f32], sample_rate_hz: f32, fft_len: usize, out: &mut [f32]) {
+    assert_eq!(magnitudes.len(), out.len(), "one weighted bin for each bin");
+    for (i, (out, &m)) in out.iter_mut().zip(magnitudes).enumerate() {
+        *out = m * a_weighting_gain(bin_to_hz(i, sample_rate_hz, fft_len));
+    }
+}
+
+/// A-weighting at `hz` as an amplitude ratio, zero at DC
+fn a_weighting_gain(hz: f32) -> f32 {
+    let f2 = hz * hz;
+    let numerator = A_POLE_4_HZ * A_POLE_4_HZ * f2 * f2;
+    let denominator = (f2 + A_POLE_1_HZ * A_POLE_1_HZ)
+        * ((f2 + A_POLE_2_HZ * A_POLE_2_HZ) * (f2 + A_POLE_3_HZ * A_POLE_3_HZ)).sqrt()
+        * (f2 + A_POLE_4_HZ * A_POLE_4_HZ);
+    numerator / denominator * A_1KHZ_GAIN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bins of 1 Hz, 0 to 16
+    const BAND_RATE: f32 = 32.0;
+    const BAND_FFT: usize = 32;
+
+    fn power(magnitudes: &[f32], low_hz: f32, high_hz: f32) -> f32 {
+        band_power(magnitudes, low_hz, high_hz, BAND_RATE, BAND_FFT)
+    }
+
+    #[test]
+    fn band_power_sums_the_bins_inside() {
+        let mut tone = [0.0; 17];
+        tone[5] = 3.0;
+        assert_eq!(power(&tone, 4.0, 6.0), 9.0);
+        assert_eq!(power(&tone, 8.0, 12.0), 0.0);
+        // Edges on bin centers include them
+        assert_eq!(power(&tone, 5.0, 5.0), 9.0);
+    }
+
+    #[test]
+    fn band_edges_between_bins_round_inwards() {
+        let flat = [1.0; 17];
+        assert_eq!(power(&flat, 4.5, 6.5), 2.0);
+        assert_eq!(power(&flat, 4.4, 6.6), 2.0);
+        // No bin center inside at all
+        assert_eq!(power(&flat, 4.4, 4.6), 0.0);
+    }
+
+    #[test]
+    fn bands_are_clamped_to_the_spectrum() {
+        let flat = [1.0; 17];
+        assert_eq!(power(&flat, 14.0, 100.0), 3.0);
+        assert_eq!(power(&flat, 20.0, 100.0), 0.0);
+        // Below 0 Hz there's only DC
+        assert_eq!(power(&flat, -5.0, 2.0), 3.0);
+        assert_eq!(power(&flat, -5.0, -1.0), 0.0);
+        // Upside down is empty
+        assert_eq!(power(&flat, 6.0, 4.0), 0.0);
+        assert_eq!(power(&[], 0.0, 16.0), 0.0);
+    }
+
+    #[test]
+    fn peak_ignores_dc() {
+        assert_eq!(find_peak_bin(&[10.0, 1.0, 3.0, 2.0]), Some(2));
+        assert_eq!(find_peak_bin(&[10.0]), None);
+        assert_eq!(find_peak_bin(&[]), None);
+        // The first of equal peaks wins
+        assert_eq!(find_peak_bin(&[0.0, 5.0, 5.0]), Some(1));
+    }
+
+    #[test]
+    fn interpolation_finds_a_parabola_vertex() {
+        // y = 10 - (x - 3.3)^2 sampled at 2, 3 and 4
+        let y = |x: f32| 10.0 - (x - 3.3) * (x - 3.3);
+        let magnitudes = [0.0, 0.0, y(2.0), y(3.0), y(4.0), 0.0];
+        let peak = interpolate_peak(&magnitudes, 3);
+        assert!((peak - 3.3).abs() < 1e-4, "{peak}");
+    }
+
+    #[test]
+    fn interpolation_is_centred_on_a_symmetric_peak() {
+        assert_eq!(interpolate_peak(&[0.0, 1.0, 4.0, 1.0, 0.0], 2), 2.0);
+        assert_eq!(interpolate_peak(&[0.0, 4.0, 4.0, 4.0], 2), 2.0);
+    }
+
+    #[test]
+    fn spur_free_range_skips_the_peak_and_dc() {
+        let magnitudes = [50.0, 0.1, 9.0, 100.0, 9.0, 0.0, 1.0, 0.0];
+        let range = spur_free_range_db(&magnitudes, 1).unwrap();
+        assert!((range - 40.0).abs() < 1e-4, "{range}");
+        // Without the guard, the peak's leakage is the spur
+        let range = spur_free_range_db(&magnitudes, 0).unwrap();
+        assert!(
+            (range - 20.0 * (100.0f32 / 9.0).log10()).abs() < 1e-4,
+            "{range}"
+        );
+
+        assert_eq!(spur_free_range_db(&[0.0, 0.0, 5.0, 0.0], 0), None);
+        assert_eq!(spur_free_range_db(&[3.0], 0), None);
+    }
+
+    #[test]
+    fn the_noise_floor_ignores_peaks() {
+        // Noise about 1 with two tones far above it, and DC, ignored
+        let mut magnitudes = [0.0; 513];
+        for (i, m) in magnitudes.iter_mut().enumerate() {
+            *m = 0.5 + (i * 37 % 101) as f32 / 100.0;
+        }
+        magnitudes[0] = 1e6;
+        magnitudes[100] = 500.0;
+        magnitudes[300] = 800.0;
+        let mut scratch = [0.0; 512];
+        let floor = noise_floor(&magnitudes, &mut scratch);
+        assert!((floor - 1.0).abs() < 0.02, "{floor}");
+        // The spectrum itself is left alone
+        assert_eq!(magnitudes[300], 800.0);
+
+        assert_eq!(noise_floor(&[0.0, 3.0, 1.0, 2.0], &mut scratch), 2.0);
+        assert_eq!(noise_floor(&[7.0], &mut []), 0.0);
+        assert_eq!(noise_floor(&[], &mut []), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "scratch")]
+    fn the_noise_floor_needs_room_for_every_bin() {
+        noise_floor(&[0.0, 3.0, 1.0, 2.0], &mut [0.0; 2]);
+    }
+
+    #[test]
+    fn peaks_stand_above_the_floor() {
+        let mut magnitudes = [1.0; 64];
+        magnitudes[0] = 100.0;
+        magnitudes[10] = 20.0;
+        magnitudes[11] = 8.0;
+        magnitudes[30] = 3.0;
+        // A flat top, counted once
+        magnitudes[50] = 9.0;
+        magnitudes[51] = 9.0;
+        magnitudes[63] = 6.0;
+        // A shoulder on the way up to one, which isn't
+        magnitudes[40] = 7.0;
+        magnitudes[41] = 7.0;
+        magnitudes[42] = 12.0;
+
+        let mut peaks = [0; 8];
+        // The floor's 1, so 3 doesn't clear 4 times it, and DC doesn't count
+        let floor = noise_floor(&magnitudes, &mut [0.0; 63]);
+        let n = find_peaks(&magnitudes, PEAK_THRESHOLD_FACTOR * floor, &mut peaks);
+        assert_eq!(&peaks[..n], &[10, 42, 50, 63]);
+        let n = find_peaks(&magnitudes, 2.0, &mut peaks);
+        assert_eq!(&peaks[..n], &[10, 30, 42, 50, 63]);
+        // More than there's room for
+        assert_eq!(find_peaks(&magnitudes, 2.0, &mut peaks[..2]), 2);
+        assert_eq!(find_peaks(&[], 0.0, &mut peaks), 0);
+    }
+
+    #[test]
+    fn a_flat_spectrum_has_no_peaks() {
+        let mut peaks = [0; 8];
+        let flat = [1.0; 2049];
+        let floor = noise_floor(&flat, &mut [0.0; 2048]);
+        assert_eq!(floor, 1.0);
+        assert_eq!(
+            find_peaks(&flat, PEAK_THRESHOLD_FACTOR * floor, &mut peaks),
+            0
+        );
+        // Silence doesn't clear a floor of 0 either
+        assert_eq!(find_peaks(&[0.0; 2049], 0.0, &mut peaks), 0);
+        // Under a lower threshold the whole thing is one flat top, from bin 1
+        assert_eq!(find_peaks(&flat, 0.5, &mut peaks), 1);
+        assert_eq!(peaks[0], 1);
+    }
+
+    #[test]
+    fn a_plateau_counts_once_wherever_it_ends() {
+        let mut magnitudes = [1.0; 64];
+        magnitudes[20..30].fill(9.0);
+        // Stepping up again straight after is a shoulder, not a top
+        magnitudes[40..45].fill(5.0);
+        magnitudes[45] = 6.0;
+        // Running off the end it's still a top
+        magnitudes[58..].fill(7.0);
+
+        let mut peaks = [0; 8];
+        let n = find_peaks(&magnitudes, 2.0, &mut peaks);
+        assert_eq!(&peaks[..n], &[20, 45, 58]);
+    }
+
+    #[test]
+    fn a_weighting_matches_the_standard() {
+        // The standard's response to a tenth of a dB, which its table of
+        // nominal values rounds further
+        for (hz, db) in [
+            (20.0, -50.4),
+            (100.0, -19.1),
+            (1_000.0, 0.0),
+            (10_000.0, -2.5),
+        ] {
+            let weighted = 20.0 * a_weighting_gain(hz).log10();
+            assert!((weighted - db).abs() < 0.1, "{hz} Hz: {weighted} dB");
+        }
+    }
+
+    #[test]
+    fn a_weighting_per_bin() {
+        // 1 kHz bins at 8 kHz, so bin 1 is the reference and DC is nulled
+        let mut out = [0.0; 4];
+        a_weighting(&[2.0, 2.0, 2.0, 2.0], 8_000.0, 8, &mut out);
+        assert_eq!(out[0], 0.0);
+        assert!((out[1] - 2.0).abs() < 1e-3, "{}", out[1]);
+        assert!(out[2] > out[1] && out[3] > out[1], "{out:?}");
+    }
+
+    #[test]
+    fn interpolation_leaves_edge_peaks_alone() {
+        assert_eq!(interpolate_peak(&[5.0, 1.0, 0.0], 0), 0.0);
+        assert_eq!(interpolate_peak(&[0.0, 1.0, 5.0], 2), 2.0);
+        assert_eq!(interpolate_peak(&[5.0], 0), 0.0);
+    }
+}
@@ -0,0 +1,339 @@
+//! Frequency response of a system, from spectra of its input and output
+//! captured at the same time.
+
+use core::fmt;
+use microfft::Complex32;
+use micromath::F32Ext;
+
+use super::fft::{self, bin_to_hz, MAX_FFT_LEN};
+use super::window::{apply_window, WindowType};
+
+/// Input bins with less than this fraction of the strongest input bin's
+/// power, 60 dB down, get no response. There's too little drive there for
+/// the output to be anything but noise, and dividing would blow that up.
+pub const INPUT_POWER_FLOOR: f32 = 1e-6;
+
+/// Estimate the transfer function `H = Sxy / Sxx` per bin, the cross
+/// spectrum of input and output over the input's auto spectrum, for a Bode
+/// plot via `fft::magnitude_phase`. For one pair of spectra that's `Y / X`.
+///
+/// Bins where the input is below `INPUT_POWER_FLOOR` of its strongest bin
+/// come out zero, as does everything for an input that's all zero.
+///
+/// Both spectra must come from the same length FFT with the same window.
+/// From `fft::rfft`, bin 0 packs DC and Nyquist together and its response
+/// means nothing. Panics unless all three slices are the same length.
+pub fn transfer_function(
+    input_spec: &[Complex32],
+    output_spec: &[Complex32],
+    h_out: &mut [Complex32],
+) {
+    assert_eq!(
+        input_spec.len(),
+        output_spec.len(),
+        "input and output spectra need the same bins"
+    );
+    assert_eq!(
+        input_spec.len(),
+        h_out.len(),
+        "one response for each bin is needed"
+    );
+
+    let strongest = input_spec.iter().map(|x| x.norm_sqr()).fold(0.0, f32::max);
+    let floor = strongest * INPUT_POWER_FLOOR;
+
+    for ((x, y), h) in input_spec.iter().zip(output_spec).zip(h_out) {
+        let sxx = x.norm_sqr();
+        *h = if sxx > floor {
+            x.conj() * *y / sxx
+        } else {
+            Complex32::new(0.0, 0.0)
+        };
+    }
+}
+
+/// The first line of a CSV of `ResponsePoint`s, one per line after it
+pub const RESPONSE_CSV_HEADER: &str = "hz,gain_db,phase_deg,coherence";
+
+/// A system's response at one frequency, from `CrossSpectrum::response`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResponsePoint {
+    pub hz: f32,
+    /// `|H|` in dB, output over input, minus infinity if nothing came out
+    pub gain_db: f32,
+    /// The output's phase less the input's, from -180 to 180
+    pub phase_deg: f32,
+    /// How much of the output the input accounts for, from 0 (none, it's
+    /// all noise) to 1 (all of it, through a linear system)
+    pub coherence: f32,
+}
+
+impl fmt::Display for ResponsePoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.3},{:.3},{:.3},{:.4}",
+            self.hz, self.gain_db, self.phase_deg, self.coherence
+        )
+    }
+}
+
+/// Welch averaged auto and cross spectra of an input `a` and output `b`,
+/// built up over as many captures as there are, for a transfer function
+/// steadier than `transfer_function` gets from one pair of spectra, and a
+/// coherence to say which bins of it to believe.
+///
+/// Each capture is cut into segments of `2 * BINS` samples overlapping by
+/// half, and every segment's spectra are summed in, so `H = Sab / Saa` and
+/// the coherence `|Sab|^2 / (Saa Sbb)` are over all of them. Noise on the
+/// output averages out of `Sab` but not `Sbb`, which is what pulls the
+/// coherence down. A single segment always has a coherence of 1, so it
+/// means little until there are several.
+///
+/// The bins run from DC to just short of Nyquist, `BINS` of them.
+/// `2 * BINS` has to be a size `fft::rfft` does.
+#[derive(Clone, Copy, Debug)]
+pub struct CrossSpectrum<const BINS: usize> {
+    window: WindowType,
+    saa: [f32; BINS],
+    sbb: [f32; BINS],
+    sab: [Complex32; BINS],
+    segments: u32,
+}
+
+impl<const BINS: usize> CrossSpectrum<BINS> {
+    pub fn new(window: WindowType) -> Self {
+        assert!(
+            fft::is_supported_len(2 * BINS),
+            "twice the bins must be a microfft size"
+        );
+        Self {
+            window,
+            saa: [0.0; BINS],
+            sbb: [0.0; BINS],
+            sab: [Complex32::new(0.0, 0.0); BINS],
+            segments: 0,
+        }
+    }
+
+    /// Segments averaged so far
+    pub fn segments(&self) -> u32 {
+        self.segments
+    }
+
+    /// Forget everything averaged so far, for a new measurement
+    pub fn clear(&mut self) {
+        *self = Self::new(self.window);
+    }
+
+    /// Average in a capture of the input `a` and the output `b`, taken at
+    /// the same time. Samples past the last whole segment are ignored, so
+    /// panics unless there's at least one. Each segment goes through a
+    /// `MAX_FFT_LEN` scratch buffer on the stack (16K), as in `welch_psd`.
+    pub fn add<const N: usize>(&mut self, a: &[f32; N], b: &[f32; N]) {
+        let len = 2 * BINS;
+        assert!(N >= len, "need at least one segment");
+
+        // The input's spectrum is kept while the FFT works on the output's
+        let mut scratch = [0.0f32; MAX_FFT_LEN];
+        let mut a_spectrum = [Complex32::new(0.0, 0.0); BINS];
+        for start in (0..=N - len).step_by(BINS) {
+            let buf = &mut scratch[..len];
+            buf.copy_from_slice(&a[start..start + len]);
+            apply_window(buf, self.window);
+            a_spectrum.copy_from_slice(fft::rfft(buf));
+            // Bin 0 has Nyquist packed into it, leaving DC
+            a_spectrum[0].im = 0.0;
+
+            let buf = &mut scratch[..len];
+            buf.copy_from_slice(&b[start..start + len]);
+            apply_window(buf, self.window);
+            let b_spectrum = fft::rfft(buf);
+            b_spectrum[0].im = 0.0;
+
+            for (k, (x, y)) in a_spectrum.iter().zip(b_spectrum.iter()).enumerate() {
+                self.saa[k] += x.norm_sqr();
+                self.sbb[k] += y.norm_sqr();
+                self.sab[k] += x.conj() * *y;
+            }
+            self.segments += 1;
+        }
+    }
+
+    /// The response at each bin the input drove, for a Bode plot. Bins with
+    /// less than `INPUT_POWER_FLOOR` of the strongest bin's input power are
+    /// left out, as there's nothing to divide by, and so is everything
+    /// before any capture's been added.
+    pub fn response(&self, sample_rate_hz: f32) -> impl Iterator<Item = ResponsePoint> + '_ {
+        let strongest = self.saa.iter().copied().fold(0.0, f32::max);
+        let floor = strongest * INPUT_POWER_FLOOR;
+        (0..BINS)
+            .filter(move |&k| strongest > 0.0 && self.saa[k] > floor)
+            .map(move |k| {
+                let (saa, sbb, sab) = (self.saa[k], self.sbb[k], self.sab[k]);
+                let h = sab / saa;
+                // A silent output would be 0 / 0, and has nothing the
+                // input accounts for
+                let coherence = if sbb > 0.0 {
+                    (sab.norm_sqr() / (saa * sbb)).min(1.0)
+                } else {
+                    0.0
+                };
+                ResponsePoint {
+                    hz: bin_to_hz(k, sample_rate_hz, 2 * BINS),
+                    gain_db: 10.0 * h.norm_sqr().log10(),
+                    phase_deg: h.im.atan2(h.re).to_degrees(),
+                    coherence,
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(re: f32, im: f32) -> Complex32 {
+        Complex32::new(re, im)
+    }
+
+    #[test]
+    fn response_is_output_over_input() {
+        // A gain of 2 delayed by a quarter turn is H = -2j
+        let input = [c(1.0, 0.0), c(0.0, 3.0), c(-2.0, 2.0)];
+        let output = input.map(|x| x * c(0.0, -2.0));
+        let mut h = [c(0.0, 0.0); 3];
+        transfer_function(&input, &output, &mut h);
+
+        for h in h {
+            assert!((h - c(0.0, -2.0)).norm_sqr() < 1e-12, "{h:?}");
+        }
+    }
+
+    #[test]
+    fn weak_input_bins_have_no_response() {
+        let input = [c(1.0, 0.0), c(1e-4, 0.0), c(0.0, 0.0)];
+        let output = [c(0.5, 0.0), c(1.0, 0.0), c(1.0, 0.0)];
+        let mut h = [c(9.0, 9.0); 3];
+        transfer_function(&input, &output, &mut h);
+        assert_eq!(h, [c(0.5, 0.0), c(0.0, 0.0), c(0.0, 0.0)]);
+
+        // Silence in gives nothing out, not NaN
+        let mut h = [c(9.0, 9.0); 3];
+        transfer_function(&[c(0.0, 0.0); 3], &output, &mut h);
+        assert_eq!(h, [c(0.0, 0.0); 3]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn spectra_must_match() {
+        transfer_function(&[c(1.0, 0.0); 4], &[c(1.0, 0.0); 3], &mut [c(0.0, 0.0); 4]);
+    }
+
+    /// The same pseudo-random noise every run, in -1..1
+    fn noise(state: &mut u32) -> f32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        (*state >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+
+    /// Two-tap average, `H = (1 + e^-jw) / 2`: a gain of `cos(w / 2)` and
+    /// a phase of `-w / 2`, carried over from one capture to the next
+    fn average(input: &[f32], last: &mut f32, output: &mut [f32]) {
+        for (y, &x) in output.iter_mut().zip(input) {
+            *y = 0.5 * (x + *last);
+            *last = x;
+        }
+    }
+
+    const BINS: usize = 64;
+    const N: usize = 512;
+
+    #[test]
+    fn averaging_measures_a_filters_response() {
+        let mut cross: CrossSpectrum<BINS> = CrossSpectrum::new(WindowType::Hann);
+        assert_eq!(cross.response(48_000.0).count(), 0);
+
+        let mut state = 0x1234_5678;
+        let mut last = 0.0;
+        for _ in 0..4 {
+            let mut a = [0.0; N];
+            a.iter_mut().for_each(|x| *x = noise(&mut state));
+            let mut b = [0.0; N];
+            average(&a, &mut last, &mut b);
+            cross.add(&a, &b);
+        }
+        // Seven half-overlapping segments a capture
+        assert_eq!(cross.segments(), 4 * 7);
+
+        let points: Vec<ResponsePoint> = cross.response(48_000.0).collect();
+        assert_eq!(points.len(), BINS);
+        // Short of the notch at Nyquist, where leakage from either side of it
+        // swamps what little gets through
+        for (k, point) in points.iter().enumerate().take(BINS * 3 / 4).skip(1) {
+            let w = core::f32::consts::PI * k as f32 / BINS as f32;
+            let gain_db = 20.0 * (w / 2.0).cos().log10();
+            assert!((point.hz - k as f32 * 375.0).abs() < 1e-3);
+            assert!((point.gain_db - gain_db).abs() < 0.1, "{k}: {point:?}");
+            assert!(
+                (point.phase_deg + (w / 2.0).to_degrees()).abs() < 0.5,
+                "{k}: {point:?}"
+            );
+            assert!(point.coherence > 0.99, "{k}: {point:?}");
+        }
+
+        cross.clear();
+        assert_eq!(cross.segments(), 0);
+        assert_eq!(cross.response(48_000.0).count(), 0);
+    }
+
+    #[test]
+    fn output_noise_lowers_the_coherence() {
+        let mut cross: CrossSpectrum<BINS> = CrossSpectrum::new(WindowType::Hann);
+        let mut state = 0xDEAD_BEEF;
+        for _ in 0..8 {
+            let mut a = [0.0; N];
+            a.iter_mut().for_each(|x| *x = noise(&mut state));
+            // As much noise on the output as there's signal
+            let mut b = a;
+            b.iter_mut().for_each(|y| *y += noise(&mut state));
+            cross.add(&a, &b);
+        }
+        let coherence: f32 =
+            cross.response(48_000.0).map(|p| p.coherence).sum::<f32>() / BINS as f32;
+        // Half the output's power is the input's
+        assert!((coherence - 0.5).abs() < 0.1, "{coherence}");
+        // And the noise averages out of the gain
+        let gain_db = cross.response(48_000.0).map(|p| p.gain_db).sum::<f32>() / BINS as f32;
+        assert!(gain_db.abs() < 0.5, "{gain_db}");
+    }
+
+    #[test]
+    fn undriven_bins_are_left_out() {
+        let mut cross: CrossSpectrum<BINS> = CrossSpectrum::new(WindowType::Rectangular);
+        // A tone on bin 8 of every segment, and nothing anywhere else
+        let a: [f32; N] = core::array::from_fn(|i| {
+            (core::f32::consts::TAU * 8.0 * i as f32 / (2 * BINS) as f32).sin()
+        });
+        cross.add(&a, &[0.0; N]);
+        let points: Vec<ResponsePoint> = cross.response(1_280.0).collect();
+        assert_eq!(points.len(), 1, "{points:?}");
+        assert_eq!(points[0].hz, 80.0);
+        // Nothing came out, so no gain to speak of, and no NaN
+        assert_eq!(points[0].gain_db, f32::NEG_INFINITY);
+        assert_eq!(points[0].coherence, 0.0);
+    }
+
+    #[test]
+    fn points_are_csv_rows() {
+        let point = ResponsePoint {
+            hz: 100.0,
+            gain_db: -3.0103,
+            phase_deg: -45.0,
+            coherence: 0.98764,
+        };
+        assert_eq!(RESPONSE_CSV_HEADER.split(',').count(), 4);
+        assert_eq!(point.to_string(), "100.000,-3.010,-45.000,0.9876");
+    }
+}
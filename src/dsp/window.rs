@@ -0,0 +1,164 @@
+//! Window functions, to cut spectral leakage from non-periodic captures.
+
+use core::f32::consts::PI;
+use micromath::F32Ext;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowType {
+    /// No window at all, the best resolution but the worst leakage
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+    /// Kaiser, with its beta. Zero is rectangular, and larger trades a
+    /// wider main lobe for lower sidelobes: about 5 is like Hamming, 8.6
+    /// like Blackman. Beta is held to `0..=MAX_KAISER_BETA`.
+    Kaiser(f32),
+}
+
+/// Largest Kaiser beta used, any more is taken as this. Its sidelobes are
+/// already far below what an `f32` spectrum resolves, and `bessel_i0` of
+/// much more would overflow.
+pub const MAX_KAISER_BETA: f32 = 50.0;
+
+/// Most terms `bessel_i0` sums, enough for `MAX_KAISER_BETA` to converge
+const I0_MAX_TERMS: u32 = 64;
+
+impl WindowType {
+    /// Value of the window at sample `i` of `n`.
+    ///
+    /// These are the periodic (DFT-even) forms, dividing by `n` rather than
+    /// `n - 1`, which is what you want for spectral analysis.
+    pub fn value(self, i: usize, n: usize) -> f32 {
+        let phase = 2.0 * PI * i as f32 / n as f32;
+
+        match self {
+            WindowType::Rectangular => 1.0,
+            WindowType::Hann => 0.5 - 0.5 * phase.cos(),
+            WindowType::Hamming => 0.54 - 0.46 * phase.cos(),
+            WindowType::Blackman => 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos(),
+            WindowType::Kaiser(beta) => {
+                let beta = kaiser_beta(beta);
+                kaiser(i, n, beta, bessel_i0(beta))
+            }
+        }
+    }
+}
+
+/// Multiply a buffer by the window, in place
+pub fn apply_window(buf: &mut [f32], window: WindowType) {
+    let n = buf.len();
+    match window {
+        // The denominator's the same for every sample, so only sum it once
+        WindowType::Kaiser(beta) => {
+            let beta = kaiser_beta(beta);
+            let i0_beta = bessel_i0(beta);
+            buf.iter_mut()
+                .enumerate()
+                .for_each(|(i, f)| *f *= kaiser(i, n, beta, i0_beta));
+        }
+        _ => buf
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, f)| *f *= window.value(i, n)),
+    }
+}
+
+/// The periodic Kaiser window at sample `i` of `n`, with `i0_beta` the
+/// `bessel_i0` of `beta`
+fn kaiser(i: usize, n: usize, beta: f32, i0_beta: f32) -> f32 {
+    let x = 2.0 * i as f32 / n as f32 - 1.0;
+    bessel_i0(beta * (1.0 - x * x).max(0.0).sqrt()) / i0_beta
+}
+
+fn kaiser_beta(beta: f32) -> f32 {
+    if beta.is_nan() {
+        0.0
+    } else {
+        beta.clamp(0.0, MAX_KAISER_BETA)
+    }
+}
+
+/// The zeroth order modified Bessel function of the first kind, from its
+/// series, the sum of `((x / 2)^k / k!)^2`. Each term is made from the one
+/// before, so there's no factorial to overflow, and the sum stops once the
+/// terms no longer change it, or after `I0_MAX_TERMS` of them.
+pub fn bessel_i0(x: f32) -> f32 {
+    let quarter_x2 = x * x / 4.0;
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..=I0_MAX_TERMS {
+        term *= quarter_x2 / (k * k) as f32;
+        sum += term;
+        if term <= sum * f32::EPSILON {
+            break;
+        }
+    }
+    sum
+}
+
+/// Sum of the squared window, which is what a windowed power spectrum has to
+/// be divided by to come out in the right units
+pub fn window_power(window: WindowType, n: usize) -> f32 {
+    (0..n).map(|i| window.value(i, n).powi(2)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bessel_i0_matches_tables() {
+        for (x, i0) in [
+            (0.0, 1.0),
+            (1.0, 1.266_066),
+            (5.0, 27.239_872),
+            (-5.0, 27.239_872),
+            (MAX_KAISER_BETA, 2.932_554e20),
+        ] {
+            let got = bessel_i0(x);
+            assert!((got - i0).abs() <= i0 * 1e-5, "I0({x}) = {got}, not {i0}");
+        }
+    }
+
+    #[test]
+    fn kaiser_tapers_more_with_beta() {
+        const N: usize = 64;
+        // Beta 0 is no window at all
+        for i in 0..N {
+            assert_eq!(WindowType::Kaiser(0.0).value(i, N), 1.0);
+        }
+
+        let mut last_edge = 1.0;
+        for beta in [2.0, 5.0, 8.6, 20.0] {
+            let window = WindowType::Kaiser(beta);
+            assert!((window.value(N / 2, N) - 1.0).abs() < 1e-6);
+            // Periodic, so symmetric about the middle
+            assert!((window.value(N / 4, N) - window.value(3 * N / 4, N)).abs() < 1e-6);
+            let edge = window.value(0, N);
+            assert!((edge - 1.0 / bessel_i0(beta)).abs() < 1e-6);
+            assert!(edge < last_edge, "{beta}: {edge}");
+            last_edge = edge;
+        }
+
+        let mut buf = [1.0; N];
+        apply_window(&mut buf, WindowType::Kaiser(8.6));
+        for (i, &w) in buf.iter().enumerate() {
+            assert!((w - WindowType::Kaiser(8.6).value(i, N)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn huge_betas_are_held_in_range() {
+        const N: usize = 256;
+        let mut buf = [1.0; N];
+        apply_window(&mut buf, WindowType::Kaiser(1e6));
+        assert!(buf.iter().all(|w| w.is_finite() && (0.0..=1.0).contains(w)));
+        assert_eq!(buf[N / 2], 1.0);
+        for (i, &w) in buf.iter().enumerate() {
+            assert_eq!(w, WindowType::Kaiser(MAX_KAISER_BETA).value(i, N));
+        }
+        assert_eq!(WindowType::Kaiser(f32::NAN).value(3, N), 1.0);
+        assert!(window_power(WindowType::Kaiser(-3.0), N) == N as f32);
+    }
+}
@@ -0,0 +1,285 @@
+//! A flight recorder: what the last captures looked like, kept where a
+//! watchdog reset doesn't wipe it, so a hang leaves something to go on.
+//!
+//! The record holds the last capture's time, rate, peak and clip count,
+//! the clips and errors since boot, and the band levels of the last
+//! `FRAMES` spectra in a ring, a byte of dBFS each. It's laid out as
+//!
+//! ```text
+//! magic "FLT1" | captures u32 | captured_at_us u64 | sample_rate_hz f32
+//!   | peak_hz f32 | peak_dbfs f32 | clipped u32 | clipped_total u32
+//!   | errors u32 | frames_written u32 | frames [[i8; FRAME_BANDS]; FRAMES] | crc32
+//! ```
+//!
+//! with a CRC-32 over the rest, so one cut short by the reset, or a cold
+//! boot's random contents, reads as no record. A capture without a peak
+//! has NaN for its frequency and level.
+
+/// Spectra kept, the last this many
+pub const FRAMES: usize = 64;
+
+/// Band levels in a frame. Octave bands from one bin up, so enough for
+/// captures up to `1 << FRAME_BANDS` samples, and any more are dropped.
+pub const FRAME_BANDS: usize = 10;
+
+/// What a band that's too quiet to measure, or missing, reads as
+pub const FLOOR_DBFS: i8 = i8::MIN;
+
+/// Bytes in front of the frames
+const HEADER_LEN: usize = 44;
+
+/// Bytes the record takes, CRC and all
+pub const RECORD_LEN: usize = HEADER_LEN + FRAMES * FRAME_BANDS + 4;
+
+/// "FLT1" read as little-endian
+const MAGIC: u32 = u32::from_le_bytes(*b"FLT1");
+
+/// A capture overran, so wasn't trusted
+pub const ERROR_OVERRUN: u32 = 1 << 0;
+/// A capture had no samples
+pub const ERROR_EMPTY: u32 = 1 << 1;
+/// A capture's buffer changed after its DMA finished
+pub const ERROR_BUFFER_CHANGED: u32 = 1 << 2;
+/// Samples clipped at the ADC rails
+pub const ERROR_CLIPPED: u32 = 1 << 3;
+
+const ERROR_NAMES: [(u32, &str); 4] = [
+    (ERROR_OVERRUN, "overrun"),
+    (ERROR_EMPTY, "empty"),
+    (ERROR_BUFFER_CHANGED, "buffer changed"),
+    (ERROR_CLIPPED, "clipped"),
+];
+
+/// The names of the `ERROR_` flags set in `errors`
+pub fn error_names(errors: u32) -> impl Iterator<Item = &'static str> {
+    ERROR_NAMES
+        .into_iter()
+        .filter(move |(flag, _)| errors & flag != 0)
+        .map(|(_, name)| name)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlightRecord {
+    /// Captures since boot
+    pub captures: u32,
+    /// When the last capture finished, in microseconds since boot
+    pub captured_at_us: u64,
+    pub sample_rate_hz: f32,
+    /// The last spectrum's peak, frequency and level, if it had one
+    pub peak: Option<(f32, f32)>,
+    /// Samples clipped in the last capture, and since boot
+    pub clipped: u32,
+    pub clipped_total: u32,
+    /// Every `ERROR_` flag raised since boot
+    pub errors: u32,
+    /// Frames pushed since boot, of which the last `FRAMES` are kept
+    frames_written: u32,
+    frames: [[i8; FRAME_BANDS]; FRAMES],
+}
+
+impl FlightRecord {
+    pub const fn new() -> Self {
+        Self {
+            captures: 0,
+            captured_at_us: 0,
+            sample_rate_hz: 0.0,
+            peak: None,
+            clipped: 0,
+            clipped_total: 0,
+            errors: 0,
+            frames_written: 0,
+            frames: [[FLOOR_DBFS; FRAME_BANDS]; FRAMES],
+        }
+    }
+
+    /// Start on a new capture, forgetting the last one's peak
+    pub fn capture(&mut self, captured_at_us: u64, sample_rate_hz: f32, clipped: u32) {
+        self.captures = self.captures.wrapping_add(1);
+        self.captured_at_us = captured_at_us;
+        self.sample_rate_hz = sample_rate_hz;
+        self.peak = None;
+        self.clipped = clipped;
+        self.clipped_total = self.clipped_total.saturating_add(clipped);
+        if clipped > 0 {
+            self.errors |= ERROR_CLIPPED;
+        }
+    }
+
+    /// Keep the capture's spectrum: its peak, and its band levels in dBFS,
+    /// rounded to the nearest dB
+    pub fn spectrum(&mut self, peak: Option<(f32, f32)>, levels_dbfs: &[f32]) {
+        self.peak = peak;
+        let mut frame = [FLOOR_DBFS; FRAME_BANDS];
+        for (band, &level) in frame.iter_mut().zip(levels_dbfs) {
+            // NaN, from a band with no power, saturates to 0, so make it
+            // the floor like minus infinity
+            if !level.is_nan() {
+                *band = round_dbfs(level);
+            }
+        }
+        self.frames[self.frames_written as usize % FRAMES] = frame;
+        self.frames_written = self.frames_written.wrapping_add(1);
+    }
+
+    /// The frames kept, oldest first
+    pub fn frames(&self) -> impl Iterator<Item = &[i8; FRAME_BANDS]> {
+        let kept = (self.frames_written as usize).min(FRAMES);
+        let next = self.frames_written as usize % FRAMES;
+        (0..kept).map(move |i| &self.frames[(next + FRAMES - kept + i) % FRAMES])
+    }
+
+    /// The record as stored, with a CRC-32 from `crc`
+    pub fn to_bytes(&self, crc: impl FnOnce(&[u8]) -> u32) -> [u8; RECORD_LEN] {
+        let mut bytes = [0; RECORD_LEN];
+        let (peak_hz, peak_dbfs) = self.peak.unwrap_or((f32::NAN, f32::NAN));
+        let header = [
+            &MAGIC.to_le_bytes()[..],
+            &self.captures.to_le_bytes(),
+            &self.captured_at_us.to_le_bytes(),
+            &self.sample_rate_hz.to_le_bytes(),
+            &peak_hz.to_le_bytes(),
+            &peak_dbfs.to_le_bytes(),
+            &self.clipped.to_le_bytes(),
+            &self.clipped_total.to_le_bytes(),
+            &self.errors.to_le_bytes(),
+            &self.frames_written.to_le_bytes(),
+        ];
+        let mut at = 0;
+        for field in header {
+            bytes[at..at + field.len()].copy_from_slice(field);
+            at += field.len();
+        }
+        for (byte, &level) in bytes[HEADER_LEN..]
+            .iter_mut()
+            .zip(self.frames.iter().flatten())
+        {
+            *byte = level as u8;
+        }
+
+        let end = RECORD_LEN - 4;
+        let checksum = crc(&bytes[..end]);
+        bytes[end..].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Read a record back, `None` if there isn't one or it's damaged
+    pub fn from_bytes(bytes: &[u8; RECORD_LEN], crc: impl FnOnce(&[u8]) -> u32) -> Option<Self> {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let f32_at = |at: usize| f32::from_bits(u32_at(at));
+
+        let end = RECORD_LEN - 4;
+        if u32_at(0) != MAGIC || crc(&bytes[..end]) != u32_at(end) {
+            return None;
+        }
+
+        let mut frames = [[FLOOR_DBFS; FRAME_BANDS]; FRAMES];
+        for (level, &byte) in frames.iter_mut().flatten().zip(&bytes[HEADER_LEN..end]) {
+            *level = byte as i8;
+        }
+        let peak = (f32_at(20), f32_at(24));
+        Some(Self {
+            captures: u32_at(4),
+            captured_at_us: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            sample_rate_hz: f32_at(16),
+            peak: (!peak.0.is_nan()).then_some(peak),
+            clipped: u32_at(28),
+            clipped_total: u32_at(32),
+            errors: u32_at(36),
+            frames_written: u32_at(40),
+            frames,
+        })
+    }
+}
+
+impl Default for FlightRecord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A level in dBFS as a whole number of dB. The cast pins it to what a
+/// byte holds, minus infinity to the floor.
+fn round_dbfs(level: f32) -> i8 {
+    if level < 0.0 {
+        (level - 0.5) as i8
+    } else {
+        (level + 0.5) as i8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::crc32::crc32;
+
+    fn recorded(spectra: usize) -> FlightRecord {
+        let mut record = FlightRecord::new();
+        for i in 0..spectra {
+            record.capture(1_000 * i as u64, 48_000.0, i as u32 % 3);
+            record.spectrum(Some((1_000.0, -6.0)), &[-(i as f32); 3]);
+        }
+        record
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut record = recorded(5);
+        record.errors |= ERROR_OVERRUN;
+        let bytes = record.to_bytes(crc32);
+        assert_eq!(&bytes[..4], b"FLT1");
+        assert_eq!(FlightRecord::from_bytes(&bytes, crc32), Some(record));
+
+        // The last capture had no peak
+        record.capture(9_000, 48_000.0, 0);
+        let read = FlightRecord::from_bytes(&record.to_bytes(crc32), crc32).unwrap();
+        assert_eq!(read.peak, None);
+        assert_eq!(read.captured_at_us, 9_000);
+        assert_eq!(read.clipped_total, 1 + 2 + 1);
+        assert_eq!(read.errors, ERROR_OVERRUN | ERROR_CLIPPED);
+        assert_eq!(
+            error_names(read.errors).collect::<Vec<_>>(),
+            ["overrun", "clipped"]
+        );
+    }
+
+    #[test]
+    fn damage_reads_as_no_record() {
+        let bytes = recorded(3).to_bytes(crc32);
+        for at in [0, 10, HEADER_LEN + 1, RECORD_LEN - 1] {
+            let mut damaged = bytes;
+            damaged[at] ^= 0x10;
+            assert_eq!(FlightRecord::from_bytes(&damaged, crc32), None, "{at}");
+        }
+        // Backup SRAM after a cold boot, near enough
+        assert_eq!(FlightRecord::from_bytes(&[0; RECORD_LEN], crc32), None);
+    }
+
+    #[test]
+    fn only_the_last_frames_are_kept() {
+        assert_eq!(FlightRecord::new().frames().count(), 0);
+        let record = recorded(3);
+        let first: Vec<i8> = record.frames().map(|f| f[0]).collect();
+        assert_eq!(first, [0, -1, -2]);
+        // Bands past the levels given sit at the floor
+        assert_eq!(record.frames().next().unwrap()[3], FLOOR_DBFS);
+
+        let record = recorded(FRAMES + 10);
+        assert_eq!(record.frames().count(), FRAMES);
+        assert_eq!(record.frames().next().unwrap()[0], -10);
+        assert_eq!(record.frames().last().unwrap()[0], -(FRAMES as i8 + 9));
+    }
+
+    #[test]
+    fn levels_round_to_a_byte() {
+        let mut record = FlightRecord::new();
+        record.spectrum(
+            None,
+            &[-6.4, -6.6, 3.5, f32::NEG_INFINITY, f32::NAN, -500.0, 200.0],
+        );
+        let frame = record.frames().next().unwrap();
+        assert_eq!(
+            &frame[..7],
+            &[-6, -7, 4, FLOOR_DBFS, FLOOR_DBFS, FLOOR_DBFS, i8::MAX]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Reading the board's frames back on a PC, with the `host` feature.
+//!
+//! `FrameReader` splits a byte stream, e.g. a serial port dump, into frames
+//! and decodes them, and `Assembler` puts each capture back together from
+//! its header and data frames. Both need std, so this never builds for the
+//! board.
+
+use crate::protocol::crc32::crc32;
+use crate::protocol::{self, CaptureHeader, Frame, FrameError, MAX_PAYLOAD_LEN};
+
+/// A frame as received, owning its data
+pub type OwnedFrame = Frame<Vec<f32>, Vec<u16>>;
+
+/// Splits a byte stream at the zero delimiters and decodes each frame
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    pending: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add received bytes, returning every frame they finish. Anything
+    /// between frames that isn't one, like console text, comes back as an
+    /// error, and a frame cut off at the end waits for the next call.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Result<OwnedFrame, FrameError>> {
+        let mut frames = Vec::new();
+        let mut buf = [0; MAX_PAYLOAD_LEN];
+
+        for &byte in bytes {
+            if byte != 0 {
+                self.pending.push(byte);
+                continue;
+            }
+            if !self.pending.is_empty() {
+                frames.push(protocol::decode(&self.pending, crc32, &mut buf));
+                self.pending.clear();
+            }
+        }
+        frames
+    }
+}
+
+/// One capture put back together
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capture {
+    pub header: CaptureHeader,
+    /// `header.bins` magnitudes, in 16-bit counts
+    pub magnitudes: Vec<f32>,
+    /// `header.samples` samples, in ADC counts
+    pub samples: Vec<u16>,
+    /// `header.bands` log mel band energies
+    pub bands: Vec<f32>,
+}
+
+/// Collects data frames under the header they follow
+#[derive(Debug, Default)]
+pub struct Assembler {
+    current: Option<Capture>,
+    /// Magnitudes, samples and band energies received for `current`
+    received: (usize, usize, usize),
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a frame, returning the capture it completes, if any. Data that
+    /// doesn't belong to the capture in progress is dropped, as is a capture
+    /// that's still missing data when the next header arrives.
+    pub fn push(&mut self, frame: OwnedFrame) -> Option<Capture> {
+        match frame {
+            Frame::Header(header) => {
+                self.current = Some(Capture {
+                    header,
+                    magnitudes: vec![0.0; header.bins as usize],
+                    samples: vec![0; header.samples as usize],
+                    bands: vec![0.0; header.bands as usize],
+                });
+                self.received = (0, 0, 0);
+            }
+            Frame::Spectrum(frame) => {
+                let capture = self.current_for(frame.sequence)?;
+                let received = place(&mut capture.magnitudes, frame.first_bin, &frame.magnitudes);
+                self.received.0 += received;
+            }
+            Frame::Raw(frame) => {
+                let capture = self.current_for(frame.sequence)?;
+                let received = place(&mut capture.samples, frame.first_sample, &frame.samples);
+                self.received.1 += received;
+            }
+            Frame::Bands(frame) => {
+                let capture = self.current_for(frame.sequence)?;
+                let received = place(&mut capture.bands, frame.first_band, &frame.energies);
+                self.received.2 += received;
+            }
+        }
+
+        let capture = self.current.as_ref()?;
+        let complete = self.received.0 >= capture.magnitudes.len()
+            && self.received.1 >= capture.samples.len()
+            && self.received.2 >= capture.bands.len();
+        if complete {
+            self.current.take()
+        } else {
+            None
+        }
+    }
+
+    fn current_for(&mut self, sequence: u32) -> Option<&mut Capture> {
+        self.current
+            .as_mut()
+            .filter(|capture| capture.header.sequence == sequence)
+    }
+}
+
+/// Copy a chunk into place, as much of it as fits. Returns how much did.
+fn place<T: Copy>(all: &mut [T], first: u16, chunk: &[T]) -> usize {
+    let Some(rest) = all.get_mut(first as usize..) else {
+        return 0;
+    };
+    let len = chunk.len().min(rest.len());
+    rest[..len].copy_from_slice(&chunk[..len]);
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        BandsFrame, BorrowedFrame, RawFrame, SpectrumFrame, CHUNK, MAX_FRAME_LEN,
+    };
+
+    fn header(sequence: u32, bins: u16, samples: u16) -> CaptureHeader {
+        CaptureHeader {
+            sequence,
+            timestamp_us: 42,
+            sample_rate_hz: 48_000.0,
+            resolution_bits: 12,
+            full_scale_volts: 3.3,
+            trusted: true,
+            bins,
+            samples,
+            bands: 0,
+        }
+    }
+
+    /// Encode frames into a stream the way the board sends them
+    fn stream(frames: &[BorrowedFrame]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for frame in frames {
+            let mut scratch = [0; MAX_PAYLOAD_LEN];
+            let mut out = [0; MAX_FRAME_LEN];
+            bytes.extend(protocol::encode(frame, crc32, &mut scratch, &mut out).unwrap());
+        }
+        bytes
+    }
+
+    #[test]
+    fn reassembles_a_capture_sent_between_console_text() {
+        let magnitudes: Vec<f32> = (0..300).map(|i| i as f32).collect();
+        let samples: Vec<u16> = (0..10).map(|i| i * 1000).collect();
+
+        let mut bytes = b"ok\r\n".to_vec();
+        bytes.extend(stream(&[
+            Frame::Header(header(3, 300, 10)),
+            Frame::Spectrum(SpectrumFrame {
+                sequence: 3,
+                first_bin: 0,
+                magnitudes: &magnitudes[..CHUNK],
+            }),
+            Frame::Raw(RawFrame {
+                sequence: 3,
+                first_sample: 0,
+                samples: &samples,
+            }),
+        ]));
+        bytes.extend(b"error: not a number\r\n");
+        bytes.extend(stream(&[Frame::Spectrum(SpectrumFrame {
+            sequence: 3,
+            first_bin: CHUNK as u16,
+            magnitudes: &magnitudes[CHUNK..],
+        })]));
+
+        // Split mid-frame, as reads from a port would be
+        let mut reader = FrameReader::new();
+        let (first, second) = bytes.split_at(bytes.len() / 2);
+        let mut results = reader.push(first);
+        results.extend(reader.push(second));
+
+        // The two bits of text fail to decode, the frames don't
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 2);
+
+        let mut assembler = Assembler::new();
+        let captures: Vec<Capture> = results
+            .into_iter()
+            .flatten()
+            .filter_map(|frame| assembler.push(frame))
+            .collect();
+        assert_eq!(
+            captures,
+            [Capture {
+                header: header(3, 300, 10),
+                magnitudes,
+                samples,
+                bands: Vec::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn reassembles_band_energies() {
+        let energies: Vec<f32> = (0..32).map(|i| i as f32 - 20.0).collect();
+        let bytes = stream(&[
+            Frame::Header(CaptureHeader {
+                bands: 32,
+                ..header(4, 0, 0)
+            }),
+            Frame::Bands(BandsFrame {
+                sequence: 4,
+                first_band: 16,
+                energies: &energies[16..],
+            }),
+            Frame::Bands(BandsFrame {
+                sequence: 4,
+                first_band: 0,
+                energies: &energies[..16],
+            }),
+        ]);
+
+        let mut assembler = Assembler::new();
+        let captures: Vec<Capture> = FrameReader::new()
+            .push(&bytes)
+            .into_iter()
+            .flatten()
+            .filter_map(|frame| assembler.push(frame))
+            .collect();
+        assert_eq!(captures.len(), 1);
+        assert_eq!(captures[0].bands, energies);
+        assert!(captures[0].magnitudes.is_empty() && captures[0].samples.is_empty());
+    }
+
+    #[test]
+    fn header_only_captures_and_stray_data() {
+        let mut assembler = Assembler::new();
+
+        // Data with no header in progress is dropped
+        let stray = Frame::Raw(RawFrame {
+            sequence: 1,
+            first_sample: 0,
+            samples: vec![1, 2, 3],
+        });
+        assert_eq!(assembler.push(stray.clone()), None);
+
+        // A capture with no data is complete as soon as its header arrives
+        let empty = assembler.push(Frame::Header(header(2, 0, 0)));
+        assert_eq!(empty.map(|c| c.header.sequence), Some(2));
+
+        // Data for the wrong capture doesn't complete this one
+        assert_eq!(assembler.push(Frame::Header(header(5, 0, 3))), None);
+        assert_eq!(assembler.push(stray), None);
+    }
+}
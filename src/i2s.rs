@@ -0,0 +1,96 @@
+//! Decoding I2S audio: which slot a mono microphone talks in, unpacking its
+//! 24-bit samples, and the bit clock divider for a sample rate.
+//!
+//! A 24-bit I2S mic like the INMP441 sends each sample MSB first in a 32-bit
+//! slot, left slot while WS is low, right while it's high, and which one it
+//! uses is set by its L/R pin. Receiving 24 bits of each slot, the SAI puts
+//! a sample in the low 24 bits of its data register with the top byte zero,
+//! so it has to be sign extended before it's any use.
+
+/// Bits per stereo frame, two 32-bit slots
+pub const FRAME_BITS: u32 = 64;
+
+/// Largest bit clock divider the SAI has (MCKDIV is 6 bits)
+pub const MAX_DIVIDER: u32 = 63;
+
+/// The slot a mono mic transmits in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    /// L/R pin tied low
+    Left,
+    /// L/R pin tied high
+    Right,
+}
+
+impl Slot {
+    /// SLOTEN bits for receiving only this slot, so DMA only sees its
+    /// samples rather than every other word being silence
+    pub const fn enable_mask(self) -> u32 {
+        match self {
+            Slot::Left => 1 << 16,
+            Slot::Right => 1 << 17,
+        }
+    }
+}
+
+/// A 24-bit two's complement sample, right-aligned in a received word, as
+/// an `i32`. Anything above bit 23 is ignored.
+pub const fn sign_extend_24(word: u32) -> i32 {
+    ((word << 8) as i32) >> 8
+}
+
+/// Divider from `kernel_hz` to the bit clock for `sample_rate_hz`, and the
+/// sample rate it actually gives. `None` if the nearest divider is out of
+/// the SAI's range.
+pub fn bit_clock_divider(kernel_hz: u32, sample_rate_hz: u32) -> Option<(u32, f32)> {
+    let bit_clock_hz = sample_rate_hz.checked_mul(FRAME_BITS)?;
+    if bit_clock_hz == 0 {
+        return None;
+    }
+    let divider = (kernel_hz + bit_clock_hz / 2) / bit_clock_hz;
+    (1..=MAX_DIVIDER)
+        .contains(&divider)
+        .then(|| (divider, kernel_hz as f32 / (divider * FRAME_BITS) as f32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_sign_extended() {
+        assert_eq!(sign_extend_24(0x00_0000), 0);
+        assert_eq!(sign_extend_24(0x00_0001), 1);
+        assert_eq!(sign_extend_24(0x7F_FFFF), 8_388_607);
+        assert_eq!(sign_extend_24(0x80_0000), -8_388_608);
+        assert_eq!(sign_extend_24(0xFF_FFFF), -1);
+        assert_eq!(sign_extend_24(0xFF_FFFE), -2);
+        // Whatever's in the top byte doesn't matter
+        assert_eq!(sign_extend_24(0xABFF_FFFF), -1);
+        assert_eq!(sign_extend_24(0xFF00_0001), 1);
+    }
+
+    #[test]
+    fn each_slot_has_its_own_enable_bit() {
+        assert_eq!(Slot::Left.enable_mask(), 0x0001_0000);
+        assert_eq!(Slot::Right.enable_mask(), 0x0002_0000);
+    }
+
+    #[test]
+    fn dividers_hit_audio_rates() {
+        // 49.152 MHz is an exact multiple of 48 kHz frames
+        assert_eq!(bit_clock_divider(49_152_000, 48_000), Some((16, 48_000.0)));
+
+        // Otherwise the nearest, and the rate that comes out
+        let (divider, rate) = bit_clock_divider(50_000_000, 48_000).unwrap();
+        assert_eq!(divider, 16);
+        assert!((rate - 48_828.125).abs() < 0.01, "{rate}");
+    }
+
+    #[test]
+    fn out_of_range_dividers_are_refused() {
+        assert_eq!(bit_clock_divider(200_000_000, 8_000), None);
+        assert_eq!(bit_clock_divider(1_000_000, 48_000), None);
+        assert_eq!(bit_clock_divider(49_152_000, 0), None);
+    }
+}
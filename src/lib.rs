@@ -0,0 +1,49 @@
+//! The parts of the lab that don't touch hardware, split out of the binary
+//! so they can be tested on the host with `cargo test --lib`, and the
+//! firmware every binary shares, which is only built for the board.
+//!
+//! The `host` feature adds std-only tools for the PC end of the frame
+//! protocol, so build with it for the host only.
+#![cfg_attr(not(any(test, feature = "host")), no_std)]
+// With std, the inherent float methods shadow micromath's `F32Ext`
+#![cfg_attr(test, allow(unused_imports))]
+
+pub mod acquisition;
+pub mod can;
+pub mod capture_state;
+pub mod chirp;
+pub mod command;
+pub mod config;
+pub mod config_store;
+pub mod dsp;
+pub mod flight_recorder;
+#[cfg(feature = "host")]
+pub mod host;
+pub mod i2s;
+#[cfg(target_os = "none")]
+pub mod logger;
+pub mod perf_budget;
+pub mod pool;
+pub mod protocol;
+pub mod replay;
+pub mod ring;
+pub mod selfcheck;
+pub mod store;
+pub mod text;
+pub mod timing;
+pub mod tone;
+pub mod trigger;
+pub mod wav;
+
+// The firmware, for the board only, and not with embassy, whose binary has
+// its own PAC and drivers in place of stm32h7xx-hal's. `utilities` comes
+// first, for `dma_buffer!`.
+#[cfg(all(target_os = "none", not(feature = "embassy")))]
+#[macro_use]
+pub mod utilities;
+#[cfg(all(target_os = "none", not(feature = "embassy")))]
+pub mod board;
+#[cfg(all(target_os = "none", not(feature = "embassy")))]
+pub mod report;
+#[cfg(all(target_os = "none", not(feature = "embassy")))]
+pub mod transport;
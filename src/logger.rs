@@ -0,0 +1,132 @@
+//! RTT logging, filtered at runtime: one level for everything, and up to
+//! `MODULE_LEVELS` modules with levels of their own, so e.g. the spectrum
+//! dump can be silenced while status lines carry on. The console's `log`
+//! command changes them, and a change applies from the next record.
+//!
+//! Built with the `no-logging` feature, every log macro compiles to nothing
+//! and none of this is ever called, beyond setting RTT up for panics.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_m::interrupt::Mutex;
+use heapless::Vec;
+use panic_rtt_target as _;
+
+use log::{LevelFilter, Metadata, Record};
+use rtt_target::{rprintln, rtt_init_print};
+
+use crate::command::{LogSetting, ModuleName};
+
+pub struct Logger;
+
+static LOGGER: Logger = Logger;
+
+/// What everything without a level of its own logs at, unless `init_with_level`
+/// says otherwise
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// How many modules can have their own level
+pub const MODULE_LEVELS: usize = 4;
+
+/// The level for everything else, as a `LevelFilter`'s discriminant
+static LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL as usize);
+
+/// Where `log reset` goes back to, the level logging started at
+static INITIAL_LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL as usize);
+
+static MODULES: Mutex<RefCell<Vec<(ModuleName, LevelFilter), MODULE_LEVELS>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Size of the RTT up buffer. Big enough to hold a whole spectrum dump, so
+/// it can be written without waiting for the host to catch up.
+const RTT_BUFFER_BYTES: usize = 8192;
+
+pub fn init() {
+    init_with_level(DEFAULT_LEVEL);
+}
+
+/// `init`, logging only `level` and above, e.g. `Warn` to quieten the
+/// spectrum dump and per-capture lines in a long run. The console can
+/// still make it more verbose.
+pub fn init_with_level(level: LevelFilter) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    INITIAL_LEVEL.store(level as usize, Ordering::Relaxed);
+    rtt_init_print!(NoBlockSkip, RTT_BUFFER_BYTES);
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(level))
+        .unwrap();
+}
+
+/// Change what's logged. Only refused, returning false, if every module
+/// level is taken.
+pub fn apply(setting: LogSetting) -> bool {
+    cortex_m::interrupt::free(|cs| {
+        let mut modules = MODULES.borrow(cs).borrow_mut();
+        match setting {
+            LogSetting::All(level) => LEVEL.store(level as usize, Ordering::Relaxed),
+            LogSetting::Module(module, level) => {
+                match modules.iter_mut().find(|(name, _)| *name == module) {
+                    Some((_, existing)) => *existing = level,
+                    None => {
+                        if modules.push((module, level)).is_err() {
+                            return false;
+                        }
+                    }
+                }
+            }
+            LogSetting::Reset => {
+                LEVEL.store(INITIAL_LEVEL.load(Ordering::Relaxed), Ordering::Relaxed);
+                modules.clear();
+            }
+        }
+
+        // The macros skip anything above this without asking the logger,
+        // so it has to let through the most verbose of the levels
+        let most = modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(level(), LevelFilter::max);
+        log::set_max_level(most);
+        true
+    })
+}
+
+/// The level for everything without a level of its own
+pub fn level() -> LevelFilter {
+    from_index(LEVEL.load(Ordering::Relaxed))
+}
+
+/// A `LevelFilter` back from its discriminant
+fn from_index(index: usize) -> LevelFilter {
+    match index {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let own = cortex_m::interrupt::free(|cs| {
+            let modules = MODULES.borrow(cs).borrow();
+            modules
+                .iter()
+                .find(|(name, _)| name.matches(metadata.target()))
+                .map(|&(_, level)| level)
+        });
+        metadata.level() <= own.unwrap_or_else(level)
+    }
+
+    fn log(&self, record: &Record) {
+        // The macros only check the most verbose level, this checks the
+        // record's own
+        if self.enabled(record.metadata()) {
+            rprintln!("{} - {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,148 @@
+//! Cycle budgets for the steps every capture goes through, for `perf-guard`
+//! runs on a real board to catch a change that made one of them slower.
+//!
+//! A budget is in cycles for `REFERENCE_LEN` samples, and scales with the
+//! length actually built: linearly for the steps that visit each sample or
+//! bin once, and as `n log2 n` for the FFT. Cycles rather than time, so a
+//! profile's budgets only differ by what the clock costs in wait states.
+
+/// A step timed for the budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Codes to floats, with the offset's drift taken off
+    Conversion,
+    /// Windowing the samples and padding them to the FFT's length
+    Window,
+    /// The FFT and its magnitudes
+    Fft,
+    /// The interpolated peak and the peaks above the noise floor
+    Peaks,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Conversion, Stage::Window, Stage::Fft, Stage::Peaks];
+}
+
+/// Samples the budgets are for
+pub const REFERENCE_LEN: usize = 1024;
+
+/// How far over budget a stage can run and still pass, for the jitter
+/// between runs from interrupts and the caches
+pub const TOLERANCE_PERCENT: u32 = 10;
+
+/// A clock profile's budgets, in cycles at `REFERENCE_LEN`, in
+/// `Stage::ALL`'s order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Profile {
+    pub core_mhz: u32,
+    pub cycles: [u32; 4],
+}
+
+/// One for each clock profile. Lower them from a known-good build's log
+/// when a change makes a stage faster on purpose, or the guard won't see
+/// it come back.
+pub const PROFILES: [Profile; 4] = [
+    Profile {
+        core_mhz: 96,
+        cycles: [40_000, 12_000, 120_000, 150_000],
+    },
+    Profile {
+        core_mhz: 200,
+        cycles: [42_000, 12_500, 126_000, 158_000],
+    },
+    Profile {
+        core_mhz: 400,
+        cycles: [44_000, 13_000, 132_000, 165_000],
+    },
+    Profile {
+        core_mhz: 480,
+        cycles: [46_000, 14_000, 138_000, 172_000],
+    },
+];
+
+impl Profile {
+    /// The profile for a core running at `core_hz`, which is what it came
+    /// up at rather than what was asked for, so a 480 MHz build that fell
+    /// back to 400 MHz is held to 400's budgets. `None` for no profile.
+    pub fn for_core_hz(core_hz: u32) -> Option<&'static Profile> {
+        PROFILES.iter().find(|p| p.core_mhz * 1_000_000 == core_hz)
+    }
+
+    /// Most cycles `stage` can take for `samples` samples in an FFT of
+    /// `fft_len`, tolerance included. Panics unless `fft_len` is a power
+    /// of two, as every FFT microfft has is.
+    pub fn budget(&self, stage: Stage, samples: usize, fft_len: usize) -> u32 {
+        assert!(fft_len.is_power_of_two(), "FFT lengths are powers of two");
+        let reference = self.cycles[stage as usize] as u64;
+        let n_log_n = |n: usize| n as u64 * n.trailing_zeros() as u64;
+        let scaled = match stage {
+            Stage::Conversion => reference * samples as u64 / REFERENCE_LEN as u64,
+            // Over the whole padded buffer, the padding's zeroed
+            Stage::Window => reference * fft_len as u64 / REFERENCE_LEN as u64,
+            Stage::Fft => reference * n_log_n(fft_len) / n_log_n(REFERENCE_LEN),
+            // Over the bins, half as many as the FFT's length
+            Stage::Peaks => reference * fft_len as u64 / REFERENCE_LEN as u64,
+        };
+        let budget = scaled * (100 + TOLERANCE_PERCENT) as u64 / 100;
+        budget.min(u32::MAX as u64) as u32
+    }
+
+    /// Whether `cycles` for `stage` is within its budget
+    pub fn within(&self, stage: Stage, cycles: u32, samples: usize, fft_len: usize) -> bool {
+        cycles <= self.budget(stage, samples, fft_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_clock_profile_has_budgets() {
+        for mhz in [96, 200, 400, 480] {
+            let profile = Profile::for_core_hz(mhz * 1_000_000).unwrap();
+            assert_eq!(profile.core_mhz, mhz);
+        }
+        assert_eq!(Profile::for_core_hz(64_000_000), None);
+    }
+
+    #[test]
+    fn budgets_have_the_tolerance_on_top() {
+        let profile = Profile {
+            core_mhz: 96,
+            cycles: [1_000, 2_000, 3_000, 4_000],
+        };
+        let at_reference = |stage| profile.budget(stage, REFERENCE_LEN, REFERENCE_LEN);
+        assert_eq!(at_reference(Stage::Conversion), 1_100);
+        assert_eq!(at_reference(Stage::Fft), 3_300);
+        assert!(profile.within(Stage::Peaks, 4_400, REFERENCE_LEN, REFERENCE_LEN));
+        assert!(!profile.within(Stage::Peaks, 4_401, REFERENCE_LEN, REFERENCE_LEN));
+    }
+
+    #[test]
+    fn budgets_scale_with_the_length() {
+        let profile = Profile {
+            core_mhz: 96,
+            cycles: [1_000, 1_000, 1_000, 1_000],
+        };
+        // Linear for each sample, n log2 n for the FFT: 4096 * 12 is 4.8
+        // times 1024 * 10
+        assert_eq!(profile.budget(Stage::Conversion, 4096, 4096), 4_400);
+        assert_eq!(profile.budget(Stage::Fft, 4096, 4096), 5_280);
+        assert_eq!(profile.budget(Stage::Fft, 256, 256), 220);
+        // Padding lengthens what's windowed and the bins, not the samples
+        assert_eq!(profile.budget(Stage::Conversion, 1024, 4096), 1_100);
+        assert_eq!(profile.budget(Stage::Window, 1024, 4096), 4_400);
+        assert_eq!(profile.budget(Stage::Peaks, 1024, 4096), 4_400);
+    }
+
+    #[test]
+    fn faster_clocks_get_no_fewer_cycles() {
+        for pair in PROFILES.windows(2) {
+            assert!(pair[0].core_mhz < pair[1].core_mhz);
+            for stage in Stage::ALL {
+                assert!(pair[0].cycles[stage as usize] <= pair[1].cycles[stage as usize]);
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! A fixed pool of capture buffers, handed between the DMA interrupt that
+//! fills them and the loop that reads them through a pair of SPSC queues.
+//!
+//! Each buffer has one `Token`, which can't be copied, and only the holder
+//! of a buffer's token may touch it. The interrupt's `Filler` holds the two
+//! DMA is pointed at; finishing one queues its token for the `Reader` and
+//! takes a free one back in its place. If there isn't one, the finished
+//! buffer is filled again and counted as dropped, so a slow reader loses
+//! whole buffers, never reads one DMA is writing.
+
+use heapless::spsc::{Consumer, Producer, Queue};
+
+/// DMA in double-buffer mode always has two buffers
+const ARMED: usize = 2;
+
+/// Ownership of one buffer of the pool
+#[derive(Debug, PartialEq, Eq)]
+pub struct Token(u8);
+
+impl Token {
+    /// Which of the pool's buffers this is
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A filled buffer on its way to the reader
+#[derive(Debug, PartialEq, Eq)]
+pub struct Descriptor {
+    pub token: Token,
+    /// When DMA finished it
+    pub captured_at_us: u64,
+    pub flags: Flags,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// Buffers were dropped between the last one queued and this, so the
+    /// two aren't contiguous
+    pub after_drop: bool,
+}
+
+/// `N` buffers' worth of tokens and the queues between the two sides. The
+/// queues only hand tokens around, the buffers themselves live wherever
+/// DMA can reach.
+pub struct Pool<const N: usize> {
+    filled: Queue<Descriptor, N>,
+    free: Queue<Token, N>,
+}
+
+impl<const N: usize> Pool<N> {
+    pub const fn new() -> Self {
+        assert!(N > ARMED, "a pool needs a buffer to spare besides the two DMA has");
+        assert!(N <= u8::MAX as usize, "tokens index buffers with a u8");
+        Self {
+            filled: Queue::new(),
+            free: Queue::new(),
+        }
+    }
+
+    /// Split into the interrupt's half, holding buffers 0 and 1 for DMA,
+    /// and the reader's, with the rest free. Every token is made here, so
+    /// only split a pool once.
+    pub fn split(&mut self) -> (Filler<'_, N>, Reader<'_, N>) {
+        let (queued, filled) = self.filled.split();
+        let (mut free, spare) = self.free.split();
+        for i in ARMED..N {
+            // Holds N - 1, and there are N - 2 spare
+            let _ = free.enqueue(Token(i as u8));
+        }
+
+        (
+            Filler {
+                armed: [Token(0), Token(1)],
+                filled: queued,
+                free: spare,
+                dropped: 0,
+                dropped_since_queued: false,
+                high_water: 0,
+            },
+            Reader { filled, free },
+        )
+    }
+}
+
+impl<const N: usize> Default for Pool<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The interrupt's half of a `Pool`
+pub struct Filler<'a, const N: usize> {
+    /// The buffers DMA's two memory addresses point at
+    armed: [Token; ARMED],
+    filled: Producer<'a, Descriptor, N>,
+    free: Consumer<'a, Token, N>,
+    dropped: u32,
+    dropped_since_queued: bool,
+    high_water: usize,
+}
+
+impl<const N: usize> Filler<'_, N> {
+    /// The buffer memory address `target` (0 or 1) is pointed at
+    pub fn armed(&self, target: usize) -> usize {
+        self.armed[target].index()
+    }
+
+    /// DMA finished `target`'s buffer at `at_us`: queue it for the reader
+    /// and swap in a free one, or fill it again if there isn't one.
+    /// Returns the buffer to point `target` at now.
+    pub fn complete(&mut self, target: usize, at_us: u64) -> usize {
+        match self.free.dequeue() {
+            Some(fresh) => {
+                let done = core::mem::replace(&mut self.armed[target], fresh);
+                let flags = Flags {
+                    after_drop: core::mem::take(&mut self.dropped_since_queued),
+                };
+                let queued = self.filled.enqueue(Descriptor {
+                    token: done,
+                    captured_at_us: at_us,
+                    flags,
+                });
+                // Every token that isn't armed fits, so this can't be full
+                assert!(queued.is_ok(), "the filled queue lost a token");
+                self.high_water = self.high_water.max(self.filled.len());
+            }
+            None => {
+                self.dropped += 1;
+                self.dropped_since_queued = true;
+            }
+        }
+        self.armed(target)
+    }
+
+    /// Buffers filled again because the reader had none free
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// The most buffers that have been waiting for the reader at once
+    pub fn high_water(&self) -> usize {
+        self.high_water
+    }
+}
+
+/// The reader's half of a `Pool`
+pub struct Reader<'a, const N: usize> {
+    filled: Consumer<'a, Descriptor, N>,
+    free: Producer<'a, Token, N>,
+}
+
+impl<const N: usize> Reader<'_, N> {
+    /// The oldest filled buffer, if any. Its token is the reader's until
+    /// it goes back with `release`.
+    pub fn take(&mut self) -> Option<Descriptor> {
+        self.filled.dequeue()
+    }
+
+    /// How many filled buffers are waiting
+    pub fn waiting(&self) -> usize {
+        self.filled.len()
+    }
+
+    /// Hand a buffer back for DMA to fill
+    pub fn release(&mut self, token: Token) {
+        // As for the filled queue, every token fits
+        let released = self.free.enqueue(token);
+        assert!(released.is_ok(), "the free queue lost a token");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_go_round_in_order() {
+        let mut pool: Pool<4> = Pool::new();
+        let (mut filler, mut reader) = pool.split();
+        assert_eq!((filler.armed(0), filler.armed(1)), (0, 1));
+
+        // Finishing buffer 0 swaps in the first spare
+        assert_eq!(filler.complete(0, 10), 2);
+        assert_eq!(filler.complete(1, 20), 3);
+        assert_eq!(reader.waiting(), 2);
+
+        let first = reader.take().unwrap();
+        assert_eq!((first.token.index(), first.captured_at_us), (0, 10));
+        assert!(!first.flags.after_drop);
+        reader.release(first.token);
+        assert_eq!(filler.complete(0, 30), 0);
+        assert_eq!(filler.high_water(), 2);
+        assert_eq!(filler.dropped(), 0);
+    }
+
+    #[test]
+    fn a_slow_reader_drops_whole_buffers() {
+        let mut pool: Pool<3> = Pool::new();
+        let (mut filler, mut reader) = pool.split();
+
+        assert_eq!(filler.complete(0, 10), 2);
+        // Nothing free, so buffer 1 is filled again rather than queued
+        assert_eq!(filler.complete(1, 20), 1);
+        assert_eq!(filler.complete(1, 30), 1);
+        assert_eq!(filler.dropped(), 2);
+
+        let token = reader.take().unwrap().token;
+        assert!(reader.take().is_none());
+        reader.release(token);
+
+        // The next one says it isn't contiguous with the last
+        assert_eq!(filler.complete(1, 40), 0);
+        let next = reader.take().unwrap();
+        assert_eq!((next.token.index(), next.captured_at_us), (1, 40));
+        assert!(next.flags.after_drop);
+    }
+}
@@ -0,0 +1,129 @@
+//! Consistent Overhead Byte Stuffing, so a zero byte can mark the end of
+//! each frame on a byte stream.
+//!
+//! COBS replaces every zero with the distance to the next one, which costs
+//! at most one byte per 254 plus one. After a lost or corrupt byte, a reader
+//! resynchronizes at the next zero.
+
+/// Largest encoding of `len` bytes, not counting the delimiter
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Encode `input` into `out`, returning the length written, or `None` if
+/// `out` is too small. No delimiter is added.
+pub fn encode(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut code_at = 0;
+    let mut written = 1;
+    let mut code = 1u8;
+
+    for &byte in input {
+        if byte == 0 {
+            *out.get_mut(code_at)? = code;
+            code_at = written;
+            written += 1;
+            code = 1;
+            continue;
+        }
+
+        *out.get_mut(written)? = byte;
+        written += 1;
+        code += 1;
+        if code == 0xFF {
+            *out.get_mut(code_at)? = code;
+            code_at = written;
+            written += 1;
+            code = 1;
+        }
+    }
+
+    *out.get_mut(code_at)? = code;
+    Some(written)
+}
+
+/// Decode one frame, without its delimiter, into `out`. Returns the length
+/// decoded, or `None` if `input` isn't valid COBS or `out` is too small.
+pub fn decode(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut read = 0;
+    let mut written = 0;
+
+    while read < input.len() {
+        let code = input[read] as usize;
+        if code == 0 || read + code > input.len() {
+            return None;
+        }
+        read += 1;
+
+        let run = &input[read..read + code - 1];
+        if run.contains(&0) {
+            return None;
+        }
+        out.get_mut(written..written + run.len())?
+            .copy_from_slice(run);
+        written += run.len();
+        read += run.len();
+
+        // A full run of 254 has no zero after it, nor does the last run
+        if code != 0xFF && read < input.len() {
+            *out.get_mut(written)? = 0;
+            written += 1;
+        }
+    }
+    Some(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![0; max_encoded_len(input.len())];
+        let len = encode(input, &mut encoded).unwrap();
+        encoded.truncate(len);
+        assert!(!encoded.contains(&0), "{encoded:?}");
+
+        let mut decoded = vec![0; input.len()];
+        let len = decode(&encoded, &mut decoded).unwrap();
+        assert_eq!(&decoded[..len], input);
+        encoded
+    }
+
+    #[test]
+    fn known_encodings() {
+        // Examples from the COBS paper and its Wikipedia page
+        assert_eq!(round_trip(&[]), [0x01]);
+        assert_eq!(round_trip(&[0x00]), [0x01, 0x01]);
+        assert_eq!(round_trip(&[0x00, 0x00]), [0x01, 0x01, 0x01]);
+        assert_eq!(
+            round_trip(&[0x11, 0x22, 0x00, 0x33]),
+            [0x03, 0x11, 0x22, 0x02, 0x33]
+        );
+        assert_eq!(
+            round_trip(&[0x11, 0x00, 0x00, 0x00]),
+            [0x02, 0x11, 0x01, 0x01, 0x01]
+        );
+    }
+
+    #[test]
+    fn long_runs_split_at_254() {
+        let input: Vec<u8> = (1..=255).collect();
+        let encoded = round_trip(&input);
+        assert_eq!(encoded.len(), max_encoded_len(input.len()));
+        assert_eq!((encoded[0], encoded[255]), (0xFF, 0x02));
+
+        let mut input = vec![0x42; 254];
+        input.push(0);
+        input.extend([0x42; 600]);
+        round_trip(&input);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut out = [0; 8];
+        assert_eq!(decode(&[0x05, 0x11], &mut out), None);
+        assert_eq!(decode(&[0x03, 0x00, 0x11], &mut out), None);
+        assert_eq!(decode(&[0x00], &mut out), None);
+        assert_eq!(encode(&[1, 2, 3], &mut [0; 3]), None);
+        assert_eq!(decode(&[0x04, 1, 2, 3], &mut [0; 2]), None);
+    }
+}
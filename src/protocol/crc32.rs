@@ -0,0 +1,67 @@
+//! The CRC-32 zlib, Ethernet and PNG use, in software.
+//!
+//! Reflected polynomial 0xEDB88320, initial value and final XOR all ones,
+//! so `crc32(b"123456789") == 0xCBF4_3926`. The firmware computes the same
+//! thing with the CRC peripheral, and this is what the host checks it with.
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// A CRC over data that arrives in pieces
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.state & 1).wrapping_neg();
+                self.state = (self.state >> 1) ^ (POLYNOMIAL & mask);
+            }
+        }
+    }
+
+    pub const fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xE8B7_BE43);
+    }
+
+    #[test]
+    fn pieces_match_the_whole() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut crc = Crc32::new();
+        for chunk in data.chunks(7) {
+            crc.update(chunk);
+        }
+        assert_eq!(crc.finish(), crc32(&data));
+    }
+}
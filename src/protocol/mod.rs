@@ -0,0 +1,316 @@
+//! The binary frame format captures are sent off the board in.
+//!
+//! Each frame is a `Frame` serialized with postcard, behind a version byte
+//! and followed by a little-endian CRC-32 of both, then COBS encoded with a
+//! zero on either side:
+//!
+//! ```text
+//! 0x00 | cobs(VERSION | postcard(Frame) | crc32 LE) | 0x00
+//! ```
+//!
+//! The leading zero means any text a transport sent before the frame ends
+//! up as a frame of its own, which fails its CRC, instead of spoiling this
+//! one. A capture goes out as its `CaptureHeader`, then its data in chunks
+//! of at most `CHUNK` values, so neither end needs a frame buffer the size of
+//! the whole capture.
+//!
+//! The frame types are generic over how the data is held, so the same types
+//! serialize borrowed slices on the board and deserialize into `Vec`s in the
+//! `host` module.
+
+pub mod cobs;
+pub mod crc32;
+
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever a frame type changes shape. Frames of another version
+/// are refused rather than misread.
+pub const VERSION: u8 = 2;
+
+/// Most values in one spectrum, bands or raw frame
+pub const CHUNK: usize = 256;
+
+/// Room to serialize the version, the largest frame and its CRC in. A
+/// chunk of `u16`s takes up to 3 bytes each as postcard varints, `f32`s
+/// always 4, and the rest of a data frame is at most 11.
+pub const MAX_PAYLOAD_LEN: usize = 1 + 16 + CHUNK * 4 + 4;
+
+/// Longest encoded frame, with its delimiters
+pub const MAX_FRAME_LEN: usize = cobs::max_encoded_len(MAX_PAYLOAD_LEN) + 2;
+
+/// Sent before a capture's data, describing it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaptureHeader {
+    /// Counts up with each capture, tying its data frames to it
+    pub sequence: u32,
+    /// Monotonic time the capture finished
+    pub timestamp_us: u64,
+    pub sample_rate_hz: f32,
+    pub resolution_bits: u8,
+    pub full_scale_volts: f32,
+    /// False if the capture overran, so its data is suspect
+    pub trusted: bool,
+    /// FFT magnitudes that follow in `SpectrumFrame`s, 0 for none
+    pub bins: u16,
+    /// Samples that follow in `RawFrame`s, 0 for none
+    pub samples: u16,
+    /// Band energies that follow in `BandsFrame`s, 0 for none
+    pub bands: u16,
+}
+
+/// Part of a capture's magnitude spectrum, in 16-bit counts
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpectrumFrame<M> {
+    pub sequence: u32,
+    /// Bin of the first magnitude
+    pub first_bin: u16,
+    pub magnitudes: M,
+}
+
+/// Part of a capture's log mel band energies, from `dsp::melbank`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BandsFrame<M> {
+    pub sequence: u32,
+    /// Index of the first band
+    pub first_band: u16,
+    pub energies: M,
+}
+
+/// Part of a capture's raw samples, in ADC counts
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RawFrame<S> {
+    pub sequence: u32,
+    /// Index of the first sample
+    pub first_sample: u16,
+    pub samples: S,
+}
+
+/// Everything that can be sent, generic over the storage for `f32`s, the
+/// magnitudes and band energies, and for samples: `&[f32]` and `&[u16]` to send, `Vec`s to receive
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Frame<M, S> {
+    Header(CaptureHeader),
+    Spectrum(SpectrumFrame<M>),
+    Raw(RawFrame<S>),
+    Bands(BandsFrame<M>),
+}
+
+/// The frame type the board sends
+pub type BorrowedFrame<'a> = Frame<&'a [f32], &'a [u16]>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// A buffer is too small for the frame
+    TooLong,
+    /// Not valid COBS, or too short to hold a version and CRC
+    Malformed,
+    /// Sent by firmware with a different `VERSION`
+    Version(u8),
+    /// The CRC doesn't match, so the frame was corrupted
+    Crc { expected: u32, actual: u32 },
+    /// The CRC matched but postcard couldn't make sense of the frame
+    Deserialize,
+}
+
+/// Encode `frame` into `out`, delimiters included, using `scratch` to
+/// serialize into. `crc` is the CRC-32 of this module's `crc32`, from
+/// whichever implementation is at hand. Returns the bytes to send.
+pub fn encode<'o, T: Serialize>(
+    frame: &T,
+    crc: impl FnOnce(&[u8]) -> u32,
+    scratch: &mut [u8],
+    out: &'o mut [u8],
+) -> Result<&'o [u8], FrameError> {
+    let (version, rest) = scratch.split_first_mut().ok_or(FrameError::TooLong)?;
+    *version = VERSION;
+    let len = 1 + postcard::to_slice(frame, rest)
+        .map_err(|_| FrameError::TooLong)?
+        .len();
+
+    let checksum = crc(&scratch[..len]).to_le_bytes();
+    scratch
+        .get_mut(len..len + 4)
+        .ok_or(FrameError::TooLong)?
+        .copy_from_slice(&checksum);
+
+    let (start, body) = out.split_first_mut().ok_or(FrameError::TooLong)?;
+    *start = 0;
+    let encoded = cobs::encode(&scratch[..len + 4], body).ok_or(FrameError::TooLong)?;
+    *out.get_mut(1 + encoded).ok_or(FrameError::TooLong)? = 0;
+    Ok(&out[..encoded + 2])
+}
+
+/// Decode one frame's bytes, without the zeros around them, using `buf` to
+/// undo the COBS into. `crc` must match the one the frame was encoded with.
+pub fn decode<'b, T: Deserialize<'b>>(
+    encoded: &[u8],
+    crc: impl FnOnce(&[u8]) -> u32,
+    buf: &'b mut [u8],
+) -> Result<T, FrameError> {
+    let len = cobs::decode(encoded, buf).ok_or(FrameError::Malformed)?;
+    if len < 5 {
+        return Err(FrameError::Malformed);
+    }
+
+    let (body, checksum) = buf[..len].split_at(len - 4);
+    let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+    let actual = crc(body);
+    if actual != expected {
+        return Err(FrameError::Crc { expected, actual });
+    }
+    if body[0] != VERSION {
+        return Err(FrameError::Version(body[0]));
+    }
+
+    postcard::from_bytes(&body[1..]).map_err(|_| FrameError::Deserialize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crc32::crc32;
+
+    type OwnedFrame = Frame<Vec<f32>, Vec<u16>>;
+
+    const HEADER: CaptureHeader = CaptureHeader {
+        sequence: 7,
+        timestamp_us: 1_234_567_890,
+        sample_rate_hz: 149_104.0,
+        resolution_bits: 16,
+        full_scale_volts: 3.3,
+        trusted: true,
+        bins: 512,
+        samples: 0,
+        bands: 0,
+    };
+
+    /// Encode a borrowed frame and decode it as the host would
+    fn round_trip(frame: &BorrowedFrame) -> Result<OwnedFrame, FrameError> {
+        let mut scratch = [0; MAX_PAYLOAD_LEN];
+        let mut out = [0; MAX_FRAME_LEN];
+        let sent = encode(frame, crc32, &mut scratch, &mut out)?;
+
+        assert_eq!((sent[0], sent[sent.len() - 1]), (0, 0));
+        let body = &sent[1..sent.len() - 1];
+        assert!(!body.contains(&0));
+
+        let mut buf = [0; MAX_PAYLOAD_LEN];
+        decode(body, crc32, &mut buf)
+    }
+
+    #[test]
+    fn header_round_trips() {
+        assert_eq!(
+            round_trip(&Frame::Header(HEADER)),
+            Ok(Frame::Header(HEADER))
+        );
+    }
+
+    #[test]
+    fn full_chunks_round_trip() {
+        let magnitudes: Vec<f32> = (0..CHUNK).map(|i| i as f32 * 0.5 - 3.0).collect();
+        let frame = Frame::Spectrum(SpectrumFrame {
+            sequence: 7,
+            first_bin: 256,
+            magnitudes: &magnitudes[..],
+        });
+        assert_eq!(
+            round_trip(&frame),
+            Ok(Frame::Spectrum(SpectrumFrame {
+                sequence: 7,
+                first_bin: 256,
+                magnitudes: magnitudes.clone(),
+            }))
+        );
+
+        // The largest varints, so the biggest raw frame there can be
+        let samples = vec![u16::MAX; CHUNK];
+        let frame = Frame::Raw(RawFrame {
+            sequence: u32::MAX,
+            first_sample: 768,
+            samples: &samples[..],
+        });
+        assert_eq!(
+            round_trip(&frame),
+            Ok(Frame::Raw(RawFrame {
+                sequence: u32::MAX,
+                first_sample: 768,
+                samples,
+            }))
+        );
+
+        let energies = [-23.0, 0.5, 12.25];
+        let frame = Frame::Bands(BandsFrame {
+            sequence: 8,
+            first_band: 0,
+            energies: &energies[..],
+        });
+        assert_eq!(
+            round_trip(&frame),
+            Ok(Frame::Bands(BandsFrame {
+                sequence: 8,
+                first_band: 0,
+                energies: energies.to_vec(),
+            }))
+        );
+    }
+
+    #[test]
+    fn corruption_fails_the_crc() {
+        let mut scratch = [0; MAX_PAYLOAD_LEN];
+        let mut out = [0; MAX_FRAME_LEN];
+        let len = encode(
+            &BorrowedFrame::Header(HEADER),
+            crc32,
+            &mut scratch,
+            &mut out,
+        )
+        .unwrap()
+        .len();
+
+        // Flip a bit in the middle, keeping it non-zero so COBS still decodes
+        let body = &mut out[1..len - 1];
+        let middle = body.len() / 2;
+        body[middle] ^= if body[middle] == 1 { 2 } else { 1 };
+
+        let mut buf = [0; MAX_PAYLOAD_LEN];
+        let result: Result<OwnedFrame, _> = decode(body, crc32, &mut buf);
+        assert!(matches!(result, Err(FrameError::Crc { .. })), "{result:?}");
+    }
+
+    #[test]
+    fn other_versions_and_garbage_are_refused() {
+        let mut serialized = [0; MAX_PAYLOAD_LEN];
+        let frame = BorrowedFrame::Header(HEADER);
+        let mut framed = vec![VERSION + 1];
+        framed.extend_from_slice(postcard::to_slice(&frame, &mut serialized).unwrap());
+        framed.extend(crc32(&framed).to_le_bytes());
+        let mut encoded = vec![0; cobs::max_encoded_len(framed.len())];
+        let len = cobs::encode(&framed, &mut encoded).unwrap();
+
+        let mut buf = [0; MAX_PAYLOAD_LEN];
+        let result: Result<OwnedFrame, _> = decode(&encoded[..len], crc32, &mut buf);
+        assert_eq!(result, Err(FrameError::Version(VERSION + 1)));
+
+        let result: Result<OwnedFrame, _> = decode(b"ok\r\n", crc32, &mut buf);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn small_buffers_are_an_error() {
+        let mut scratch = [0; 8];
+        let mut out = [0; MAX_FRAME_LEN];
+        let frame = BorrowedFrame::Header(HEADER);
+        assert_eq!(
+            encode(&frame, crc32, &mut scratch, &mut out),
+            Err(FrameError::TooLong)
+        );
+
+        let mut scratch = [0; MAX_PAYLOAD_LEN];
+        let mut out = [0; 8];
+        assert_eq!(
+            encode(&frame, crc32, &mut scratch, &mut out),
+            Err(FrameError::TooLong)
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! A `SampleSource` that plays back a recorded capture from flash instead
+//! of the ADC, so pipeline changes can be checked against a known capture
+//! on the board, through the same `dsp::pipeline::analyze_capture` as the
+//! live data.
+//!
+//! Record raw codes as little-endian `u16`s, e.g. from a `mode raw` log or
+//! `output frames`, and embed them with `include_samples!`:
+//!
+//! ```ignore
+//! static GOLDEN: &[u16] = lab_3::include_samples!("../../captures/golden.bin");
+//! let mut source = FlashReplaySource::new(GOLDEN, Playback::Looping);
+//! ```
+//!
+//! A `const` array of codes works as well, and doesn't need the file, but
+//! the macro keeps the data in its own `.rodata.replay` section, which
+//! `cortex-m-rt` links into flash with the rest of `.rodata` and the map
+//! file lists on its own.
+
+use crate::acquisition::{AcqError, SampleSource};
+
+/// What happens once the recording runs out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Playback {
+    /// Go back to the start, so a fill can span the end and the beginning
+    Looping,
+    /// Stop at the end: the fill that reaches it is partial, and the rest
+    /// get nothing
+    OneShot,
+}
+
+/// Fills each buffer with the next samples of `data`
+#[derive(Clone, Copy, Debug)]
+pub struct FlashReplaySource {
+    data: &'static [u16],
+    playback: Playback,
+    position: usize,
+}
+
+impl FlashReplaySource {
+    pub const fn new(data: &'static [u16], playback: Playback) -> Self {
+        Self {
+            data,
+            playback,
+            position: 0,
+        }
+    }
+
+    /// Play from the start again
+    pub fn rewind(&mut self) {
+        self.position = 0;
+    }
+
+    /// Whether a one-shot recording has been played to the end. A looping
+    /// one never finishes.
+    pub fn finished(&self) -> bool {
+        self.playback == Playback::OneShot && self.position == self.data.len()
+    }
+}
+
+impl SampleSource for FlashReplaySource {
+    /// Copy out the next `buf.len()` samples. Running out of a one-shot
+    /// recording is a timeout with the samples there were, like a capture
+    /// that stopped early; an empty recording never has any.
+    fn fill(&mut self, buf: &mut [u16]) -> Result<(), AcqError> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.position == self.data.len() {
+                if self.playback == Playback::OneShot || self.data.is_empty() {
+                    return Err(AcqError::Timeout { received: filled });
+                }
+                self.position = 0;
+            }
+
+            let n = (buf.len() - filled).min(self.data.len() - self.position);
+            buf[filled..filled + n].copy_from_slice(&self.data[self.position..self.position + n]);
+            filled += n;
+            self.position += n;
+        }
+        Ok(())
+    }
+}
+
+/// Embed a file of little-endian `u16` codes as a `&'static [u16]` in the
+/// `.rodata.replay` section, for `FlashReplaySource`. A trailing odd byte
+/// is left out.
+#[macro_export]
+macro_rules! include_samples {
+    ($path:literal) => {{
+        // `include_bytes!` is only byte aligned, which `u16`s can't be read
+        // from
+        #[repr(C, align(2))]
+        struct Aligned<T: ?Sized>(T);
+
+        #[link_section = ".rodata.replay"]
+        static BYTES: Aligned<[u8; include_bytes!($path).len()]> = Aligned(*include_bytes!($path));
+
+        // Safety: aligned for `u16` by the wrapper, any two bytes are a
+        // valid `u16`, and the target is little-endian like the recording
+        unsafe { core::slice::from_raw_parts(BYTES.0.as_ptr().cast::<u16>(), BYTES.0.len() / 2) }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static RECORDING: [u16; 5] = [10, 11, 12, 13, 14];
+
+    #[test]
+    fn looping_wraps_round() {
+        let mut source = FlashReplaySource::new(&RECORDING, Playback::Looping);
+        let mut buf = [0; 3];
+        assert_eq!(source.fill(&mut buf), Ok(()));
+        assert_eq!(buf, [10, 11, 12]);
+        assert_eq!(source.fill(&mut buf), Ok(()));
+        assert_eq!(buf, [13, 14, 10]);
+
+        // Longer than the recording goes round more than once
+        let mut long = [0; 12];
+        assert_eq!(source.fill(&mut long), Ok(()));
+        assert_eq!(long, [11, 12, 13, 14, 10, 11, 12, 13, 14, 10, 11, 12]);
+        assert!(!source.finished());
+    }
+
+    #[test]
+    fn one_shot_runs_out() {
+        let mut source = FlashReplaySource::new(&RECORDING, Playback::OneShot);
+        let mut buf = [0; 3];
+        assert_eq!(source.fill(&mut buf), Ok(()));
+        assert_eq!(
+            source.fill(&mut buf),
+            Err(AcqError::Timeout { received: 2 })
+        );
+        assert_eq!(buf[..2], [13, 14]);
+        assert!(source.finished());
+        assert_eq!(
+            source.fill(&mut buf),
+            Err(AcqError::Timeout { received: 0 })
+        );
+
+        source.rewind();
+        assert_eq!(source.fill(&mut buf), Ok(()));
+        assert_eq!(buf, [10, 11, 12]);
+
+        // Nothing to loop round
+        let mut empty = FlashReplaySource::new(&[], Playback::Looping);
+        assert_eq!(empty.fill(&mut buf), Err(AcqError::Timeout { received: 0 }));
+        assert_eq!(empty.fill(&mut []), Ok(()));
+    }
+}
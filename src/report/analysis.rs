@@ -0,0 +1,463 @@
+//! Checking, converting and transforming a finished capture, and handing
+//! what comes out to a sink.
+
+#[cfg(feature = "perf-guard")]
+use core::sync::atomic::{AtomicU32, Ordering};
+use log::{debug, error, info, warn};
+use micromath::F32Ext;
+
+use cortex_m::peripheral::{DWT, SCB};
+
+use crate::board::{CaptureInfo, ADC_VREF, SIZE};
+use crate::config::{Config, Output, OutputMode};
+use crate::dsp::autocorr_pitch;
+use crate::dsp::fft::bin_width_hz;
+use crate::dsp::melbank::{self, MelBank};
+use crate::dsp::pipeline;
+use crate::dsp::scaling::{correct_offset, AdcScale};
+use crate::dsp::spectrum::{band_power, find_peaks, noise_floor, PEAK_THRESHOLD_FACTOR};
+use crate::flight_recorder::{self, FRAME_BANDS};
+use crate::perf_budget::Stage;
+use crate::timing;
+use crate::transport::sink::Sink;
+use crate::trigger::LevelTrigger;
+use crate::utilities;
+
+use super::logging::log_histogram;
+use super::modes::{log_dither, log_envelope, log_notch};
+use super::sinks::deliver;
+use super::{normalize_slice, FFT_LEN, WINDOW, ZERO_PAD_FACTOR};
+
+/// Copy the capture into DTCM with MDMA, overlapping it with other work,
+/// rather than converting straight out of the DMA buffer
+const USE_MDMA_COPY: bool = true;
+
+/// Log a histogram of each capture's raw codes, a bin per code over the
+/// range the capture covers (or `HISTOGRAM_BINS` bins if it's wider), for
+/// spotting missing codes and stuck bits
+const LOG_HISTOGRAM: bool = false;
+
+/// The range `autocorr_pitch` searches for a fundamental in. Its cost goes
+/// with how many lags that is, so with the sample rate over `PITCH_MIN_HZ`.
+const PITCH_MIN_HZ: f32 = 50.0;
+const PITCH_MAX_HZ: f32 = 2_000.0;
+
+/// With `mode mel`, the bands a keyword spotter takes, up to `MEL_HIGH_HZ`
+/// or Nyquist if that's lower. At the ADC's full rate the bottom bands are
+/// narrower than a bin, and can come out empty, so retune to 16 kS/s or so.
+const MEL_BANDS: usize = 32;
+const MEL_LOW_HZ: f32 = 20.0;
+const MEL_HIGH_HZ: f32 = 8_000.0;
+
+/// Most peaks above the noise floor counted for the log
+const MAX_LOGGED_PEAKS: usize = 32;
+
+/// With `buzzer`, the range to play peaks in, and how far above the noise
+/// one has to be to play at all
+#[cfg(feature = "buzzer")]
+const BUZZER_MIN_HZ: f32 = 100.0;
+#[cfg(feature = "buzzer")]
+const BUZZER_MAX_HZ: f32 = 5_000.0;
+#[cfg(feature = "buzzer")]
+const BUZZER_THRESHOLD_DBFS: f32 = -50.0;
+
+/// With `perf-guard`, the cycles each `Stage` took on the last capture, in
+/// `Stage::ALL`'s order, for `ci_check` to hold to their budgets
+#[cfg(feature = "perf-guard")]
+pub(super) static STAGE_CYCLES: [AtomicU32; 4] = [const { AtomicU32::new(0) }; 4];
+
+/// Keep `cycles` for `stage`, with `perf-guard`
+fn record_cycles(_stage: Stage, _cycles: u32) {
+    #[cfg(feature = "perf-guard")]
+    STAGE_CYCLES[_stage as usize].store(_cycles, Ordering::Relaxed);
+}
+
+/// Check, convert and transform one finished capture, and write it to
+/// `sink`, as `config.mode` asks. Returns the capture's mean in raw counts,
+/// if any samples arrived and it met the trigger.
+pub fn process_capture(
+    target_buffer: &mut [u16],
+    capture: &CaptureInfo,
+    config: &Config,
+    sink: &mut dyn Sink,
+    #[cfg(feature = "can")] can: Option<&mut utilities::can::CanBus>,
+    packed: bool,
+    scale: &AdcScale,
+    scb: &mut SCB,
+    fft_buffer: &mut [f32; FFT_LEN],
+) -> Option<f32> {
+    let CaptureInfo {
+        valid,
+        trusted,
+        captured_at_us,
+        sample_rate_hz,
+        buffer_crc,
+        die_degc,
+        offset_correction,
+        ..
+    } = *capture;
+
+    // Nothing should write the buffer once DMA stops, so a change here means
+    // the DMA or cache isn't doing what we think
+    let crc = utilities::crc::crc32_samples(&target_buffer[..valid]);
+    if crc != buffer_crc {
+        error!(
+            "CAPTURE BUFFER CHANGED after DMA: CRC {:#010x} at completion, {:#010x} now",
+            buffer_crc, crc
+        );
+    }
+
+    match utilities::rtc::now() {
+        Some(time) => info!(
+            "Capture at {} ({} us), {} samples",
+            time, captured_at_us, valid
+        ),
+        None => info!("Capture at {} us, {} samples", captured_at_us, valid),
+    }
+
+    if packed {
+        utilities::dma::unpack_8bit(target_buffer, valid);
+    }
+
+    // Optionally have MDMA move the raw samples into DTCM while the CPU gets
+    // on with the checks below, then convert from there
+    static mut RAW_COPY: [u16; SIZE] = [0; SIZE];
+    let raw_copy = unsafe { &mut *core::ptr::addr_of_mut!(RAW_COPY) };
+    let convert_start = DWT::cycle_count();
+    // Safety: the copy is always waited on below, never leaked
+    let copy = USE_MDMA_COPY.then(|| unsafe {
+        utilities::mdma::copy_u16(&target_buffer[..valid], &mut raw_copy[..valid], scb)
+    });
+
+    let clipped = target_buffer[..valid]
+        .iter()
+        .filter(|&&c| scale.is_clipped(c))
+        .count();
+    if clipped > 0 {
+        warn!("{} samples clipped at the ADC rails", clipped);
+    }
+    utilities::flight_recorder::update(|record| {
+        record.capture(captured_at_us, sample_rate_hz, clipped as u32);
+        if crc != buffer_crc {
+            record.errors |= flight_recorder::ERROR_BUFFER_CHANGED;
+        }
+        if !trusted {
+            record.errors |= flight_recorder::ERROR_OVERRUN;
+        }
+        if valid == 0 {
+            record.errors |= flight_recorder::ERROR_EMPTY;
+        }
+    });
+    if LOG_HISTOGRAM && valid > 0 {
+        log_histogram(&target_buffer[..valid]);
+    }
+
+    // Calculate the average of the buffer, into a u32 so we don't overflow
+    let sum = target_buffer.iter().fold(0u32, |acc, f| acc + *f as u32);
+
+    // Fall back to the DMA buffer if the copy failed
+    let copied = copy.is_some_and(|copy| copy.wait());
+    let raw: &[u16] = if copied {
+        &raw_copy[..valid]
+    } else {
+        &target_buffer[..valid]
+    };
+
+    if let Some(degc) = die_degc {
+        info!(
+            "Offset correction: {} counts at {} C",
+            offset_correction, degc
+        );
+    }
+
+    // Extract these u32 into floats so we can take the fft, taking off the
+    // offset's drift on the way
+    let mut samples: [f32; SIZE] = [0.0; SIZE];
+    for (i, value) in raw.iter().enumerate() {
+        // Two paths - either copy and convet normally or set some to zero, depending on the part of the lab
+        samples[i] = correct_offset(*value, offset_correction, scale.max_count()) as f32;
+
+        // if i < SIZE - (124) {
+        //     samples[i] = *value as f32;
+        // } else {
+        //     samples[i] = 0.0;
+        // }
+    }
+
+    let convert_cycles = DWT::cycle_count().wrapping_sub(convert_start);
+    record_cycles(Stage::Conversion, convert_cycles);
+    info!(
+        "Sample conversion took {} cycles ({})",
+        convert_cycles,
+        if USE_MDMA_COPY { "MDMA" } else { "CPU" }
+    );
+
+    // Optionally, pull out the first 256 samples (for the lab)
+    // let mut buffer: [f32; 256] = samples[0..256].try_into().unwrap();
+
+    if valid == 0 {
+        error!("No samples arrived, skipping the FFT");
+        return None;
+    }
+
+    // Like a scope in normal mode, a capture that never crosses the trigger
+    // level is dropped
+    let triggered_at = match config.trigger {
+        Some(trigger) => {
+            let in_counts = LevelTrigger::new(scale.volts_to_counts(trigger.level), trigger.edge);
+            let Some(at) = in_counts.find(samples[..valid].iter().copied()) else {
+                info!("Not triggered at {} V {:?}", trigger.level, trigger.edge);
+                return None;
+            };
+            at
+        }
+        None => 0,
+    };
+
+    let time_domain = matches!(config.mode, OutputMode::Raw | OutputMode::Waveform);
+    if time_domain {
+        deliver(sink, capture, scale, &[], &raw[triggered_at..], &[]);
+    }
+
+    // Normalize the samples to remove dc offset. For a partial buffer only the
+    // samples that arrived count, and the rest stays as deliberate zero padding.
+    let normalize_start = DWT::cycle_count();
+    let mean = normalize_slice(&mut samples[..valid]);
+    debug!(
+        "Normalizing {} samples took {} cycles",
+        valid,
+        DWT::cycle_count().wrapping_sub(normalize_start)
+    );
+
+    info!("Average: {} ({} V)", mean, scale.counts_to_volts(mean));
+
+    // Raw and waveform modes skip the FFT, the samples are the output
+    if !time_domain {
+        analyze(
+            &samples,
+            raw,
+            capture,
+            config,
+            sink,
+            #[cfg(feature = "can")]
+            can,
+            scale,
+            fft_buffer,
+        );
+    }
+
+    Some(mean)
+}
+
+/// Transform a capture whose mean has been taken off, and write it to
+/// `sink` along with its `raw` codes, as `config.mode` asks. Shared by every
+/// source, ADC or not, with no codes from those that have none. The samples
+/// are windowed and padded into `fft_buffer`, which the FFT works in, so it
+/// should be a `dma_buffer!` rather than on the stack.
+pub fn analyze(
+    samples: &[f32; SIZE],
+    raw: &[u16],
+    capture: &CaptureInfo,
+    config: &Config,
+    sink: &mut dyn Sink,
+    #[cfg(feature = "can")] can: Option<&mut utilities::can::CanBus>,
+    scale: &AdcScale,
+    fft_buffer: &mut [f32; FFT_LEN],
+) {
+    let CaptureInfo {
+        trusted,
+        sample_rate_hz,
+        ..
+    } = *capture;
+
+    // Filtering's to show the spectrum's round trip, so only comes with it
+    if let (Some(band), OutputMode::Spectrum) = (config.notch, config.mode) {
+        log_notch(samples, band, sample_rate_hz, fft_buffer);
+    }
+
+    // Get the FFT using microfft, timed so clock profiles can be compared.
+    // Magnitudes are in 16-bit counts whatever the resolution, and the bins
+    // are `FFT_LEN` wide, which `pipeline::peak` goes by.
+    let window_start = utilities::clocks::now_cycles();
+    pipeline::zero_pad(samples, WINDOW, fft_buffer);
+    record_cycles(
+        Stage::Window,
+        timing::elapsed_cycles(window_start, utilities::clocks::now_cycles()),
+    );
+    let mut magnitudes = [0.0; FFT_LEN / 2];
+    let fft_start = utilities::clocks::now_cycles();
+    pipeline::magnitudes(fft_buffer, scale, &mut magnitudes);
+    let fft_cycles = timing::elapsed_cycles(fft_start, utilities::clocks::now_cycles());
+    record_cycles(Stage::Fft, fft_cycles);
+    info!(
+        "FFT took {} cycles, {} us at {} MHz",
+        fft_cycles,
+        timing::cycles_to_us(fft_cycles, utilities::clocks::core_hz()),
+        utilities::clocks::core_hz() / 1_000_000
+    );
+
+    // Interpolating between bins gets a tone's frequency well inside a bin.
+    // The peaks above the noise floor are found along with it, and both
+    // are logged after, so the timing's only theirs.
+    let peaks_start = utilities::clocks::now_cycles();
+    let peak = pipeline::peak(&magnitudes, sample_rate_hz);
+    // The median's picked out of a copy, too big for the stack
+    static mut FLOOR_SCRATCH: [f32; FFT_LEN / 2] = [0.0; FFT_LEN / 2];
+    // Safety: only ever used here, and `analyze` never runs twice at once
+    let scratch = unsafe { &mut *core::ptr::addr_of_mut!(FLOOR_SCRATCH) };
+    let floor = noise_floor(&magnitudes, scratch);
+    let mut peaks = [0; MAX_LOGGED_PEAKS];
+    let count = find_peaks(&magnitudes, PEAK_THRESHOLD_FACTOR * floor, &mut peaks);
+    record_cycles(
+        Stage::Peaks,
+        timing::elapsed_cycles(peaks_start, utilities::clocks::now_cycles()),
+    );
+    let peak = peak.map(|peak| {
+        info!(
+            "Peak at bin {} = {} Hz, at {} S/s",
+            peak.bin, peak.hz, sample_rate_hz
+        );
+        (peak.hz, peak.magnitude)
+    });
+
+    // To compare with the peak, which a harmonic stronger than the
+    // fundamental takes for itself. Timed, as it's the slowest step here.
+    let pitch_start = DWT::cycle_count();
+    let pitch = autocorr_pitch(&samples[..], sample_rate_hz, PITCH_MIN_HZ, PITCH_MAX_HZ);
+    let pitch_cycles = DWT::cycle_count().wrapping_sub(pitch_start);
+    match pitch {
+        Some(hz) => info!(
+            "Autocorrelation pitch {} Hz, in {} cycles",
+            hz, pitch_cycles
+        ),
+        None => info!("No autocorrelation pitch, in {} cycles", pitch_cycles),
+    }
+
+    #[cfg(feature = "can")]
+    if let Some(can) = can {
+        broadcast(can, config, trusted, peak, &magnitudes, sample_rate_hz);
+    }
+
+    // Whatever the mode, so the record has the spectra leading up to a hang
+    let mut levels = [f32::NEG_INFINITY; FRAME_BANDS];
+    let bands = band_levels_dbfs(&magnitudes, sample_rate_hz, &mut levels);
+    let full_scale = AdcScale::new(16, ADC_VREF);
+    utilities::flight_recorder::update(|record| {
+        let peak = peak.map(|(hz, magnitude)| (hz, full_scale.magnitude_to_dbfs(magnitude, SIZE)));
+        record.spectrum(peak, &levels[..bands]);
+    });
+
+    // What the peak stands out of, and whatever else does
+    info!(
+        "Noise floor {} dBFS, {} peaks {}x above it",
+        full_scale.magnitude_to_dbfs(floor, SIZE),
+        count,
+        PEAK_THRESHOLD_FACTOR
+    );
+
+    // Play the peak, unless it's down in the noise. Padding doesn't change
+    // a tone's magnitude, so it's against the reference for `SIZE` samples.
+    #[cfg(feature = "buzzer")]
+    utilities::buzzer::play(peak.and_then(|(hz, magnitude)| {
+        let level = AdcScale::new(16, ADC_VREF).magnitude_to_dbfs(magnitude, SIZE);
+        (level > BUZZER_THRESHOLD_DBFS).then(|| hz.clamp(BUZZER_MIN_HZ, BUZZER_MAX_HZ))
+    }));
+
+    // The envelope and the dither comparison need the samples and the FFT
+    // buffer, so are logged here rather than by a sink
+    match config.mode {
+        OutputMode::Envelope | OutputMode::Dither if !config.output.contains(Output::Log) => {}
+        OutputMode::Envelope | OutputMode::Dither if !trusted => {
+            warn!("Spectrum not logged, the capture overran");
+        }
+        OutputMode::Envelope => log_envelope(samples, sample_rate_hz, scale, fft_buffer),
+        OutputMode::Dither => log_dither(samples, scale, fft_buffer),
+        _ => {}
+    }
+
+    let mel;
+    let bands: &[f32] = if config.mode == OutputMode::Mel {
+        mel = mel_bands(&magnitudes, sample_rate_hz);
+        &mel
+    } else {
+        &[]
+    };
+    deliver(sink, capture, scale, &magnitudes, raw, bands);
+}
+
+/// The log mel band energies of a spectrum `FFT_LEN` long. The bank's
+/// made again for each capture, as the rate can change, which costs a
+/// `powf` a band.
+fn mel_bands(magnitudes: &[f32], sample_rate_hz: f32) -> [f32; MEL_BANDS] {
+    let high_hz = MEL_HIGH_HZ.min(sample_rate_hz / 2.0);
+    let bank: MelBank<MEL_BANDS> = MelBank::new(sample_rate_hz, FFT_LEN, MEL_LOW_HZ, high_hz);
+    let mut power = [0.0; FFT_LEN / 2];
+    for (power, magnitude) in power.iter_mut().zip(magnitudes) {
+        *power = magnitude * magnitude;
+    }
+    let mut energies = bank.apply(&power);
+    melbank::log_compress(&mut energies);
+    energies
+}
+
+/// `band_power` of a spectrum `FFT_LEN` long. Padding puts
+/// `ZERO_PAD_FACTOR` bins where there was one, each about as strong, so
+/// that's divided back out to keep levels the same whatever the padding.
+pub(super) fn padded_band_power(
+    magnitudes: &[f32],
+    low_hz: f32,
+    high_hz: f32,
+    sample_rate_hz: f32,
+) -> f32 {
+    band_power(magnitudes, low_hz, high_hz, sample_rate_hz, FFT_LEN) / ZERO_PAD_FACTOR as f32
+}
+
+/// Octave bands from one bin width up to Nyquist, as `(low, high)` Hz
+pub(super) fn octave_bands(sample_rate_hz: f32) -> impl Iterator<Item = (f32, f32)> {
+    let nyquist = sample_rate_hz / 2.0;
+    let first = bin_width_hz(sample_rate_hz, SIZE);
+    core::iter::successors(Some(first), |low| Some(low * 2.0))
+        .take_while(move |&low| low < nyquist)
+        .map(move |low| (low, (low * 2.0).min(nyquist)))
+}
+
+/// The level of each of `octave_bands` in dBFS, into `levels`, returning
+/// how many there were room for. Magnitudes are in 16-bit counts, so
+/// levels are against a 16-bit full scale, and power's a sum of squared
+/// magnitudes, so it's 10 log10 against the reference squared.
+fn band_levels_dbfs(magnitudes: &[f32], sample_rate_hz: f32, levels: &mut [f32]) -> usize {
+    let reference = AdcScale::new(16, ADC_VREF).dbfs_reference(SIZE);
+    let mut count = 0;
+    for ((low, high), level) in octave_bands(sample_rate_hz).zip(levels) {
+        let power = padded_band_power(magnitudes, low, high, sample_rate_hz);
+        *level = 10.0 * (power / (reference * reference)).log10();
+        count += 1;
+    }
+    count
+}
+
+/// Put a spectrum's peak, or its band levels in `mode bands`, on the CAN bus.
+/// Magnitudes are in 16-bit counts, so levels are against a 16-bit full scale.
+#[cfg(feature = "can")]
+fn broadcast(
+    can: &mut utilities::can::CanBus,
+    config: &Config,
+    trusted: bool,
+    peak: Option<(f32, f32)>,
+    magnitudes: &[f32],
+    sample_rate_hz: f32,
+) {
+    let full_scale = AdcScale::new(16, ADC_VREF);
+
+    if config.mode == OutputMode::Bands {
+        let mut levels = [0.0; 16];
+        let count = band_levels_dbfs(magnitudes, sample_rate_hz, &mut levels);
+        can.send_bands(&levels[..count]);
+    } else if let Some((peak_hz, magnitude)) = peak {
+        can.send_peak(
+            peak_hz,
+            full_scale.magnitude_to_dbfs(magnitude, SIZE),
+            trusted,
+        );
+    }
+}
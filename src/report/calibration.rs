@@ -0,0 +1,188 @@
+//! The two-point calibration and the offset drift's, fed a capture at a
+//! time, and saving what they measure with the settings.
+
+use log::{error, info};
+
+use stm32h7xx_hal::delay::Delay;
+
+use crate::board::{CaptureInfo, Source, CALIBRATION_PAUSE_MS};
+use crate::config::Config;
+use crate::config_store::Settings;
+use crate::dsp::scaling::{
+    AdcScale, DriftCalibration, OffsetDrift, TwoPointCalibration, MIN_DRIFT_SPAN_DEGC,
+};
+use crate::utilities;
+use crate::utilities::console::Console;
+
+/// Captures a drift calibration waits for the die to warm by
+/// `MIN_DRIFT_SPAN_DEGC` before it gives up
+const DRIFT_MAX_CAPTURES: u32 = 1_000;
+
+/// The calibrations under way, each fed the captures until it's done
+pub struct Calibrations {
+    two_point: Option<TwoPointCalibration>,
+    drift: Option<DriftCalibration>,
+}
+
+impl Calibrations {
+    /// Starting on `two_point`, if there is one, with nothing on the drift
+    pub fn new(two_point: Option<TwoPointCalibration>) -> Self {
+        Self {
+            two_point,
+            drift: None,
+        }
+    }
+
+    /// Whether either still wants captures
+    pub fn running(&self) -> bool {
+        self.two_point.is_some() || self.drift.is_some()
+    }
+
+    /// Start over on the offset drift, see `start_drift_calibration`
+    pub fn start_drift(&mut self, source: &mut Source, delay: &mut Delay) {
+        self.drift = Some(start_drift_calibration(source, delay));
+    }
+
+    /// Feed each calibration under way `capture` and its `mean`, dropping
+    /// those that are done with
+    pub fn step(
+        &mut self,
+        mean: Option<f32>,
+        capture: &CaptureInfo,
+        scale: &AdcScale,
+        delay: &mut Delay,
+        source: &mut Source,
+    ) {
+        if let Some(run) = self.two_point.as_mut() {
+            if !calibration_step(run, mean, scale, delay) {
+                self.two_point = None;
+            }
+        }
+        if let Some(run) = self.drift.as_mut() {
+            if !drift_calibration_step(run, mean, capture, source) {
+                self.drift = None;
+            }
+        }
+    }
+}
+
+/// Feed a capture's mean to the calibration and save it once
+/// both points are in. Returns whether another capture is wanted.
+fn calibration_step(
+    run: &mut TwoPointCalibration,
+    mean: Option<f32>,
+    scale: &AdcScale,
+    delay: &mut Delay,
+) -> bool {
+    let Some(mean) = mean else {
+        error!("Calibration capture was empty, taking it again");
+        return true;
+    };
+
+    match run.feed(mean, scale) {
+        None => {
+            info!(
+                "Calibration zero reads {} counts. Apply {} V to the input within {} s",
+                mean,
+                run.reference_volts(),
+                CALIBRATION_PAUSE_MS / 1000
+            );
+            utilities::watchdog::delay_ms(delay, CALIBRATION_PAUSE_MS);
+            true
+        }
+        Some(cal) if cal.is_plausible() => {
+            utilities::backup::save_calibration(&cal);
+            info!("Saved calibration {:?}, it's used from the next reset", cal);
+            false
+        }
+        Some(cal) => {
+            error!("Calibration {:?} is implausible, not saving it", cal);
+            false
+        }
+    }
+}
+
+/// Start measuring the offset drift for `cal`. The input has to be grounded
+/// by hand, shorted to the board's ground within `CALIBRATION_PAUSE_MS`, and
+/// stay that way while the board warms by `MIN_DRIFT_SPAN_DEGC` (leave it
+/// running, or warm it with a hot air gun held well back). Correction stops
+/// until it's done, so the captures read the raw offset.
+fn start_drift_calibration(source: &mut Source, delay: &mut Delay) -> DriftCalibration {
+    source.set_drift(OffsetDrift::NONE);
+    info!(
+        "Drift calibration: ground the input within {} s, and keep it grounded",
+        CALIBRATION_PAUSE_MS / 1000
+    );
+    utilities::watchdog::delay_ms(delay, CALIBRATION_PAUSE_MS);
+    DriftCalibration::new()
+}
+
+/// Feed a drift calibration the mean of a grounded capture, saving the fit
+/// and correcting with it once the die's warmed enough, or going back to
+/// the stored one after `DRIFT_MAX_CAPTURES`. Returns whether another
+/// capture is wanted.
+fn drift_calibration_step(
+    run: &mut DriftCalibration,
+    mean: Option<f32>,
+    capture: &CaptureInfo,
+    source: &mut Source,
+) -> bool {
+    let Some(mean) = mean else {
+        error!("Drift calibration capture was empty, taking it again");
+        return true;
+    };
+    let Some(degc) = capture.die_degc else {
+        error!("Drift calibration couldn't read the die temperature, taking it again");
+        return true;
+    };
+
+    match run.feed(degc, mean) {
+        None if run.readings() >= DRIFT_MAX_CAPTURES => {
+            error!(
+                "The die didn't warm by {} C in {} captures, keeping the old drift",
+                MIN_DRIFT_SPAN_DEGC, DRIFT_MAX_CAPTURES
+            );
+            source.set_drift(utilities::backup::load_drift().unwrap_or(OffsetDrift::NONE));
+            false
+        }
+        None => {
+            if let Some((first_degc, first_offset)) = run.first() {
+                info!(
+                    "Drift calibration: {} counts at {} C, from {} counts at {} C",
+                    mean, degc, first_offset, first_degc
+                );
+            }
+            true
+        }
+        Some(drift) if drift.is_plausible() => {
+            utilities::backup::save_drift(&drift);
+            source.set_drift(drift);
+            info!("Saved offset drift {:?}, correcting with it now", drift);
+            false
+        }
+        Some(drift) => {
+            error!("Offset drift {:?} is implausible, not saving it", drift);
+            source.set_drift(utilities::backup::load_drift().unwrap_or(OffsetDrift::NONE));
+            false
+        }
+    }
+}
+
+/// Save `config`, the calibration `scale` corrects with and the source's
+/// offset drift to internal flash for the next boot, and say how it went
+pub fn save_settings(
+    console: &mut Console,
+    config: &Config,
+    scale: &AdcScale,
+    source: &mut Source,
+) {
+    let settings = Settings {
+        config: *config,
+        calibration: scale.calibration(),
+        drift: source.drift(),
+    };
+    match utilities::internal_flash::save_settings(&settings, source.scb()) {
+        Ok(slot) => console.reply(format_args!("saved to flash slot {}", slot)),
+        Err(e) => console.reply(format_args!("error: saving failed: {:?}", e)),
+    }
+}
@@ -0,0 +1,123 @@
+//! With `ci-test`, checking a capture and ending the run with a status the
+//! probe passes on, and with `perf-guard`, holding each stage to its budget.
+//! `capture-soak` soaks the source at boot and ends its run the same way.
+
+#[cfg(feature = "ci-test")]
+use log::{error, info};
+
+#[cfg(feature = "capture-soak")]
+use crate::board::Source;
+#[cfg(feature = "ci-test")]
+use crate::board::{CaptureInfo, SIZE};
+#[cfg(feature = "ci-test")]
+use crate::selfcheck;
+#[cfg(feature = "perf-guard")]
+use crate::utilities;
+
+#[cfg(feature = "perf-guard")]
+use super::FFT_LEN;
+
+/// With `capture-soak`, how many captures to take back to back at boot
+#[cfg(feature = "capture-soak")]
+const SOAK_CAPTURES: u32 = 10_000;
+
+/// With `ci-test`, check a finished capture, log a PASS or FAIL and end the
+/// run through semihosting, so the probe exits with a matching status.
+/// `raw` is the whole buffer, after any unpacking. This never returns, but
+/// isn't `-> !` so the rest of the capture loop doesn't warn as unreachable.
+#[cfg(feature = "ci-test")]
+pub fn ci_check(raw: &[u16], capture: &CaptureInfo) {
+    let mut samples = [0.0; SIZE];
+    let report = selfcheck::check(raw, capture.complete(), &mut samples);
+    let verdict = |ok| if ok { "ok" } else { "FAILED" };
+    info!("Capture complete and clean: {}", verdict(report.complete));
+    info!(
+        "Variance {} counts^2, at least {}: {}",
+        report.variance,
+        selfcheck::MIN_VARIANCE,
+        verdict(report.variance_ok())
+    );
+    info!(
+        "{} non-finite bins: {}",
+        report.non_finite_bins,
+        verdict(report.spectrum_ok())
+    );
+    info!(
+        "DC after normalizing {} per sample, at most {}: {}",
+        report.dc_per_sample,
+        selfcheck::MAX_DC_PER_SAMPLE,
+        verdict(report.dc_ok())
+    );
+
+    let passed = report.passed();
+    #[cfg(feature = "perf-guard")]
+    let passed = within_budgets() && passed;
+
+    if passed {
+        info!("PASS");
+    } else {
+        error!("FAIL");
+    }
+    exit(passed);
+    // Without a debugger attached exiting does nothing, so stay put
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// With `capture-soak`, take `SOAK_CAPTURES` captures back to back and end
+/// the run with how it went. Without a debugger a failed soak stays put,
+/// and a passed one returns to measure as usual.
+#[cfg(feature = "capture-soak")]
+pub fn soak(source: &mut Source) {
+    let passed = source.soak(SOAK_CAPTURES);
+    exit(passed);
+    while !passed {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// End the run through semihosting with `passed`'s status. Without a
+/// debugger attached this does nothing.
+fn exit(passed: bool) {
+    use cortex_m_semihosting::debug;
+
+    debug::exit(if passed {
+        debug::EXIT_SUCCESS
+    } else {
+        debug::EXIT_FAILURE
+    });
+}
+
+/// With `perf-guard`, whether each stage of the last capture kept to its
+/// budget for this clock, logging any that didn't. The conversion's over
+/// the capture's `SIZE`, everything after it over the padded `FFT_LEN`.
+#[cfg(feature = "perf-guard")]
+fn within_budgets() -> bool {
+    use core::sync::atomic::Ordering;
+
+    use crate::perf_budget::{Profile, Stage, TOLERANCE_PERCENT};
+
+    use super::analysis::STAGE_CYCLES;
+
+    let core_hz = utilities::clocks::core_hz();
+    let Some(profile) = Profile::for_core_hz(core_hz) else {
+        error!("No cycle budgets for a {} Hz core: FAILED", core_hz);
+        return false;
+    };
+    let mut passed = true;
+    for stage in Stage::ALL {
+        let cycles = STAGE_CYCLES[stage as usize].load(Ordering::Relaxed);
+        let budget = profile.budget(stage, SIZE, FFT_LEN);
+        if profile.within(stage, cycles, SIZE, FFT_LEN) {
+            info!("{:?} took {} cycles, within {}: ok", stage, cycles, budget);
+        } else {
+            error!(
+                "{:?} took {} cycles, over its budget of {} at {} MHz with {}% tolerance: FAILED",
+                stage, cycles, budget, profile.core_mhz, TOLERANCE_PERCENT
+            );
+            passed = false;
+        }
+    }
+    passed
+}
@@ -0,0 +1,251 @@
+//! Captures and status as log lines: the spectrum dump, samples, bands,
+//! levels, long Welch spectra and the status report.
+
+use core::fmt::Write;
+use heapless::String;
+use log::{info, warn};
+
+use cortex_m::peripheral::DWT;
+
+use crate::board::SIZE;
+use crate::dsp::psd::welch_psd;
+use crate::dsp::samples::{center_samples, histogram};
+use crate::dsp::scaling::AdcScale;
+use crate::dsp::spectrum::find_peak_bin;
+use crate::dsp::window::WindowType;
+use crate::text::{signed_csv_rows, TruncatingString, SIGNED_CSV_HEADER};
+#[cfg(any(feature = "usb", feature = "ethernet"))]
+use crate::transport;
+use crate::utilities::{self, batch::LogBatch};
+
+use super::analysis::{octave_bands, padded_band_power};
+
+/// Characters in a full-scale bar, for `log_level`
+const BAR_WIDTH: usize = 40;
+
+/// Level at the empty end of the bar, anything quieter shows nothing
+const BAR_FLOOR_DBFS: f32 = -60.0;
+
+/// Most bins `log_histogram` logs, a code each if the range is no wider
+const HISTOGRAM_BINS: usize = 256;
+
+/// Where the spectrum dump is logged, for `log spectrum <level>`
+const SPECTRUM_TARGET: &str = "spectrum";
+
+/// How the spectrum dump is laid out in the log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SpectrumLayout {
+    /// `bin,magnitude` a line, which plots straight from the log
+    PerLine,
+    /// `first bin,magnitude,magnitude,...`, `BINS_PER_LINE` to a line, a log
+    /// call each, for a shorter dump
+    Batched,
+}
+
+const SPECTRUM_LAYOUT: SpectrumLayout = SpectrumLayout::PerLine;
+const BINS_PER_LINE: usize = 8;
+/// Room for `BINS_PER_LINE` typical magnitudes; a longer line is cut short
+const BATCHED_LINE_BYTES: usize = 256;
+
+fn log_spectrum_per_line(magnitudes: &[f32]) {
+    let mut batch: LogBatch<1024> = LogBatch::with_target(SPECTRUM_TARGET);
+    for (i, magnitude) in magnitudes.iter().enumerate() {
+        // Fun fact: this print is very cheap due to deferred formatting! Give it a look!
+        batch.push(format_args!("{i},{}", magnitude));
+    }
+    batch.flush();
+}
+
+fn log_spectrum_batched(magnitudes: &[f32]) {
+    for (i, bins) in magnitudes.chunks(BINS_PER_LINE).enumerate() {
+        let mut line: TruncatingString<BATCHED_LINE_BYTES> = TruncatingString::new();
+        // Never fails, anything that doesn't fit is cut off with an ellipsis
+        let _ = write!(line, "{}", i * BINS_PER_LINE);
+        for magnitude in bins {
+            let _ = write!(line, ",{}", magnitude);
+        }
+        info!(target: SPECTRUM_TARGET, "{}", line.as_str());
+    }
+}
+
+/// Log the FFT magnitudes in `SPECTRUM_LAYOUT`, timed to compare the
+/// layouts, and logged where `log spectrum off` doesn't hide it
+pub(super) fn log_spectrum(magnitudes: &[f32]) {
+    let dump_start = DWT::cycle_count();
+    match SPECTRUM_LAYOUT {
+        SpectrumLayout::PerLine => log_spectrum_per_line(magnitudes),
+        SpectrumLayout::Batched => log_spectrum_batched(magnitudes),
+    }
+    let dump_cycles = DWT::cycle_count().wrapping_sub(dump_start);
+    info!(
+        "Spectrum dump ({:?}) took {} cycles, {} us",
+        SPECTRUM_LAYOUT,
+        dump_cycles,
+        utilities::clocks::cycles_to_us(dump_cycles)
+    );
+}
+
+/// Log samples as `index,count` lines, for `mode raw`
+pub fn log_raw(samples: &[u16]) {
+    let mut batch: LogBatch<1024> = LogBatch::new();
+    for (i, sample) in samples.iter().enumerate() {
+        batch.push(format_args!("{i},{}", sample));
+    }
+    batch.flush();
+}
+
+/// Log codes centred on mid-scale as signed CSV, for `mode waveform`
+pub(super) fn log_waveform(codes: &[u16], sample_rate_hz: f32, scale: &AdcScale) {
+    let mut centred = [0.0; SIZE];
+    center_samples(codes, &mut centred, scale.full_scale_code());
+    log_signed_csv(&centred[..codes.len()], sample_rate_hz);
+}
+
+/// Log signed samples as `text::write_samples_signed_csv` writes them, the
+/// header and then a line each, for `mode waveform`
+pub fn log_signed_csv(samples: &[f32], sample_rate_hz: f32) {
+    let mut batch: LogBatch<1024> = LogBatch::new();
+    batch.push(format_args!("{}", SIGNED_CSV_HEADER));
+    for row in signed_csv_rows(samples, sample_rate_hz) {
+        batch.push(format_args!("{}", row));
+    }
+    batch.flush();
+}
+
+/// Log samples as `index,count` lines, for `mode raw` from a source whose
+/// counts aren't ADC codes
+pub fn log_raw_counts(samples: &[f32]) {
+    let mut batch: LogBatch<1024> = LogBatch::new();
+    for (i, sample) in samples.iter().enumerate() {
+        batch.push(format_args!("{i},{}", *sample as i32));
+    }
+    batch.flush();
+}
+
+/// Log `code,count` for the range of codes in `samples`, for `LOG_HISTOGRAM`
+pub(super) fn log_histogram(samples: &[u16]) {
+    let min = samples.iter().copied().min().unwrap_or(0);
+    let max = samples.iter().copied().max().unwrap_or(0);
+    let bins = (max as usize - min as usize + 1).min(HISTOGRAM_BINS);
+    let mut counts = [0; HISTOGRAM_BINS];
+    histogram(samples, &mut counts[..bins], min, max);
+
+    let span = max as usize - min as usize + 1;
+    let mut batch: LogBatch<1024> = LogBatch::new();
+    for (i, count) in counts[..bins].iter().enumerate() {
+        // The first code in each bin
+        batch.push(format_args!("{},{}", min as usize + i * span / bins, count));
+    }
+    batch.flush();
+}
+
+/// Log the power in octave bands from one bin up to Nyquist, for `mode bands`
+pub(super) fn log_bands(magnitudes: &[f32], sample_rate_hz: f32) {
+    let mut batch: LogBatch<1024> = LogBatch::new();
+    for (low, high) in octave_bands(sample_rate_hz) {
+        let power = padded_band_power(magnitudes, low, high, sample_rate_hz);
+        batch.push(format_args!("{}-{} Hz,{}", low, high, power));
+    }
+    batch.flush();
+}
+
+/// Log each mel band's log energy as `band,energy`, for `mode mel`
+pub(super) fn log_mel(bands: &[f32]) {
+    let mut batch: LogBatch<1024> = LogBatch::new();
+    for (i, energy) in bands.iter().enumerate() {
+        batch.push(format_args!("{i},{}", energy));
+    }
+    batch.flush();
+}
+
+/// Log a level, in counts with the mean already off, as dBFS with a bar
+/// like a VU meter's, and RMS volts for the ADC
+pub fn log_level(rms_counts: f32, scale: &AdcScale) {
+    let dbfs = scale.rms_to_dbfs(rms_counts);
+    let fraction = (1.0 - dbfs / BAR_FLOOR_DBFS).clamp(0.0, 1.0);
+    let mut bar: String<BAR_WIDTH> = String::new();
+    for _ in 0..(fraction * BAR_WIDTH as f32) as usize {
+        // Never more than BAR_WIDTH characters
+        let _ = bar.push('#');
+    }
+
+    // The mic's counts aren't volts
+    #[cfg(not(feature = "source-i2s"))]
+    {
+        // RMS is a difference from the mean, so the offset doesn't apply
+        let volts = scale.counts_to_volts(rms_counts) - scale.counts_to_volts(0.0);
+        info!(
+            "{:>7.1} dBFS {:>6.3} V |{:<w$}|",
+            dbfs,
+            volts,
+            bar,
+            w = BAR_WIDTH
+        );
+    }
+    #[cfg(feature = "source-i2s")]
+    info!("{:>7.1} dBFS |{:<w$}|", dbfs, bar, w = BAR_WIDTH);
+}
+
+/// Welch average `long`, samples at `rate_hz`, in Hann windowed segments of
+/// `segment_len` overlapping by half, and log the peak and then every bin
+/// as `hz,psd` in counts²/Hz. Takes the mean off `long` first.
+pub fn log_welch_spectrum(long: &mut [f32], segment_len: usize, psd: &mut [f32], rate_hz: f32) {
+    let mean = long.iter().sum::<f32>() / long.len() as f32;
+    long.iter_mut().for_each(|x| *x -= mean);
+    welch_psd(
+        long,
+        segment_len,
+        segment_len / 2,
+        WindowType::Hann,
+        psd,
+        rate_hz,
+    );
+
+    let bin_hz = rate_hz / segment_len as f32;
+    match find_peak_bin(psd) {
+        Some(bin) => info!(
+            "Peak at {} Hz, {} counts²/Hz, bins {} Hz apart",
+            bin as f32 * bin_hz,
+            psd[bin],
+            bin_hz
+        ),
+        None => info!("No peak, bins {} Hz apart", bin_hz),
+    }
+
+    let mut batch: LogBatch<1024> = LogBatch::new();
+    batch.push(format_args!("hz,psd"));
+    for (bin, power) in psd.iter().enumerate() {
+        batch.push(format_args!("{:.4},{}", bin as f32 * bin_hz, power));
+    }
+    batch.flush();
+}
+
+/// Status, so long runs can report data integrity
+pub fn log_status() {
+    info!(
+        "Status: ADC overruns={} clock={:?}",
+        utilities::adc::overrun_count(),
+        utilities::clocks::source()
+    );
+    if utilities::clocks::hse_failed() {
+        warn!("HSE failed while running, the CSS switched to HSI");
+    }
+    #[cfg(feature = "usb")]
+    info!("USB frames dropped: {}", transport::usb::dropped_frames());
+    #[cfg(feature = "ethernet")]
+    info!("UDP frames dropped: {}", transport::udp::dropped_frames());
+    #[cfg(feature = "can")]
+    info!(
+        "CAN messages dropped: {}, bus-off {} times",
+        utilities::can::dropped_messages(),
+        utilities::can::bus_off_count()
+    );
+    if let Some((high_water, dropped)) = utilities::dma::pool_stats() {
+        info!(
+            "DMA buffers: queued at most {}/{}, {} dropped",
+            high_water,
+            utilities::dma::POOL_SIZE,
+            dropped
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! `fft`'s capture loop with the mic as the source. Raw mode logs the mic's
+//! samples but has no frames for them, and nothing is stored, as both
+//! expect 16-bit ADC codes.
+
+use log::{info, warn};
+
+use crate::board::{Board, FloatSource, SIZE};
+use crate::config::OutputMode;
+
+use super::{
+    analyze, log_raw_counts, log_signed_csv, normalize_slice, service_console, with_sinks, FFT_LEN,
+};
+
+/// Capture from the mic on `board` for good, with `fft_buffer` for the
+/// FFT's input
+pub fn run(board: Board, fft_buffer: &mut [f32; FFT_LEN]) -> ! {
+    let Board {
+        mut console,
+        mut config,
+        mut delay,
+        mut source,
+        scale,
+        limits,
+        #[cfg(feature = "can")]
+        mut can,
+        #[cfg(feature = "sd-card")]
+        mut sd,
+        ..
+    } = board;
+    // The buzzer needs captures to keep coming, so it never stops
+    let mut measuring = !cfg!(any(feature = "low-power", feature = "buzzer"));
+
+    loop {
+        service_console(&mut console, &mut config, &limits, &mut delay);
+        if core::mem::take(&mut config.calibrate) {
+            console.reply(format_args!(
+                "error: there's nothing to calibrate on the mic"
+            ));
+        }
+        // Nothing here owns the SCB to keep the cache in step with flash
+        if core::mem::take(&mut config.save) {
+            console.reply(format_args!(
+                "error: settings can only be saved with the ADC as the source"
+            ));
+        }
+
+        let mut samples = [0.0; SIZE];
+        let capture = source.capture(&mut samples);
+        let valid = capture.valid;
+        info!(
+            "Capture at {} us, {} samples",
+            capture.captured_at_us, valid
+        );
+        if valid == 0 {
+            continue;
+        }
+
+        if matches!(config.mode, OutputMode::Raw | OutputMode::Waveform) {
+            if !capture.trusted {
+                warn!("Samples not logged, the capture overran");
+            } else if config.mode == OutputMode::Waveform {
+                // Already signed, in the mic's own counts
+                log_signed_csv(&samples[..valid], capture.sample_rate_hz);
+            } else {
+                log_raw_counts(&samples[..valid]);
+            }
+            continue;
+        }
+
+        let mean = normalize_slice(&mut samples[..valid]);
+        info!("Average: {} counts", mean);
+        // There are no ADC codes, so nothing for the card
+        with_sinks(
+            &config,
+            &mut console,
+            #[cfg(feature = "sd-card")]
+            &mut sd,
+            &scale,
+            |sink| {
+                analyze(
+                    &samples,
+                    &[],
+                    &capture,
+                    &config,
+                    sink,
+                    #[cfg(feature = "can")]
+                    can.as_mut(),
+                    &scale,
+                    fft_buffer,
+                )
+            },
+        );
+
+        // As for the ADC, stop after the first capture until asked for more
+        if measuring {
+            measuring = false;
+            config.running = false;
+            info!("Stopped, send `start` on the console to capture continuously");
+        }
+    }
+}
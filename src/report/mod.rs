@@ -0,0 +1,93 @@
+//! What the binaries do with a capture once they have one: checking,
+//! converting and transforming it, and logging, sending or keeping what
+//! comes out. Also the console, which every capture loop services.
+
+use stm32h7xx_hal::delay::Delay;
+
+use crate::board::SIZE;
+use crate::config::{Config, Limits};
+use crate::dsp::fft::MAX_FFT_LEN;
+use crate::dsp::samples;
+use crate::dsp::window::WindowType;
+use crate::utilities::{self, console::Console};
+
+mod analysis;
+#[cfg(not(feature = "source-i2s"))]
+mod calibration;
+#[cfg(any(feature = "ci-test", feature = "capture-soak"))]
+mod ci;
+mod logging;
+#[cfg(feature = "source-i2s")]
+pub mod mic;
+mod modes;
+#[cfg(not(feature = "source-i2s"))]
+pub mod session;
+mod sinks;
+#[cfg(all(feature = "rtic", not(feature = "source-i2s")))]
+pub mod tasks;
+
+pub use analysis::{analyze, process_capture};
+#[cfg(feature = "ci-test")]
+pub use ci::ci_check;
+#[cfg(feature = "capture-soak")]
+pub use ci::soak;
+pub use logging::{
+    log_level, log_raw, log_raw_counts, log_signed_csv, log_status, log_welch_spectrum,
+};
+#[cfg(feature = "sd-card")]
+pub use sinks::CardSink;
+#[cfg(feature = "qspi-flash")]
+pub use sinks::{dump_slot, keep_capture};
+pub use sinks::{send_frames, with_sinks, LogSink};
+
+/// How many times the capture's length the FFT is, zero padded: 1 for
+/// none, or 2 or 4 for a finer-looking spectrum to look over. The bins get
+/// narrower, but tones closer than a bin of `SIZE` still don't separate.
+pub const ZERO_PAD_FACTOR: usize = 1;
+
+/// Length of the FFT, and of the buffer `analyze` is given for it
+pub const FFT_LEN: usize = SIZE * ZERO_PAD_FACTOR;
+
+const _: () = assert!(
+    ZERO_PAD_FACTOR.is_power_of_two() && FFT_LEN <= MAX_FFT_LEN,
+    "the padded length has to be an FFT size microfft has"
+);
+
+/// Applied to the captured samples before they're padded
+const WINDOW: WindowType = WindowType::Rectangular;
+
+/// How often to check the console for commands while stopped
+pub const CONSOLE_POLL_MS: u32 = 10;
+
+/// Normalize the contents of an array in place
+/// This produces a mere 20 instructions, despite using very high-level FP semantics
+/// https://godbolt.org/z/vG9cb5ofG
+/// It's `samples::normalize` now, whose `f32` instance is the same fold and
+/// loop, so should still come to that; `process_capture` logs its cycles
+/// at debug to check against a build from before.
+pub fn normalize_slice(slice: &mut [f32]) -> f32 {
+    samples::normalize(slice)
+}
+
+/// Apply any commands that have arrived, and while stopped, wait for a
+/// `start` or a `dump`, reporting status every second
+pub fn service_console(
+    console: &mut Console,
+    config: &mut Config,
+    limits: &Limits,
+    delay: &mut Delay,
+) {
+    // Every capture loop comes through here, so this is where a stall shows
+    utilities::watchdog::feed();
+    console.poll(config, limits);
+
+    let mut idle_ms = 0;
+    while !config.running && config.dump.is_none() {
+        utilities::watchdog::delay_ms(delay, CONSOLE_POLL_MS);
+        idle_ms += CONSOLE_POLL_MS;
+        if idle_ms.is_multiple_of(1000) {
+            log_status();
+        }
+        console.poll(config, limits);
+    }
+}
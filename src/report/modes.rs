@@ -0,0 +1,127 @@
+//! What `mode envelope`, `mode dither` and a notch log on top of the
+//! spectrum. Each needs the samples and the FFT buffer, rather than what a
+//! sink's given, so `analyze` calls them itself.
+
+use log::{info, warn};
+use microfft::Complex32;
+
+use cortex_m::peripheral::DWT;
+
+use crate::board::SIZE;
+use crate::dsp::dither::{requantize, DitherMode};
+use crate::dsp::envelope::{envelope, modulation_depth};
+use crate::dsp::fft;
+use crate::dsp::filter::Biquad;
+use crate::dsp::pipeline;
+use crate::dsp::samples::rms;
+use crate::dsp::scaling::AdcScale;
+use crate::dsp::spectrum::spur_free_range_db;
+use crate::dsp::window::WindowType;
+use crate::utilities;
+
+use super::{normalize_slice, FFT_LEN, WINDOW};
+
+/// With `mode envelope`, the envelope's lowpass: above the modulation, and
+/// far enough below the carrier to smooth it out
+const ENVELOPE_CUTOFF_HZ: f32 = 200.0;
+
+/// With `mode dither`, the resolution captures are quantized down to, so
+/// the idle tones show well above the ADC's own noise
+const DITHER_BITS: u8 = 8;
+/// Bins either side of the peak that are its leakage, not spurs
+const DITHER_GUARD_BINS: usize = 3;
+
+/// Filter `low_hz` to `high_hz` out of `samples` in the frequency domain,
+/// through `fft_buffer`, and log the RMS before and after, which only
+/// differ by what was in the band if the inverse FFT undoes the forward
+pub(super) fn log_notch(
+    samples: &[f32; SIZE],
+    (low_hz, high_hz): (f32, f32),
+    sample_rate_hz: f32,
+    fft_buffer: &mut [f32; FFT_LEN],
+) {
+    // Every bin of the full spectrum, which is too much for the stack
+    static mut SCRATCH: [Complex32; FFT_LEN] = [Complex32::new(0.0, 0.0); FFT_LEN];
+    // Safety: only ever used here, and `analyze` never runs twice at once
+    let scratch = unsafe { &mut *core::ptr::addr_of_mut!(SCRATCH) };
+
+    let start = DWT::cycle_count();
+    // Unwindowed, or the window would come back with the signal
+    pipeline::zero_pad(samples, WindowType::Rectangular, fft_buffer);
+    let spectrum = fft::rfft(fft_buffer);
+    fft::notch(spectrum, low_hz, high_hz, sample_rate_hz);
+    let mut filtered = [0.0; SIZE];
+    fft::irfft(spectrum, scratch, &mut filtered);
+    let cycles = DWT::cycle_count().wrapping_sub(start);
+
+    info!(
+        "Notch {}-{} Hz: RMS {} before, {} after, in {} us",
+        low_hz,
+        high_hz,
+        rms(samples),
+        rms(&filtered),
+        utilities::clocks::cycles_to_us(cycles)
+    );
+}
+
+/// Log the depth of the modulation on a carrier, and the frequency it's at
+/// from the envelope's own spectrum, for `mode envelope`
+pub(super) fn log_envelope(
+    samples: &[f32; SIZE],
+    sample_rate_hz: f32,
+    scale: &AdcScale,
+    fft_buffer: &mut [f32; FFT_LEN],
+) {
+    let mut shape = *samples;
+    let mut lowpass = Biquad::lowpass(ENVELOPE_CUTOFF_HZ, sample_rate_hz, 0.707);
+    envelope(&mut shape, &mut lowpass);
+    let Some(depth) = modulation_depth(&shape) else {
+        info!("Envelope: no carrier");
+        return;
+    };
+
+    // The modulation's a tone on top of the envelope's mean
+    normalize_slice(&mut shape);
+    pipeline::zero_pad(&shape, WINDOW, fft_buffer);
+    let mut magnitudes = [0.0; FFT_LEN / 2];
+    pipeline::magnitudes(fft_buffer, scale, &mut magnitudes);
+    match pipeline::peak(&magnitudes, sample_rate_hz) {
+        Some(peak) => info!(
+            "Envelope: modulation depth {}%, at {} Hz",
+            depth * 100.0,
+            peak.hz
+        ),
+        None => info!("Envelope: modulation depth {}%", depth * 100.0),
+    }
+}
+
+/// Quantize the capture down to `DITHER_BITS` each way `DitherMode` can,
+/// and log the spur-free range of each, for `mode dither`. Without dither
+/// a tone's harmonics stand out of the floor, with it they should be gone.
+pub(super) fn log_dither(samples: &[f32; SIZE], scale: &AdcScale, fft_buffer: &mut [f32; FFT_LEN]) {
+    let step = (1u32 << scale.bits().saturating_sub(DITHER_BITS)) as f32;
+    for mode in [
+        DitherMode::Off,
+        DitherMode::Additive,
+        DitherMode::Subtractive,
+    ] {
+        let mut quantized = *samples;
+        requantize(&mut quantized, step, mode, || {
+            utilities::rng::next_f32_pm(step / 2.0)
+        });
+        pipeline::zero_pad(&quantized, WINDOW, fft_buffer);
+        let mut magnitudes = [0.0; FFT_LEN / 2];
+        pipeline::magnitudes(fft_buffer, scale, &mut magnitudes);
+        match spur_free_range_db(&magnitudes, DITHER_GUARD_BINS) {
+            Some(range) => info!(
+                "Dither {:?} at {} bits: spurs {} dB down",
+                mode, DITHER_BITS, range
+            ),
+            None => info!("Dither {:?} at {} bits: no peak", mode, DITHER_BITS),
+        }
+    }
+    let (seed, clock) = utilities::rng::error_counts();
+    if seed + clock > 0 {
+        warn!("RNG errors: {} seed, {} clock", seed, clock);
+    }
+}
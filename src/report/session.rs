@@ -0,0 +1,289 @@
+//! `fft`'s capture loop with the ADC as the source, one `Action` of the
+//! capture state machine at a time. `fft` steps the machine; a `Session`
+//! turns the console and the button into its events, and does what each
+//! action asks.
+
+use log::{info, warn};
+
+use crate::board::{self, Board, CaptureInfo, SIZE};
+use crate::capture_state::{Action, CaptureStateMachine, Event};
+#[cfg(all(feature = "sd-card", feature = "qspi-flash"))]
+use crate::config::Output;
+use crate::dsp::scaling::TwoPointCalibration;
+use crate::flight_recorder::ERROR_EMPTY;
+use crate::utilities;
+
+use super::calibration::{save_settings, Calibrations};
+#[cfg(feature = "qspi-flash")]
+use super::{dump_slot, keep_capture};
+use super::{log_status, process_capture, with_sinks, CONSOLE_POLL_MS, FFT_LEN};
+
+/// Without `low-power`, how many captures to measure the sample rate over
+/// at boot, before stopping to wait for a `start` on the console
+const RATE_CAPTURES: u32 = 4;
+
+/// The board, the last capture and what came of it, and what the console
+/// and the button last said, to turn changes into events
+pub struct Session {
+    board: Board,
+    /// The FFT's input, padding and all, kept off the stack
+    fft_buffer: &'static mut [f32; FFT_LEN],
+    /// Each capture's copy, out of the DMA buffer
+    raw: [u16; SIZE],
+    capture: Option<CaptureInfo>,
+    mean: Option<f32>,
+    /// Still taking the captures that measure the rate at boot
+    measuring: bool,
+    calibrations: Calibrations,
+    running: bool,
+    pressed: bool,
+    /// The rate the source was last tuned to
+    rate_hz: Option<f32>,
+    idle_ms: u32,
+}
+
+impl Session {
+    /// Capture on `board`, starting on `two_point` if there is one
+    pub fn new(
+        board: Board,
+        fft_buffer: &'static mut [f32; FFT_LEN],
+        two_point: Option<TwoPointCalibration>,
+    ) -> Self {
+        Self {
+            running: board.config.running,
+            pressed: board::button_pressed(&board.button),
+            rate_hz: board.config.rate_hz,
+            board,
+            fft_buffer,
+            raw: [0; SIZE],
+            capture: None,
+            mean: None,
+            // The buzzer needs captures to keep coming, so it never stops
+            measuring: !cfg!(any(feature = "low-power", feature = "buzzer")),
+            calibrations: Calibrations::new(two_point),
+            idle_ms: 0,
+        }
+    }
+
+    /// Apply any commands that have arrived, and report a `start` or `stop`
+    /// among them and a press of the button
+    pub fn poll(&mut self) -> [Option<Event>; 2] {
+        let Board {
+            console,
+            config,
+            limits,
+            button,
+            ..
+        } = &mut self.board;
+        // Every capture loop comes through here, so this is where a stall shows
+        utilities::watchdog::feed();
+        console.poll(config, limits);
+        let command = (config.running != self.running).then_some(if config.running {
+            Event::Start
+        } else {
+            Event::Stop
+        });
+        self.running = config.running;
+
+        let pressed = board::button_pressed(button);
+        let press = (pressed && !self.pressed).then_some(Event::Button);
+        self.pressed = pressed;
+        [command, press]
+    }
+
+    /// Show whether `machine` is carrying on in the config, however it was
+    /// started or stopped
+    pub fn follow(&mut self, machine: &CaptureStateMachine) {
+        self.board.config.running = machine.running();
+        self.running = machine.running();
+    }
+
+    /// Do what `action` asks, and say what came of it
+    pub fn act(&mut self, action: Action) -> Option<Event> {
+        match action {
+            Action::Wait => self.wait(),
+            Action::Arm => self.arm(),
+            Action::Capture => self.capture(),
+            Action::Process => self.process(),
+            Action::Output => self.output(),
+            Action::Recover => self.recover(),
+        }
+    }
+
+    fn wait(&mut self) -> Option<Event> {
+        utilities::watchdog::delay_ms(&mut self.board.delay, CONSOLE_POLL_MS);
+        self.idle_ms += CONSOLE_POLL_MS;
+        if self.idle_ms.is_multiple_of(1000) {
+            log_status();
+        }
+        self.requests();
+        None
+    }
+
+    fn arm(&mut self) -> Option<Event> {
+        self.idle_ms = 0;
+        // Sleep between captures. Status is only reported while stopped, so
+        // report after every capture.
+        #[cfg(feature = "low-power")]
+        if self.capture.is_some() {
+            log_status();
+            self.board.source.stop();
+        }
+        self.requests();
+
+        let Board {
+            console,
+            config,
+            delay,
+            source,
+            ..
+        } = &mut self.board;
+        // Once the rate is measured at boot, wait for the console to ask for more
+        let measured = self
+            .capture
+            .as_ref()
+            .is_some_and(|c| source.rate_captures() >= RATE_CAPTURES || !c.complete());
+        if core::mem::take(&mut config.calibrate) {
+            self.calibrations.start_drift(source, delay);
+        }
+        if self.measuring && measured && !self.calibrations.running() {
+            self.measuring = false;
+            info!("Stopped, send `start` on the console or press the button for more");
+            Some(Event::Stop)
+        } else {
+            // Settings only change between acquisitions
+            if config.rate_hz != self.rate_hz {
+                source.retune(console, config);
+                self.rate_hz = config.rate_hz;
+            }
+            Some(Event::Armed)
+        }
+    }
+
+    fn capture(&mut self) -> Option<Event> {
+        let info = self.board.source.capture(&mut self.raw);
+        let failed = info.valid == 0;
+        self.capture = Some(info);
+        Some(if failed {
+            Event::CaptureFailed
+        } else {
+            Event::Captured
+        })
+    }
+
+    fn process(&mut self) -> Option<Event> {
+        let info = self.capture.as_ref().expect("processing without a capture");
+        let Board {
+            console,
+            config,
+            source,
+            scale,
+            packed,
+            #[cfg(feature = "can")]
+            can,
+            #[cfg(feature = "sd-card")]
+            sd,
+            ..
+        } = &mut self.board;
+        let (raw, fft_buffer) = (&mut self.raw, &mut *self.fft_buffer);
+        self.mean = with_sinks(
+            config,
+            console,
+            #[cfg(feature = "sd-card")]
+            sd,
+            scale,
+            |sink| {
+                process_capture(
+                    raw,
+                    info,
+                    config,
+                    sink,
+                    #[cfg(feature = "can")]
+                    can.as_mut(),
+                    *packed,
+                    scale,
+                    source.scb(),
+                    fft_buffer,
+                )
+            },
+        );
+        #[cfg(feature = "ci-test")]
+        super::ci_check(&self.raw, info);
+        Some(if self.mean.is_some() {
+            Event::Processed
+        } else {
+            Event::Skipped
+        })
+    }
+
+    fn output(&mut self) -> Option<Event> {
+        let info = self.capture.as_ref().expect("outputting without a capture");
+        let Board {
+            delay,
+            source,
+            scale,
+            #[cfg(all(feature = "sd-card", feature = "qspi-flash"))]
+            config,
+            #[cfg(all(feature = "sd-card", feature = "qspi-flash"))]
+            sd,
+            #[cfg(feature = "qspi-flash")]
+            flash,
+            ..
+        } = &mut self.board;
+        // Only captures that met the trigger and didn't overrun are kept
+        #[cfg(feature = "qspi-flash")]
+        if info.trusted {
+            keep_capture(
+                #[cfg(feature = "sd-card")]
+                config.output.contains(Output::Card).then_some(&*sd),
+                flash,
+                &self.raw[..info.valid],
+                info,
+                scale,
+                source.scb(),
+            );
+        }
+        if let Some((counts, n)) = utilities::adc::injected_reading() {
+            info!(
+                "Reference: {} V (injected conversion {})",
+                scale.counts_to_volts(counts as f32),
+                n
+            );
+        }
+        self.calibrations
+            .step(self.mean, info, scale, delay, source);
+        Some(Event::OutputDone)
+    }
+
+    fn recover(&mut self) -> Option<Event> {
+        // `capture` already started the source over on every attempt, so
+        // there's only the state to go through
+        warn!("No samples arrived, starting the capture over");
+        utilities::flight_recorder::update(|record| {
+            record.errors |= ERROR_EMPTY;
+        });
+        log_status();
+        Some(Event::Recovered)
+    }
+
+    /// A `dump` or `save` from the console, which only happen between
+    /// acquisitions
+    fn requests(&mut self) {
+        let Board {
+            console,
+            config,
+            source,
+            scale,
+            #[cfg(feature = "qspi-flash")]
+            flash,
+            ..
+        } = &mut self.board;
+        #[cfg(feature = "qspi-flash")]
+        if let Some(slot) = config.dump.take() {
+            dump_slot(console, flash, slot, scale);
+        }
+        if core::mem::take(&mut config.save) {
+            save_settings(console, config, scale, source);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Where captures go: the log, frames on the console and the SD card as
+//! sinks, and the captures kept in QSPI flash.
+
+use log::{error, warn};
+
+#[cfg(feature = "qspi-flash")]
+use cortex_m::peripheral::SCB;
+
+use crate::board::CaptureInfo;
+use crate::config::{Config, Output, OutputMode};
+use crate::dsp::scaling::AdcScale;
+use crate::protocol::CaptureHeader;
+use crate::transport::frames::FrameSink;
+use crate::transport::sink::{self, Backpressure, Fanout, Sink};
+use crate::transport::{self, TransportError};
+#[cfg(any(feature = "sd-card", feature = "qspi-flash"))]
+use crate::utilities;
+use crate::utilities::console::Console;
+
+use super::logging::{log_bands, log_mel, log_raw, log_spectrum, log_waveform};
+
+/// Send a capture's magnitudes or samples as protocol frames, outside the
+/// capture loop's sinks
+pub fn send_frames(
+    console: &mut Console,
+    capture: &CaptureInfo,
+    scale: &AdcScale,
+    magnitudes: &[f32],
+    samples: &[u16],
+) {
+    let header = capture_header(capture, scale);
+    let mut write = |bytes: &[u8]| console.write_frame(bytes);
+    match transport::frames::send_capture(&mut write, header, magnitudes, samples, &[]) {
+        // Nobody's listening on USB, or the Ethernet link is down or
+        // backed up, which their status counts show
+        Ok(()) | Err(TransportError::WouldBlock) => {}
+        #[cfg(feature = "ethernet")]
+        Err(TransportError::Overrun) => {}
+        Err(e) => error!("Sending frames failed: {:?}", e),
+    }
+}
+
+/// The header a capture's sent with, before a sink fills in its counts
+fn capture_header(capture: &CaptureInfo, scale: &AdcScale) -> CaptureHeader {
+    CaptureHeader {
+        sequence: 0,
+        timestamp_us: capture.captured_at_us,
+        sample_rate_hz: capture.sample_rate_hz,
+        resolution_bits: scale.bits(),
+        full_scale_volts: scale.full_scale_volts(),
+        trusted: capture.trusted,
+        bins: 0,
+        samples: 0,
+        bands: 0,
+    }
+}
+
+/// Write a capture's parts to `sink`, any of them empty if there aren't any
+pub(super) fn deliver(
+    sink: &mut dyn Sink,
+    capture: &CaptureInfo,
+    scale: &AdcScale,
+    magnitudes: &[f32],
+    samples: &[u16],
+    bands: &[f32],
+) {
+    let header = capture_header(capture, scale);
+    if let Err(e) = sink::deliver(sink, header, magnitudes, samples, bands) {
+        error!("Writing the capture failed: {:?}", e);
+    }
+}
+
+/// Run `f` with a sink for every output in `config.output`: the logger,
+/// frames on the console and the SD card, whichever are on. Frames show
+/// whatever `config.mode` does, and the card takes the samples.
+pub fn with_sinks<R>(
+    config: &Config,
+    console: &mut Console,
+    #[cfg(feature = "sd-card")] sd: &mut utilities::sd::SdLogger,
+    scale: &AdcScale,
+    f: impl FnOnce(&mut dyn Sink) -> R,
+) -> R {
+    let mut log = LogSink::new(config.mode, scale);
+    let backpressure = console.frames_backpressure();
+    let mut frames = FrameSink::new(|bytes: &[u8]| console.write_frame(bytes), backpressure)
+        .showing(config.mode);
+    #[cfg(feature = "sd-card")]
+    let mut card = CardSink::new(sd, scale);
+
+    let mut sinks: Fanout<3> = Fanout::new();
+    if config.output.contains(Output::Log) {
+        sinks.push(&mut log);
+    }
+    if config.output.contains(Output::Frames) {
+        sinks.push(&mut frames);
+    }
+    #[cfg(feature = "sd-card")]
+    if config.output.contains(Output::Card) {
+        sinks.push(&mut card);
+    }
+    f(&mut sinks)
+}
+
+/// The logger as a `Sink`, logging the part `mode` shows, unless the
+/// capture overran. Frames carry whether it's trusted, for the host to
+/// decide, but text goes by without saying.
+pub struct LogSink<'a> {
+    mode: OutputMode,
+    scale: &'a AdcScale,
+    header: Option<CaptureHeader>,
+}
+
+impl<'a> LogSink<'a> {
+    pub fn new(mode: OutputMode, scale: &'a AdcScale) -> Self {
+        Self {
+            mode,
+            scale,
+            header: None,
+        }
+    }
+
+    /// The capture under way, if `part` of it's to be logged, with a
+    /// warning instead if it overran
+    fn logging(&self, part: &[impl Copy], what: &str) -> Option<CaptureHeader> {
+        let header = self.header.filter(|_| !part.is_empty())?;
+        if !header.trusted {
+            warn!("{} not logged, the capture overran", what);
+            return None;
+        }
+        Some(header)
+    }
+}
+
+impl Sink for LogSink<'_> {
+    fn backpressure(&self) -> Backpressure {
+        Backpressure::Block
+    }
+
+    fn begin_capture(&mut self, header: &CaptureHeader) -> Result<(), TransportError> {
+        self.header = Some(*header);
+        Ok(())
+    }
+
+    fn write_spectrum(&mut self, magnitudes: &[f32]) -> Result<(), TransportError> {
+        if !matches!(self.mode, OutputMode::Spectrum | OutputMode::Bands) {
+            return Ok(());
+        }
+        let Some(header) = self.logging(magnitudes, "Spectrum") else {
+            return Ok(());
+        };
+        if self.mode == OutputMode::Bands {
+            log_bands(magnitudes, header.sample_rate_hz);
+        } else {
+            log_spectrum(magnitudes);
+        }
+        Ok(())
+    }
+
+    fn write_raw(&mut self, samples: &[u16]) -> Result<(), TransportError> {
+        if !matches!(self.mode, OutputMode::Raw | OutputMode::Waveform) {
+            return Ok(());
+        }
+        let Some(header) = self.logging(samples, "Samples") else {
+            return Ok(());
+        };
+        if self.mode == OutputMode::Waveform {
+            log_waveform(samples, header.sample_rate_hz, self.scale);
+        } else {
+            log_raw(samples);
+        }
+        Ok(())
+    }
+
+    fn write_bands(&mut self, bands: &[f32]) -> Result<(), TransportError> {
+        if self.mode == OutputMode::Mel && self.logging(bands, "Spectrum").is_some() {
+            log_mel(bands);
+        }
+        Ok(())
+    }
+
+    fn end_capture(&mut self) -> Result<(), TransportError> {
+        self.header = None;
+        Ok(())
+    }
+}
+
+/// The SD card as a `Sink`, saving the samples of every capture that
+/// didn't overrun as a WAV file. A card that fails stops saving, which
+/// `SdLogger` logs, so it never fails itself.
+#[cfg(feature = "sd-card")]
+pub struct CardSink<'a> {
+    sd: &'a mut utilities::sd::SdLogger,
+    scale: &'a AdcScale,
+    header: Option<CaptureHeader>,
+}
+
+#[cfg(feature = "sd-card")]
+impl<'a> CardSink<'a> {
+    pub fn new(sd: &'a mut utilities::sd::SdLogger, scale: &'a AdcScale) -> Self {
+        Self {
+            sd,
+            scale,
+            header: None,
+        }
+    }
+}
+
+#[cfg(feature = "sd-card")]
+impl Sink for CardSink<'_> {
+    fn backpressure(&self) -> Backpressure {
+        Backpressure::Block
+    }
+
+    fn begin_capture(&mut self, header: &CaptureHeader) -> Result<(), TransportError> {
+        self.header = Some(*header);
+        Ok(())
+    }
+
+    fn write_spectrum(&mut self, _magnitudes: &[f32]) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    fn write_raw(&mut self, samples: &[u16]) -> Result<(), TransportError> {
+        if let Some(header) = self.header.filter(|header| header.trusted) {
+            if !samples.is_empty() {
+                self.sd
+                    .write_capture(samples, header.sample_rate_hz, self.scale);
+            }
+        }
+        Ok(())
+    }
+
+    fn end_capture(&mut self) -> Result<(), TransportError> {
+        self.header = None;
+        Ok(())
+    }
+}
+
+/// Keep a capture in flash, for `dump`, unless it's gone to `card`, the SD
+/// card if captures are being saved to it
+#[cfg(feature = "qspi-flash")]
+pub fn keep_capture(
+    #[cfg(feature = "sd-card")] card: Option<&utilities::sd::SdLogger>,
+    flash: &mut utilities::qspi::Flash,
+    samples: &[u16],
+    capture: &CaptureInfo,
+    scale: &AdcScale,
+    scb: &mut SCB,
+) {
+    #[cfg(feature = "sd-card")]
+    if card.is_some_and(|sd| sd.saving()) {
+        return;
+    }
+    flash.save(
+        samples,
+        capture.captured_at_us,
+        capture.sample_rate_hz,
+        scale.bits(),
+        scb,
+    );
+}
+
+/// Send a capture stored in flash as protocol frames, for `dump`
+#[cfg(feature = "qspi-flash")]
+pub fn dump_slot(
+    console: &mut Console,
+    flash: &utilities::qspi::Flash,
+    slot: u8,
+    scale: &AdcScale,
+) {
+    let Some((header, samples)) = flash.stored(slot as usize) else {
+        console.reply(format_args!("error: nothing stored in slot {}", slot));
+        return;
+    };
+    let capture = CaptureInfo {
+        valid: samples.len(),
+        trusted: true,
+        captured_at_us: header.timestamp_us,
+        sample_rate_hz: header.sample_rate_hz,
+        buffer_crc: header.samples_crc,
+        die_degc: None,
+        // Stored as captured, before any correction
+        offset_correction: 0,
+    };
+    send_frames(console, &capture, scale, &[], samples);
+}
@@ -0,0 +1,164 @@
+//! What `fft_rtic`'s tasks do with each buffer, leaving the binary only the
+//! RTIC app that hands the buffers on: a `Processor` for `process` to turn
+//! them into spectra, and a `ConsoleOutput` for `output` to send them.
+
+use log::{info, warn};
+
+use cortex_m::peripheral::DWT;
+
+use crate::board::{CaptureInfo, Source, SIZE};
+use crate::config::{Config, Limits, Output};
+use crate::dsp::pipeline::{self, Peak};
+use crate::dsp::scaling::AdcScale;
+use crate::dsp::window::{apply_window, WindowType};
+use crate::timing::elapsed_cycles;
+use crate::utilities::{self, console::Console};
+
+use super::send_frames;
+
+/// Applied to each buffer before its FFT
+const TASK_WINDOW: WindowType = WindowType::Hann;
+
+/// A gap between buffers this much longer than a buffer takes to convert
+/// means acquisition stalled
+const STALL_FACTOR: f32 = 1.5;
+
+/// What `process` hands `output`
+pub struct Spectrum {
+    capture: CaptureInfo,
+    magnitudes: [f32; SIZE / 2],
+    peak: Option<Peak>,
+    /// Copying, windowing, the FFT and the peak
+    process_cycles: u32,
+    /// From `dma` handing the buffer over to `process` starting on it
+    latency_cycles: u32,
+    /// Buffers `process` lost, and spectra `output` was too busy for, so far
+    buffers_lost: u32,
+    spectra_skipped: u32,
+}
+
+/// The source and everything `process` keeps between buffers
+pub struct Processor {
+    source: Source,
+    scale: AdcScale,
+    raw: [u16; SIZE],
+    samples: [f32; SIZE],
+    buffers_lost: u32,
+    spectra_skipped: u32,
+}
+
+impl Processor {
+    /// Process the buffers `source`, already started, fills
+    pub fn new(source: Source, scale: AdcScale) -> Self {
+        Self {
+            source,
+            scale,
+            raw: [0; SIZE],
+            samples: [0.0; SIZE],
+            buffers_lost: 0,
+            spectra_skipped: 0,
+        }
+    }
+
+    /// Copy out the buffer `dma` handed over at `handed_over_at`, window it,
+    /// FFT it and find the peak. `None`, and counted, if it wasn't complete.
+    pub fn spectrum(&mut self, handed_over_at: u32) -> Option<Spectrum> {
+        let start = DWT::cycle_count();
+        let capture = self.source.capture(&mut self.raw);
+        if !capture.complete() {
+            self.buffers_lost += 1;
+            return None;
+        }
+
+        pipeline::remove_mean(&self.raw[..], &mut self.samples[..]);
+        apply_window(&mut self.samples, TASK_WINDOW);
+        let mut magnitudes = [0.0; SIZE / 2];
+        pipeline::magnitudes(&mut self.samples, &self.scale, &mut magnitudes);
+        let peak = pipeline::peak(&magnitudes, capture.sample_rate_hz);
+
+        Some(Spectrum {
+            capture,
+            magnitudes,
+            peak,
+            process_cycles: elapsed_cycles(start, DWT::cycle_count()),
+            latency_cycles: elapsed_cycles(handed_over_at, start),
+            buffers_lost: self.buffers_lost,
+            spectra_skipped: self.spectra_skipped,
+        })
+    }
+
+    /// Count a spectrum `output` was still too busy to take
+    pub fn skipped(&mut self) {
+        self.spectra_skipped += 1;
+    }
+}
+
+/// The console and everything `output` needs to send a spectrum on it
+pub struct ConsoleOutput {
+    console: Console,
+    config: Config,
+    limits: Limits,
+    scale: AdcScale,
+}
+
+impl ConsoleOutput {
+    pub fn new(console: Console, config: Config, limits: Limits, scale: AdcScale) -> Self {
+        Self {
+            console,
+            config,
+            limits,
+            scale,
+        }
+    }
+
+    /// Apply any commands that have arrived, keeping the rate `fft_rtic`
+    /// booted with, and say whether to send the next spectrum
+    pub fn poll(&mut self) -> bool {
+        let rate_hz = self.config.rate_hz;
+        self.console.poll(&mut self.config, &self.limits);
+        if self.config.rate_hz != rate_hz {
+            warn!("fft_rtic keeps the rate it booted with");
+            self.config.rate_hz = rate_hz;
+        }
+        self.config.running
+    }
+
+    /// Send `spectrum` as frames, or its peak as a reply if frames are off,
+    /// returning the cycles it took
+    pub fn send(&mut self, spectrum: &Spectrum) -> u32 {
+        let start = DWT::cycle_count();
+        if self.config.output.contains(Output::Frames) {
+            send_frames(
+                &mut self.console,
+                &spectrum.capture,
+                &self.scale,
+                &spectrum.magnitudes,
+                &[],
+            );
+        } else if let Some(peak) = spectrum.peak {
+            self.console
+                .reply(format_args!("peak {} Hz, bin {}", peak.hz, peak.bin));
+        }
+        elapsed_cycles(start, DWT::cycle_count())
+    }
+}
+
+/// Log where the cycles went, and whether acquisition kept up through the
+/// output: `during` buffers arrived while it took `output_cycles`, at most
+/// `longest_gap` cycles apart
+pub fn log_timing(spectrum: &Spectrum, output_cycles: u32, during: u32, longest_gap: u32) {
+    let buffer_cycles =
+        SIZE as f32 / spectrum.capture.sample_rate_hz * utilities::clocks::core_hz() as f32;
+    info!(
+        "Process took {} cycles, {} after the buffer arrived; output {} cycles",
+        spectrum.process_cycles, spectrum.latency_cycles, output_cycles
+    );
+    info!(
+        "During output: {} buffers, longest gap {} cycles against {} a buffer; {} lost, \
+         {} spectra skipped",
+        during, longest_gap, buffer_cycles as u32, spectrum.buffers_lost, spectrum.spectra_skipped
+    );
+    if longest_gap as f32 > buffer_cycles * STALL_FACTOR {
+        warn!("Acquisition stalled while output was in progress");
+    }
+}
@@ -0,0 +1,184 @@
+//! A lock-free queue for handing buffer indices from an interrupt to main.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A single-producer, single-consumer ring of up to `N` values.
+///
+/// One context (e.g. the DMA-complete interrupt) may `enqueue` and one other
+/// (e.g. main) may `dequeue`; that's a contract of use rather than something
+/// the types enforce, since both sides share a `static`.
+///
+/// `head` and `tail` count modulo `2 * N` rather than `N`, so equal
+/// positions mean empty and positions `N` apart mean full, and all `N`
+/// slots are usable.
+pub struct SampleRing<const N: usize> {
+    slots: UnsafeCell<[usize; N]>,
+    /// Next position to write, only stored by the producer
+    head: AtomicUsize,
+    /// Next position to read, only stored by the consumer
+    tail: AtomicUsize,
+}
+
+// Safety: a slot is only written by the producer while it's outside
+// `tail..head`, and only read by the consumer while it's inside, with the
+// release/acquire pairs on head and tail ordering the slot accesses.
+unsafe impl<const N: usize> Sync for SampleRing<N> {}
+
+impl<const N: usize> SampleRing<N> {
+    pub const fn new() -> Self {
+        assert!(N > 0, "a SampleRing needs at least one slot");
+        Self {
+            slots: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// The slot for a position. A raw pointer rather than a reference, as
+    /// the other side may be using a different slot of the same array.
+    fn slot(&self, position: usize) -> *mut usize {
+        // Safety: `position % N` is always in bounds
+        unsafe { self.slots.get().cast::<usize>().add(position % N) }
+    }
+
+    fn distance(head: usize, tail: usize) -> usize {
+        (head + 2 * N - tail) % (2 * N)
+    }
+
+    /// Number of values waiting. Only a snapshot if the other side is active.
+    pub fn len(&self) -> usize {
+        Self::distance(
+            self.head.load(Ordering::Acquire),
+            self.tail.load(Ordering::Acquire),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    /// Add `value` at the back. Producer only.
+    /// Hands `value` back if the ring is full.
+    pub fn enqueue(&self, value: usize) -> Result<(), usize> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if Self::distance(head, tail) == N {
+            return Err(value);
+        }
+
+        // Safety: the slot isn't between tail and head, so the consumer won't
+        // touch it until the store to head below publishes it
+        unsafe { self.slot(head).write(value) };
+        self.head.store((head + 1) % (2 * N), Ordering::Release);
+        Ok(())
+    }
+
+    /// Take the value at the front. Consumer only.
+    pub fn dequeue(&self) -> Option<usize> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        // Safety: the slot is between tail and head, so the producer won't
+        // reuse it until the store to tail below frees it
+        let value = unsafe { self.slot(tail).read() };
+        self.tail.store((tail + 1) % (2 * N), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<const N: usize> Default for SampleRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn empty_and_full() {
+        let ring: SampleRing<3> = SampleRing::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.dequeue(), None);
+
+        for i in 0..3 {
+            assert_eq!(ring.enqueue(i), Ok(()));
+        }
+        assert!(ring.is_full());
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.enqueue(99), Err(99));
+
+        assert_eq!(ring.dequeue(), Some(0));
+        assert!(!ring.is_full());
+        assert_eq!(ring.enqueue(3), Ok(()));
+        assert_eq!(ring.enqueue(4), Err(4));
+    }
+
+    #[test]
+    fn fifo_order_across_many_wraps() {
+        let ring: SampleRing<4> = SampleRing::new();
+
+        for i in 0..100 {
+            ring.enqueue(2 * i).unwrap();
+            ring.enqueue(2 * i + 1).unwrap();
+            assert_eq!(ring.dequeue(), Some(2 * i));
+            assert_eq!(ring.dequeue(), Some(2 * i + 1));
+            assert!(ring.is_empty());
+        }
+    }
+
+    #[test]
+    fn single_slot() {
+        let ring: SampleRing<1> = SampleRing::new();
+        assert_eq!(ring.enqueue(7), Ok(()));
+        assert!(ring.is_full());
+        assert_eq!(ring.enqueue(8), Err(8));
+        assert_eq!(ring.dequeue(), Some(7));
+        assert!(ring.is_empty());
+    }
+
+    /// A producer thread standing in for the ISR, racing main as consumer.
+    /// Yielding when blocked keeps this quick on a single core.
+    #[test]
+    fn concurrent_producer_and_consumer() {
+        const COUNT: usize = 50_000;
+        static RING: SampleRing<3> = SampleRing::new();
+
+        let producer = thread::spawn(|| {
+            for i in 0..COUNT {
+                while RING.enqueue(i).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < COUNT {
+            match RING.dequeue() {
+                Some(value) => {
+                    assert_eq!(value, expected, "lost, duplicated or reordered");
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+            assert!(RING.len() <= RING.capacity());
+        }
+
+        producer.join().unwrap();
+        assert!(RING.is_empty());
+    }
+}
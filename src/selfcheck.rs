@@ -0,0 +1,131 @@
+//! Sanity checks on a capture, for `ci-test` runs on a real board: that the
+//! ADC delivered a whole clean buffer that actually moves, that its
+//! spectrum is all numbers, and that taking the mean off left no DC.
+
+use micromath::F32Ext;
+
+use crate::dsp::{fft, pipeline};
+
+/// Least variance, in counts², for the input to be moving at all. Even a
+/// grounded input has more noise than this, a stuck ADC has none.
+pub const MIN_VARIANCE: f32 = 0.1;
+
+/// Most DC left in bin 0 after the mean is taken off, per sample in counts.
+/// It's only rounding in the mean and the FFT's sums, so it's tiny.
+pub const MAX_DC_PER_SAMPLE: f32 = 0.01;
+
+/// How a capture fared against each check
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Report {
+    /// Every sample arrived, and the ADC didn't overrun
+    pub complete: bool,
+    /// Of the raw codes, in counts²
+    pub variance: f32,
+    /// Bins with an infinite or NaN part
+    pub non_finite_bins: usize,
+    /// DC left in the spectrum of the normalized samples, per sample
+    pub dc_per_sample: f32,
+}
+
+impl Report {
+    pub fn variance_ok(&self) -> bool {
+        self.variance >= MIN_VARIANCE
+    }
+
+    pub fn spectrum_ok(&self) -> bool {
+        self.non_finite_bins == 0
+    }
+
+    pub fn dc_ok(&self) -> bool {
+        self.dc_per_sample <= MAX_DC_PER_SAMPLE
+    }
+
+    pub fn passed(&self) -> bool {
+        self.complete && self.variance_ok() && self.spectrum_ok() && self.dc_ok()
+    }
+}
+
+/// Check `raw`, a whole capture, using `samples` as scratch for its FFT.
+/// `complete` is whether the capture delivered all of it cleanly. Panics
+/// unless the lengths match, or if the FFT length isn't supported.
+pub fn check(raw: &[u16], complete: bool, samples: &mut [f32]) -> Report {
+    pipeline::remove_mean(raw, samples);
+    let variance = samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32;
+
+    let spectrum = fft::rfft(samples);
+    let non_finite_bins = spectrum
+        .iter()
+        .filter(|bin| !bin.re.is_finite() || !bin.im.is_finite())
+        .count();
+    // Bin 0's imaginary part is the Nyquist bin, only the real part is DC
+    let dc_per_sample = spectrum[0].re.abs() / raw.len() as f32;
+
+    Report {
+        complete,
+        variance,
+        non_finite_bins,
+        dc_per_sample,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone() -> [u16; 1024] {
+        core::array::from_fn(|i| {
+            let phase = 2.0 * core::f32::consts::PI * 37.0 * i as f32 / 1024.0;
+            (30_000.0 + 2_000.0 * phase.sin()) as u16
+        })
+    }
+
+    #[test]
+    fn a_good_capture_passes() {
+        let report = check(&tone(), true, &mut [0.0; 1024]);
+        assert!(report.passed(), "{report:?}");
+        // A sine's variance is half its amplitude squared
+        assert!((report.variance / 2e6 - 1.0).abs() < 0.01, "{report:?}");
+    }
+
+    #[test]
+    fn an_incomplete_capture_fails() {
+        let report = check(&tone(), false, &mut [0.0; 1024]);
+        assert!(!report.passed());
+        assert!(report.variance_ok() && report.spectrum_ok() && report.dc_ok());
+    }
+
+    #[test]
+    fn a_stuck_adc_fails() {
+        let report = check(&[12_345; 1024], true, &mut [0.0; 1024]);
+        assert_eq!(report.variance, 0.0);
+        assert!(!report.variance_ok());
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn each_check_has_its_say() {
+        let good = Report {
+            complete: true,
+            variance: 1.0,
+            non_finite_bins: 0,
+            dc_per_sample: 0.0,
+        };
+        assert!(good.passed());
+        assert!(!Report {
+            non_finite_bins: 1,
+            ..good
+        }
+        .passed());
+        assert!(!Report {
+            dc_per_sample: 0.5,
+            ..good
+        }
+        .passed());
+        // NaN is no good either
+        assert!(!Report {
+            variance: f32::NAN,
+            ..good
+        }
+        .passed());
+    }
+}
@@ -0,0 +1,151 @@
+//! How captures are laid out in flash, in a ring of fixed-size slots.
+//!
+//! Each slot is one erase sector: a `SlotHeader` at the start, then the
+//! samples as little-endian `u16`s. The header is programmed last, after the
+//! samples, so a slot whose header checks out was written in full. Erased
+//! flash reads as all ones, which never has the magic number, so empty and
+//! half-written slots both read as empty.
+//!
+//! Captures go into the slot after the newest one, wrapping around, so every
+//! sector is erased once per lap of the ring rather than the first few
+//! wearing out.
+
+/// Bytes in a slot, a 4 KiB erase sector
+pub const SLOT_LEN: usize = 4096;
+
+/// Bytes at the start of a slot for its header
+pub const HEADER_LEN: usize = 32;
+
+/// Most samples that fit in one slot
+pub const MAX_SAMPLES: usize = (SLOT_LEN - HEADER_LEN) / 2;
+
+/// "CAP1" read as little-endian, at the start of every written slot
+const MAGIC: u32 = u32::from_le_bytes(*b"CAP1");
+
+/// What's stored in front of a capture's samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlotHeader {
+    /// Counts up with each capture stored, so the newest slot is the one
+    /// with the highest
+    pub sequence: u32,
+    /// Monotonic time the capture finished
+    pub timestamp_us: u64,
+    pub sample_rate_hz: f32,
+    pub resolution_bits: u8,
+    pub samples: u16,
+    /// CRC-32 of the samples as they're stored
+    pub samples_crc: u32,
+}
+
+impl SlotHeader {
+    /// The header as stored: its fields, then a CRC-32 of them from `crc`
+    pub fn to_bytes(&self, crc: impl FnOnce(&[u8]) -> u32) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.timestamp_us.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.sample_rate_hz.to_le_bytes());
+        bytes[20..22].copy_from_slice(&self.samples.to_le_bytes());
+        bytes[22] = self.resolution_bits;
+        // bytes[23] is padding
+        bytes[24..28].copy_from_slice(&self.samples_crc.to_le_bytes());
+        let header_crc = crc(&bytes[..28]);
+        bytes[28..32].copy_from_slice(&header_crc.to_le_bytes());
+        bytes
+    }
+
+    /// Read a stored header back. `None` for an empty or damaged slot, or
+    /// one claiming more samples than fit.
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN], crc: impl FnOnce(&[u8]) -> u32) -> Option<Self> {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        if u32_at(0) != MAGIC || crc(&bytes[..28]) != u32_at(28) {
+            return None;
+        }
+        let header = Self {
+            sequence: u32_at(4),
+            timestamp_us: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            sample_rate_hz: f32::from_bits(u32_at(16)),
+            samples: u16::from_le_bytes([bytes[20], bytes[21]]),
+            resolution_bits: bytes[22],
+            samples_crc: u32_at(24),
+        };
+        (header.samples as usize <= MAX_SAMPLES).then_some(header)
+    }
+}
+
+/// Slot to store the next capture in and the sequence number to give it,
+/// from each slot's header: the one after the newest capture, or the first
+/// if none are stored
+pub fn next_slot(headers: &[Option<SlotHeader>]) -> (usize, u32) {
+    let newest = headers
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, header)| header.map(|h| (slot, h.sequence)))
+        .max_by_key(|&(_, sequence)| sequence);
+
+    match newest {
+        Some((slot, sequence)) => ((slot + 1) % headers.len(), sequence.wrapping_add(1)),
+        None => (0, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::crc32::crc32;
+
+    const HEADER: SlotHeader = SlotHeader {
+        sequence: 12,
+        timestamp_us: 9_876_543_210,
+        sample_rate_hz: 149_104.5,
+        resolution_bits: 16,
+        samples: 1024,
+        samples_crc: 0xDEAD_BEEF,
+    };
+
+    fn stored(sequence: u32) -> Option<SlotHeader> {
+        Some(SlotHeader { sequence, ..HEADER })
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let bytes = HEADER.to_bytes(crc32);
+        assert_eq!(&bytes[..4], b"CAP1");
+        assert_eq!(SlotHeader::from_bytes(&bytes, crc32), Some(HEADER));
+    }
+
+    #[test]
+    fn erased_and_damaged_slots_are_empty() {
+        assert_eq!(SlotHeader::from_bytes(&[0xFF; HEADER_LEN], crc32), None);
+
+        let mut bytes = HEADER.to_bytes(crc32);
+        bytes[10] ^= 1;
+        assert_eq!(SlotHeader::from_bytes(&bytes, crc32), None);
+
+        let too_long = SlotHeader {
+            samples: MAX_SAMPLES as u16 + 1,
+            ..HEADER
+        };
+        assert_eq!(
+            SlotHeader::from_bytes(&too_long.to_bytes(crc32), crc32),
+            None
+        );
+    }
+
+    #[test]
+    fn slots_fill_in_order_then_wrap() {
+        assert_eq!(next_slot(&[None, None, None]), (0, 1));
+        assert_eq!(next_slot(&[stored(1), None, None]), (1, 2));
+        assert_eq!(next_slot(&[stored(1), stored(2), stored(3)]), (0, 4));
+        // After wrapping, overwrite the oldest
+        assert_eq!(next_slot(&[stored(4), stored(2), stored(3)]), (1, 5));
+    }
+
+    #[test]
+    fn a_damaged_slot_is_skipped_over() {
+        // A slot lost to a power cut doesn't reset the ring
+        assert_eq!(next_slot(&[stored(7), None, stored(6)]), (1, 8));
+        assert_eq!(next_slot(&[stored(6), stored(7), None]), (2, 8));
+    }
+}
@@ -0,0 +1,199 @@
+//! Formatting into fixed-size buffers, for log lines built up a piece at a
+//! time, and the CSV that signed samples are dumped as.
+
+use core::fmt;
+use heapless::String;
+
+/// What ends a line that didn't fit
+pub const ELLIPSIS: &str = "...";
+
+/// A `heapless::String` that never fails to write: whatever doesn't fit is
+/// dropped, and the end of what did is replaced with `ELLIPSIS` to show it.
+/// Every write after that is ignored.
+pub struct TruncatingString<const N: usize> {
+    buf: String<N>,
+    truncated: bool,
+}
+
+impl<const N: usize> TruncatingString<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: String::new(),
+            truncated: false,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.buf.as_str()
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.truncated = false;
+    }
+}
+
+impl<const N: usize> fmt::Write for TruncatingString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.truncated {
+            return Ok(());
+        }
+        if self.buf.push_str(s).is_ok() {
+            return Ok(());
+        }
+
+        // Keep as much as leaves room for the ellipsis, cutting back what's
+        // already there if need be, but only ever between characters
+        self.truncated = true;
+        let keep = N.saturating_sub(ELLIPSIS.len());
+        if self.buf.len() > keep {
+            let end = floor_char_boundary(self.buf.as_str(), keep);
+            self.buf.truncate(end);
+        } else {
+            let end = floor_char_boundary(s, keep - self.buf.len());
+            // Fits, as it's no longer than `keep`
+            let _ = self.buf.push_str(&s[..end]);
+        }
+        let room = N - self.buf.len();
+        let _ = self.buf.push_str(&ELLIPSIS[..room.min(ELLIPSIS.len())]);
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for TruncatingString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The first line of `write_samples_signed_csv`
+pub const SIGNED_CSV_HEADER: &str = "index,time_s,value";
+
+/// One row of `write_samples_signed_csv`. Times have 9 decimal places and
+/// values 6, whatever they are, so every row parses the same way in a
+/// spreadsheet. Negative values keep their sign, as the samples are
+/// bipolar, e.g. from `samples::center_samples`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SignedCsvRow {
+    pub index: usize,
+    pub time_s: f64,
+    pub value: f32,
+}
+
+impl fmt::Display for SignedCsvRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{:.9},{:.6}", self.index, self.time_s, self.value)
+    }
+}
+
+/// A row for each of `samples`, timed from the first at `sample_rate_hz`.
+/// Times are worked out in double precision, as a float's 24 bits would
+/// run out of digits for the later rows.
+pub fn signed_csv_rows(
+    samples: &[f32],
+    sample_rate_hz: f32,
+) -> impl Iterator<Item = SignedCsvRow> + '_ {
+    samples
+        .iter()
+        .enumerate()
+        .map(move |(index, &value)| SignedCsvRow {
+            index,
+            time_s: index as f64 / sample_rate_hz as f64,
+            value,
+        })
+}
+
+/// Write `samples` as CSV, `SIGNED_CSV_HEADER` and then a line for each
+/// from `signed_csv_rows`, for time-domain waveforms rather than spectra
+pub fn write_samples_signed_csv(
+    out: &mut impl fmt::Write,
+    samples: &[f32],
+    sample_rate_hz: f32,
+) -> fmt::Result {
+    writeln!(out, "{}", SIGNED_CSV_HEADER)?;
+    for row in signed_csv_rows(samples, sample_rate_hz) {
+        writeln!(out, "{}", row)?;
+    }
+    Ok(())
+}
+
+/// The largest index no greater than `index` that starts a character
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    if index >= s.len() {
+        return s.len();
+    }
+    (0..=index)
+        .rev()
+        .find(|&i| s.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn fits_unchanged() {
+        let mut line: TruncatingString<16> = TruncatingString::new();
+        write!(line, "{},{}", 3, 4.5).unwrap();
+        assert_eq!(line.as_str(), "3,4.5");
+        assert!(!line.truncated());
+    }
+
+    #[test]
+    fn overflow_ends_in_an_ellipsis() {
+        let mut line: TruncatingString<10> = TruncatingString::new();
+        write!(line, "0123456789abc").unwrap();
+        assert_eq!(line.as_str(), "0123456...");
+        assert!(line.truncated());
+        // Nothing more goes on the end
+        write!(line, "x").unwrap();
+        assert_eq!(line.as_str(), "0123456...");
+
+        // Cut back into what was already there, not just the last write
+        line.clear();
+        write!(line, "012345678").unwrap();
+        write!(line, "9a").unwrap();
+        assert_eq!(line.as_str(), "0123456...");
+    }
+
+    #[test]
+    fn signed_csv_keeps_signs_and_precision() {
+        let mut csv: String<128> = String::new();
+        write_samples_signed_csv(&mut csv, &[-0.5, 0.25, 1.0 / 3.0], 48_000.0).unwrap();
+        assert_eq!(
+            csv.as_str(),
+            "index,time_s,value\n\
+             0,0.000000000,-0.500000\n\
+             1,0.000020833,0.250000\n\
+             2,0.000041667,0.333333\n"
+        );
+
+        // A late row, a long way into a slow capture, still gets every digit
+        let row = SignedCsvRow {
+            index: 1_000_000,
+            time_s: 1_000_000.0 / 48_000.0,
+            value: -1e-4,
+        };
+        let mut line: String<32> = String::new();
+        write!(line, "{row}").unwrap();
+        assert_eq!(line.as_str(), "1000000,20.833333333,-0.000100");
+    }
+
+    #[test]
+    fn overflow_only_cuts_between_characters() {
+        let mut line: TruncatingString<8> = TruncatingString::new();
+        write!(line, "ab°°°°").unwrap();
+        assert_eq!(line.as_str(), "ab°...");
+
+        // Too small for all of the ellipsis
+        let mut tiny: TruncatingString<2> = TruncatingString::new();
+        write!(tiny, "abc").unwrap();
+        assert_eq!(tiny.as_str(), "..");
+    }
+}
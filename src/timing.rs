@@ -0,0 +1,181 @@
+//! Arithmetic for hardware timers: extending them past their width, timing
+//! with the cycle counter, and setting the watchdog's timeout.
+
+/// Combine a wrap count and a 32-bit counter reading into one 64-bit count.
+///
+/// `wrap_pending` is whether the counter had wrapped without the interrupt
+/// having counted it yet, which happens when reading from inside a critical
+/// section or a higher priority interrupt. A small count then belongs to the
+/// next wrap, while a large one was read before the wrap, so was already
+/// covered by `wraps`.
+pub fn extend(wraps: u32, count: u32, wrap_pending: bool) -> u64 {
+    let wraps = if wrap_pending && count < u32::MAX / 2 {
+        wraps as u64 + 1
+    } else {
+        wraps as u64
+    };
+
+    (wraps << 32) | count as u64
+}
+
+/// Cycles from `start` to `end`, two readings of the 32-bit DWT cycle
+/// counter. It wraps every few seconds at full speed, 8.9 s at 480 MHz,
+/// which wrapping subtraction ignores as long as the two are less than one
+/// wrap apart.
+pub fn elapsed_cycles(start: u32, end: u32) -> u32 {
+    end.wrapping_sub(start)
+}
+
+/// `cycles` of a `core_hz` clock in microseconds, zero if the clock's not
+/// known yet. Worked out in double precision, as a float's 24 bits would
+/// round off counts past about 16 million.
+pub fn cycles_to_us(cycles: u32, core_hz: u32) -> f32 {
+    if core_hz == 0 {
+        return 0.0;
+    }
+    (cycles as f64 * 1e6 / core_hz as f64) as f32
+}
+
+/// How buffers arrived while something slow, like writing out a spectrum,
+/// was under way, from cycle counter readings as each one came in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Arrivals {
+    last_at: Option<u32>,
+    watching: bool,
+    during: u32,
+    longest_gap_cycles: u32,
+}
+
+impl Arrivals {
+    /// A buffer arrived at `at` cycles
+    pub fn buffer(&mut self, at: u32) {
+        if let (Some(last), true) = (self.last_at, self.watching) {
+            self.during += 1;
+            self.longest_gap_cycles = self.longest_gap_cycles.max(elapsed_cycles(last, at));
+        }
+        self.last_at = Some(at);
+    }
+
+    /// Start counting arrivals, and the gaps before them
+    pub fn begin(&mut self) {
+        self.watching = true;
+        self.during = 0;
+        self.longest_gap_cycles = 0;
+    }
+
+    /// How many buffers arrived since `begin`, and the longest gap before one
+    pub fn end(&mut self) -> (u32, u32) {
+        self.watching = false;
+        (self.during, self.longest_gap_cycles)
+    }
+}
+
+/// Largest IWDG reload value, RLR is 12 bits
+pub const WATCHDOG_MAX_RELOAD: u16 = 0xFFF;
+
+/// IWDG prescaler register value and reload for a watchdog that fires
+/// `timeout_ms` after its last feed, counting `lsi_hz`. The finest
+/// prescaler that fits is used, rounding the timeout down so it never
+/// fires later than asked. `None` for a timeout too long for the watchdog,
+/// or too short to count.
+pub fn watchdog_settings(lsi_hz: u32, timeout_ms: u32) -> Option<(u8, u16)> {
+    // PR from 0 to 6 divides by 4 up to 256
+    (0..=6u8).find_map(|pr| {
+        let divider = 4u64 << pr;
+        let ticks = lsi_hz as u64 * timeout_ms as u64 / 1000 / divider;
+        if ticks == 0 || ticks > WATCHDOG_MAX_RELOAD as u64 + 1 {
+            return None;
+        }
+        Some((pr, (ticks - 1) as u16))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_pending_wrap() {
+        assert_eq!(extend(0, 0, false), 0);
+        assert_eq!(extend(3, 42, false), (3 << 32) | 42);
+        assert_eq!(extend(3, u32::MAX, false), (3 << 32) | u32::MAX as u64);
+    }
+
+    #[test]
+    fn pending_wrap_with_a_small_count_is_counted() {
+        // Read just after the counter wrapped, before the interrupt ran
+        assert_eq!(extend(3, 5, true), (4 << 32) | 5);
+        assert_eq!(extend(0, 0, true), 1 << 32);
+    }
+
+    #[test]
+    fn pending_wrap_with_a_count_near_max_is_not() {
+        // Read just before the wrap, which then went pending
+        assert_eq!(
+            extend(3, u32::MAX - 5, true),
+            (3 << 32) | (u32::MAX - 5) as u64
+        );
+        assert_eq!(extend(3, u32::MAX, true), (3 << 32) | u32::MAX as u64);
+    }
+
+    #[test]
+    fn time_never_goes_backwards_across_a_wrap() {
+        let before = extend(7, u32::MAX, true);
+        let after = extend(7, 0, true);
+        assert_eq!(after, before + 1);
+    }
+
+    #[test]
+    fn cycle_deltas_span_the_wrap() {
+        assert_eq!(elapsed_cycles(100, 350), 250);
+        assert_eq!(elapsed_cycles(u32::MAX - 9, 20), 30);
+        assert_eq!(elapsed_cycles(7, 7), 0);
+
+        assert_eq!(cycles_to_us(480, 480_000_000), 1.0);
+        assert_eq!(
+            cycles_to_us(elapsed_cycles(u32::MAX - 95, 864), 96_000_000),
+            10.0
+        );
+        // A whole wrap, rounded once to what a float holds at 44 s
+        assert!((cycles_to_us(u32::MAX, 96_000_000) - 44_739_242.7).abs() < 4.0);
+        assert_eq!(cycles_to_us(1_000, 0), 0.0);
+    }
+
+    #[test]
+    fn arrivals_count_only_while_watching() {
+        let mut arrivals = Arrivals::default();
+        arrivals.buffer(1_000);
+        arrivals.begin();
+        // The gap before the first one counts, from the last seen before
+        arrivals.buffer(3_000);
+        arrivals.buffer(3_500);
+        assert_eq!(arrivals.end(), (2, 2_000));
+        arrivals.buffer(10_000);
+        arrivals.begin();
+        assert_eq!(arrivals.end(), (0, 0));
+
+        // Across the counter wrapping
+        arrivals.buffer(u32::MAX - 99);
+        arrivals.begin();
+        arrivals.buffer(400);
+        assert_eq!(arrivals.end(), (1, 500));
+    }
+
+    #[test]
+    fn watchdog_uses_the_finest_prescaler_that_fits() {
+        // 32 kHz / 4 counts 8 ticks a millisecond, up to 512 ms
+        assert_eq!(watchdog_settings(32_000, 500), Some((0, 3_999)));
+        assert_eq!(watchdog_settings(32_000, 512), Some((0, 4_095)));
+        assert_eq!(watchdog_settings(32_000, 513), Some((1, 2_051)));
+        // 8 s needs /64, 15.625 kHz / 1000 ticks a second
+        assert_eq!(watchdog_settings(32_000, 8_000), Some((4, 3_999)));
+        // The longest, /256 with a full reload
+        assert_eq!(watchdog_settings(32_000, 32_768), Some((6, 4_095)));
+    }
+
+    #[test]
+    fn watchdog_timeouts_out_of_range_are_refused() {
+        assert_eq!(watchdog_settings(32_000, 33_000), None);
+        assert_eq!(watchdog_settings(32_000, 0), None);
+    }
+}
@@ -0,0 +1,160 @@
+//! Playing a frequency on a timer's PWM output: the prescaler and reload to
+//! make it, and gliding between frequencies so changes don't click.
+
+/// Prescaler and auto-reload for a PWM frequency, as PSC and ARR take them.
+/// The output runs at `timer_hz / ((prescaler + 1) * (reload + 1))`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PwmSettings {
+    pub prescaler: u16,
+    pub reload: u16,
+}
+
+impl PwmSettings {
+    /// Compare value for a square wave, high for half of each period, which
+    /// is `reload + 1` counts
+    pub fn half_duty(&self) -> u16 {
+        self.reload.div_ceil(2)
+    }
+
+    /// The frequency that comes out of a timer counting `timer_hz`
+    pub fn frequency_hz(&self, timer_hz: u32) -> f32 {
+        timer_hz as f32 / ((self.prescaler as f32 + 1.0) * (self.reload as f32 + 1.0))
+    }
+}
+
+/// Settings for the nearest frequency to `hz` a 16-bit timer counting
+/// `timer_hz` makes. The smallest prescaler that fits is used, for the
+/// finest steps. `None` if `hz` is too low even at the largest prescaler,
+/// or too high to have a period of at least two counts.
+pub fn pwm_settings(timer_hz: u32, hz: f32) -> Option<PwmSettings> {
+    if hz.is_nan() || hz <= 0.0 {
+        return None;
+    }
+    let period = timer_hz as f32 / hz;
+    if period < 2.0 {
+        return None;
+    }
+    // Round up, so the reload for it is never over 16 bits
+    let counts_per_tick = period / 65_536.0;
+    let mut prescaler = counts_per_tick as u32;
+    if (prescaler as f32) < counts_per_tick {
+        prescaler += 1;
+    }
+    let prescaler = prescaler.max(1);
+    if prescaler > 65_536 {
+        return None;
+    }
+    let ticks = (period / prescaler as f32 + 0.5) as u32;
+    Some(PwmSettings {
+        prescaler: (prescaler - 1) as u16,
+        reload: (ticks.clamp(2, 65_536) - 1) as u16,
+    })
+}
+
+/// A frequency that follows a target at no more than a set number of
+/// octaves a second, so a new target is swept to rather than jumped to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Glide {
+    hz: f32,
+    octaves_per_second: f32,
+}
+
+impl Glide {
+    pub const fn new(hz: f32, octaves_per_second: f32) -> Self {
+        Self {
+            hz,
+            octaves_per_second,
+        }
+    }
+
+    pub fn hz(&self) -> f32 {
+        self.hz
+    }
+
+    /// Go straight to `hz`, for starting from silence
+    pub fn jump(&mut self, hz: f32) {
+        self.hz = hz;
+    }
+
+    /// Move toward `target_hz` for `dt_s` seconds, and return where it's
+    /// got to. Steps are ratios, so the sweep sounds even at any pitch. For
+    /// small steps a ratio of `1 + x ln 2` is as good as `2^x`, and cheap.
+    pub fn step(&mut self, target_hz: f32, dt_s: f32) -> f32 {
+        let limit = 1.0 + self.octaves_per_second * dt_s * core::f32::consts::LN_2;
+        self.hz = target_hz.clamp(self.hz / limit, self.hz * limit);
+        self.hz
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_make_the_frequency_asked_for() {
+        let timer_hz = 200_000_000;
+        for hz in [100.0, 440.0, 1_000.0, 4_000.0] {
+            let settings = pwm_settings(timer_hz, hz).unwrap();
+            let error = (settings.frequency_hz(timer_hz) - hz).abs() / hz;
+            assert!(error < 1e-3, "{hz}: {settings:?}");
+        }
+
+        // Fast enough for 16 bits on its own, so no prescaling
+        assert_eq!(
+            pwm_settings(48_000_000, 1_000.0),
+            Some(PwmSettings {
+                prescaler: 0,
+                reload: 47_999,
+            })
+        );
+        // 200 MHz at 100 Hz is 2e6 counts, 31 of 65,536
+        assert_eq!(
+            pwm_settings(200_000_000, 100.0),
+            Some(PwmSettings {
+                prescaler: 30,
+                reload: 64_515,
+            })
+        );
+    }
+
+    #[test]
+    fn out_of_range_frequencies_have_no_settings() {
+        // Over 2^32 counts a period
+        assert_eq!(pwm_settings(200_000_000, 0.01), None);
+        assert_eq!(pwm_settings(1_000, 600.0), None);
+        assert_eq!(pwm_settings(1_000_000, 0.0), None);
+        assert_eq!(pwm_settings(1_000_000, f32::NAN), None);
+    }
+
+    #[test]
+    fn half_duty_is_half_the_period() {
+        let settings = PwmSettings {
+            prescaler: 0,
+            reload: 999,
+        };
+        assert_eq!(settings.half_duty(), 500);
+    }
+
+    #[test]
+    fn glide_is_slew_limited() {
+        // Four octaves a second, stepped at 1 kHz
+        let mut glide = Glide::new(1_000.0, 4.0);
+        let hz = glide.step(2_000.0, 0.001);
+        assert!((hz - 1_002.77).abs() < 0.01, "{hz}");
+
+        // An octave takes about a quarter of a second either way
+        let steps = (0..1_000)
+            .take_while(|_| glide.step(2_000.0, 0.001) < 2_000.0)
+            .count();
+        assert!((240..=260).contains(&steps), "{steps}");
+        let steps = (0..1_000)
+            .take_while(|_| glide.step(1_000.0, 0.001) > 1_000.0)
+            .count();
+        assert!((240..=260).contains(&steps), "{steps}");
+
+        // Once there it stays put
+        assert_eq!(glide.step(1_000.0, 0.001), 1_000.0);
+        glide.jump(300.0);
+        assert_eq!(glide.hz(), 300.0);
+    }
+}
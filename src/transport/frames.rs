@@ -0,0 +1,184 @@
+//! Sending captures as `crate::protocol` frames: a header, then the data in
+//! chunks, each checksummed with the CRC unit.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::config::OutputMode;
+use crate::protocol::{
+    self, BandsFrame, BorrowedFrame, CaptureHeader, Frame, FrameError, RawFrame, SpectrumFrame,
+    CHUNK, MAX_FRAME_LEN, MAX_PAYLOAD_LEN,
+};
+
+use super::sink::{self, Backpressure, Sink};
+use super::TransportError;
+use crate::utilities::crc::crc32;
+
+/// Sequence number for the next capture
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+impl From<FrameError> for TransportError {
+    fn from(_: FrameError) -> Self {
+        // Only a frame too big for its buffer fails to encode, and nothing
+        // the caller can do about that
+        TransportError::Io
+    }
+}
+
+/// Send `header` and then `magnitudes`, `samples` and `bands` after it,
+/// through `write`. The header's sequence number and lengths are filled in here.
+pub fn send_capture(
+    write: &mut impl FnMut(&[u8]) -> Result<(), TransportError>,
+    header: CaptureHeader,
+    magnitudes: &[f32],
+    samples: &[u16],
+    bands: &[f32],
+) -> Result<(), TransportError> {
+    let mut sink = FrameSink::new(write, Backpressure::Block);
+    sink::deliver(&mut sink, header, magnitudes, samples, bands)
+}
+
+/// What of a capture goes as frames
+#[derive(Clone, Copy, Debug)]
+struct Parts {
+    spectrum: bool,
+    raw: bool,
+    bands: bool,
+}
+
+impl Parts {
+    const ALL: Self = Self {
+        spectrum: true,
+        raw: true,
+        bands: true,
+    };
+
+    /// The samples, the spectrum or the mel bands, whichever `mode` shows.
+    /// Octave bands, the envelope and the dither comparison are only ever
+    /// logged.
+    fn of(mode: OutputMode) -> Self {
+        Self {
+            spectrum: mode == OutputMode::Spectrum,
+            raw: matches!(mode, OutputMode::Raw | OutputMode::Waveform),
+            bands: mode == OutputMode::Mel,
+        }
+    }
+}
+
+/// Frames as a `Sink`, each one through `write`, whose backpressure the
+/// sink has. The header goes from `begin_capture`, with the sequence number
+/// filled in, and each part in chunks from its own `write_`.
+pub struct FrameSink<W> {
+    write: W,
+    backpressure: Backpressure,
+    /// Only what this mode shows, rather than everything
+    mode: Option<OutputMode>,
+    /// The capture under way, and what of it goes
+    sending: Option<(u32, Parts)>,
+}
+
+impl<W: FnMut(&[u8]) -> Result<(), TransportError>> FrameSink<W> {
+    /// A sink that sends every part of a capture
+    pub fn new(write: W, backpressure: Backpressure) -> Self {
+        Self {
+            write,
+            backpressure,
+            mode: None,
+            sending: None,
+        }
+    }
+
+    /// Send only the part `mode` shows, with the others left out of the
+    /// header, as `output frames` does
+    pub fn showing(self, mode: OutputMode) -> Self {
+        Self {
+            mode: Some(mode),
+            ..self
+        }
+    }
+
+    /// The capture's sequence number, if `part` of it is to go
+    fn sending(&self, part: impl Fn(Parts) -> bool) -> Option<u32> {
+        self.sending
+            .filter(|&(_, parts)| part(parts))
+            .map(|(sequence, _)| sequence)
+    }
+}
+
+impl<W: FnMut(&[u8]) -> Result<(), TransportError>> Sink for FrameSink<W> {
+    fn backpressure(&self) -> Backpressure {
+        self.backpressure
+    }
+
+    fn begin_capture(&mut self, header: &CaptureHeader) -> Result<(), TransportError> {
+        let parts = self.mode.map_or(Parts::ALL, Parts::of);
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let header = CaptureHeader {
+            sequence,
+            bins: if parts.spectrum { header.bins } else { 0 },
+            samples: if parts.raw { header.samples } else { 0 },
+            bands: if parts.bands { header.bands } else { 0 },
+            ..*header
+        };
+        self.sending = Some((sequence, parts));
+        send(&mut self.write, &Frame::Header(header))
+    }
+
+    fn write_spectrum(&mut self, magnitudes: &[f32]) -> Result<(), TransportError> {
+        let Some(sequence) = self.sending(|parts| parts.spectrum) else {
+            return Ok(());
+        };
+        for (i, chunk) in magnitudes.chunks(CHUNK).enumerate() {
+            let frame = Frame::Spectrum(SpectrumFrame {
+                sequence,
+                first_bin: (i * CHUNK) as u16,
+                magnitudes: chunk,
+            });
+            send(&mut self.write, &frame)?;
+        }
+        Ok(())
+    }
+
+    fn write_raw(&mut self, samples: &[u16]) -> Result<(), TransportError> {
+        let Some(sequence) = self.sending(|parts| parts.raw) else {
+            return Ok(());
+        };
+        for (i, chunk) in samples.chunks(CHUNK).enumerate() {
+            let frame = Frame::Raw(RawFrame {
+                sequence,
+                first_sample: (i * CHUNK) as u16,
+                samples: chunk,
+            });
+            send(&mut self.write, &frame)?;
+        }
+        Ok(())
+    }
+
+    fn write_bands(&mut self, bands: &[f32]) -> Result<(), TransportError> {
+        let Some(sequence) = self.sending(|parts| parts.bands) else {
+            return Ok(());
+        };
+        for (i, chunk) in bands.chunks(CHUNK).enumerate() {
+            let frame = Frame::Bands(BandsFrame {
+                sequence,
+                first_band: (i * CHUNK) as u16,
+                energies: chunk,
+            });
+            send(&mut self.write, &frame)?;
+        }
+        Ok(())
+    }
+
+    fn end_capture(&mut self) -> Result<(), TransportError> {
+        self.sending = None;
+        Ok(())
+    }
+}
+
+fn send(
+    write: &mut impl FnMut(&[u8]) -> Result<(), TransportError>,
+    frame: &BorrowedFrame,
+) -> Result<(), TransportError> {
+    let mut scratch = [0; MAX_PAYLOAD_LEN];
+    let mut out = [0; MAX_FRAME_LEN];
+    write(protocol::encode(frame, crc32, &mut scratch, &mut out)?)
+}
@@ -0,0 +1,37 @@
+//! Ways of getting captures off the board.
+//!
+//! Every transport reports failures as a `TransportError`, so the output
+//! pipeline can use `?` no matter which sink it's writing to.
+
+pub mod frames;
+pub mod sink;
+#[cfg(feature = "ethernet")]
+pub mod udp;
+#[cfg(feature = "usb")]
+pub mod usb;
+
+/// Errors shared by every transport
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TransportError {
+    /// Not ready right now, try again later
+    WouldBlock,
+    /// Data arrived or was queued faster than it could be handled, some lost
+    Overrun,
+    /// The underlying peripheral or medium failed
+    Io,
+    /// Didn't finish in the time allowed
+    Timeout,
+}
+
+impl<E> From<nb::Error<E>> for TransportError
+where
+    E: Into<TransportError>,
+{
+    fn from(e: nb::Error<E>) -> Self {
+        match e {
+            nb::Error::WouldBlock => TransportError::WouldBlock,
+            nb::Error::Other(e) => e.into(),
+        }
+    }
+}
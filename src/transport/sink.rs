@@ -0,0 +1,181 @@
+//! Everywhere a capture can go, behind the one trait, so the pipeline
+//! writes each capture to a `&mut dyn Sink` whatever's listening.
+//!
+//! A capture is a `begin_capture`, then its parts, each at most once, then
+//! `end_capture`. A sink takes whichever parts it has a use for and ignores
+//! the rest. How it behaves when it can't keep up is its `Backpressure`,
+//! and it's never anything else: a sink that drops doesn't wait first, and
+//! one that blocks doesn't give up. `Fanout` sends a capture to several,
+//! those that block last, so a slow one never holds up the rest.
+
+use heapless::Vec;
+use log::error;
+
+use crate::protocol::CaptureHeader;
+
+use super::TransportError;
+
+/// What a sink does with a capture it can't take as fast as it comes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Waits until it's all gone, holding up the capture loop but never
+    /// losing any of it: the logger, frames over the UART, the SD card
+    Block,
+    /// Gives up on the rest of the capture straight away, with
+    /// `WouldBlock` or `Timeout`, and takes the next one whole: frames over
+    /// USB with no host listening
+    Drop,
+    /// Queues what it has room for to go later, and drops the rest of the
+    /// capture with `Overrun` once the queue's full: frames over UDP
+    Buffer,
+}
+
+pub trait Sink {
+    fn backpressure(&self) -> Backpressure;
+
+    /// Start on a capture. The header's `bins`, `samples` and `bands` say
+    /// how much of each part is to come.
+    fn begin_capture(&mut self, header: &CaptureHeader) -> Result<(), TransportError>;
+
+    /// The FFT magnitudes, in 16-bit counts
+    fn write_spectrum(&mut self, magnitudes: &[f32]) -> Result<(), TransportError>;
+
+    /// The ADC codes
+    fn write_raw(&mut self, samples: &[u16]) -> Result<(), TransportError>;
+
+    /// Band energies, which most sinks have no use for
+    fn write_bands(&mut self, _bands: &[f32]) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    fn end_capture(&mut self) -> Result<(), TransportError>;
+}
+
+/// Send one capture through `sink`: `header`, with the counts filled in
+/// from the parts, then each part in turn. An error stops the capture there.
+pub fn deliver(
+    sink: &mut dyn Sink,
+    header: CaptureHeader,
+    magnitudes: &[f32],
+    samples: &[u16],
+    bands: &[f32],
+) -> Result<(), TransportError> {
+    let header = CaptureHeader {
+        bins: magnitudes.len() as u16,
+        samples: samples.len() as u16,
+        bands: bands.len() as u16,
+        ..header
+    };
+    sink.begin_capture(&header)?;
+    sink.write_spectrum(magnitudes)?;
+    sink.write_raw(samples)?;
+    sink.write_bands(bands)?;
+    sink.end_capture()
+}
+
+/// Up to `N` sinks as one. A sink that fails part way through a capture is
+/// passed over for the rest of it and takes the next one afresh, so it only
+/// ever loses what it couldn't take. Errors that are a sink's backpressure
+/// at work are left to its own counts, anything else is logged. It never
+/// fails itself.
+pub struct Fanout<'a, const N: usize> {
+    /// Each sink, and whether it's failed on the capture under way
+    sinks: Vec<(&'a mut dyn Sink, bool), N>,
+}
+
+impl<'a, const N: usize> Fanout<'a, N> {
+    pub const fn new() -> Self {
+        Self { sinks: Vec::new() }
+    }
+
+    /// Add a sink, behind the others that block and ahead of the rest.
+    /// Panics past `N`.
+    pub fn push(&mut self, sink: &'a mut dyn Sink) {
+        let at = match sink.backpressure() {
+            Backpressure::Block => self.sinks.len(),
+            _ => self
+                .sinks
+                .iter()
+                .position(|(s, _)| s.backpressure() == Backpressure::Block)
+                .unwrap_or(self.sinks.len()),
+        };
+        assert!(
+            self.sinks.insert(at, (sink, false)).is_ok(),
+            "more than {} sinks",
+            N
+        );
+    }
+
+    /// Run `step` on every sink that hasn't failed on this capture
+    fn each(&mut self, mut step: impl FnMut(&mut dyn Sink) -> Result<(), TransportError>) {
+        for (sink, failed) in self.sinks.iter_mut() {
+            if *failed {
+                continue;
+            }
+            if let Err(e) = step(&mut **sink) {
+                *failed = true;
+                let backpressure = sink.backpressure();
+                let expected = match backpressure {
+                    Backpressure::Block => false,
+                    Backpressure::Drop => {
+                        matches!(e, TransportError::WouldBlock | TransportError::Timeout)
+                    }
+                    Backpressure::Buffer => {
+                        matches!(e, TransportError::WouldBlock | TransportError::Overrun)
+                    }
+                };
+                if !expected {
+                    error!("A {:?} sink failed: {:?}", backpressure, e);
+                }
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for Fanout<'_, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Sink for Fanout<'_, N> {
+    /// As held up as the most held up of its sinks
+    fn backpressure(&self) -> Backpressure {
+        let any = |b| self.sinks.iter().any(|(s, _)| s.backpressure() == b);
+        if any(Backpressure::Block) {
+            Backpressure::Block
+        } else if any(Backpressure::Buffer) {
+            Backpressure::Buffer
+        } else {
+            Backpressure::Drop
+        }
+    }
+
+    fn begin_capture(&mut self, header: &CaptureHeader) -> Result<(), TransportError> {
+        self.sinks
+            .iter_mut()
+            .for_each(|(_, failed)| *failed = false);
+        self.each(|sink| sink.begin_capture(header));
+        Ok(())
+    }
+
+    fn write_spectrum(&mut self, magnitudes: &[f32]) -> Result<(), TransportError> {
+        self.each(|sink| sink.write_spectrum(magnitudes));
+        Ok(())
+    }
+
+    fn write_raw(&mut self, samples: &[u16]) -> Result<(), TransportError> {
+        self.each(|sink| sink.write_raw(samples));
+        Ok(())
+    }
+
+    fn write_bands(&mut self, bands: &[f32]) -> Result<(), TransportError> {
+        self.each(|sink| sink.write_bands(bands));
+        Ok(())
+    }
+
+    fn end_capture(&mut self) -> Result<(), TransportError> {
+        self.each(|sink| sink.end_capture());
+        Ok(())
+    }
+}
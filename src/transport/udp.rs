@@ -0,0 +1,252 @@
+//! Frames over Ethernet, with the `ethernet` feature: each frame is sent as
+//! one UDP datagram to a fixed address, so `nc -ul 9000 > capture.bin` on
+//! that machine collects the same byte stream the serial port carries.
+//!
+//! The Nucleo's LAN8742A PHY is wired to the MAC over RMII on PA1, PA2, PA7,
+//! PC1, PC4, PC5, PG11, PG13 and PB13, in CN14's RJ45 jack. Addresses are
+//! static, there's no DHCP: set `ADDRESS` and `DESTINATION` for the network.
+//!
+//! A whole capture doesn't fit in one datagram, the spectrum alone is 2 KiB
+//! and a datagram carries 1472 bytes unfragmented, but a frame always does.
+//! Each datagram decodes on its own, and one lost on the way costs only the
+//! chunk it carried, which the host sees as a gap in the capture.
+//!
+//! Nothing here waits for the network. With the link down, or the socket's
+//! queue full because frames come faster than the wire takes them, a frame
+//! is dropped and counted, and capture carries on.
+//!
+//! The DMA descriptors and the packet buffers they point to live in SRAM3,
+//! which DMA reaches and the data cache doesn't cover while it's left off,
+//! as it is here. If the cache is ever turned on, SRAM3 needs an MPU region
+//! marking it non-cacheable first.
+
+#[cfg(feature = "usb")]
+compile_error!("The ethernet and usb features both want the frames, pick one");
+
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+use log::info;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet, SocketStorage};
+use smoltcp::socket::udp::{self, PacketBuffer, PacketMetadata};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address};
+use stm32h7xx_hal::ethernet::{self, phy::LAN8742A, DesRing, EthernetDMA, EthernetMAC, PHY};
+use stm32h7xx_hal::gpio::{Speed, PA1, PA2, PA7, PB13, PC1, PC4, PC5, PG11, PG13};
+use stm32h7xx_hal::pac::{self, interrupt, Interrupt};
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+
+use super::TransportError;
+use crate::utilities::monotonic;
+
+/// Locally administered, so it can't clash with a real vendor's
+const MAC: [u8; 6] = [0x02, 0x00, 0x11, 0xE2, 0x48, 0x07];
+
+/// The board's address and prefix length
+const ADDRESS: [u8; 4] = [192, 168, 1, 50];
+const PREFIX_LEN: u8 = 24;
+
+/// Where frames are sent
+const DESTINATION: [u8; 4] = [192, 168, 1, 10];
+const PORT: u16 = 9000;
+
+/// Descriptors each way. Four of each is what the HAL's examples run.
+const TX_DESCRIPTORS: usize = 4;
+const RX_DESCRIPTORS: usize = 4;
+
+/// Frames the socket queues for the wire, a little over one capture's
+/// worth of header and spectrum
+const TX_QUEUE: usize = 8;
+
+type Device = EthernetDMA<TX_DESCRIPTORS, RX_DESCRIPTORS>;
+
+/// The RMII pins, as they come out of reset
+pub type Pins = (PA1, PA2, PC1, PA7, PC4, PC5, PG11, PG13, PB13);
+
+/// The Ethernet peripherals, as the PAC names them
+pub type Eth = (pac::ETHERNET_MAC, pac::ETHERNET_MTL, pac::ETHERNET_DMA);
+
+#[link_section = ".sram3.eth"]
+static mut DES_RING: MaybeUninit<DesRing<TX_DESCRIPTORS, RX_DESCRIPTORS>> = MaybeUninit::uninit();
+
+static mut TX_METADATA: [PacketMetadata<IpEndpoint>; TX_QUEUE] = [PacketMetadata::EMPTY; TX_QUEUE];
+static mut TX_PAYLOAD: [u8; TX_QUEUE * crate::protocol::MAX_FRAME_LEN] =
+    [0; TX_QUEUE * crate::protocol::MAX_FRAME_LEN];
+
+/// Nothing's received, but a socket needs somewhere to put it
+static mut RX_METADATA: [PacketMetadata<IpEndpoint>; 1] = [PacketMetadata::EMPTY; 1];
+static mut RX_PAYLOAD: [u8; 64] = [0; 64];
+
+static mut SOCKETS: [SocketStorage<'static>; 1] = [SocketStorage::EMPTY; 1];
+
+/// The network stack, polled by the interrupt and by `write_frame`
+struct Net {
+    device: Device,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    socket: SocketHandle,
+}
+
+impl Net {
+    fn poll(&mut self) {
+        let now = Instant::from_micros(monotonic::now_us() as i64);
+        self.iface.poll(now, &mut self.device, &mut self.sockets);
+    }
+}
+
+static NET: Mutex<RefCell<Option<Net>>> = Mutex::new(RefCell::new(None));
+
+/// Frames dropped since boot
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Sends frames as UDP datagrams. There's only ever one, `init` makes it.
+pub struct UdpFrames {
+    phy: LAN8742A<EthernetMAC>,
+}
+
+/// Bring up the MAC and PHY and start answering ARP. Only call this once.
+/// Doesn't wait for a link, frames are dropped until there is one.
+pub fn init(eth: Eth, pins: Pins, prec: rec::Eth1Mac, clocks: &CoreClocks) -> UdpFrames {
+    let (ref_clk, mdio, mdc, crs_dv, rxd0, rxd1, tx_en, txd0, txd1) = pins;
+    let pins = (
+        ref_clk.into_alternate::<11>().speed(Speed::VeryHigh),
+        mdio.into_alternate::<11>().speed(Speed::VeryHigh),
+        mdc.into_alternate::<11>().speed(Speed::VeryHigh),
+        crs_dv.into_alternate::<11>().speed(Speed::VeryHigh),
+        rxd0.into_alternate::<11>().speed(Speed::VeryHigh),
+        rxd1.into_alternate::<11>().speed(Speed::VeryHigh),
+        tx_en.into_alternate::<11>().speed(Speed::VeryHigh),
+        txd0.into_alternate::<11>().speed(Speed::VeryHigh),
+        txd1.into_alternate::<11>().speed(Speed::VeryHigh),
+    );
+
+    let mac = EthernetAddress(MAC);
+    // Safety: `init` runs once, so these are the only references
+    let (mut device, eth_mac) = unsafe {
+        let ring = (*core::ptr::addr_of_mut!(DES_RING)).write(DesRing::new());
+        ethernet::new(eth.0, eth.1, eth.2, pins, ring, mac, prec, clocks)
+    };
+
+    let mut phy = LAN8742A::new(eth_mac.set_phy_addr(0));
+    phy.phy_reset();
+    phy.phy_init();
+
+    let now = Instant::from_micros(monotonic::now_us() as i64);
+    let mut iface = Interface::new(
+        Config::new(HardwareAddress::Ethernet(mac)),
+        &mut device,
+        now,
+    );
+    iface.update_ip_addrs(|addrs| {
+        let _ = addrs.push(IpCidr::new(
+            IpAddress::Ipv4(Ipv4Address(ADDRESS)),
+            PREFIX_LEN,
+        ));
+    });
+
+    // Safety: as for the descriptors
+    let (mut sockets, mut socket) = unsafe {
+        let rx = PacketBuffer::new(
+            &mut (*core::ptr::addr_of_mut!(RX_METADATA))[..],
+            &mut (*core::ptr::addr_of_mut!(RX_PAYLOAD))[..],
+        );
+        let tx = PacketBuffer::new(
+            &mut (*core::ptr::addr_of_mut!(TX_METADATA))[..],
+            &mut (*core::ptr::addr_of_mut!(TX_PAYLOAD))[..],
+        );
+        (
+            SocketSet::new(&mut (*core::ptr::addr_of_mut!(SOCKETS))[..]),
+            udp::Socket::new(rx, tx),
+        )
+    };
+    socket
+        .bind(PORT)
+        .expect("a fixed, non-zero port always binds");
+    let socket = sockets.add(socket);
+
+    let net = Net {
+        device,
+        iface,
+        sockets,
+        socket,
+    };
+    cortex_m::interrupt::free(|cs| NET.borrow(cs).replace(Some(net)));
+    // Safety: the handler only polls the stack
+    unsafe {
+        ethernet::enable_interrupt();
+        NVIC::unmask(Interrupt::ETH);
+    }
+
+    info!(
+        "UDP frames from {} to {}:{}",
+        Ipv4Address(ADDRESS),
+        Ipv4Address(DESTINATION),
+        PORT
+    );
+    UdpFrames { phy }
+}
+
+impl UdpFrames {
+    /// Queue one encoded frame as a datagram, or drop it if the link is down
+    /// (`WouldBlock`) or the queue is full (`Overrun`)
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        if !self.phy.poll_link() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return Err(TransportError::WouldBlock);
+        }
+
+        let destination = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address(DESTINATION)), PORT);
+        cortex_m::interrupt::free(|cs| {
+            let mut net = NET.borrow(cs).borrow_mut();
+            let Some(net) = net.as_mut() else {
+                return Err(TransportError::Io);
+            };
+
+            let queued = net
+                .sockets
+                .get_mut::<udp::Socket>(net.socket)
+                .send_slice(frame, destination);
+            // Send what the descriptors have room for, the interrupt sends
+            // the rest as they come free
+            net.poll();
+            match queued {
+                Ok(()) => Ok(()),
+                Err(udp::SendError::BufferFull) => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                    Err(TransportError::Overrun)
+                }
+                Err(udp::SendError::Unaddressable) => Err(TransportError::Io),
+            }
+        })
+    }
+
+    /// Whether the PHY has a link partner
+    pub fn link_up(&mut self) -> bool {
+        self.phy.poll_link()
+    }
+}
+
+/// Frames dropped since boot, for the status log
+pub fn dropped_frames() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[interrupt]
+fn ETH() {
+    // Safety: only clears the interrupt flags
+    unsafe { ethernet::interrupt_handler() };
+
+    cortex_m::interrupt::free(|cs| {
+        let mut net = NET.borrow(cs).borrow_mut();
+        let Some(net) = net.as_mut() else {
+            return;
+        };
+        net.poll();
+
+        // Nothing's expected, so drain anything sent to the port
+        let socket = net.sockets.get_mut::<udp::Socket>(net.socket);
+        while socket.recv().is_ok() {}
+    });
+}
@@ -0,0 +1,208 @@
+//! Frames over USB, with the `usb` feature: a CDC-ACM serial port on the
+//! Nucleo's user USB connector (CN13, OTG2 FS on PA11 and PA12), so it
+//! turns up as /dev/ttyACM* or a COM port with no driver.
+//!
+//! Full speed bulk gets several hundred kB/s through, where the ST-LINK's
+//! COM port manages about 11. The console stays on the ST-LINK, and anything
+//! sent to this port is ignored.
+//!
+//! The OTG interrupt polls the device, so it enumerates and keeps data
+//! moving whatever the capture loop is doing. A frame is only sent while a
+//! host has the port open (DTR set), otherwise it's dropped and counted.
+//! One that can't get out in `FRAME_TIMEOUT_US`, because the host has
+//! stopped reading, is cut short the same way, and the CRC tells the host.
+//!
+//! USB needs an accurate 48 MHz, which comes from HSI48 trimmed by the CRS
+//! against the host's 1 kHz start of frame packets, so no crystal's needed.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+use log::info;
+use stm32h7xx_hal::gpio::{PA11, PA12};
+use stm32h7xx_hal::pac::{self, interrupt, Interrupt};
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::rec::{self, UsbClkSel};
+use stm32h7xx_hal::rcc::CoreClocks;
+use stm32h7xx_hal::usb_hs::{UsbBus, USB2};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{
+    StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid,
+};
+use usbd_serial::SerialPort;
+
+use super::TransportError;
+
+/// The shared VID/PID for CDC-ACM devices, which every OS binds its serial
+/// driver to
+const VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x27dd);
+
+/// Longest a frame may wait for the host to make room, so a host that's
+/// stopped reading only stalls capture briefly
+const FRAME_TIMEOUT_US: u64 = 5_000;
+
+/// Bytes the port buffers for sending, room for a whole frame
+const WRITE_BUFFER: usize = 2048;
+
+const CRS_CR_CEN: u32 = 1 << 5;
+const CRS_CR_AUTOTRIMEN: u32 = 1 << 6;
+/// SYNCSRC = 10, USB2's start of frame, which is also its reset value
+const CRS_CFGR_SYNCSRC_USB2: u32 = 0b10 << 28;
+const CRS_CFGR_SYNCSRC_MASK: u32 = 0b11 << 28;
+
+const PWR_CR3_USB33DEN: u32 = 1 << 24;
+const PWR_CR3_USB33RDY: u32 = 1 << 26;
+
+type Bus = UsbBus<USB2>;
+type Port = SerialPort<'static, Bus, [u8; 128], [u8; WRITE_BUFFER]>;
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+static mut BUS: Option<UsbBusAllocator<Bus>> = None;
+
+/// The device and port, polled by the interrupt and written by `write_frame`
+static USB: Mutex<RefCell<Option<(UsbDevice<'static, Bus>, Port)>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Frames dropped, or cut short, since boot
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Sends frames over USB. There's only ever one, `init` makes it.
+pub struct UsbFrames {
+    _private: (),
+}
+
+/// The OTG2 peripherals, as the PAC names them
+pub type Otg = (
+    pac::OTG2_HS_GLOBAL,
+    pac::OTG2_HS_DEVICE,
+    pac::OTG2_HS_PWRCLK,
+);
+
+/// Bring up USB and start answering the host. Only call this once.
+pub fn init(
+    otg: Otg,
+    pins: (PA11, PA12),
+    prec: rec::Usb2Otg,
+    crs: pac::CRS,
+    crs_prec: rec::Crs,
+    clocks: &CoreClocks,
+) -> UsbFrames {
+    assert!(clocks.hsi48_ck().is_some(), "USB needs HSI48 running");
+
+    // Trim HSI48 to the host's start of frame packets
+    crs_prec.enable();
+    crs.cfgr.modify(|r, w| unsafe {
+        w.bits(r.bits() & !CRS_CFGR_SYNCSRC_MASK | CRS_CFGR_SYNCSRC_USB2)
+    });
+    crs.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | CRS_CR_AUTOTRIMEN | CRS_CR_CEN) });
+
+    // The transceiver runs from VDD33USB, which is only monitored on request
+    // Safety: only this bit of CR3 is changed
+    let pwr = unsafe { &*pac::PWR::ptr() };
+    pwr.cr3
+        .modify(|r, w| unsafe { w.bits(r.bits() | PWR_CR3_USB33DEN) });
+    while pwr.cr3.read().bits() & PWR_CR3_USB33RDY == 0 {}
+
+    let (global, device, pwrclk) = otg;
+    let usb = USB2::new(
+        global,
+        device,
+        pwrclk,
+        pins.0.into_alternate(),
+        pins.1.into_alternate(),
+        prec.kernel_clk_mux(UsbClkSel::Hsi48),
+        clocks,
+    );
+
+    // Safety: `init` runs once, so these are the only references
+    let bus: &'static UsbBusAllocator<Bus> = unsafe {
+        let ep_memory = &mut *core::ptr::addr_of_mut!(EP_MEMORY);
+        (*core::ptr::addr_of_mut!(BUS)).insert(UsbBus::new(usb, ep_memory))
+    };
+
+    let serial = SerialPort::new_with_store(bus, [0; 128], [0; WRITE_BUFFER]);
+    let strings = StringDescriptors::default()
+        .manufacturer("Cobular")
+        .product("STM32H7 spectrum frames");
+    let device = UsbDeviceBuilder::new(bus, VID_PID)
+        .strings(&[strings])
+        .expect("one language of strings fits")
+        .device_class(usbd_serial::USB_CLASS_CDC)
+        .build();
+
+    cortex_m::interrupt::free(|cs| USB.borrow(cs).replace(Some((device, serial))));
+    // Safety: the handler only polls the device
+    unsafe { NVIC::unmask(Interrupt::OTG_FS) };
+
+    info!("USB frames on CN13 ({:04x}:{:04x})", VID_PID.0, VID_PID.1);
+    UsbFrames { _private: () }
+}
+
+impl UsbFrames {
+    /// Queue one encoded frame for the host, or drop it if there's no host
+    /// listening (`WouldBlock`) or it stops taking data (`Timeout`)
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        if !self.is_open() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return Err(TransportError::WouldBlock);
+        }
+
+        let start = crate::utilities::monotonic::now_us();
+        let mut rest = frame;
+        while !rest.is_empty() {
+            let written = cortex_m::interrupt::free(|cs| {
+                let mut usb = USB.borrow(cs).borrow_mut();
+                let (_, serial) = usb.as_mut()?;
+                serial.write(rest).ok()
+            });
+            match written {
+                Some(n) => rest = &rest[n..],
+                None if crate::utilities::monotonic::now_us() - start > FRAME_TIMEOUT_US => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                    return Err(TransportError::Timeout);
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the host is enumerated and has the port open
+    pub fn is_open(&self) -> bool {
+        cortex_m::interrupt::free(|cs| {
+            USB.borrow(cs)
+                .borrow()
+                .as_ref()
+                .is_some_and(|(device, serial)| {
+                    device.state() == UsbDeviceState::Configured && serial.dtr()
+                })
+        })
+    }
+}
+
+/// Frames dropped since boot, for the status log
+pub fn dropped_frames() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[interrupt]
+fn OTG_FS() {
+    cortex_m::interrupt::free(|cs| {
+        let mut usb = USB.borrow(cs).borrow_mut();
+        let Some((device, serial)) = usb.as_mut() else {
+            return;
+        };
+
+        if device.poll(&mut [serial]) {
+            // Commands go to the console, so throw away anything sent here
+            let mut discard = [0; 64];
+            while let Ok(n) = serial.read(&mut discard) {
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+    });
+}
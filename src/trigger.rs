@@ -0,0 +1,320 @@
+//! Software triggering, finding where a capture crosses a level, or
+//! waiting for a capture loud enough to be worth processing.
+
+use serde::{Deserialize, Serialize};
+
+use crate::acquisition::{AcqError, SampleSource};
+
+/// Which way the signal has to cross the level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// Trigger where the signal crosses `level` in the direction of `edge`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LevelTrigger {
+    pub level: f32,
+    pub edge: Edge,
+}
+
+impl LevelTrigger {
+    pub const fn new(level: f32, edge: Edge) -> Self {
+        Self { level, edge }
+    }
+
+    /// Index of the first sample at or past the level that follows one on
+    /// the other side of it. A signal that starts past the level and stays
+    /// there never crossed it, so doesn't trigger.
+    pub fn find(&self, samples: impl IntoIterator<Item = f32>) -> Option<usize> {
+        let mut samples = samples.into_iter();
+        let mut previous = samples.next()?;
+
+        for (i, sample) in samples.enumerate() {
+            let crossed = match self.edge {
+                Edge::Rising => previous < self.level && sample >= self.level,
+                Edge::Falling => previous > self.level && sample <= self.level,
+            };
+            if crossed {
+                return Some(i + 1);
+            }
+            previous = sample;
+        }
+        None
+    }
+}
+
+/// The last `N` samples of a stream, for the history before a trigger
+pub struct HistoryRing<const N: usize> {
+    buf: [u16; N],
+    /// Where the next sample goes, so the oldest is here once it's full
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> HistoryRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Forget everything, e.g. after a gap in the stream
+    pub fn clear(&mut self) {
+        self.next = 0;
+        self.len = 0;
+    }
+
+    /// Add `samples` to the end, pushing out the oldest once there are `N`
+    pub fn extend(&mut self, samples: &[u16]) {
+        // Only the last `N` would survive anyway
+        let samples = &samples[samples.len().saturating_sub(N)..];
+        let (tail, head) = samples.split_at(samples.len().min(N - self.next));
+        self.buf[self.next..self.next + tail.len()].copy_from_slice(tail);
+        self.buf[..head.len()].copy_from_slice(head);
+        self.next = (self.next + samples.len()) % N.max(1);
+        self.len = (self.len + samples.len()).min(N);
+    }
+
+    /// Copy the most recent samples, oldest first, into the end of `out`,
+    /// as many as there are up to `out.len()`. Returns how many; the start
+    /// of `out` is left alone if there weren't enough.
+    pub fn latest(&self, out: &mut [u16]) -> usize {
+        let count = out.len().min(self.len);
+        let skip = out.len() - count;
+        let out = &mut out[skip..];
+        // `count` back from `next`, wrapping round the end
+        let first = (self.next + N - count) % N.max(1);
+        let (tail, head) = out.split_at_mut(count.min(N - first));
+        tail.copy_from_slice(&self.buf[first..first + tail.len()]);
+        head.copy_from_slice(&self.buf[..head.len()]);
+        count
+    }
+}
+
+impl<const N: usize> Default for HistoryRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where the signal starts in a buffer from `armed_acquire`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Triggered {
+    /// The first sample of history, later than 0 if there wasn't enough
+    /// yet for all the pretrigger asked for
+    pub start: usize,
+    /// How many blocks were captured, the one that triggered included
+    pub blocks: usize,
+}
+
+/// Capture blocks from `source` until one's RMS about its own mean, in
+/// codes, exceeds `threshold_rms`, like a scope in normal mode, so quiet
+/// stretches are never processed. `out` is `pretrigger` samples of history
+/// followed by the block, whose length is the rest of `out`: the block that
+/// triggered is filled into `out[pretrigger..]`, with the samples from just
+/// before it ahead of it.
+///
+/// Every block goes into `history`, so it should be kept between calls for
+/// the history to carry on, and be at least `pretrigger` long. It's only
+/// contiguous with the block if the source is, as in
+/// `AcquisitionMode::Continuous`; one-shot captures have gaps between
+/// them. A failed fill leaves a gap too, so history is cleared and the
+/// error returned. Gives up with `None` after `max_blocks` quiet ones.
+/// Panics if `history` is too short or `out` has no room for a block.
+pub fn armed_acquire<S: SampleSource, const N: usize>(
+    source: &mut S,
+    threshold_rms: f32,
+    pretrigger: usize,
+    history: &mut HistoryRing<N>,
+    out: &mut [u16],
+    max_blocks: usize,
+) -> Result<Option<Triggered>, AcqError> {
+    assert!(pretrigger <= N, "the history can't hold the pretrigger");
+    assert!(
+        out.len() > pretrigger,
+        "no room for a block after the pretrigger"
+    );
+
+    let (before, block) = out.split_at_mut(pretrigger);
+    for blocks in 1..=max_blocks {
+        if let Err(e) = source.fill(block) {
+            history.clear();
+            return Err(e);
+        }
+
+        let triggered = ac_mean_square(block) > threshold_rms * threshold_rms;
+        // History up to the block, before the block goes into it
+        let start = if triggered {
+            pretrigger - history.latest(before)
+        } else {
+            0
+        };
+        history.extend(block);
+        if triggered {
+            return Ok(Some(Triggered { start, blocks }));
+        }
+    }
+    Ok(None)
+}
+
+/// Mean square of `codes` about their mean, so an ADC's mid-scale offset
+/// doesn't count as signal
+fn ac_mean_square(codes: &[u16]) -> f32 {
+    if codes.is_empty() {
+        return 0.0;
+    }
+    let n = codes.len() as f32;
+    let mean = codes.iter().map(|&c| c as f32).sum::<f32>() / n;
+    codes
+        .iter()
+        .map(|&c| {
+            let d = c as f32 - mean;
+            d * d
+        })
+        .sum::<f32>()
+        / n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAMP: [f32; 7] = [0.0, 1.0, 2.0, 3.0, 2.0, 1.0, 0.0];
+
+    #[test]
+    fn rising_and_falling_edges() {
+        let rising = LevelTrigger::new(1.5, Edge::Rising);
+        assert_eq!(rising.find(RAMP), Some(2));
+        let falling = LevelTrigger::new(1.5, Edge::Falling);
+        assert_eq!(falling.find(RAMP), Some(5));
+    }
+
+    #[test]
+    fn reaching_the_level_counts_as_crossing() {
+        assert_eq!(LevelTrigger::new(2.0, Edge::Rising).find(RAMP), Some(2));
+        assert_eq!(LevelTrigger::new(2.0, Edge::Falling).find(RAMP), Some(4));
+    }
+
+    #[test]
+    fn starting_past_the_level_isnt_a_crossing() {
+        let trigger = LevelTrigger::new(0.5, Edge::Rising);
+        assert_eq!(trigger.find([1.0, 2.0, 3.0]), None);
+        assert_eq!(trigger.find([1.0, 0.0, 1.0]), Some(2));
+        assert_eq!(trigger.find([]), None);
+        assert_eq!(trigger.find([0.0]), None);
+    }
+
+    #[test]
+    fn history_keeps_the_latest_in_order() {
+        let mut ring: HistoryRing<4> = HistoryRing::new();
+        let mut out = [0; 4];
+        assert_eq!(ring.latest(&mut out), 0);
+
+        ring.extend(&[1, 2, 3]);
+        assert_eq!(ring.latest(&mut out), 3);
+        assert_eq!(out, [0, 1, 2, 3]);
+
+        // Wraps round the end, pushing out the oldest
+        ring.extend(&[4, 5]);
+        assert_eq!(ring.len(), 4);
+        assert_eq!(ring.latest(&mut out), 4);
+        assert_eq!(out, [2, 3, 4, 5]);
+        let mut two = [0; 2];
+        assert_eq!(ring.latest(&mut two), 2);
+        assert_eq!(two, [4, 5]);
+
+        // Longer than the ring only keeps its end
+        ring.extend(&[6, 7, 8, 9, 10, 11]);
+        assert_eq!(ring.latest(&mut out), 4);
+        assert_eq!(out, [8, 9, 10, 11]);
+
+        ring.clear();
+        assert!(ring.is_empty());
+        assert_eq!(ring.latest(&mut out), 0);
+    }
+
+    /// Plays back blocks, each filled with a square wave of its amplitude
+    /// about mid-scale, numbered so they can be told apart
+    struct Blocks<'a> {
+        amplitudes: &'a [u16],
+        next: usize,
+    }
+
+    impl SampleSource for Blocks<'_> {
+        fn fill(&mut self, buf: &mut [u16]) -> Result<(), AcqError> {
+            let Some(&amplitude) = self.amplitudes.get(self.next) else {
+                return Err(AcqError::Overrun);
+            };
+            self.next += 1;
+            for (i, code) in buf.iter_mut().enumerate() {
+                let swing = if i % 2 == 0 { amplitude } else { 0 };
+                *code = 1_000 * self.next as u16 + swing;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn triggers_on_a_loud_block_with_history_before_it() {
+        let mut source = Blocks {
+            amplitudes: &[0, 2, 100],
+            next: 0,
+        };
+        let mut history: HistoryRing<8> = HistoryRing::new();
+        let mut out = [0; 3 + 4];
+        // A square wave of swing 2 has an RMS of 1 about its mean
+        let triggered = armed_acquire(&mut source, 1.0, 3, &mut history, &mut out, 10);
+        assert_eq!(
+            triggered,
+            Ok(Some(Triggered {
+                start: 0,
+                blocks: 3
+            }))
+        );
+        // The last of the second block, then all of the third
+        assert_eq!(out, [2_000, 2_002, 2_000, 3_100, 3_000, 3_100, 3_000]);
+    }
+
+    #[test]
+    fn early_triggers_and_quiet_stretches() {
+        // Loud from the start, so there's no history yet
+        let mut source = Blocks {
+            amplitudes: &[50, 0, 0],
+            next: 0,
+        };
+        let mut history: HistoryRing<4> = HistoryRing::new();
+        let mut out = [0; 2 + 2];
+        let triggered = armed_acquire(&mut source, 10.0, 2, &mut history, &mut out, 5);
+        assert_eq!(
+            triggered,
+            Ok(Some(Triggered {
+                start: 2,
+                blocks: 1
+            }))
+        );
+
+        // Never loud enough, then the source fails, which clears the history
+        assert_eq!(
+            armed_acquire(&mut source, 10.0, 2, &mut history, &mut out, 2),
+            Ok(None)
+        );
+        assert_eq!(history.len(), 4);
+        assert_eq!(
+            armed_acquire(&mut source, 10.0, 2, &mut history, &mut out, 2),
+            Err(AcqError::Overrun)
+        );
+        assert!(history.is_empty());
+    }
+}
@@ -0,0 +1,487 @@
+//! Raw register helpers for ADC1 (and the few ADC3 needs) that the HAL
+//! doesn't cover, and resolution handling shared by the capture ADCs.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::NVIC;
+use log::{error, info};
+use stm32h7xx_hal::hal::adc::{Channel, OneShot};
+use stm32h7xx_hal::pac::{interrupt, Interrupt};
+use stm32h7xx_hal::{adc, pac};
+
+use crate::acquisition::{ExternalTrigger, SampleTime, TriggerEdge};
+use crate::dsp::scaling::AdcScale;
+use crate::trigger::Edge;
+
+/// Number of captures spoiled by an ADC overrun since boot
+static OVERRUNS: AtomicU32 = AtomicU32::new(0);
+
+const ADC_CR_ADSTART: u32 = 1 << 2;
+const ADC_CR_JADSTART: u32 = 1 << 3;
+const ADC_CR_ADCALLIN: u32 = 1 << 16;
+const ADC_CR_ADCALDIF: u32 = 1 << 30;
+const ADC_CR_ADCAL: u32 = 1 << 31;
+const ADC_CFGR_EXTSEL_SHIFT: u32 = 5;
+const ADC_CFGR_EXTSEL_MASK: u32 = 0b1_1111 << ADC_CFGR_EXTSEL_SHIFT;
+const ADC_CFGR_EXTEN_SHIFT: u32 = 10;
+const ADC_CFGR_EXTEN_MASK: u32 = 0b11 << ADC_CFGR_EXTEN_SHIFT;
+const ADC_CFGR_CONT: u32 = 1 << 13;
+const ADC_ISR_JEOC: u32 = 1 << 5;
+const ADC_IER_JEOCIE: u32 = 1 << 5;
+const ADC_JSQR_JEXTSEL_SHIFT: u32 = 2;
+const ADC_JSQR_JEXTEN_SHIFT: u32 = 7;
+const ADC_JSQR_JSQ1_SHIFT: u32 = 9;
+
+/// The last injected conversion, with the count of them in the top half so
+/// a reader can tell a new one from the same one again
+static INJECTED: AtomicU32 = AtomicU32::new(0);
+
+fn adc1() -> &'static pac::adc1::RegisterBlock {
+    // Safety: only used while the HAL's ADC1 is idle or converting into DMA,
+    // and each helper touches bits the HAL doesn't cache
+    unsafe { &*pac::ADC1::ptr() }
+}
+
+/// Stop any ongoing regular conversions and wait for the ADC to go idle
+pub fn stop_conversions() {
+    let adc = adc1();
+
+    if adc.cr.read().adstart().bit_is_set() {
+        adc.cr.modify(|_, w| w.adstp().set_bit());
+        while adc.cr.read().adstp().bit_is_set() {}
+    }
+}
+
+/// Dump the conversion state and trigger configuration, for when the ADC
+/// isn't producing data
+pub fn log_registers() {
+    let adc = adc1();
+    let cfgr = adc.cfgr.read().bits();
+
+    error!(
+        "ADC1: ISR={:#010x} CR={:#010x} CFGR={:#010x} SQR1={:#010x}",
+        adc.isr.read().bits(),
+        adc.cr.read().bits(),
+        cfgr,
+        adc.sqr1.read().bits(),
+    );
+    // EXTEN == 0 means software triggered, so EXTSEL is ignored
+    error!(
+        "ADC1 trigger: EXTEN={} EXTSEL={} CONT={} DMNGT={}",
+        (cfgr >> 10) & 0b11,
+        (cfgr >> 5) & 0b1_1111,
+        (cfgr >> 13) & 1,
+        cfgr & 0b11,
+    );
+}
+
+/// Check for and clear an ADC overrun, counting it if there was one.
+///
+/// OVR means a conversion finished before DMA read the previous one, so at
+/// least one sample was dropped and the buffer no longer has even timing.
+/// Call this after `stop_conversions`, otherwise a free-running ADC with
+/// nobody reading overruns as soon as DMA has its last sample.
+pub fn check_overrun() -> bool {
+    let adc = adc1();
+
+    if adc.isr.read().ovr().bit_is_set() {
+        // Write 1 to clear
+        adc.isr.write(|w| w.ovr().set_bit());
+        OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        true
+    } else {
+        false
+    }
+}
+
+/// `stop_conversions` for ADC3
+pub fn stop_conversions_adc3() {
+    // Safety: as for ADC1, only while the HAL's ADC3 is idle or feeding DMA
+    let adc = unsafe { &*pac::ADC3::ptr() };
+
+    if adc.cr.read().adstart().bit_is_set() {
+        adc.cr.modify(|_, w| w.adstp().set_bit());
+        while adc.cr.read().adstp().bit_is_set() {}
+    }
+}
+
+/// `check_overrun` for ADC3, counted in the same total as ADC1
+pub fn check_overrun_adc3() -> bool {
+    // Safety: as for ADC1, OVR is a flag the HAL doesn't cache
+    let adc = unsafe { &*pac::ADC3::ptr() };
+
+    if adc.isr.read().ovr().bit_is_set() {
+        adc.isr.write(|w| w.ovr().set_bit());
+        OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        true
+    } else {
+        false
+    }
+}
+
+pub fn overrun_count() -> u32 {
+    OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Wait `settling_us` after enabling an ADC, see `MIN_SETTLING_US`. Timed
+/// on the core clock, so it works where there's no `Delay` to hand.
+pub fn settle(settling_us: u32, sys_ck_hz: u32) {
+    cortex_m::asm::delay(settling_us.saturating_mul(sys_ck_hz / 1_000_000));
+}
+
+/// Convert `channel` `n` times with the ADC idle, at whatever sampling
+/// time it's set to, and throw the results away, so a capture started next
+/// gets none of the conversions taken while the ADC settles
+pub fn discard_conversions<ADC, PIN, A>(adc: &mut A, channel: &mut PIN, n: usize)
+where
+    A: OneShot<ADC, u32, PIN>,
+    PIN: Channel<ADC>,
+{
+    for _ in 0..n {
+        let _: Result<u32, _> = nb::block!(adc.read(channel));
+    }
+}
+
+/// VBAT reaches ADC3 through a bridge that divides it by this
+pub const VBAT_DIVIDER: f32 = 4.0;
+
+/// Read the VBAT pin's voltage through ADC3's internal VBAT/4 channel,
+/// with ADC3 idle, converting with `scale` as for the capture so the same
+/// calibration applies. The bridge draws from the battery while it's on, so
+/// it's only switched on for the one conversion, which gets the longest
+/// sampling time as the datasheet's minimum for VBAT is several us.
+/// `None` if the conversion failed, the bridge is switched off either way.
+pub fn read_vbat_volts(
+    adc: &mut adc::Adc<pac::ADC3, adc::Enabled>,
+    scale: &AdcScale,
+) -> Option<f32> {
+    // Safety: VBATEN is only ever set here, and the HAL doesn't cache it
+    let common = unsafe { &*pac::ADC3_COMMON::ptr() };
+    common.ccr.modify(|_, w| w.vbaten().set_bit());
+
+    let sample_time = adc.get_sample_time();
+    adc.set_sample_time(adc::AdcSampleTime::T_810);
+    let mut vbat = adc::Vbat::new();
+    let code: Option<u32> = nb::block!(adc.read(&mut vbat)).ok();
+    adc.set_sample_time(sample_time);
+
+    common.ccr.modify(|_, w| w.vbaten().clear_bit());
+    Some(scale.counts_to_volts(code? as f32) * VBAT_DIVIDER)
+}
+
+/// Temperature sensor codes ST measured at 30 and 110 C, 16-bit with VDDA
+/// at 3.3 V (DS12110's temperature sensor calibration values)
+const TS_CAL1: *const u16 = 0x1FF1_E820 as *const u16;
+const TS_CAL2: *const u16 = 0x1FF1_E840 as *const u16;
+const TS_CAL1_DEGC: f32 = 30.0;
+const TS_CAL2_DEGC: f32 = 110.0;
+const TS_CAL_VDDA: f32 = 3.3;
+
+/// The die temperature in C, from ADC3's internal sensor with ADC3 idle,
+/// on the line through the two factory calibration points. The reading is
+/// scaled to the 16 bits and 3.3 V those were taken at, from `scale`'s
+/// resolution and reference. Switched on only for the one conversion, at
+/// the longest sampling time, as the sensor needs at least 9 us. `None` if
+/// the conversion failed.
+pub fn read_die_degc(adc: &mut adc::Adc<pac::ADC3, adc::Enabled>, scale: &AdcScale) -> Option<f32> {
+    // Safety: VSENSEEN is only ever set here, and the HAL doesn't cache it
+    let common = unsafe { &*pac::ADC3_COMMON::ptr() };
+    common.ccr.modify(|_, w| w.vsenseen().set_bit());
+
+    let sample_time = adc.get_sample_time();
+    adc.set_sample_time(adc::AdcSampleTime::T_810);
+    let mut sensor = adc::Temperature::new();
+    let code: Option<u32> = nb::block!(adc.read(&mut sensor)).ok();
+    adc.set_sample_time(sample_time);
+
+    common.ccr.modify(|_, w| w.vsenseen().clear_bit());
+    let code = code?;
+
+    // Safety: both are in the system memory's read-only calibration area
+    let (cal1, cal2) = unsafe { (ptr::read_volatile(TS_CAL1), ptr::read_volatile(TS_CAL2)) };
+    let code = (code << (16 - scale.bits() as u32)) as f32 * scale.full_scale_volts() / TS_CAL_VDDA;
+    Some(
+        TS_CAL1_DEGC
+            + (code - cal1 as f32) * (TS_CAL2_DEGC - TS_CAL1_DEGC) / (cal2 as f32 - cal1 as f32),
+    )
+}
+
+/// What starts an injected conversion
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InjectedTrigger {
+    /// `start_injected`
+    Software,
+    /// A hardware event, by its JEXTSEL number (RM0433 table 212, e.g. 0
+    /// for TIM1 TRGO)
+    External { source: u8, edge: Edge },
+}
+
+/// Convert `pin` as ADC1's one injected channel, on `trigger`, reading the
+/// result from the ADC interrupt into `injected_reading`.
+///
+/// Injected conversions preempt the regular sequence: a regular conversion
+/// in progress is restarted after the injected one, so DMA still gets every
+/// regular sample, but the regular timing stretches by one injected
+/// conversion each time the trigger fires. Only the injected sequencer is
+/// touched here, so call this with no injected conversion running.
+pub fn configure_injected<PIN: Channel<pac::ADC1, ID = u8>>(
+    _adc: &mut adc::Adc<pac::ADC1, adc::Enabled>,
+    _pin: &mut PIN,
+    sample_time: SampleTime,
+    trigger: InjectedTrigger,
+) {
+    let adc = adc1();
+    let channel = PIN::channel() as u32;
+
+    // The channel's input has to be preselected on the H7, as for regular
+    // channels, and gets its own sampling time
+    adc.pcsel
+        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << channel) });
+    let smp = smp_bits(sample_time);
+    if channel < 10 {
+        let shift = channel * 3;
+        adc.smpr1
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b111 << shift) | smp << shift) });
+    } else {
+        let shift = (channel - 10) * 3;
+        adc.smpr2
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b111 << shift) | smp << shift) });
+    }
+
+    // JL = 0 is a sequence of one, and JEXTEN = 0 leaves it to software
+    let (source, exten) = match trigger {
+        InjectedTrigger::Software => (0, 0b00),
+        InjectedTrigger::External { source, edge } => match edge {
+            Edge::Rising => (source as u32, 0b01),
+            Edge::Falling => (source as u32, 0b10),
+        },
+    };
+    let jsqr = channel << ADC_JSQR_JSQ1_SHIFT
+        | exten << ADC_JSQR_JEXTEN_SHIFT
+        | (source & 0b1_1111) << ADC_JSQR_JEXTSEL_SHIFT;
+    adc.jsqr.write(|w| unsafe { w.bits(jsqr) });
+
+    adc.isr.write(|w| unsafe { w.bits(ADC_ISR_JEOC) });
+    adc.ier
+        .modify(|r, w| unsafe { w.bits(r.bits() | ADC_IER_JEOCIE) });
+    // Safety: the handler only reads the injected result
+    unsafe { NVIC::unmask(Interrupt::ADC) };
+
+    // With a hardware trigger, JADSTART arms the sequencer for the events
+    if trigger != InjectedTrigger::Software {
+        start_injected();
+    }
+    info!(
+        "ADC1 injected: channel {} on {:?}, {:?} sampling",
+        channel, trigger, sample_time
+    );
+}
+
+/// Make `pos` and `neg` a differential pair: converting `pos`'s channel
+/// reads the voltage between them, at mid-scale for none, rather than `pos`
+/// against VREF-. Each channel has one negative input, so `neg` has to be
+/// pos's INN, and it's preselected as the INP it doubles as, as the H7
+/// needs for it to connect.
+///
+/// DIFSEL can only be written with the ADC disabled, hence taking it
+/// before `enable`. The HAL only calibrates single-ended, and differential
+/// conversions have their own offset factor, so this runs the differential
+/// calibration too, skipping the linearity one the HAL's already done.
+pub fn into_differential<POS, NEG>(
+    adc: adc::Adc<pac::ADC1, adc::Disabled>,
+    _pos: &POS,
+    _neg: &NEG,
+) -> adc::Adc<pac::ADC1, adc::Disabled>
+where
+    POS: Channel<pac::ADC1, ID = u8>,
+    NEG: Channel<pac::ADC1, ID = u8>,
+{
+    let regs = adc1();
+    let channel = POS::channel() as u32;
+
+    regs.difsel
+        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << channel) });
+    regs.pcsel
+        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << NEG::channel()) });
+
+    // Which calibration is set up first, then started
+    regs.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !ADC_CR_ADCALLIN | ADC_CR_ADCALDIF) });
+    regs.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | ADC_CR_ADCAL) });
+    while regs.cr.read().bits() & ADC_CR_ADCAL != 0 {}
+    regs.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !ADC_CR_ADCALDIF) });
+
+    info!(
+        "ADC1 differential: channel {} less its INN, calibrated to {:#x}",
+        channel,
+        regs.calfact.read().bits() >> 16
+    );
+    adc
+}
+
+/// Start an injected conversion now, or with a hardware trigger, arm the
+/// sequencer for the next event
+pub fn start_injected() {
+    adc1()
+        .cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | ADC_CR_JADSTART) });
+}
+
+/// Start ADC1's regular conversions on `edge` of `source` from now on, one
+/// conversion per trigger, see `ExternalTrigger` for which edges suit
+/// which sources.
+///
+/// EXTSEL and EXTEN can only be written with ADSTART clear, so a sequence
+/// already started is stopped for the write and armed again after, behind
+/// the trigger. CONT is cleared too: with it set, as the HAL's
+/// `start_conversion_dma` leaves it, the first trigger would start the ADC
+/// free-running. So call this after that as well as before, as
+/// `start_conversion_dma` does. The source has to be running for anything
+/// to convert, `discard_conversions` included.
+pub fn set_trigger(
+    _adc: &mut adc::Adc<pac::ADC1, adc::Enabled>,
+    source: ExternalTrigger,
+    edge: TriggerEdge,
+) {
+    let adc = adc1();
+    let started = adc.cr.read().bits() & ADC_CR_ADSTART != 0;
+    // Only a triggered conversion can be in progress, and stopping drops it
+    stop_conversions();
+
+    adc.cfgr.modify(|r, w| unsafe {
+        w.bits(
+            r.bits() & !(ADC_CFGR_EXTSEL_MASK | ADC_CFGR_EXTEN_MASK | ADC_CFGR_CONT)
+                | source.extsel() << ADC_CFGR_EXTSEL_SHIFT
+                | edge.exten() << ADC_CFGR_EXTEN_SHIFT,
+        )
+    });
+    if started {
+        adc.cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | ADC_CR_ADSTART) });
+    }
+}
+
+/// The HAL's `start_conversion_dma`, converting on `trigger` if there is
+/// one rather than free-running
+pub fn start_conversion_dma<PIN: Channel<pac::ADC1, ID = u8>>(
+    adc: &mut adc::Adc<pac::ADC1, adc::Enabled>,
+    channel: &mut PIN,
+    mode: adc::AdcDmaMode,
+    trigger: Option<(ExternalTrigger, TriggerEdge)>,
+) {
+    match trigger {
+        None => adc.start_conversion_dma(channel, mode),
+        Some((source, edge)) => {
+            // Set first, so starting only arms the ADC, then again for CONT
+            set_trigger(adc, source, edge);
+            adc.start_conversion_dma(channel, mode);
+            set_trigger(adc, source, edge);
+        }
+    }
+}
+
+/// The latest injected conversion and how many there have been, or `None`
+/// if there hasn't been one yet. The count wraps at 65536.
+pub fn injected_reading() -> Option<(u16, u16)> {
+    let packed = INJECTED.load(Ordering::Acquire);
+    let count = (packed >> 16) as u16;
+    (packed != 0).then_some((packed as u16, count))
+}
+
+/// The ADC1 and ADC2 interrupt, which only the injected channel enables
+#[interrupt]
+fn ADC() {
+    let adc = adc1();
+
+    if adc.isr.read().bits() & ADC_ISR_JEOC != 0 {
+        // Reading JDR1 doesn't clear JEOC, it's write 1 to clear
+        let value = adc.jdr1.read().bits() as u16;
+        adc.isr.write(|w| unsafe { w.bits(ADC_ISR_JEOC) });
+
+        let count = (INJECTED.load(Ordering::Relaxed) >> 16).wrapping_add(1) & 0xFFFF;
+        // Count 0 would read as no reading yet, so skip it on wrapping
+        let count = count.max(1);
+        INJECTED.store(count << 16 | value as u32, Ordering::Release);
+    }
+}
+
+/// SMPR code for a sampling time
+const fn smp_bits(sample_time: SampleTime) -> u32 {
+    match sample_time {
+        SampleTime::T_1_5 => 0b000,
+        SampleTime::T_2_5 => 0b001,
+        SampleTime::T_8_5 => 0b010,
+        SampleTime::T_16_5 => 0b011,
+        SampleTime::T_32_5 => 0b100,
+        SampleTime::T_64_5 => 0b101,
+        SampleTime::T_387_5 => 0b110,
+        SampleTime::T_810_5 => 0b111,
+    }
+}
+
+/// Number of bits a resolution setting produces
+pub const fn resolution_bits(resolution: adc::Resolution) -> u8 {
+    match resolution {
+        adc::Resolution::SixteenBit => 16,
+        adc::Resolution::FourteenBit => 14,
+        adc::Resolution::TwelveBit => 12,
+        adc::Resolution::TenBit => 10,
+        adc::Resolution::EightBit => 8,
+    }
+}
+
+/// The HAL's setting for a sampling time
+pub const fn hal_sample_time(t: SampleTime) -> adc::AdcSampleTime {
+    match t {
+        SampleTime::T_1_5 => adc::AdcSampleTime::T_1,
+        SampleTime::T_2_5 => adc::AdcSampleTime::T_2,
+        SampleTime::T_8_5 => adc::AdcSampleTime::T_8,
+        SampleTime::T_16_5 => adc::AdcSampleTime::T_16,
+        SampleTime::T_32_5 => adc::AdcSampleTime::T_32,
+        SampleTime::T_64_5 => adc::AdcSampleTime::T_64,
+        SampleTime::T_387_5 => adc::AdcSampleTime::T_387,
+        SampleTime::T_810_5 => adc::AdcSampleTime::T_810,
+    }
+}
+
+/// The HAL's `set_resolution` is an inherent method on each ADC, so this
+/// lets one helper take whichever ADC we capture with
+pub trait SetResolution {
+    fn set_resolution(&mut self, resolution: adc::Resolution);
+}
+
+impl<ED> SetResolution for adc::Adc<pac::ADC1, ED> {
+    fn set_resolution(&mut self, resolution: adc::Resolution) {
+        adc::Adc::<pac::ADC1, ED>::set_resolution(self, resolution)
+    }
+}
+
+impl<ED> SetResolution for adc::Adc<pac::ADC3, ED> {
+    fn set_resolution(&mut self, resolution: adc::Resolution) {
+        adc::Adc::<pac::ADC3, ED>::set_resolution(self, resolution)
+    }
+}
+
+/// Set the ADC's resolution and return the scale that goes with it.
+///
+/// Going through this keeps counts-to-volts tied to the full-scale code the
+/// ADC actually produces, so dropping to 12 bits for speed doesn't leave
+/// magnitudes off by 16x.
+pub fn set_resolution_scaled(
+    adc: &mut impl SetResolution,
+    resolution: adc::Resolution,
+    vref: f32,
+) -> AdcScale {
+    adc.set_resolution(resolution);
+
+    let scale = AdcScale::new(resolution_bits(resolution), vref);
+    info!(
+        "ADC resolution: {} bit, full scale {} counts = {} V",
+        scale.bits(),
+        scale.max_count(),
+        scale.full_scale_volts()
+    );
+    scale
+}
@@ -0,0 +1,243 @@
+//! The 4 KiB backup SRAM, which keeps its contents through resets (and on
+//! VBAT, with the backup regulator on), and what we keep in it.
+//!
+//! Nothing here is linked into the region, and `memory.x` gives the linker
+//! nothing to put there. Each record has a fixed offset and a magic word,
+//! so a cold boot's random contents read as empty.
+
+use core::mem::size_of;
+use core::ptr;
+use stm32h7xx_hal::pac;
+
+use crate::dsp::scaling::{Calibration, OffsetDrift};
+use crate::flight_recorder::{self, FlightRecord};
+
+pub const BACKUP_SRAM_BASE: usize = 0x3880_0000;
+pub const BACKUP_SRAM_BYTES: usize = 4 * 1024;
+
+/// Where the calibration record lives
+const CALIBRATION_OFFSET: usize = 0;
+
+/// Marks a calibration record as written by `save_calibration`
+const CALIBRATION_MAGIC: u32 = 0xCA1B_0A7D;
+
+/// The offset drift record goes straight after
+const DRIFT_OFFSET: usize = CALIBRATION_OFFSET + size_of::<CalibrationRecord>();
+
+/// Marks a drift record as written by `save_drift`
+const DRIFT_MAGIC: u32 = 0xD21F_7C0E;
+
+/// Then the flight recorder, which checks itself with a CRC
+const FLIGHT_OFFSET: usize = DRIFT_OFFSET + size_of::<DriftRecord>();
+
+/// And what the last hard fault left, word aligned as the records above
+/// come to a whole number of words
+const FAULT_OFFSET: usize = FLIGHT_OFFSET + flight_recorder::RECORD_LEN;
+
+/// Marks a fault record as written by `save_fault`
+const FAULT_MAGIC: u32 = 0xFA17_D00D;
+
+const _: () = assert!(FAULT_OFFSET % 4 == 0);
+const _: () = assert!(FAULT_OFFSET + size_of::<FaultRecord>() <= BACKUP_SRAM_BYTES);
+
+const PWR_CR1_DBP: u32 = 1 << 8;
+const PWR_CR2_BREN: u32 = 1 << 0;
+const PWR_CR2_BRRDY: u32 = 1 << 16;
+const RCC_AHB4ENR_BKPRAMEN: u32 = 1 << 28;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CalibrationRecord {
+    magic: u32,
+    offset: f32,
+    gain: f32,
+    /// Inverted copy of the magic, in case only part of a write landed
+    check: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DriftRecord {
+    magic: u32,
+    reference_degc: f32,
+    reference_offset: f32,
+    counts_per_degc: f32,
+    /// As for `CalibrationRecord`
+    check: u32,
+}
+
+/// Where a hard fault happened, from its exception frame and the fault
+/// status registers
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    pub pc: u32,
+    pub lr: u32,
+    /// SCB_CFSR and SCB_HFSR, which say what kind of fault it was
+    pub cfsr: u32,
+    pub hfsr: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FaultRecord {
+    magic: u32,
+    fault: Fault,
+    /// As for `CalibrationRecord`
+    check: u32,
+}
+
+/// Lift the write protection on the backup domain: the RTC, the backup
+/// SRAM and RCC's BDCR. Nothing turns it back on, so calling this again
+/// is harmless.
+pub fn allow_domain_writes() {
+    // Safety: the HAL never uses DBP, so nothing else owns it
+    let pwr = unsafe { &*pac::PWR::ptr() };
+
+    pwr.cr1
+        .modify(|r, w| unsafe { w.bits(r.bits() | PWR_CR1_DBP) });
+    while pwr.cr1.read().bits() & PWR_CR1_DBP == 0 {}
+}
+
+/// Clock the backup SRAM and enable writes to it, and keep it powered from
+/// VBAT when the main supply goes away
+pub fn enable() {
+    // Safety: the HAL's PWR and RCC don't model the backup SRAM's clock
+    // enable or its regulator, so these bits are ours
+    let pwr = unsafe { &*pac::PWR::ptr() };
+    let rcc = unsafe { &*pac::RCC::ptr() };
+
+    allow_domain_writes();
+
+    rcc.ahb4enr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_AHB4ENR_BKPRAMEN) });
+
+    pwr.cr2
+        .modify(|r, w| unsafe { w.bits(r.bits() | PWR_CR2_BREN) });
+    while pwr.cr2.read().bits() & PWR_CR2_BRRDY == 0 {}
+}
+
+fn calibration_record() -> *mut CalibrationRecord {
+    (BACKUP_SRAM_BASE + CALIBRATION_OFFSET) as *mut CalibrationRecord
+}
+
+/// Persist `cal` for `load_calibration` to find after a reset.
+/// `enable` must have been called.
+pub fn save_calibration(cal: &Calibration) {
+    let record = CalibrationRecord {
+        magic: CALIBRATION_MAGIC,
+        offset: cal.offset,
+        gain: cal.gain,
+        check: !CALIBRATION_MAGIC,
+    };
+
+    // Safety: the record is within the backup SRAM and nothing else uses it.
+    // Volatile, as far as the compiler knows nobody reads this back.
+    unsafe { ptr::write_volatile(calibration_record(), record) };
+}
+
+/// The calibration last saved, or `None` if the backup SRAM has never held
+/// one (or lost it, without VBAT). `enable` must have been called.
+pub fn load_calibration() -> Option<Calibration> {
+    // Safety: any bit pattern is a valid record, it's checked below
+    let record = unsafe { ptr::read_volatile(calibration_record()) };
+
+    if record.magic != CALIBRATION_MAGIC || record.check != !CALIBRATION_MAGIC {
+        return None;
+    }
+
+    let cal = Calibration {
+        offset: record.offset,
+        gain: record.gain,
+    };
+    cal.is_plausible().then_some(cal)
+}
+
+/// Forget the saved calibration
+pub fn clear_calibration() {
+    // Safety: as in save_calibration
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!((*calibration_record()).magic), 0) };
+}
+
+fn drift_record() -> *mut DriftRecord {
+    (BACKUP_SRAM_BASE + DRIFT_OFFSET) as *mut DriftRecord
+}
+
+/// Persist `drift` for `load_drift`, as for `save_calibration`
+pub fn save_drift(drift: &OffsetDrift) {
+    let record = DriftRecord {
+        magic: DRIFT_MAGIC,
+        reference_degc: drift.reference_degc,
+        reference_offset: drift.reference_offset,
+        counts_per_degc: drift.counts_per_degc,
+        check: !DRIFT_MAGIC,
+    };
+
+    // Safety: as in save_calibration, the record is after that one's
+    unsafe { ptr::write_volatile(drift_record(), record) };
+}
+
+/// The offset drift last saved, if there's a plausible one
+pub fn load_drift() -> Option<OffsetDrift> {
+    // Safety: any bit pattern is a valid record, it's checked below
+    let record = unsafe { ptr::read_volatile(drift_record()) };
+
+    if record.magic != DRIFT_MAGIC || record.check != !DRIFT_MAGIC {
+        return None;
+    }
+
+    let drift = OffsetDrift {
+        reference_degc: record.reference_degc,
+        reference_offset: record.reference_offset,
+        counts_per_degc: record.counts_per_degc,
+    };
+    drift.is_plausible().then_some(drift)
+}
+
+fn flight_record() -> *mut [u8; flight_recorder::RECORD_LEN] {
+    (BACKUP_SRAM_BASE + FLIGHT_OFFSET) as *mut [u8; flight_recorder::RECORD_LEN]
+}
+
+/// Keep `record` for `load_flight` to find after a reset. `enable` and
+/// `crc::init` must have been called.
+pub fn save_flight(record: &FlightRecord) {
+    let bytes = record.to_bytes(super::crc::crc32);
+    // Safety: as in save_calibration, after the drift record
+    unsafe { ptr::write_volatile(flight_record(), bytes) };
+}
+
+/// The flight record last saved, if one's there and checks out
+pub fn load_flight() -> Option<FlightRecord> {
+    // Safety: any bytes are a valid array, they're checked below
+    let bytes = unsafe { ptr::read_volatile(flight_record()) };
+    FlightRecord::from_bytes(&bytes, super::crc::crc32)
+}
+
+fn fault_record() -> *mut FaultRecord {
+    (BACKUP_SRAM_BASE + FAULT_OFFSET) as *mut FaultRecord
+}
+
+/// Keep `fault` for `take_fault` after the reset that follows it. Only
+/// writes, so it's safe from the fault handler.
+pub fn save_fault(fault: &Fault) {
+    let record = FaultRecord {
+        magic: FAULT_MAGIC,
+        fault: *fault,
+        check: !FAULT_MAGIC,
+    };
+    // Safety: as in save_calibration, after the flight record
+    unsafe { ptr::write_volatile(fault_record(), record) };
+}
+
+/// The fault saved before the last reset, if there was one, forgetting it
+/// so the next boot doesn't report it again
+pub fn take_fault() -> Option<Fault> {
+    // Safety: any bit pattern is a valid record, it's checked below
+    let record = unsafe { ptr::read_volatile(fault_record()) };
+    if record.magic != FAULT_MAGIC || record.check != !FAULT_MAGIC {
+        return None;
+    }
+    // Safety: as in clear_calibration
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!((*fault_record()).magic), 0) };
+    Some(record.fault)
+}
@@ -0,0 +1,82 @@
+//! Batched log output, for dumping lots of short lines quickly.
+//!
+//! Each `info!` call goes through the logger and RTT on its own, and the spectrum
+//! dump used to pace them with a delay so RTT didn't drop lines. `LogBatch`
+//! instead assembles lines in a stack `heapless::String` and logs the whole
+//! chunk once it's full, so there are far fewer, larger writes.
+//!
+//! Buffer size tradeoff: a larger `N` means fewer log calls but more stack,
+//! and a chunk bigger than the RTT up buffer (see `logger`) would be dropped
+//! whole, so `N` must stay below it. 1K is a good middle ground. Only the first
+//! line of each chunk gets the `INFO - ` prefix.
+//!
+//! A batch logs to one target, so the logger can give it a level of its
+//! own: `with_target` for one the console's `log` command can name.
+
+use core::fmt::{self, Write};
+use heapless::String;
+use log::info;
+
+pub struct LogBatch<const N: usize> {
+    buf: String<N>,
+    target: &'static str,
+}
+
+impl<const N: usize> LogBatch<N> {
+    pub const fn new() -> Self {
+        Self::with_target(module_path!())
+    }
+
+    pub const fn with_target(target: &'static str) -> Self {
+        Self {
+            buf: String::new(),
+            target,
+        }
+    }
+
+    /// Append a line, flushing first if it doesn't fit
+    pub fn push(&mut self, line: fmt::Arguments) {
+        if self.try_push(line).is_ok() {
+            return;
+        }
+
+        self.flush();
+        if self.try_push(line).is_err() {
+            // Too long for an empty buffer, so it goes out on its own
+            info!(target: self.target, "{}", line);
+        }
+    }
+
+    /// Log whatever has been collected so far
+    pub fn flush(&mut self) {
+        if !self.buf.is_empty() {
+            info!(target: self.target, "{}", self.buf.as_str());
+            self.buf.clear();
+        }
+    }
+
+    fn try_push(&mut self, line: fmt::Arguments) -> fmt::Result {
+        let len = self.buf.len();
+
+        // The logger adds the final newline, so only separate lines
+        let result = if self.buf.is_empty() {
+            self.buf.write_fmt(line)
+        } else {
+            self.buf
+                .write_char('\n')
+                .and_then(|()| self.buf.write_fmt(line))
+        };
+
+        if result.is_err() {
+            // Don't leave half a line behind
+            self.buf.truncate(len);
+        }
+        result
+    }
+}
+
+impl<const N: usize> Default for LogBatch<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
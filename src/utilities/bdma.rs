@@ -0,0 +1,87 @@
+//! Completion of the ADC3 capture channel, BDMA channel 0.
+//!
+//! Counterpart to the `dma` module for the D3 domain path. Like it, this
+//! only clears flags and toggles interrupt enables, so it works alongside a
+//! live HAL `Transfer`. This module owns the `BDMA_CH0` interrupt handler.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::{DWT, NVIC};
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::pac::{interrupt, Interrupt};
+
+use super::dma::{arm_timeout, timed_out, CaptureError, DmaError};
+use super::monotonic;
+
+static TRANSFER_DONE: AtomicBool = AtomicBool::new(false);
+static TRANSFER_ERROR: AtomicBool = AtomicBool::new(false);
+
+fn bdma() -> &'static pac::bdma::RegisterBlock {
+    // Safety: only status flags and channel 0's interrupt enables are touched
+    unsafe { &*pac::BDMA::ptr() }
+}
+
+fn set_interrupts(enable: bool) {
+    bdma().ch[0]
+        .cr
+        .modify(|_, w| w.tcie().bit(enable).teie().bit(enable));
+}
+
+/// How many of `len` requested samples BDMA has delivered so far
+pub fn received(len: usize) -> usize {
+    len.saturating_sub(bdma().ch[0].ndtr.read().ndt().bits() as usize)
+}
+
+/// Wait in `wfi` for the channel's handler to flag completion or an error.
+/// BDMA only has a transfer error, so that's the only `DmaError` reported.
+/// As in `dma::wait_for_interrupt`, TIM5 wakes us when the timeout is up.
+pub fn wait_for_interrupt(len: usize, timeout_cycles: u32) -> Result<(), CaptureError> {
+    let start = DWT::cycle_count();
+
+    TRANSFER_DONE.store(false, Ordering::Relaxed);
+    TRANSFER_ERROR.store(false, Ordering::Relaxed);
+    set_interrupts(true);
+    // Safety: the handler only touches this channel's flags and the atomics
+    unsafe { NVIC::unmask(Interrupt::BDMA_CH0) };
+    arm_timeout(timeout_cycles);
+
+    let result = loop {
+        if TRANSFER_ERROR.load(Ordering::Acquire) {
+            break Err(DmaError::Transfer.into());
+        }
+        if TRANSFER_DONE.load(Ordering::Acquire) {
+            break Ok(());
+        }
+        if timed_out(start, timeout_cycles) {
+            break Err(CaptureError::Timeout {
+                received: received(len),
+            });
+        }
+
+        // Masked, so the handler can't finish between the check and the `wfi`
+        cortex_m::interrupt::free(|_| {
+            if !TRANSFER_DONE.load(Ordering::Acquire) && !TRANSFER_ERROR.load(Ordering::Acquire) {
+                cortex_m::asm::wfi();
+            }
+        });
+    };
+
+    monotonic::disarm_wakeup();
+    NVIC::mask(Interrupt::BDMA_CH0);
+    set_interrupts(false);
+
+    result
+}
+
+#[interrupt]
+fn BDMA_CH0() {
+    let isr = bdma().isr.read();
+
+    if isr.teif0().bit_is_set() {
+        TRANSFER_ERROR.store(true, Ordering::Release);
+    } else if isr.tcif0().bit_is_set() {
+        TRANSFER_DONE.store(true, Ordering::Release);
+    }
+
+    // Clearing the global flag clears all of channel 0's flags
+    bdma().ifcr.write(|w| w.cgif0().set_bit());
+}
@@ -0,0 +1,49 @@
+//! Statically allocated buffers in specific RAM regions.
+//!
+//! Which RAM a buffer lives in matters for DMA: DMA1/2 can't reach DTCM, and
+//! BDMA can only reach SRAM4. The linker script (`memory.x`) gives each
+//! region a section, and `dma_buffer!` places a buffer in one of them.
+
+use core::mem::MaybeUninit;
+
+/// AXISRAM, reachable by every master except BDMA
+pub const AXISRAM_BYTES: usize = 512 * 1024;
+
+/// SRAM4 in the D3 domain, the only RAM BDMA can reach
+pub const SRAM4_BYTES: usize = 64 * 1024;
+
+/// Declare a zeroed `[T; N]` in a linker section and hand out the single
+/// `&'static mut` to it, e.g. `dma_buffer!(".axisram", u16, SIZE)`.
+///
+/// Each expansion is its own static, so don't expand it in a loop: the
+/// reference must only be created once.
+#[macro_export]
+macro_rules! dma_buffer {
+    ($section:literal, $t:ty, $n:expr) => {{
+        #[link_section = $section]
+        static mut BUFFER: core::mem::MaybeUninit<[$t; $n]> = core::mem::MaybeUninit::uninit();
+
+        // Safety: this expansion's static is only ever referenced here
+        unsafe { $crate::utilities::buffer::zeroed(core::ptr::addr_of_mut!(BUFFER)) }
+    }};
+}
+
+/// Zero an uninitialised array and return it as initialised.
+///
+/// # Safety
+///
+/// `buf` must point to a static that nothing else references, and this must
+/// only be called once for it.
+pub unsafe fn zeroed<T: Copy + Default, const N: usize>(
+    buf: *mut MaybeUninit<[T; N]>,
+) -> &'static mut [T; N] {
+    // Convert an uninitialised array into an array of uninitialised
+    let slots = buf as *mut MaybeUninit<T>;
+    // Initialise memory to valid values
+    for i in 0..N {
+        // Never create even a _temporary_ reference to uninitialised memory
+        slots.add(i).write(MaybeUninit::new(T::default()));
+    }
+
+    &mut *(buf as *mut [T; N])
+}
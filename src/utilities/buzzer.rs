@@ -0,0 +1,101 @@
+//! Playing the detected peak on a piezo buzzer, with the `buzzer` feature,
+//! as a square wave from TIM3 CH1 on PC6 (CN7).
+//!
+//! Each capture sets a target with `play`, and TIM3's update interrupt
+//! glides its frequency there a period at a time, so a new peak sweeps
+//! rather than jumps. PSC, ARR and CCR1 are all preloaded, so whatever the
+//! interrupt writes takes effect together on the next update, and a period
+//! is never cut short.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::NVIC;
+use log::info;
+use stm32h7xx_hal::gpio::PC6;
+use stm32h7xx_hal::pac::{self, interrupt, Interrupt};
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+
+use crate::tone::{self, Glide};
+
+/// How fast the pitch may sweep. Faster sounds like clicks between notes,
+/// slower lags behind the captures.
+const GLIDE_OCTAVES_PER_SECOND: f32 = 8.0;
+
+const TIM_CR1_ARPE: u32 = 1 << 7;
+const TIM_DIER_UIE: u32 = 1 << 0;
+const TIM_SR_UIF: u32 = 1 << 0;
+
+/// What to play, as `f32` bits, with 0 for silence
+static TARGET_HZ: AtomicU32 = AtomicU32::new(0);
+
+/// TIM3's kernel clock, for the interrupt to work out settings from
+static TIMER_HZ: AtomicU32 = AtomicU32::new(0);
+
+fn tim3() -> &'static pac::tim3::RegisterBlock {
+    // Safety: `init` took TIM3, after which only this module touches it
+    unsafe { &*pac::TIM3::ptr() }
+}
+
+/// Start TIM3 running silently, ready to `play`
+pub fn init(tim3: pac::TIM3, pin: PC6, prec: rec::Tim3, clocks: &CoreClocks) {
+    // The HAL sets up PWM mode and the compare preload, and the interrupt
+    // retunes it from there
+    let mut pwm = tim3.pwm(pin.into_alternate::<2>(), 1.kHz(), prec, clocks);
+    pwm.set_duty(0);
+    pwm.enable();
+    TIMER_HZ.store(clocks.timx_ker_ck().raw(), Ordering::Relaxed);
+
+    let tim = self::tim3();
+    tim.cr1
+        .modify(|r, w| unsafe { w.bits(r.bits() | TIM_CR1_ARPE) });
+    tim.dier.write(|w| unsafe { w.bits(TIM_DIER_UIE) });
+    // Safety: the handler only touches TIM3 and the atomics
+    unsafe { NVIC::unmask(Interrupt::TIM3) };
+
+    info!("Buzzer on PC6");
+}
+
+/// Glide to `hz`, or go quiet for `None`. Frequencies the timer can't make
+/// are quiet too.
+pub fn play(hz: Option<f32>) {
+    let target = hz.filter(|&hz| hz > 0.0).unwrap_or(0.0);
+    TARGET_HZ.store(target.to_bits(), Ordering::Relaxed);
+}
+
+#[interrupt]
+fn TIM3() {
+    static mut GLIDE: Glide = Glide::new(0.0, GLIDE_OCTAVES_PER_SECOND);
+    static mut PLAYING: bool = false;
+
+    let tim = tim3();
+    // rc_w0, so only the update flag is cleared
+    tim.sr.write(|w| unsafe { w.bits(!TIM_SR_UIF) });
+
+    let target = f32::from_bits(TARGET_HZ.load(Ordering::Relaxed));
+    let settings = if target > 0.0 {
+        // From silence there's nothing to glide from
+        let hz = if *PLAYING {
+            GLIDE.step(target, 1.0 / GLIDE.hz())
+        } else {
+            GLIDE.jump(target);
+            target
+        };
+        tone::pwm_settings(TIMER_HZ.load(Ordering::Relaxed), hz)
+    } else {
+        None
+    };
+
+    *PLAYING = settings.is_some();
+    match settings {
+        Some(settings) => {
+            tim.psc
+                .write(|w| unsafe { w.bits(settings.prescaler as u32) });
+            tim.arr.write(|w| unsafe { w.bits(settings.reload as u32) });
+            tim.ccr1
+                .write(|w| unsafe { w.bits(settings.half_duty() as u32) });
+        }
+        // A zero compare holds the pin low, and the timer carries on at
+        // whatever it was so the interrupt keeps coming
+        None => tim.ccr1.write(|w| unsafe { w.bits(0) }),
+    }
+}
@@ -0,0 +1,177 @@
+//! Broadcasting each capture's results on FDCAN1, with the `can` feature,
+//! as classic CAN at 500 kbit/s. See `crate::can` for the message layouts.
+//!
+//! The Nucleo has no CAN transceiver, so wire one (an SN65HVD230 or similar)
+//! to PD0 RX and PD1 TX on CN9, and terminate the bus.
+//!
+//! After a spectrum, one message at `PEAK_ID` carries the peak frequency and
+//! level. In `mode bands` the octave band levels go instead, four to a
+//! message, at `BANDS_ID` onwards.
+//!
+//! A disconnected or broken bus mustn't hold up capture. Nothing here waits:
+//! with every mailbox still full, a message that hasn't gone yet is stale by
+//! the next capture, so it's aborted and replaced. Without another node to
+//! acknowledge them, errors pile up until the controller goes bus-off and
+//! stops, and the next send restarts it, which rejoins the bus once it's
+//! seen 129 runs of 11 recessive bits.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use fdcan::config::NominalBitTiming;
+use fdcan::frame::{FrameFormat, TxFrameHeader};
+use fdcan::id::StandardId;
+use fdcan::{FdCan, Mailbox, NormalOperationMode};
+use log::{error, info, warn};
+use stm32h7xx_hal::can::Can;
+use stm32h7xx_hal::gpio::{Speed, PD0, PD1};
+use stm32h7xx_hal::pac::{self, FDCAN1};
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+
+use crate::can;
+
+const BITRATE: u32 = 500_000;
+
+/// ID of the peak message. Lower IDs win arbitration, so set these to fit
+/// in with the rest of the bus.
+const PEAK_ID: u16 = 0x120;
+
+/// ID of the first bands message, the rest follow on from it
+const BANDS_ID: u16 = 0x130;
+
+/// Most bands messages, so they stay clear of the next ID range
+const MAX_BANDS_MESSAGES: usize = 8;
+
+/// PSR.BO, set while the controller is bus-off
+const FDCAN_PSR_BO: u32 = 1 << 7;
+/// CCCR.INIT, which the controller sets itself on going bus-off
+const FDCAN_CCCR_INIT: u32 = 1 << 0;
+
+const MAILBOXES: [Mailbox; 3] = [Mailbox::_0, Mailbox::_1, Mailbox::_2];
+
+/// Messages replaced before they were sent, since boot
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Times the bus has been restarted after going bus-off, since boot
+static BUS_OFF: AtomicU32 = AtomicU32::new(0);
+
+pub struct CanBus {
+    can: FdCan<Can<FDCAN1>, NormalOperationMode>,
+    /// Counts captures broadcast, for receivers to spot a missed one
+    counter: u8,
+}
+
+/// Start FDCAN1 at `BITRATE` from its kernel clock, PLL1 Q. `None`, and
+/// logged, if the clock can't make the bitrate exactly.
+pub fn init(
+    fdcan1: pac::FDCAN1,
+    pins: (PD0, PD1),
+    prec: rec::Fdcan,
+    clocks: &CoreClocks,
+) -> Option<CanBus> {
+    let kernel_hz = clocks
+        .pll1_q_ck()
+        .expect("FDCAN is clocked from PLL1 Q")
+        .raw();
+    let Some(timing) = can::bit_timing(kernel_hz, BITRATE) else {
+        error!(
+            "FDCAN can't make {} bit/s from {} Hz, CAN is off",
+            BITRATE, kernel_hz
+        );
+        return None;
+    };
+
+    let (rx, tx) = pins;
+    let mut can = fdcan1.fdcan(
+        tx.into_alternate().speed(Speed::VeryHigh),
+        rx.into_alternate().speed(Speed::VeryHigh),
+        prec.kernel_clk_mux(rec::FdcanClkSel::Pll1Q),
+    );
+    // Timings from `bit_timing` are never zero
+    can.set_nominal_bit_timing(NominalBitTiming {
+        prescaler: timing.prescaler.try_into().unwrap(),
+        seg1: timing.seg1.try_into().unwrap(),
+        seg2: timing.seg2.try_into().unwrap(),
+        sync_jump_width: timing.sync_jump_width.try_into().unwrap(),
+    });
+    // Retransmit until acknowledged, with no other node that's until bus-off
+    can.set_automatic_retransmit(true);
+    let can = can.into_normal();
+
+    info!(
+        "CAN at {} bit/s on PD0/PD1, peak at {:#x}, bands from {:#x}",
+        BITRATE, PEAK_ID, BANDS_ID
+    );
+    Some(CanBus { can, counter: 0 })
+}
+
+impl CanBus {
+    /// Broadcast a capture's peak
+    pub fn send_peak(&mut self, peak_hz: f32, level_dbfs: f32, trusted: bool) {
+        let payload = can::peak_payload(peak_hz, level_dbfs, self.counter, trusted);
+        self.counter = self.counter.wrapping_add(1);
+        self.send(PEAK_ID, &payload);
+    }
+
+    /// Broadcast a capture's band levels, dropping any past
+    /// `MAX_BANDS_MESSAGES` messages' worth
+    pub fn send_bands(&mut self, levels_dbfs: &[f32]) {
+        let messages = can::band_payloads(levels_dbfs).take(MAX_BANDS_MESSAGES);
+        for (id, payload) in (BANDS_ID..).zip(messages) {
+            self.send(id, &payload);
+        }
+    }
+
+    fn send(&mut self, id: u16, payload: &[u8; 8]) {
+        self.recover_bus_off();
+
+        let header = TxFrameHeader {
+            len: payload.len() as u8,
+            frame_format: FrameFormat::Standard,
+            id: StandardId::new(id).expect("IDs are 11 bits").into(),
+            bit_rate_switching: false,
+            marker: None,
+        };
+        // A full set of mailboxes means nothing's getting out, so whatever's
+        // in them is out of date and goes
+        if self.can.transmit(header, payload).is_err() {
+            for mailbox in MAILBOXES {
+                if self.can.abort(mailbox) {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            if self.can.transmit(header, payload).is_err() {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Restart the controller if errors have taken it bus-off. It rejoins
+    /// the bus by itself once the bus has been idle long enough.
+    fn recover_bus_off(&mut self) {
+        // Safety: only INIT is changed, which the driver leaves clear in
+        // normal operation
+        let fdcan = unsafe { &*pac::FDCAN1::ptr() };
+        if fdcan.psr.read().bits() & FDCAN_PSR_BO == 0
+            || fdcan.cccr.read().bits() & FDCAN_CCCR_INIT == 0
+        {
+            return;
+        }
+
+        if BUS_OFF.fetch_add(1, Ordering::Relaxed) == 0 {
+            warn!("CAN bus-off, is anything else on the bus? Restarting");
+        }
+        fdcan
+            .cccr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !FDCAN_CCCR_INIT) });
+    }
+}
+
+/// Messages dropped since boot, for the status log
+pub fn dropped_messages() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Times the bus went bus-off since boot, for the status log
+pub fn bus_off_count() -> u32 {
+    BUS_OFF.load(Ordering::Relaxed)
+}
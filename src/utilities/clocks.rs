@@ -0,0 +1,234 @@
+//! Build-time clock profiles, and a record of what the RCC actually achieved.
+//!
+//! The default is 96 MHz. `clock-200`, `clock-400` and `clock-480` select
+//! faster profiles; 480 MHz needs VOS0, which `board::init` enables for it. The HAL
+//! picks the bus prescalers and flash wait states from the sys_ck we ask for.
+//!
+//! Everything runs from HSI unless `hse` selects the board's HSE, the
+//! Nucleo's 8 MHz clock from the ST-LINK or the DevEBox's crystal. HSI is
+//! only good to about 1%, which shows up directly in the bin frequencies.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_m_rt::exception;
+use log::{info, warn};
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::rcc::{CoreClocks, Rcc};
+use stm32h7xx_hal::time::Hertz;
+
+#[cfg(feature = "hse")]
+use crate::board::target;
+
+#[cfg(any(
+    all(feature = "clock-200", feature = "clock-400"),
+    all(feature = "clock-200", feature = "clock-480"),
+    all(feature = "clock-400", feature = "clock-480"),
+))]
+compile_error!("Select at most one of the clock-200, clock-400 and clock-480 features");
+
+/// System clock for the selected profile
+#[cfg(not(any(feature = "clock-200", feature = "clock-400", feature = "clock-480")))]
+pub const SYS_CK: Hertz = Hertz::MHz(96);
+#[cfg(feature = "clock-200")]
+pub const SYS_CK: Hertz = Hertz::MHz(200);
+#[cfg(feature = "clock-400")]
+pub const SYS_CK: Hertz = Hertz::MHz(400);
+#[cfg(feature = "clock-480")]
+pub const SYS_CK: Hertz = Hertz::MHz(480);
+
+/// Fastest sys_ck short of VOS0
+const VOS1_SYS_CK_MAX: Hertz = Hertz::MHz(400);
+
+/// How long to give HSE to start before falling back to HSI, in HSI cycles.
+/// Crystals take a few ms, so this is 100 ms at 64 MHz.
+const HSE_STARTUP_CYCLES: u32 = 6_400_000;
+
+const RCC_CR_HSEON: u32 = 1 << 16;
+const RCC_CR_HSERDY: u32 = 1 << 17;
+const RCC_CR_HSEBYP: u32 = 1 << 18;
+const RCC_CR_CSSHSEON: u32 = 1 << 19;
+const RCC_CICR_HSECSSC: u32 = 1 << 10;
+
+/// Where the PLLs get their reference from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    Hsi,
+    Hse,
+}
+
+static USING_HSE: AtomicBool = AtomicBool::new(false);
+
+/// Set by the CSS when HSE fails after startup
+static HSE_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Fastest ADC kernel clock that's in spec on both silicon revisions
+pub const ADC_KER_CK_MAX: Hertz = Hertz::MHz(36);
+
+/// Core clock recorded by `init`, for turning cycle counts into time
+static CORE_HZ: AtomicU32 = AtomicU32::new(0);
+
+/// The profile's sys_ck, or the fastest VOS1 allows if the profile needs
+/// VOS0 and the core isn't at it
+pub fn sys_ck(vos0: bool) -> Hertz {
+    if !vos0 && SYS_CK.raw() > VOS1_SYS_CK_MAX.raw() {
+        VOS1_SYS_CK_MAX
+    } else {
+        SYS_CK
+    }
+}
+
+/// Point the RCC at HSE, if `hse` selects it and it starts. Call before
+/// `freeze`, which would otherwise wait forever on a dead HSE.
+pub fn select_source(rcc: Rcc) -> Rcc {
+    #[cfg(feature = "hse")]
+    {
+        if start_hse() {
+            USING_HSE.store(true, Ordering::Relaxed);
+            let rcc = rcc.use_hse(target::HSE);
+            return if target::HSE_BYPASS {
+                rcc.bypass_hse()
+            } else {
+                rcc
+            };
+        }
+        log::error!("HSE didn't start within 100 ms, falling back to HSI");
+    }
+    rcc
+}
+
+/// Try starting HSE ourselves, so we can give up on it
+#[cfg(feature = "hse")]
+fn start_hse() -> bool {
+    // Safety: freeze reconfigures all of this, we're only probing
+    let rcc = unsafe { &*pac::RCC::ptr() };
+
+    let bypass = if target::HSE_BYPASS { RCC_CR_HSEBYP } else { 0 };
+    // HSEBYP can only be changed with HSE off
+    rcc.cr
+        .modify(|r, w| unsafe { w.bits((r.bits() & !RCC_CR_HSEBYP) | bypass) });
+    rcc.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_CR_HSEON) });
+
+    const POLL_CYCLES: u32 = 1000;
+    for _ in 0..HSE_STARTUP_CYCLES / POLL_CYCLES {
+        if rcc.cr.read().bits() & RCC_CR_HSERDY != 0 {
+            return true;
+        }
+        cortex_m::asm::delay(POLL_CYCLES);
+    }
+
+    rcc.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !RCC_CR_HSEON) });
+    false
+}
+
+/// Watch HSE with the clock security system. If it fails the hardware
+/// switches to HSI and raises an NMI, which we record.
+fn enable_css() {
+    // Safety: CSSHSEON is set once and never touched by the HAL
+    let rcc = unsafe { &*pac::RCC::ptr() };
+    rcc.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_CR_CSSHSEON) });
+}
+
+pub fn source() -> ClockSource {
+    if USING_HSE.load(Ordering::Relaxed) && !HSE_FAILED.load(Ordering::Relaxed) {
+        ClockSource::Hse
+    } else {
+        ClockSource::Hsi
+    }
+}
+
+/// Whether the CSS has caught HSE failing since boot
+pub fn hse_failed() -> bool {
+    HSE_FAILED.load(Ordering::Relaxed)
+}
+
+/// Record and log the clocks `freeze` produced, warning about any that are
+/// out of spec or differ from what the profile asked for
+pub fn init(clocks: &CoreClocks) {
+    let sys_ck = clocks.sys_ck();
+    CORE_HZ.store(clocks.c_ck().raw(), Ordering::Relaxed);
+    if USING_HSE.load(Ordering::Relaxed) {
+        enable_css();
+    }
+
+    info!("Clock source: {:?}", source());
+
+    info!(
+        "Clocks: sys_ck={} MHz c_ck={} MHz hclk={} MHz pclk1={} MHz pclk2={} MHz pclk4={} MHz",
+        sys_ck.raw() / 1_000_000,
+        clocks.c_ck().raw() / 1_000_000,
+        clocks.hclk().raw() / 1_000_000,
+        clocks.pclk1().raw() / 1_000_000,
+        clocks.pclk2().raw() / 1_000_000,
+        clocks.pclk4().raw() / 1_000_000,
+    );
+    if sys_ck != SYS_CK {
+        warn!(
+            "sys_ck is {} Hz, the profile asked for {} Hz",
+            sys_ck.raw(),
+            SYS_CK.raw()
+        );
+    }
+
+    match clocks.pll2_p_ck() {
+        Some(adc_ker_ck) => {
+            info!("ADC kernel clock (PLL2 P): {} kHz", adc_ker_ck.raw() / 1000);
+            if adc_ker_ck > ADC_KER_CK_MAX {
+                warn!(
+                    "ADC kernel clock is above the {} MHz maximum",
+                    ADC_KER_CK_MAX.raw() / 1_000_000
+                );
+            }
+        }
+        None => warn!("PLL2 P isn't running, the ADC has no kernel clock"),
+    }
+}
+
+/// Fastest clock an MCO pin can usefully drive
+pub const MCO_MAX_HZ: u32 = 50_000_000;
+
+/// MCO prescaler to apply to `source_hz`: `divider`, raised until the output
+/// is under `MCO_MAX_HZ`, and limited to the 1 to 15 the hardware has
+pub const fn mco_prescaler(source_hz: u32, divider: u32) -> u32 {
+    let min = source_hz.div_ceil(MCO_MAX_HZ);
+    let prescaler = if divider > min { divider } else { min };
+
+    if prescaler < 1 {
+        1
+    } else if prescaler > 15 {
+        15
+    } else {
+        prescaler
+    }
+}
+
+/// Core clock in Hz, zero before `init`
+pub fn core_hz() -> u32 {
+    CORE_HZ.load(Ordering::Relaxed)
+}
+
+/// The DWT cycle counter, which `board::init` starts, for timestamps and
+/// timing. It wraps, so take differences with `timing::elapsed_cycles`.
+pub fn now_cycles() -> u32 {
+    cortex_m::peripheral::DWT::cycle_count()
+}
+
+/// Convert a DWT cycle count to microseconds at the recorded core clock
+pub fn cycles_to_us(cycles: u32) -> u32 {
+    match core_hz() / 1_000_000 {
+        0 => 0,
+        mhz => cycles / mhz,
+    }
+}
+
+/// The only NMI source we enable is the HSE clock security system. Logging
+/// here could corrupt a log line the NMI interrupted, so just record it.
+#[exception]
+fn NonMaskableInt() {
+    // Safety: write 1 to clear just the CSS flag
+    let rcc = unsafe { &*pac::RCC::ptr() };
+    rcc.cicr.write(|w| unsafe { w.bits(RCC_CICR_HSECSSC) });
+
+    HSE_FAILED.store(true, Ordering::Relaxed);
+}
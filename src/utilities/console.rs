@@ -0,0 +1,245 @@
+//! A line-based command console on the board's virtual COM port, USART3
+//! through the ST-LINK on the Nucleo, for changing capture settings without
+//! reflashing.
+//!
+//! The UART's interrupt only moves received bytes into an SPSC queue.
+//! `Console::poll` drains it from the main loop, assembles lines, and applies
+//! each as a `Command`, replying `ok` or why not. See `crate::command` for
+//! what's understood.
+//!
+//! Protocol frames go out of the console too, or with the `usb` or
+//! `ethernet` feature, over USB or UDP once `frames_over_usb` or
+//! `frames_over_udp` hands it the transport.
+//!
+//! The UART isn't clocked in Stop, so with `low-power` anything typed while
+//! the board sleeps is lost. Send commands just after a capture is logged.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+use heapless::spsc::{Consumer, Producer, Queue};
+use heapless::Vec;
+use log::{info, warn};
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::CoreClocks;
+use stm32h7xx_hal::serial::{Event, Rx, Tx};
+
+use crate::command::Command;
+use crate::config::{Config, Limits};
+
+use crate::board::target::{self, VcpPins, VcpRec, VcpUsart};
+use crate::transport::sink::Backpressure;
+#[cfg(feature = "ethernet")]
+use crate::transport::udp::UdpFrames;
+#[cfg(feature = "usb")]
+use crate::transport::usb::UsbFrames;
+use crate::transport::TransportError;
+
+const BAUD: u32 = 115_200;
+
+/// Bytes the interrupt can hold for `poll`, which is one less than this
+const RX_QUEUE: usize = 64;
+
+/// Longest command line, anything longer is refused whole
+const LINE_MAX: usize = 48;
+
+static mut QUEUE: Queue<u8, RX_QUEUE> = Queue::new();
+
+/// The interrupt's half: the receiver and the queue's producer end
+static RX: Mutex<RefCell<Option<(Rx<VcpUsart>, Producer<'static, u8, RX_QUEUE>)>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Bytes lost to a full queue or a receive error since the last `poll`
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+pub struct Console {
+    tx: Tx<VcpUsart>,
+    rx: Consumer<'static, u8, RX_QUEUE>,
+    line: Vec<u8, LINE_MAX>,
+    /// The current line got too long, so skip to the end of it
+    discarding: bool,
+    /// Where frames go instead, if set
+    #[cfg(feature = "usb")]
+    usb: Option<UsbFrames>,
+    #[cfg(feature = "ethernet")]
+    udp: Option<UdpFrames>,
+}
+
+/// Start the board's VCP UART at 115200 8N1 and hand its receiver to the
+/// interrupt. Only call this once, the queue can only be split once.
+pub fn init(usart: VcpUsart, pins: VcpPins, prec: VcpRec, clocks: &CoreClocks) -> Console {
+    let mut serial = usart
+        .serial(pins, BAUD.bps(), prec, clocks)
+        .expect("the console UART can't make 115200 baud from its kernel clock");
+    serial.listen(Event::Rxne);
+    let (tx, rx) = serial.split();
+
+    // Safety: `init` runs once, so this is the only reference to the queue
+    let (producer, consumer) = unsafe { (*core::ptr::addr_of_mut!(QUEUE)).split() };
+    cortex_m::interrupt::free(|cs| RX.borrow(cs).replace(Some((rx, producer))));
+    // Safety: the handler only reads bytes into the queue
+    unsafe { NVIC::unmask(target::VCP_INTERRUPT) };
+
+    info!("Console on {} at {} baud", target::VCP_NAME, BAUD);
+    Console {
+        tx,
+        rx: consumer,
+        line: Vec::new(),
+        discarding: false,
+        #[cfg(feature = "usb")]
+        usb: None,
+        #[cfg(feature = "ethernet")]
+        udp: None,
+    }
+}
+
+impl Console {
+    /// Apply every whole line received since the last call to `config`,
+    /// replying to each one
+    pub fn poll(&mut self, config: &mut Config, limits: &Limits) {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Console lost {} bytes", dropped);
+            self.reply(format_args!("error: lost {} bytes, resend", dropped));
+        }
+
+        while let Some(byte) = self.rx.dequeue() {
+            match byte {
+                b'\r' | b'\n' => {
+                    if !self.discarding && !self.line.is_empty() {
+                        self.execute(config, limits);
+                    }
+                    self.line.clear();
+                    self.discarding = false;
+                }
+                _ if self.discarding => {}
+                _ => {
+                    if self.line.push(byte).is_err() {
+                        self.discarding = true;
+                        self.reply(format_args!("error: longer than {} bytes", LINE_MAX));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a line back to the terminal
+    pub fn reply(&mut self, line: fmt::Arguments) {
+        reply(&mut self.tx, line);
+    }
+
+    /// Send binary data, like protocol frames, blocking until it's all gone
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            // Blocking writes only ever wait, they don't fail
+            let _ = nb::block!(self.tx.write(byte));
+        }
+    }
+
+    /// Send frames over USB from now on, rather than the UART
+    #[cfg(feature = "usb")]
+    pub fn frames_over_usb(&mut self, usb: UsbFrames) {
+        self.usb = Some(usb);
+    }
+
+    /// Send frames as UDP datagrams from now on, rather than the UART
+    #[cfg(feature = "ethernet")]
+    pub fn frames_over_udp(&mut self, udp: UdpFrames) {
+        self.udp = Some(udp);
+    }
+
+    /// Send one encoded protocol frame. Over the UART this blocks until
+    /// it's sent, over USB it's dropped if no host is listening, and over
+    /// UDP if the link is down.
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        #[cfg(feature = "usb")]
+        if let Some(usb) = self.usb.as_mut() {
+            return usb.write_frame(frame);
+        }
+        #[cfg(feature = "ethernet")]
+        if let Some(udp) = self.udp.as_mut() {
+            return udp.write_frame(frame);
+        }
+        self.write_bytes(frame);
+        Ok(())
+    }
+
+    /// How `write_frame` behaves when frames come faster than they go
+    pub fn frames_backpressure(&self) -> Backpressure {
+        #[cfg(feature = "usb")]
+        if self.usb.is_some() {
+            return Backpressure::Drop;
+        }
+        #[cfg(feature = "ethernet")]
+        if self.udp.is_some() {
+            return Backpressure::Buffer;
+        }
+        Backpressure::Block
+    }
+
+    fn execute(&mut self, config: &mut Config, limits: &Limits) {
+        let Ok(line) = core::str::from_utf8(&self.line) else {
+            reply(&mut self.tx, format_args!("error: not text"));
+            return;
+        };
+
+        match line.parse::<Command>() {
+            Ok(Command::Log(setting)) => {
+                if crate::logger::apply(setting) {
+                    info!("Console: {}", line.trim());
+                    reply(&mut self.tx, format_args!("ok"));
+                } else {
+                    reply(
+                        &mut self.tx,
+                        format_args!(
+                            "error: only {} modules can have a level, `log reset` to clear them",
+                            crate::logger::MODULE_LEVELS
+                        ),
+                    );
+                }
+            }
+            Ok(command) => match config.apply(command, limits) {
+                Ok(()) => {
+                    info!("Console: {}", line.trim());
+                    reply(&mut self.tx, format_args!("ok"));
+                }
+                Err(e) => reply(&mut self.tx, format_args!("error: {}", e)),
+            },
+            Err(e) => reply(&mut self.tx, format_args!("error: {}", e)),
+        }
+    }
+}
+
+fn reply(tx: &mut Tx<VcpUsart>, line: fmt::Arguments) {
+    // Writes block until sent rather than fail, so there's nothing to handle
+    let _ = tx.write_fmt(line);
+    let _ = tx.write_str("\r\n");
+}
+
+/// The body of the VCP UART's interrupt, which the board module binds
+pub fn on_receive() {
+    cortex_m::interrupt::free(|cs| {
+        let mut rx = RX.borrow(cs).borrow_mut();
+        let Some((rx, queue)) = rx.as_mut() else {
+            return;
+        };
+
+        // Reading clears RXNE, and an error read clears its flag, so drain
+        // everything to leave nothing pending
+        loop {
+            match rx.read() {
+                Ok(byte) => {
+                    if queue.enqueue(byte).is_err() {
+                        DROPPED.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    });
+}
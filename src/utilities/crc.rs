@@ -0,0 +1,134 @@
+//! CRC-32 on the CRC unit, set up to give the same result as zlib's
+//! `crc32` (and `crate::protocol::crc32`), so the host can check frames.
+//!
+//! zlib's CRC is reflected: bytes go in least significant bit first and the
+//! result comes out bit reversed, then inverted. The unit's reset state is
+//! the right polynomial and initial value but neither reflection, and it
+//! has no final XOR, so those are set up here and done in software. Get
+//! either reflection wrong and the result is still a plausible looking
+//! number, so `init` checks the standard test vector:
+//!
+//! ```text
+//! crc32(b"123456789") == 0xCBF4_3926
+//! ```
+//!
+//! and that a longer buffer agrees with the software CRC the host uses.
+
+use log::{error, info};
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::rec;
+
+use crate::protocol::crc32 as software;
+
+const CRC_CR_RESET: u32 = 1 << 0;
+/// REV_IN = 01, reverse the bits of each byte going in
+const CRC_CR_REV_IN_BYTE: u32 = 0b01 << 5;
+const CRC_CR_REV_OUT: u32 = 1 << 7;
+
+const POLYNOMIAL: u32 = 0x04C1_1DB7;
+const INITIAL: u32 = 0xFFFF_FFFF;
+
+const CHECK_INPUT: &[u8] = b"123456789";
+const CHECK_VALUE: u32 = 0xCBF4_3926;
+
+fn crc() -> &'static pac::crc::RegisterBlock {
+    // Safety: `init` took the CRC unit, and only this module uses it
+    unsafe { &*pac::CRC::ptr() }
+}
+
+/// Clock and configure the CRC unit, and check it agrees with zlib
+pub fn init(_crc: pac::CRC, prec: rec::Crc) {
+    prec.enable().reset();
+
+    let crc = crc();
+    crc.pol.write(|w| unsafe { w.bits(POLYNOMIAL) });
+    // POLYSIZE = 00 is 32 bits
+    crc.cr
+        .write(|w| unsafe { w.bits(CRC_CR_REV_IN_BYTE | CRC_CR_REV_OUT) });
+
+    // An odd length, so both the word and byte paths are exercised
+    let mut pattern = [0u8; 255];
+    pattern
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = (i as u8).wrapping_mul(31));
+
+    let check = crc32(CHECK_INPUT);
+    if check != CHECK_VALUE {
+        error!(
+            "CRC unit gives {:#010x} for the check string, not {:#010x}",
+            check, CHECK_VALUE
+        );
+    } else if crc32(&pattern) != software::crc32(&pattern) {
+        error!("CRC unit disagrees with the software CRC");
+    } else {
+        info!("CRC unit matches zlib's CRC-32");
+    }
+}
+
+/// A CRC over data that arrives in pieces. Each `Digest` keeps its own
+/// state, so other CRCs can be taken between its updates.
+#[derive(Clone, Copy, Debug)]
+pub struct Digest {
+    /// The unit's internal register, i.e. before the output reversal
+    state: u32,
+}
+
+impl Digest {
+    pub const fn new() -> Self {
+        Self { state: INITIAL }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let crc = crc();
+
+        // Reset loads INIT into the register, carrying on from our state
+        crc.init.write(|w| unsafe { w.bits(self.state) });
+        crc.cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CRC_CR_RESET) });
+
+        // Whole words go in big-endian, so with each byte's bits reversed
+        // the first byte's least significant bit is still the first bit in
+        let mut words = data.chunks_exact(4);
+        for word in &mut words {
+            let word = u32::from_be_bytes(word.try_into().unwrap());
+            crc.dr.write(|w| unsafe { w.bits(word) });
+        }
+        // The rest a byte at a time, which needs byte-wide writes to DR
+        let dr = core::ptr::addr_of!(crc.dr) as *mut u8;
+        for &byte in words.remainder() {
+            // Safety: DR accepts 8-bit writes, and takes them as one byte
+            unsafe { core::ptr::write_volatile(dr, byte) };
+        }
+
+        // DR reads back reversed by REV_OUT
+        self.state = crc.dr.read().bits().reverse_bits();
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.state.reverse_bits()
+    }
+}
+
+impl Default for Digest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32 of `data`, as zlib computes it
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut digest = Digest::new();
+    digest.update(data);
+    digest.finish()
+}
+
+/// CRC-32 of samples as they sit in memory, little-endian
+pub fn crc32_samples(samples: &[u16]) -> u32 {
+    // Safety: any u16 is two valid bytes, and u8 has no alignment to meet
+    let bytes = unsafe {
+        core::slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 2)
+    };
+    crc32(bytes)
+}
@@ -0,0 +1,284 @@
+//! A linear chirp out of DAC1 channel 1 on PA4 (CN7), with the `dac-chirp`
+//! feature, as a stimulus to capture the response to.
+//!
+//! `play_chirp` works the sweep out into a table in AXISRAM, and arms the
+//! DAC to step through it on each TIM6 update, fed by DMA1 stream 1. Nothing
+//! comes out until `Chirp::start`, which is called just before the ADC's
+//! conversions are, so each capture sees the sweep from its first sample.
+//!
+//! Between sweeps the channel isn't triggered, so a code written to it goes
+//! straight out. A sweep's first code is preloaded, so the first trigger
+//! puts it on the pin and DMA fetches the rest, one code behind the
+//! triggers. After the last code DMA has stopped, and the DAC flags an
+//! underrun and holds mid-scale, which is harmless. The trigger can only be
+//! changed with the channel off, which it is for a few cycles either side
+//! of each sweep.
+//!
+//! The registers are driven directly, as for the SAI: RM0433 §26 for the
+//! DAC and §39 for TIM6.
+
+use log::{info, warn};
+use stm32h7xx_hal::dac::{Disabled, C1};
+use stm32h7xx_hal::delay::Delay;
+use stm32h7xx_hal::dma::dma::Stream1;
+use stm32h7xx_hal::gpio::{Analog, PA4};
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+
+use crate::chirp::{self, DAC_MID};
+
+/// Codes a second out of the DAC. Its output buffer settles in about
+/// `SETTLING_S`, so much faster than this only smooths the steps.
+pub const DAC_RATE_HZ: u32 = 200_000;
+
+/// Longest sweep, in codes: 64 KiB of AXISRAM, 163 ms at `DAC_RATE_HZ`
+const TABLE_LEN: usize = 32_768;
+
+/// How long the output buffer takes to settle to a new code (DS12110 §6.3.21)
+const SETTLING_S: f32 = 2e-6;
+
+/// Of the DAC's half range, leaving the output buffer some headroom
+const AMPLITUDE: f32 = 0.9;
+
+/// Dither added to each code of a sweep from the RNG, up to this many codes
+/// either way, so the DAC's own quantization doesn't put idle tones in
+/// what's captured. 0 for none, which is the sweep as it always was.
+const DITHER_CODES: f32 = 0.0;
+
+/// DMAMUX1 request for DAC1 channel 1 (RM0433 table 121)
+const DMAREQ_DAC1_CH1: u32 = 67;
+/// DMAMUX1 channel feeding DMA1 stream 1
+const DMAMUX1_DMA1_S1: usize = 1;
+
+/// Memory to peripheral, memory increments, 16-bit both ends, high priority
+const DMA_CR_PLAY: u32 = (0b01 << 6) // DIR: memory to peripheral
+    | (1 << 10) // MINC
+    | (0b01 << 11) // PSIZE: half-word
+    | (0b01 << 13) // MSIZE: half-word
+    | (0b10 << 16); // PL: high
+const DMA_CR_EN: u32 = 1 << 0;
+/// Every stream 1 flag in LISR and LIFCR
+const DMA_LIFCR_ALL1: u32 = 0b11_1101 << 6;
+
+/// Triggered by TIM6's TRGO, with DMA requests
+const DAC_CR_TRIGGERED: u32 = (1 << 1) // TEN1
+    | (5 << 2) // TSEL1: tim6_trgo
+    | (1 << 12); // DMAEN1
+const DAC_CR_EN1: u32 = 1 << 0;
+const DAC_SR_DMAUDR1: u32 = 1 << 13;
+
+/// TRGO on each update
+const TIM_CR2_MMS_UPDATE: u32 = 0b010 << 4;
+const TIM_CR1_CEN: u32 = 1 << 0;
+
+/// Asking for a sweep the table or the DAC can't play
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChirpError {
+    /// More codes than the table holds
+    TooLong { max_ms: u32 },
+    /// Past half the DAC's rate, which would alias
+    TooFast { max_hz: f32 },
+}
+
+/// DAC1 channel 1 and its DMA stream, idling at mid-scale between sweeps
+pub struct Dac {
+    _channel: C1<pac::DAC, Disabled>,
+    _stream: Stream1<pac::DMA1>,
+    table: &'static mut [u16; TABLE_LEN],
+    /// What's in the table, so a repeat of the same sweep isn't recomputed
+    filled: Option<(f32, f32, usize)>,
+}
+
+/// TIM6, pacing the DAC at `DAC_RATE_HZ`, or as near as it divides to
+pub struct DacTimer {
+    _tim6: pac::TIM6,
+    rate_hz: f32,
+}
+
+fn dac() -> &'static pac::dac::RegisterBlock {
+    // Safety: `init` took the DAC, after which only this module touches it
+    unsafe { &*pac::DAC::ptr() }
+}
+
+fn dma1() -> &'static pac::dma1::RegisterBlock {
+    // Safety: `init` took stream 1, the only one touched here
+    unsafe { &*pac::DMA1::ptr() }
+}
+
+fn tim6() -> &'static pac::tim6::RegisterBlock {
+    // Safety: `init` took TIM6
+    unsafe { &*pac::TIM6::ptr() }
+}
+
+/// Calibrate the DAC's output buffer and park it at mid-scale, with TIM6
+/// ready to pace it. DMA1's clock must already be on, which taking its
+/// streams does.
+pub fn init(
+    dac1: pac::DAC,
+    pin: PA4<Analog>,
+    prec: rec::Dac12,
+    stream: Stream1<pac::DMA1>,
+    tim6: pac::TIM6,
+    tim_prec: rec::Tim6,
+    clocks: &CoreClocks,
+    delay: &mut Delay,
+) -> (Dac, DacTimer) {
+    let channel = dac1.dac(pin, prec).calibrate_buffer(delay);
+    idle();
+
+    // Safety: only this channel's request is set
+    let dmamux = unsafe { &*pac::DMAMUX1::ptr() };
+    dmamux.ccr[DMAMUX1_DMA1_S1].write(|w| unsafe { w.bits(DMAREQ_DAC1_CH1) });
+
+    tim_prec.enable().reset();
+    let timer_hz = clocks.timx_ker_ck().raw();
+    let reload = (timer_hz / DAC_RATE_HZ).clamp(2, 65_536) - 1;
+    let tim = self::tim6();
+    tim.psc.write(|w| unsafe { w.bits(0) });
+    tim.arr.write(|w| unsafe { w.bits(reload) });
+    tim.cr2.write(|w| unsafe { w.bits(TIM_CR2_MMS_UPDATE) });
+    let rate_hz = timer_hz as f32 / (reload + 1) as f32;
+
+    info!("DAC chirps on PA4 at {} S/s", rate_hz);
+    (
+        Dac {
+            _channel: channel,
+            _stream: stream,
+            table: dma_buffer!(".axisram", u16, TABLE_LEN),
+            filled: None,
+        },
+        DacTimer {
+            _tim6: tim6,
+            rate_hz,
+        },
+    )
+}
+
+/// Arm a sweep from `start_hz` to `end_hz` over `duration_ms`, to play from
+/// `Chirp::start`. The table is only worked out again for a different sweep.
+pub fn play_chirp<'a>(
+    dac: &'a mut Dac,
+    timer: &'a mut DacTimer,
+    start_hz: f32,
+    end_hz: f32,
+    duration_ms: u32,
+) -> Result<Chirp<'a>, ChirpError> {
+    let len = (timer.rate_hz * duration_ms as f32 / 1000.0) as usize;
+    if len > TABLE_LEN {
+        return Err(ChirpError::TooLong {
+            max_ms: (TABLE_LEN as f32 / timer.rate_hz * 1000.0) as u32,
+        });
+    }
+    let max_hz = timer.rate_hz / 2.0;
+    if start_hz.max(end_hz) > max_hz {
+        return Err(ChirpError::TooFast { max_hz });
+    }
+    if len < 2 {
+        // Nothing but the idle code to play
+        return Ok(Chirp {
+            _dac: dac,
+            timer,
+            armed: false,
+        });
+    }
+
+    let sweep = Some((start_hz, end_hz, len));
+    if dac.filled != sweep {
+        // The dither's in the table, so it repeats with each play of the
+        // same sweep, but not with the sweep's own period
+        chirp::fill_linear_chirp_dithered(
+            &mut dac.table[..len],
+            timer.rate_hz,
+            start_hz,
+            end_hz,
+            AMPLITUDE,
+            || {
+                if DITHER_CODES > 0.0 {
+                    super::rng::next_f32_pm(DITHER_CODES)
+                } else {
+                    0.0
+                }
+            },
+        );
+        dac.filled = sweep;
+    }
+
+    // The DAC only takes a new code on a trigger, so the first can wait in
+    // DHR and the rest follow by DMA
+    let st = &dma1().st[1];
+    st.cr.write(|w| unsafe { w.bits(0) });
+    while st.cr.read().bits() & DMA_CR_EN != 0 {}
+    dma1().lifcr.write(|w| unsafe { w.bits(DMA_LIFCR_ALL1) });
+    dac().sr.write(|w| unsafe { w.bits(DAC_SR_DMAUDR1) });
+    dac().cr.write(|w| unsafe { w.bits(0) });
+    dac().cr.write(|w| unsafe { w.bits(DAC_CR_TRIGGERED) });
+    dac()
+        .cr
+        .write(|w| unsafe { w.bits(DAC_CR_TRIGGERED | DAC_CR_EN1) });
+    dac()
+        .dhr12r1
+        .write(|w| unsafe { w.bits(dac.table[0] as u32) });
+    st.par
+        .write(|w| unsafe { w.bits(dac().dhr12r1.as_ptr() as u32) });
+    st.m0ar
+        .write(|w| unsafe { w.bits(dac.table[1..].as_ptr() as u32) });
+    st.ndtr.write(|w| unsafe { w.bits(len as u32 - 1) });
+    st.cr.write(|w| unsafe { w.bits(DMA_CR_PLAY | DMA_CR_EN) });
+
+    Ok(Chirp {
+        _dac: dac,
+        timer,
+        armed: true,
+    })
+}
+
+/// Take the trigger off and put out mid-scale, straight away
+fn idle() {
+    dac().cr.write(|w| unsafe { w.bits(0) });
+    dac().cr.write(|w| unsafe { w.bits(DAC_CR_EN1) });
+    dac().dhr12r1.write(|w| unsafe { w.bits(DAC_MID as u32) });
+}
+
+/// An armed sweep. Dropping it stops the timer, the rest of the sweep with
+/// it, and parks the DAC back at mid-scale.
+pub struct Chirp<'a> {
+    _dac: &'a mut Dac,
+    timer: &'a mut DacTimer,
+    /// Whether DMA has a sweep to play, which an empty one doesn't
+    armed: bool,
+}
+
+impl Chirp<'_> {
+    /// Start the sweep. The counter starts on its last count, so the first
+    /// code lands on the pin a single tick from now, `1 / DAC_RATE_HZ`, with
+    /// the output buffer's couple of microseconds on top. Started just
+    /// before the ADC, the sweep lags the capture by that, `lag_samples`.
+    pub fn start(&self) {
+        let tim = tim6();
+        let reload = tim.arr.read().bits();
+        tim.cnt.write(|w| unsafe { w.bits(reload) });
+        tim.cr1.write(|w| unsafe { w.bits(TIM_CR1_CEN) });
+    }
+
+    /// How many samples into a capture at `sample_rate_hz`, started right
+    /// after this, the sweep's first code lands
+    pub fn lag_samples(&self, sample_rate_hz: f32) -> f32 {
+        sample_rate_hz * (1.0 / self.timer.rate_hz + SETTLING_S)
+    }
+}
+
+impl Drop for Chirp<'_> {
+    fn drop(&mut self) {
+        tim6().cr1.write(|w| unsafe { w.bits(0) });
+        if self.armed {
+            let st = &dma1().st[1];
+            let remaining = st.ndtr.read().bits();
+            st.cr.write(|w| unsafe { w.bits(0) });
+            if remaining != 0 {
+                warn!("Chirp cut short with {} codes to go", remaining);
+            }
+        }
+        idle();
+    }
+}
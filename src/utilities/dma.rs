@@ -0,0 +1,583 @@
+//! Status of the ADC capture stream, DMA1 stream 0.
+//!
+//! The HAL's `Transfer` only exposes the transfer-complete flag, so the rest
+//! of the stream's status is read straight from the DMA1 registers. This is
+//! read-only apart from clearing flags, the interrupt enables and the idle
+//! memory address in double-buffer mode, so it's safe alongside a live
+//! `Transfer` on the same stream.
+//!
+//! This module also owns the `DMA1_STR0` interrupt handler, which in
+//! continuous acquisition hands each buffer of a `crate::pool::Pool` over as
+//! it fills, and points the stream at a free one. With `rtic` the handler is
+//! RTIC's to bind, see `on_interrupt`.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::{DWT, NVIC, SCB};
+use log::error;
+use stm32h7xx_hal::dma::config::{FifoThreshold as HalFifoThreshold, Priority};
+use stm32h7xx_hal::dma::traits::{Direction, Stream, TargetAddress};
+use stm32h7xx_hal::dma::Transfer;
+use stm32h7xx_hal::pac;
+#[cfg(not(feature = "rtic"))]
+use stm32h7xx_hal::pac::interrupt;
+use stm32h7xx_hal::pac::Interrupt;
+
+use crate::acquisition::{DmaPriority, FifoThreshold};
+use crate::pool::{Descriptor, Filler, Pool, Reader, Token};
+
+use super::{clocks, monotonic};
+use crate::board::SIZE;
+
+const SCB_SCR_SEVONPEND: u32 = 1 << 4;
+
+/// How to wait for a capture to finish
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitMode {
+    /// Spin on the status flags at full clock
+    BusyWait,
+    /// `wfi` until the stream's interrupt handler reports completion
+    Interrupt,
+    /// Clock-gate the core in sleep until the stream raises an interrupt
+    LowPower,
+}
+
+/// Set by the stream interrupt handler when the transfer completes
+static TRANSFER_DONE: AtomicBool = AtomicBool::new(false);
+/// Set by the stream interrupt handler on an error, 0 for none
+static TRANSFER_ERROR: AtomicU8 = AtomicU8::new(0);
+
+/// Buffers for continuous acquisition: two for DMA, and two to queue while
+/// the reader is busy
+pub const POOL_SIZE: usize = 4;
+
+/// Set between `start_continuous` and `stop_continuous`
+static CONTINUOUS: AtomicBool = AtomicBool::new(false);
+
+static mut POOL: Pool<POOL_SIZE> = Pool::new();
+
+/// The interrupt's half of the pool
+static FILLER: Mutex<RefCell<Option<Filler<'static, POOL_SIZE>>>> = Mutex::new(RefCell::new(None));
+
+/// Where the pool's buffers are, `SIZE` samples each
+static POOL_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// The HAL's setting for a stream priority
+pub const fn hal_priority(priority: DmaPriority) -> Priority {
+    match priority {
+        DmaPriority::Low => Priority::Low,
+        DmaPriority::Medium => Priority::Medium,
+        DmaPriority::High => Priority::High,
+        DmaPriority::VeryHigh => Priority::VeryHigh,
+    }
+}
+
+/// The HAL's setting for a FIFO threshold
+pub const fn hal_fifo_threshold(threshold: FifoThreshold) -> HalFifoThreshold {
+    match threshold {
+        FifoThreshold::Quarter => HalFifoThreshold::QuarterFull,
+        FifoThreshold::Half => HalFifoThreshold::HalfFull,
+        FifoThreshold::ThreeQuarters => HalFifoThreshold::ThreeQuarterFull,
+        FifoThreshold::Full => HalFifoThreshold::Full,
+    }
+}
+
+/// Errors reported by the stream in LISR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaError {
+    /// Bus error on a transfer, e.g. the buffer isn't reachable from DMA1
+    Transfer,
+    /// A request arrived in direct mode before the previous one finished
+    DirectMode,
+    /// FIFO underrun/overrun, or a burst that doesn't fit the FIFO threshold
+    Fifo,
+}
+
+impl DmaError {
+    fn code(self) -> u8 {
+        match self {
+            DmaError::Transfer => 1,
+            DmaError::DirectMode => 2,
+            DmaError::Fifo => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(DmaError::Transfer),
+            2 => Some(DmaError::DirectMode),
+            3 => Some(DmaError::Fifo),
+            _ => None,
+        }
+    }
+}
+
+/// Why a capture didn't deliver a full buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureError {
+    Dma(DmaError),
+    /// The ADC overran, so samples were dropped from the buffer
+    Overrun,
+    /// Nothing completed in time; `received` samples had arrived by then
+    Timeout {
+        received: usize,
+    },
+}
+
+/// A transfer didn't complete in the cycles it was given
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeout;
+
+impl From<DmaError> for CaptureError {
+    fn from(e: DmaError) -> Self {
+        CaptureError::Dma(e)
+    }
+}
+
+/// Cycles to allow for `samples` conversions at `sample_rate_hz` on a core
+/// running at `core_hz`, stretched by `safety_factor` for slack
+pub fn timeout_cycles(
+    samples: usize,
+    sample_rate_hz: f32,
+    core_hz: u32,
+    safety_factor: f32,
+) -> u32 {
+    let seconds = samples as f32 / sample_rate_hz * safety_factor;
+    let cycles = seconds * core_hz as f32;
+
+    // The cycle counter is 32 bits, about 44s at 96MHz
+    if cycles >= u32::MAX as f32 {
+        u32::MAX
+    } else {
+        cycles as u32
+    }
+}
+
+fn dma1() -> &'static pac::dma1::RegisterBlock {
+    // Safety: we only read status and write the write-1-to-clear register
+    unsafe { &*pac::DMA1::ptr() }
+}
+
+pub fn transfer_complete() -> bool {
+    dma1().lisr.read().tcif0().bit_is_set()
+}
+
+/// The first error flag set on the stream, if any
+pub fn error() -> Option<DmaError> {
+    let lisr = dma1().lisr.read();
+
+    if lisr.teif0().bit_is_set() {
+        Some(DmaError::Transfer)
+    } else if lisr.dmeif0().bit_is_set() {
+        Some(DmaError::DirectMode)
+    } else if lisr.feif0().bit_is_set() {
+        Some(DmaError::Fifo)
+    } else {
+        None
+    }
+}
+
+/// Clear every flag for the stream, ready for the next transfer
+pub fn clear_flags() {
+    dma1().lifcr.write(|w| {
+        w.ctcif0()
+            .set_bit()
+            .chtif0()
+            .set_bit()
+            .cteif0()
+            .set_bit()
+            .cdmeif0()
+            .set_bit()
+            .cfeif0()
+            .set_bit()
+    });
+}
+
+/// Number of transfers the stream still has to make
+pub fn remaining() -> u16 {
+    dma1().st[0].ndtr.read().ndt().bits()
+}
+
+/// Dump the stream registers, for when something has gone wrong
+pub fn log_state() {
+    let dma = dma1();
+    let st = &dma.st[0];
+
+    error!(
+        "DMA1 S0: LISR={:#010x} CR={:#010x} NDTR={} FCR={:#010x} PAR={:#010x} M0AR={:#010x}",
+        dma.lisr.read().bits(),
+        st.cr.read().bits(),
+        st.ndtr.read().ndt().bits(),
+        st.fcr.read().bits(),
+        st.par.read().bits(),
+        st.m0ar.read().bits(),
+    );
+}
+
+/// How many of `len` requested samples DMA has delivered so far
+pub fn received(len: usize) -> usize {
+    len.saturating_sub(remaining() as usize)
+}
+
+/// Whether more than `timeout_cycles` have passed since the cycle count `start`
+pub fn timed_out(start: u32, timeout_cycles: u32) -> bool {
+    // Wrapping subtraction keeps this right across counter overflow
+    DWT::cycle_count().wrapping_sub(start) > timeout_cycles
+}
+
+/// Have TIM5 wake the core once `timeout_cycles` are up, for waits that
+/// sleep and so only check `timed_out` when something wakes them. Rounded up
+/// a microsecond, so the wake-up never lands before `timed_out` agrees.
+pub fn arm_timeout(timeout_cycles: u32) {
+    monotonic::arm_wakeup(clocks::cycles_to_us(timeout_cycles).saturating_add(1));
+}
+
+/// Spin until the stream either completes, reports an error or times out.
+/// This replaces waiting on `get_transfer_complete_flag()` alone, which
+/// never returns if the stream errors out or the ADC never converts.
+///
+/// `len` is the buffer length, used to report how much arrived on timeout.
+/// The DWT cycle counter must be running.
+pub fn wait_for_transfer(len: usize, timeout_cycles: u32) -> Result<(), CaptureError> {
+    let start = DWT::cycle_count();
+
+    loop {
+        if let Some(e) = error() {
+            return Err(e.into());
+        }
+        if transfer_complete() {
+            return Ok(());
+        }
+        if timed_out(start, timeout_cycles) {
+            return Err(CaptureError::Timeout {
+                received: received(len),
+            });
+        }
+    }
+}
+
+/// Spin until any HAL `Transfer` completes, for at most `max_cycles`. Unlike
+/// `wait_for_transfer` this only knows the transfer-complete flag, so an
+/// erroring stream just times out, but it works on any stream. The DWT cycle
+/// counter must be running.
+pub fn wait_transfer_timeout<STREAM, CONFIG, PERIPHERAL, DIR, BUF, TXFRT>(
+    transfer: &mut Transfer<STREAM, PERIPHERAL, DIR, BUF, TXFRT>,
+    max_cycles: u32,
+) -> Result<(), Timeout>
+where
+    STREAM: Stream<Config = CONFIG>,
+    DIR: Direction,
+    PERIPHERAL: TargetAddress<DIR>,
+{
+    let start = DWT::cycle_count();
+
+    while !transfer.get_transfer_complete_flag() {
+        if timed_out(start, max_cycles) {
+            return Err(Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Sleep until the stream completes or errors, instead of spinning.
+///
+/// The stream's interrupts are enabled in DMA but left masked in the NVIC,
+/// and SEVONPEND is set so the interrupt going pending raises an event that
+/// wakes `wfe`, without ever running a handler. Anything else can wake us too
+/// (debugger, other events), so every wake-up re-checks the DMA flags rather
+/// than assuming the transfer is done.
+///
+/// TIM5 is armed to wake us when the timeout is up, so a stream that never
+/// fires and never errors still returns. Its interrupt is unmasked, so it
+/// runs its handler as well as ending the `wfe`.
+pub fn acquire_low_power(
+    scb: &mut SCB,
+    len: usize,
+    timeout_cycles: u32,
+) -> Result<(), CaptureError> {
+    let start = DWT::cycle_count();
+
+    // Clock-gated sleep rather than deep sleep, the DMA needs its clocks
+    scb.clear_sleepdeep();
+    unsafe { scb.scr.modify(|r| r | SCB_SCR_SEVONPEND) };
+    NVIC::mask(Interrupt::DMA1_STR0);
+    set_interrupts(true);
+    arm_timeout(timeout_cycles);
+
+    let result = loop {
+        // Unpend first, so the next completion is a fresh pending edge
+        NVIC::unpend(Interrupt::DMA1_STR0);
+
+        if let Some(e) = error() {
+            break Err(e.into());
+        }
+        if transfer_complete() {
+            break Ok(());
+        }
+        if timed_out(start, timeout_cycles) {
+            break Err(CaptureError::Timeout {
+                received: received(len),
+            });
+        }
+
+        // If the stream finished between the check and here, the event is
+        // already latched and this returns immediately
+        cortex_m::asm::wfe();
+    };
+
+    monotonic::disarm_wakeup();
+    set_interrupts(false);
+    NVIC::unpend(Interrupt::DMA1_STR0);
+    unsafe { scb.scr.modify(|r| r & !SCB_SCR_SEVONPEND) };
+
+    result
+}
+
+/// Enable or disable every interrupt the stream can raise. FIFO errors have
+/// their own enable in FCR, needed once the FIFO is on for byte packing.
+fn set_interrupts(enable: bool) {
+    let st = &dma1().st[0];
+    st.cr
+        .modify(|_, w| w.tcie().bit(enable).teie().bit(enable).dmeie().bit(enable));
+    st.fcr.modify(|_, w| w.feie().bit(enable));
+}
+
+/// Wait in `wfi` for the stream's interrupt handler to flag completion.
+///
+/// The handler only runs while this is waiting, so the other modes keep
+/// seeing the raw DMA flags. TIM5 is armed to wake us when the timeout is
+/// up, so a stream that never fires still returns.
+pub fn wait_for_interrupt(len: usize, timeout_cycles: u32) -> Result<(), CaptureError> {
+    let start = DWT::cycle_count();
+
+    TRANSFER_DONE.store(false, Ordering::Relaxed);
+    TRANSFER_ERROR.store(0, Ordering::Relaxed);
+    set_interrupts(true);
+    // Safety: the handler only touches this stream's flags and the atomics
+    unsafe { NVIC::unmask(Interrupt::DMA1_STR0) };
+    arm_timeout(timeout_cycles);
+
+    let result = loop {
+        if let Some(e) = DmaError::from_code(TRANSFER_ERROR.load(Ordering::Acquire)) {
+            break Err(e.into());
+        }
+        if TRANSFER_DONE.load(Ordering::Acquire) {
+            break Ok(());
+        }
+        if timed_out(start, timeout_cycles) {
+            break Err(CaptureError::Timeout {
+                received: received(len),
+            });
+        }
+
+        // Check again with interrupts masked, so the handler can't finish
+        // between the check and the `wfi`. A pending interrupt still wakes
+        // `wfi`, and runs as soon as the critical section ends.
+        cortex_m::interrupt::free(|_| {
+            if !TRANSFER_DONE.load(Ordering::Acquire) && TRANSFER_ERROR.load(Ordering::Acquire) == 0
+            {
+                cortex_m::asm::wfi();
+            }
+        });
+    };
+
+    monotonic::disarm_wakeup();
+    NVIC::mask(Interrupt::DMA1_STR0);
+    set_interrupts(false);
+
+    result
+}
+
+/// Set up the pool of buffers for continuous acquisition in `buffers`,
+/// keeping its filling half for the interrupt and returning the reader's.
+/// Only call this once, the pool can only be split once.
+pub fn init_pool(buffers: &'static mut [[u16; SIZE]; POOL_SIZE]) -> Reader<'static, POOL_SIZE> {
+    POOL_BASE.store(buffers.as_mut_ptr() as usize, Ordering::Relaxed);
+    // Safety: this is the only reference to the pool, and it's only taken
+    // once
+    let (filler, reader) = unsafe { (*core::ptr::addr_of_mut!(POOL)).split() };
+    cortex_m::interrupt::free(|cs| FILLER.borrow(cs).replace(Some(filler)));
+    reader
+}
+
+fn pool_buffer(index: usize) -> *mut u16 {
+    (POOL_BASE.load(Ordering::Relaxed) as *mut u16).wrapping_add(index * SIZE)
+}
+
+/// The buffer `token` is for, to read while it's held
+pub fn pool_samples(token: &Token) -> &[u16] {
+    // Safety: holding the token means DMA isn't pointed at the buffer
+    unsafe { core::slice::from_raw_parts(pool_buffer(token.index()), SIZE) }
+}
+
+/// The buffers DMA starts on, for `Transfer::init` in double-buffer mode
+///
+/// # Safety
+///
+/// Only for setting up the transfer, and the references must go when the
+/// transfer's freed, as the interrupt moves the stream on to other buffers.
+pub unsafe fn armed_buffers() -> (&'static mut [u16], &'static mut [u16]) {
+    let (first, second) = cortex_m::interrupt::free(|cs| {
+        let filler = FILLER.borrow(cs).borrow();
+        let filler = filler.as_ref().expect("init_pool hasn't been called");
+        (filler.armed(0), filler.armed(1))
+    });
+    (
+        core::slice::from_raw_parts_mut(pool_buffer(first), SIZE),
+        core::slice::from_raw_parts_mut(pool_buffer(second), SIZE),
+    )
+}
+
+/// Have the stream's interrupt hand each filled buffer over, for a
+/// double-buffered transfer set up with its transfer-complete interrupt
+/// enabled. Call between `Transfer::init` and `start`. Anything `reader`
+/// still had queued is from before, so it's handed straight back.
+pub fn start_continuous(reader: &mut Reader<'static, POOL_SIZE>) {
+    while let Some(stale) = reader.take() {
+        reader.release(stale.token);
+    }
+    TRANSFER_ERROR.store(0, Ordering::Relaxed);
+    CONTINUOUS.store(true, Ordering::Release);
+    // Safety: the handler only touches this stream's registers, the atomics
+    // and the pool's filling half
+    unsafe { NVIC::unmask(Interrupt::DMA1_STR0) };
+}
+
+/// Stop handing buffers over, before freeing the transfer
+pub fn stop_continuous() {
+    NVIC::mask(Interrupt::DMA1_STR0);
+    CONTINUOUS.store(false, Ordering::Release);
+    NVIC::unpend(Interrupt::DMA1_STR0);
+}
+
+/// Wait for the next filled buffer. Its token is the caller's until it goes
+/// back with `Reader::release`, and its flags say if buffers were dropped
+/// before it.
+pub fn next_buffer(
+    reader: &mut Reader<'static, POOL_SIZE>,
+    timeout_cycles: u32,
+) -> Result<Descriptor, CaptureError> {
+    let start = DWT::cycle_count();
+    arm_timeout(timeout_cycles);
+
+    let result = loop {
+        if let Some(e) = DmaError::from_code(TRANSFER_ERROR.load(Ordering::Acquire)) {
+            break Err(e.into());
+        }
+        if let Some(filled) = reader.take() {
+            break Ok(filled);
+        }
+        if timed_out(start, timeout_cycles) {
+            break Err(CaptureError::Timeout { received: 0 });
+        }
+
+        // As in `wait_for_interrupt`, so a buffer can't land between the
+        // check and the `wfi`
+        cortex_m::interrupt::free(|_| {
+            if reader.waiting() == 0 && TRANSFER_ERROR.load(Ordering::Acquire) == 0 {
+                cortex_m::asm::wfi();
+            }
+        });
+    };
+
+    monotonic::disarm_wakeup();
+    result
+}
+
+/// The most buffers that have waited for the reader at once, and how many
+/// were dropped for want of a free one, once `init_pool` has been called
+pub fn pool_stats() -> Option<(usize, u32)> {
+    cortex_m::interrupt::free(|cs| {
+        let filler = FILLER.borrow(cs).borrow();
+        filler.as_ref().map(|f| (f.high_water(), f.dropped()))
+    })
+}
+
+/// Hand the buffer DMA just finished over, and point the address it was at
+/// at the one to fill next. In double-buffer mode CT says which address the
+/// stream moved on to, so the finished one is the other.
+fn hand_over() {
+    let st = &dma1().st[0];
+    let finished = if st.cr.read().ct().bit_is_set() { 0 } else { 1 };
+    let at = monotonic::now_us();
+    let next = cortex_m::interrupt::free(|cs| {
+        let mut filler = FILLER.borrow(cs).borrow_mut();
+        filler.as_mut().map(|f| f.complete(finished, at))
+    });
+    let Some(next) = next else {
+        return;
+    };
+
+    // Safety: DMA allows writing the address it isn't currently using
+    let address = pool_buffer(next) as u32;
+    if finished == 0 {
+        st.m0ar.write(|w| unsafe { w.bits(address) });
+    } else {
+        st.m1ar.write(|w| unsafe { w.bits(address) });
+    }
+}
+
+/// Everything the stream's interrupt does: records an error, completion or
+/// a buffer to hand over, and clears the flags. With `rtic`, `fft_rtic`'s DMA
+/// task has the interrupt and calls this.
+pub fn on_interrupt() {
+    if let Some(e) = error() {
+        TRANSFER_ERROR.store(e.code(), Ordering::Release);
+    } else if CONTINUOUS.load(Ordering::Acquire) {
+        // If the handler's held up for a whole buffer, one of them is lost
+        // without being counted, which the gap in timestamps shows
+        if transfer_complete() {
+            hand_over();
+        }
+    } else if transfer_complete() {
+        TRANSFER_DONE.store(true, Ordering::Release);
+    }
+
+    // The flags are level triggered, so clear them or we come straight back
+    clear_flags();
+}
+
+#[cfg(not(feature = "rtic"))]
+#[interrupt]
+fn DMA1_STR0() {
+    on_interrupt();
+}
+
+/// Wait for the stream with the chosen strategy
+pub fn wait(
+    mode: WaitMode,
+    scb: &mut SCB,
+    len: usize,
+    timeout_cycles: u32,
+) -> Result<(), CaptureError> {
+    match mode {
+        WaitMode::BusyWait => wait_for_transfer(len, timeout_cycles),
+        WaitMode::Interrupt => wait_for_interrupt(len, timeout_cycles),
+        WaitMode::LowPower => acquire_low_power(scb, len, timeout_cycles),
+    }
+}
+
+/// Make the stream read the ADC a byte at a time and pack two samples into
+/// each half-word of the buffer. Only valid for 8-bit conversions, and the
+/// FIFO must be enabled in the `DmaConfig` for the packing to happen.
+///
+/// Call between `Transfer::init` and `start`, while the stream is disabled.
+/// The buffer then holds `len` samples in its first `len / 2` half-words,
+/// ready for `unpack_8bit`.
+pub fn set_byte_packing() {
+    // PSIZE = 0b00, 8 bit peripheral reads
+    dma1().st[0]
+        .cr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << 11)) });
+}
+
+/// Spread `samples` byte-packed samples back out to one per half-word,
+/// in place. Working backwards means no packed byte is overwritten before
+/// it's been read.
+pub fn unpack_8bit(buf: &mut [u16], samples: usize) {
+    for i in (0..samples).rev() {
+        let word = buf[i / 2];
+        buf[i] = if i % 2 == 0 { word & 0xff } else { word >> 8 };
+    }
+}
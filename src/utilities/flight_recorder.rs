@@ -0,0 +1,114 @@
+//! Keeping `crate::flight_recorder`'s record in backup SRAM, updated as each
+//! capture goes through, and logging the last run's if it ended in a hang
+//! or a fault.
+//!
+//! A hang ends in a watchdog reset, which `reset_cause` reports. A hard
+//! fault would otherwise spin in cortex-m-rt's handler until the watchdog
+//! came too, so the handler here saves where it happened and resets
+//! straight away; `recover` finds it on the next boot.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use log::{info, warn};
+
+use crate::flight_recorder::{self, FlightRecord};
+use crate::text::TruncatingString;
+
+use super::backup::{self, Fault};
+use super::batch::LogBatch;
+use super::reset_cause::ResetCause;
+
+/// This run's record, copied to backup SRAM whenever it changes
+static RECORD: Mutex<RefCell<FlightRecord>> = Mutex::new(RefCell::new(FlightRecord::new()));
+
+/// Log the record the last run left if a watchdog or a fault ended it,
+/// then start this run's. `backup::enable` and `crc::init` must have been
+/// called.
+pub fn recover(cause: ResetCause) {
+    let fault = backup::take_fault();
+    if let Some(fault) = fault {
+        warn!(
+            "The last run hard faulted at PC {:#010x}, LR {:#010x} (CFSR {:#010x}, HFSR {:#010x})",
+            fault.pc, fault.lr, fault.cfsr, fault.hfsr
+        );
+    }
+
+    if cause.is_watchdog() || fault.is_some() {
+        match backup::load_flight() {
+            Some(record) => log_record(&record),
+            None => warn!("No flight record survived the reset"),
+        }
+    }
+
+    // Only this run's captures from here on
+    backup::save_flight(&FlightRecord::new());
+}
+
+/// Change this run's record and save it
+pub fn update(change: impl FnOnce(&mut FlightRecord)) {
+    cortex_m::interrupt::free(|cs| {
+        let mut record = RECORD.borrow(cs).borrow_mut();
+        change(&mut record);
+        backup::save_flight(&record);
+    });
+}
+
+fn log_record(record: &FlightRecord) {
+    warn!(
+        "Flight record: {} captures, the last {} us after boot at {} S/s",
+        record.captures, record.captured_at_us, record.sample_rate_hz
+    );
+    match record.peak {
+        Some((hz, dbfs)) => warn!("Last peak {} Hz at {} dBFS", hz, dbfs),
+        None => warn!("The last capture had no peak"),
+    }
+    warn!(
+        "{} samples clipped in the last capture, {} in all",
+        record.clipped, record.clipped_total
+    );
+
+    let mut errors: TruncatingString<64> = TruncatingString::new();
+    for (i, name) in flight_recorder::error_names(record.errors).enumerate() {
+        let _ = write!(errors, "{}{}", if i == 0 { "" } else { ", " }, name);
+    }
+    if !errors.as_str().is_empty() {
+        warn!("Errors: {}", errors.as_str());
+    }
+
+    // Oldest first, a line of band levels in dBFS each
+    info!(
+        "Last {} band frames, dBFS in octaves from one bin up:",
+        record.frames().count()
+    );
+    let mut batch: LogBatch<1024> = LogBatch::new();
+    let mut line: TruncatingString<64> = TruncatingString::new();
+    for (i, frame) in record.frames().enumerate() {
+        line.clear();
+        let _ = write!(line, "{}", i);
+        for level in frame {
+            let _ = write!(line, ",{}", level);
+        }
+        batch.push(format_args!("{}", line.as_str()));
+    }
+    batch.flush();
+}
+
+/// Save where the fault happened and reset, rather than wait on the
+/// watchdog. Logging here could corrupt a log line the fault interrupted,
+/// as for the NMI.
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    // Safety: reads of the fault status registers
+    let scb = &*SCB::PTR;
+    backup::save_fault(&Fault {
+        pc: frame.pc(),
+        lr: frame.lr(),
+        cfsr: scb.cfsr.read(),
+        hfsr: scb.hfsr.read(),
+    });
+    SCB::sys_reset()
+}
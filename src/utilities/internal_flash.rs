@@ -0,0 +1,277 @@
+//! Keeping the settings in the H743's own flash, in the two slots
+//! `crate::config_store` lays out, so `save` outlasts a power cycle where
+//! backup SRAM only outlasts a reset.
+//!
+//! Flash is erased a 128 KiB sector at a time, so each slot has a sector
+//! to itself, the last two of bank 2, rather than sharing the last one:
+//! erasing one slot never touches the other, which is what lets a save cut
+//! short fall back to the slot before it.
+//!
+//! The controller runs the erase and program on its own while the core
+//! carries on, but a read of the bank it's writing stalls until it's done,
+//! up to seconds for an erase. An interrupt handler in that bank would
+//! stall with it, so `memory.x` keeps everything the linker places in bank
+//! 1 and nothing runs from bank 2. What has to reach the controller
+//! back to back, the two keys, starting an erase and the eight words of a
+//! flash word, is written in a critical section, so no handler gets in
+//! between; the waits for each step leave interrupts on, feeding the
+//! watchdog. Saves are only made between captures, so there's no
+//! acquisition to hold up either.
+//!
+//! The registers are driven directly at their RM0433 §4.9 offsets.
+
+use core::ptr;
+
+use cortex_m::peripheral::SCB;
+use log::{error, info, warn};
+
+use crate::config_store::{self, Record, Settings, RECORD_LEN};
+
+/// Where each slot's sector starts: bank 2, sectors 6 and 7
+const SLOT_ADDRESSES: [usize; 2] = [0x081C_0000, 0x081E_0000];
+/// Their numbers within bank 2, for erasing
+const SLOT_SECTORS: [u32; 2] = [6, 7];
+
+const FLASH_BASE: usize = 0x5200_2000;
+const FLASH_KEYR2: usize = 0x104;
+const FLASH_CR2: usize = 0x10C;
+const FLASH_SR2: usize = 0x110;
+const FLASH_CCR2: usize = 0x114;
+
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+
+const FLASH_CR_LOCK: u32 = 1 << 0;
+const FLASH_CR_PG: u32 = 1 << 1;
+const FLASH_CR_SER: u32 = 1 << 2;
+/// 64-bit parallelism, for VDD 2.7 V to 3.6 V
+const FLASH_CR_PSIZE_X64: u32 = 0b11 << 4;
+const FLASH_CR_START: u32 = 1 << 7;
+const FLASH_CR_SNB_SHIFT: u32 = 8;
+const FLASH_SR_BSY: u32 = 1 << 0;
+/// A write is queued or in progress
+const FLASH_SR_QW: u32 = 1 << 2;
+/// WRPERR, PGSERR, STRBERR, INCERR, OPERR, RDPERR, RDSERR, SNECCERR and
+/// DBECCERR, and EOP besides for clearing them
+const FLASH_SR_ERRORS: u32 = 0x07EE_0000;
+const FLASH_CCR_CLEAR_ALL: u32 = 0x07EF_0000;
+
+/// What the controller programs at once
+const FLASH_WORD_LEN: usize = 32;
+
+/// Datasheet worst cases, with some margin
+const ERASE_TIMEOUT_US: u64 = 4_000_000;
+const PROGRAM_TIMEOUT_US: u64 = 1_000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum FlashError {
+    /// The bank stayed locked after its keys were written, until a reset
+    Locked,
+    /// An erase or program never finished
+    Timeout,
+    /// The controller flagged an error, these SR2 bits
+    Controller(u32),
+    /// The settings don't fit in a slot
+    TooLong,
+    /// What reads back isn't what was written
+    Verify,
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    (FLASH_BASE + offset) as *mut u32
+}
+
+fn read_reg(offset: usize) -> u32 {
+    // Safety: a flash controller register, and reading them has no effect
+    unsafe { ptr::read_volatile(reg(offset)) }
+}
+
+fn write_reg(offset: usize, value: u32) {
+    // Safety: only this module writes bank 2's registers
+    unsafe { ptr::write_volatile(reg(offset), value) }
+}
+
+/// The slot's bytes as they are in flash
+fn slot_bytes(slot: usize) -> [u8; RECORD_LEN] {
+    // Safety: the slot is within flash and always readable
+    unsafe { ptr::read_volatile(SLOT_ADDRESSES[slot] as *const [u8; RECORD_LEN]) }
+}
+
+fn read_slots() -> [Option<Record>; 2] {
+    [0, 1].map(|slot| Record::from_bytes(&slot_bytes(slot), super::crc::crc32))
+}
+
+/// The settings last saved, or `None` if neither slot holds any that check
+/// out, with the log saying which slot they came from or why there were
+/// none. `crc::init` must have been called.
+pub fn load_settings() -> Option<Settings> {
+    let records = read_slots();
+    for (slot, record) in records.iter().enumerate() {
+        if record.is_none() && !config_store::is_erased(&slot_bytes(slot)) {
+            warn!("Settings slot {} in flash is damaged, ignoring it", slot);
+        }
+    }
+
+    match config_store::newest(&records) {
+        Some((slot, record)) => {
+            info!(
+                "Loaded settings {} from flash slot {}",
+                record.sequence, slot
+            );
+            Some(record.settings)
+        }
+        None => {
+            info!("No settings saved in flash, using the defaults");
+            None
+        }
+    }
+}
+
+/// Save `settings` over the older slot, leaving the newer one alone until
+/// this one's read back and checked. Returns the slot written.
+pub fn save_settings(settings: &Settings, scb: &mut SCB) -> Result<usize, FlashError> {
+    let (slot, sequence) = config_store::next_slot(&read_slots());
+    let record = Record {
+        sequence,
+        settings: *settings,
+    };
+    let bytes = record
+        .to_bytes(super::crc::crc32)
+        .map_err(|_| FlashError::TooLong)?;
+
+    let result = write_slot(slot, &bytes, scb).and_then(|()| {
+        match Record::from_bytes(&slot_bytes(slot), super::crc::crc32) {
+            Some(read) if read == record => Ok(slot),
+            _ => Err(FlashError::Verify),
+        }
+    });
+    match result {
+        Ok(_) => info!("Saved settings {} to flash slot {}", sequence, slot),
+        Err(e) => error!(
+            "Saving settings {} to flash slot {} failed: {:?}",
+            sequence, slot, e
+        ),
+    }
+    result
+}
+
+/// Check a damaged slot is passed over: save `settings` twice, write
+/// garbage over the second, and see the first is what loads, logging PASS
+/// or FAIL. Both slots end up written, the older with `settings`.
+#[cfg(feature = "flash-store-test")]
+pub fn corruption_test(settings: &Settings, scb: &mut SCB) -> bool {
+    let saved = save_settings(settings, scb).and_then(|_| save_settings(settings, scb));
+    let Ok(newer) = saved else {
+        error!("Flash store test: FAIL, couldn't save");
+        return false;
+    };
+    let sequence = read_slots()[newer].map_or(0, |r| r.sequence);
+
+    let mut garbage = [0; RECORD_LEN];
+    for (i, byte) in garbage.iter_mut().enumerate() {
+        *byte = (i as u8).wrapping_mul(37) ^ 0xA5;
+    }
+    if let Err(e) = write_slot(newer, &garbage, scb) {
+        error!(
+            "Flash store test: FAIL, couldn't corrupt slot {}: {:?}",
+            newer, e
+        );
+        return false;
+    }
+
+    let records = read_slots();
+    let passed = records[newer].is_none()
+        && config_store::newest(&records).is_some_and(|(slot, record)| {
+            slot != newer
+                && record.sequence == sequence.wrapping_sub(1)
+                && record.settings == *settings
+        });
+    if passed && load_settings() == Some(*settings) {
+        info!("Flash store test: PASS, slot {} was passed over", newer);
+        true
+    } else {
+        error!("Flash store test: FAIL, slot {} wasn't passed over", newer);
+        false
+    }
+}
+
+/// Erase the slot's sector and program `bytes` into it
+fn write_slot(slot: usize, bytes: &[u8; RECORD_LEN], scb: &mut SCB) -> Result<(), FlashError> {
+    unlock()?;
+    let result = erase(SLOT_SECTORS[slot]).and_then(|()| program(SLOT_ADDRESSES[slot], bytes));
+    write_reg(FLASH_CR2, FLASH_CR_LOCK);
+
+    // Reads go through the D-cache, which may still hold what was there
+    if SCB::dcache_enabled() {
+        scb.invalidate_dcache_by_address(SLOT_ADDRESSES[slot], RECORD_LEN);
+    }
+    result
+}
+
+fn unlock() -> Result<(), FlashError> {
+    if read_reg(FLASH_CR2) & FLASH_CR_LOCK != 0 {
+        // Both keys in a row, or the bank locks until a reset
+        cortex_m::interrupt::free(|_| {
+            write_reg(FLASH_KEYR2, FLASH_KEY1);
+            write_reg(FLASH_KEYR2, FLASH_KEY2);
+        });
+    }
+    if read_reg(FLASH_CR2) & FLASH_CR_LOCK != 0 {
+        return Err(FlashError::Locked);
+    }
+    write_reg(FLASH_CCR2, FLASH_CCR_CLEAR_ALL);
+    Ok(())
+}
+
+fn erase(sector: u32) -> Result<(), FlashError> {
+    cortex_m::interrupt::free(|_| {
+        let cr = FLASH_CR_SER | FLASH_CR_PSIZE_X64 | (sector << FLASH_CR_SNB_SHIFT);
+        write_reg(FLASH_CR2, cr);
+        write_reg(FLASH_CR2, cr | FLASH_CR_START);
+    });
+    let result = wait_done(ERASE_TIMEOUT_US);
+    write_reg(FLASH_CR2, 0);
+    result
+}
+
+/// Program `bytes` from `address` a flash word at a time, each written
+/// as the eight words that fill the controller's write buffer
+fn program(address: usize, bytes: &[u8]) -> Result<(), FlashError> {
+    write_reg(FLASH_CR2, FLASH_CR_PG | FLASH_CR_PSIZE_X64);
+    let mut result = Ok(());
+    for (i, flash_word) in bytes.chunks_exact(FLASH_WORD_LEN).enumerate() {
+        let target = (address + i * FLASH_WORD_LEN) as *mut u32;
+        cortex_m::interrupt::free(|_| {
+            for (j, word) in flash_word.chunks_exact(4).enumerate() {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                // Safety: within the slot's sector, erased above
+                unsafe { ptr::write_volatile(target.add(j), word) };
+            }
+            cortex_m::asm::dsb();
+        });
+        result = wait_done(PROGRAM_TIMEOUT_US);
+        if result.is_err() {
+            break;
+        }
+    }
+    write_reg(FLASH_CR2, 0);
+    result
+}
+
+/// Wait for the queued erase or program to finish, and check it went
+fn wait_done(timeout_us: u64) -> Result<(), FlashError> {
+    let start = super::monotonic::now_us();
+    while read_reg(FLASH_SR2) & (FLASH_SR_QW | FLASH_SR_BSY) != 0 {
+        super::watchdog::feed();
+        if super::monotonic::now_us() - start > timeout_us {
+            return Err(FlashError::Timeout);
+        }
+    }
+    match read_reg(FLASH_SR2) & FLASH_SR_ERRORS {
+        0 => Ok(()),
+        errors => {
+            write_reg(FLASH_CCR2, FLASH_CCR_CLEAR_ALL);
+            Err(FlashError::Controller(errors))
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Stop mode between captures, woken by the RTC wakeup timer.
+//!
+//! Stop turns off every clock but LSI/LSE, with RAM and registers retained.
+//! We wake up on HSI with HSE and both PLLs off, so `stop_for` turns them
+//! back on and switches the system clock back to PLL1 before returning. The PLL
+//! configuration itself survives, as do the peripherals' registers, but
+//! anything that was mid-flight (ADC conversions, DMA) must be restarted.
+//!
+//! The debugger loses the core in Stop unless DBGMCU is set up for it, which
+//! is why this is behind the `low-power` feature.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::{DWT, NVIC, SCB};
+use log::info;
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::pac::{interrupt, Interrupt};
+
+use super::clocks::{self, ClockSource};
+use super::rtc::{self, WakeupClock};
+
+/// EXTI line the RTC wakeup timer is wired to
+const EXTI_RTC_WAKEUP: u32 = 1 << 19;
+
+const PWR_CR1_LPDS: u32 = 1 << 0;
+const PWR_CPUCR_PDDS_MASK: u32 = 0b111;
+const PWR_CSR1_ACTVOSRDY: u32 = 1 << 13;
+const RCC_CR_HSEON: u32 = 1 << 16;
+const RCC_CR_HSERDY: u32 = 1 << 17;
+const RCC_CR_PLL1ON: u32 = 1 << 24;
+const RCC_CR_PLL1RDY: u32 = 1 << 25;
+const RCC_CR_PLL2ON: u32 = 1 << 26;
+const RCC_CR_PLL2RDY: u32 = 1 << 27;
+const RCC_CFGR_SW_MASK: u32 = 0b111;
+const RCC_CFGR_SW_PLL1: u32 = 0b011;
+
+static WAKEUPS: AtomicU32 = AtomicU32::new(0);
+
+/// Route the RTC wakeup timer to an interrupt that can bring us out of
+/// Stop. `rtc::init` must have been called.
+pub fn init() {
+    // Safety: only the RTC wakeup line's bits are changed
+    let exti = unsafe { &*pac::EXTI::ptr() };
+    exti.rtsr1
+        .modify(|r, w| unsafe { w.bits(r.bits() | EXTI_RTC_WAKEUP) });
+    exti.cpuimr1
+        .modify(|r, w| unsafe { w.bits(r.bits() | EXTI_RTC_WAKEUP) });
+
+    // Safety: the handler only clears the wakeup flags
+    unsafe { NVIC::unmask(Interrupt::RTC_WKUP) };
+}
+
+/// Enter Stop for about `seconds`, then restore the clocks
+pub fn stop_for(scb: &mut SCB, seconds: u16) {
+    // Safety: LPDS and PDDS only matter on entering Stop, which the HAL
+    // never does
+    let pwr = unsafe { &*pac::PWR::ptr() };
+
+    rtc::start_wakeup_timer(seconds.saturating_sub(1), WakeupClock::Seconds, true);
+
+    // All domains to Stop rather than Standby, with the low-power regulator
+    pwr.cpucr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !PWR_CPUCR_PDDS_MASK) });
+    pwr.cr1
+        .modify(|r, w| unsafe { w.bits(r.bits() | PWR_CR1_LPDS) });
+
+    scb.set_sleepdeep();
+    cortex_m::asm::dsb();
+    cortex_m::asm::wfi();
+    scb.clear_sleepdeep();
+
+    rtc::stop_wakeup_timer();
+    restore_clocks();
+
+    let wakeups = WAKEUPS.fetch_add(1, Ordering::Relaxed) + 1;
+    info!(
+        "Woke from Stop (#{}), core clock measures ~{} MHz",
+        wakeups,
+        measure_core_hz() / 1_000_000
+    );
+}
+
+pub fn wakeup_count() -> u32 {
+    WAKEUPS.load(Ordering::Relaxed)
+}
+
+/// Bring back the PLLs and the PLL1 system clock `freeze` set up
+fn restore_clocks() {
+    // Safety: we're restoring exactly what the HAL configured
+    let pwr = unsafe { &*pac::PWR::ptr() };
+    let rcc = unsafe { &*pac::RCC::ptr() };
+
+    // The regulator has to be back at the run voltage before we speed up
+    while pwr.csr1.read().bits() & PWR_CSR1_ACTVOSRDY == 0 {}
+
+    // Stop turned HSE off too, and the PLLs won't lock without their
+    // reference. HSEBYP is retained, so this is just switching it back on.
+    if clocks::source() == ClockSource::Hse {
+        rcc.cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | RCC_CR_HSEON) });
+        while rcc.cr.read().bits() & RCC_CR_HSERDY == 0 {}
+    }
+
+    rcc.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_CR_PLL1ON | RCC_CR_PLL2ON) });
+    while rcc.cr.read().bits() & (RCC_CR_PLL1RDY | RCC_CR_PLL2RDY)
+        != (RCC_CR_PLL1RDY | RCC_CR_PLL2RDY)
+    {}
+
+    rcc.cfgr
+        .modify(|r, w| unsafe { w.bits((r.bits() & !RCC_CFGR_SW_MASK) | RCC_CFGR_SW_PLL1) });
+    // SWS, bits 5:3, reports the switch once it's happened
+    while (rcc.cfgr.read().bits() >> 3) & RCC_CFGR_SW_MASK != RCC_CFGR_SW_PLL1 {}
+}
+
+/// Count core cycles against the RTC's wakeup timer.
+/// Only as good as the RTC clock, a few percent on LSI, but plenty to tell
+/// the PLL from HSI. The DWT cycle counter must be running.
+pub fn measure_core_hz() -> u32 {
+    // 200 ticks of ~2 kHz, a tenth of a second
+    const TICKS: u32 = 200;
+
+    rtc::start_wakeup_timer(TICKS as u16 - 1, WakeupClock::RtcDiv16, false);
+    let start = DWT::cycle_count();
+    while !rtc::wakeup_flag() {}
+    let cycles = DWT::cycle_count().wrapping_sub(start);
+    rtc::stop_wakeup_timer();
+    rtc::clear_wakeup_flag();
+
+    let tick_hz = rtc::source().hz() / 16;
+    (cycles as u64 * tick_hz as u64 / TICKS as u64) as u32
+}
+
+#[interrupt]
+fn RTC_WKUP() {
+    rtc::clear_wakeup_flag();
+
+    // Safety: write 1 to clear just our pending bit
+    let exti = unsafe { &*pac::EXTI::ptr() };
+    exti.cpupr1.write(|w| unsafe { w.bits(EXTI_RTC_WAKEUP) });
+}
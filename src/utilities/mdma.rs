@@ -0,0 +1,134 @@
+//! Memory to memory copies on MDMA channel 0, to move buffers without the CPU.
+//!
+//! MDMA is the only DMA that can write the TCMs (through the AHBS port), so it
+//! can pull a capture out of AXISRAM into DTCM, where the CPU then reads it
+//! with no wait states. The PAC's registers are driven directly as the
+//! transfer is trivial: one software-triggered block (RM0433 §12).
+
+use core::marker::PhantomData;
+use cortex_m::peripheral::SCB;
+use stm32h7xx_hal::pac;
+
+const ISR_TEIF: u32 = 1 << 0;
+const ISR_CTCIF: u32 = 1 << 1;
+const IFCR_ALL: u32 = 0b1_1111;
+const CR_EN: u32 = 1 << 0;
+const CR_SWRQ: u32 = 1 << 16;
+
+/// Increment both addresses by half-words, move 32 bytes per buffer
+/// transfer, trigger a whole block per request, software requests only
+const TCR_COPY_U16: u32 = 0b10 // SINC: increment
+    | (0b10 << 2) // DINC: increment
+    | (0b01 << 4) // SSIZE: half-word
+    | (0b01 << 6) // DSIZE: half-word
+    | (0b01 << 8) // SINCOS: half-word steps
+    | (0b01 << 10) // DINCOS: half-word steps
+    | (31 << 18) // TLEN: 32 byte buffer transfers
+    | (0b01 << 28) // TRGM: block transfer
+    | (1 << 30); // SWRM: software request
+
+/// Destination is on the AHB/TCM bus rather than AXI
+const TBR_DBUS_TCM: u32 = 1 << 17;
+
+/// Largest block MDMA can move in one go, BNDT is 17 bits of bytes
+const MAX_BYTES: usize = (1 << 17) - 1;
+
+fn mdma() -> &'static pac::mdma::RegisterBlock {
+    // Safety: channel 0 is only driven from here, and only ever by one copy
+    // at a time, as a copy holds the buffers until it's dropped
+    unsafe { &*pac::MDMA::ptr() }
+}
+
+fn is_tcm(addr: usize) -> bool {
+    // ITCM at 0x0000_0000 (64K), DTCM at 0x2000_0000 (128K)
+    addr < 0x0001_0000 || (0x2000_0000..0x2002_0000).contains(&addr)
+}
+
+/// A copy in flight. Borrows both buffers until it completes, and waits for
+/// completion if dropped so they can't be touched mid-copy.
+pub struct CopyInProgress<'a> {
+    _buffers: PhantomData<(&'a [u16], &'a mut [u16])>,
+}
+
+impl CopyInProgress<'_> {
+    /// Whether the channel has finished moving the block
+    pub fn is_complete(&self) -> bool {
+        mdma().ch[0].isr.read().bits() & (ISR_CTCIF | ISR_TEIF) != 0
+    }
+
+    /// Block until the transfer finishes. Returns false on a transfer error,
+    /// with MDMA's error status logged.
+    pub fn wait(self) -> bool {
+        while !self.is_complete() {}
+
+        let ok = mdma().ch[0].isr.read().bits() & ISR_TEIF == 0;
+        if !ok {
+            log::error!(
+                "MDMA transfer error, ESR={:#010x}",
+                mdma().ch[0].esr.read().bits()
+            );
+        }
+        ok
+    }
+}
+
+impl Drop for CopyInProgress<'_> {
+    fn drop(&mut self) {
+        while !self.is_complete() {}
+
+        let ch = &mdma().ch[0];
+        ch.cr.write(|w| unsafe { w.bits(0) });
+        ch.ifcr.write(|w| unsafe { w.bits(IFCR_ALL) });
+    }
+}
+
+/// Start copying `src` into `dst` in the background.
+///
+/// The MDMA clock must already be enabled (`ccdr.peripheral.MDMA.enable()`).
+///
+/// MDMA goes straight to memory, so if the D-cache is on, the CPU's writes to
+/// `src` must be cleaned out first. That's done here. `dst` would need
+/// invalidating before the CPU reads it, but the intended destination is
+/// DTCM, which is never cached. For a cacheable `dst`, invalidate it after
+/// the copy completes, and keep it 32-byte aligned so no neighbouring data
+/// shares its cache lines.
+///
+/// # Safety
+///
+/// The returned `CopyInProgress` must be waited on or dropped, never leaked
+/// with `mem::forget` or similar. Its `Drop` is what stops MDMA writing `dst`
+/// after the borrows end.
+pub unsafe fn copy_u16<'a>(
+    src: &'a [u16],
+    dst: &'a mut [u16],
+    scb: &mut SCB,
+) -> CopyInProgress<'a> {
+    assert_eq!(src.len(), dst.len());
+    let bytes = src.len() * 2;
+    assert!(bytes <= MAX_BYTES, "too big for a single MDMA block");
+
+    if SCB::dcache_enabled() {
+        scb.clean_dcache_by_slice(src);
+    }
+
+    // Make sure the channel is idle and its flags are clear
+    let ch = &mdma().ch[0];
+    ch.cr.write(|w| w.bits(0));
+    while ch.cr.read().bits() & CR_EN != 0 {}
+    ch.ifcr.write(|w| w.bits(IFCR_ALL));
+
+    let dst_addr = dst.as_mut_ptr() as usize;
+    ch.tcr.write(|w| w.bits(TCR_COPY_U16));
+    ch.bndtr.write(|w| w.bits(bytes as u32));
+    ch.sar.write(|w| w.bits(src.as_ptr() as u32));
+    ch.dar.write(|w| w.bits(dst_addr as u32));
+    ch.tbr
+        .write(|w| w.bits(if is_tcm(dst_addr) { TBR_DBUS_TCM } else { 0 }));
+
+    ch.cr.write(|w| w.bits(CR_EN));
+    ch.cr.write(|w| w.bits(CR_EN | CR_SWRQ));
+
+    CopyInProgress {
+        _buffers: PhantomData,
+    }
+}
@@ -1,3 +1,37 @@
-pub mod logger;
+pub mod adc;
+pub mod backup;
+pub mod batch;
+#[cfg(feature = "adc3")]
+pub mod bdma;
 #[macro_use]
-mod power;
\ No newline at end of file
+pub mod buffer;
+#[cfg(feature = "buzzer")]
+pub mod buzzer;
+#[cfg(feature = "can")]
+pub mod can;
+pub mod clocks;
+pub mod console;
+pub mod crc;
+#[cfg(feature = "dac-chirp")]
+pub mod dac;
+pub mod dma;
+pub mod flight_recorder;
+pub mod internal_flash;
+#[cfg(feature = "low-power")]
+pub mod low_power;
+pub mod mdma;
+pub mod monotonic;
+#[macro_use]
+pub mod power;
+#[cfg(feature = "qspi-flash")]
+pub mod qspi;
+pub mod reset_cause;
+pub mod rng;
+pub mod rtc;
+#[cfg(feature = "source-i2s")]
+pub mod sai;
+#[cfg(feature = "sd-card")]
+pub mod sd;
+#[cfg(feature = "swo")]
+pub mod swo;
+pub mod watchdog;
@@ -0,0 +1,114 @@
+//! A monotonic microsecond clock from TIM5, for timestamping captures.
+//!
+//! TIM5 is a 32-bit timer, so at 1 MHz it wraps every 71.6 minutes. Its
+//! update interrupt counts the wraps, and `now_us` combines the two into a
+//! 64-bit count that won't wrap in our lifetime.
+//!
+//! TIM5's kernel clock stops in Stop mode, so with `low-power` this clock
+//! stands still while asleep. It measures time spent running, and the gaps
+//! between captures should be taken from the RTC instead.
+//!
+//! Compare channel 1 is spare, and `arm_wakeup` uses it to raise an interrupt
+//! after a delay, so code sleeping in `wfi`/`wfe` can bound how long it sleeps.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::NVIC;
+use stm32h7xx_hal::pac::{self, interrupt, Interrupt};
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+
+use crate::timing::extend;
+
+const TICK_HZ: u32 = 1_000_000;
+
+const TIM_CR1_CEN: u32 = 1 << 0;
+const TIM_DIER_UIE: u32 = 1 << 0;
+const TIM_DIER_CC1IE: u32 = 1 << 1;
+const TIM_SR_UIF: u32 = 1 << 0;
+const TIM_SR_CC1IF: u32 = 1 << 1;
+const TIM_EGR_UG: u32 = 1 << 0;
+
+/// Times TIM5 has wrapped since `init`
+static WRAPS: AtomicU32 = AtomicU32::new(0);
+
+/// Start TIM5 free-running at 1 MHz from zero
+pub fn init(_tim5: pac::TIM5, prec: rec::Tim5, clocks: &CoreClocks) {
+    prec.enable().reset();
+
+    // Safety: `init` took TIM5, after which only this module touches it
+    let tim = unsafe { &*pac::TIM5::ptr() };
+
+    let prescaler = clocks.timx_ker_ck().raw() / TICK_HZ;
+    tim.psc.write(|w| unsafe { w.bits(prescaler - 1) });
+    tim.arr.write(|w| unsafe { w.bits(u32::MAX) });
+
+    // Load the prescaler now rather than at the first wrap, then drop the
+    // update flag that sets so it isn't counted as one
+    tim.egr.write(|w| unsafe { w.bits(TIM_EGR_UG) });
+    tim.sr.write(|w| unsafe { w.bits(0) });
+    WRAPS.store(0, Ordering::Relaxed);
+
+    tim.dier.write(|w| unsafe { w.bits(TIM_DIER_UIE) });
+    // Safety: the handler only counts wraps and ends wake-ups
+    unsafe { NVIC::unmask(Interrupt::TIM5) };
+    tim.cr1.write(|w| unsafe { w.bits(TIM_CR1_CEN) });
+}
+
+/// Microseconds since `init`
+pub fn now_us() -> u64 {
+    // Safety: `init` took TIM5, after which only this module touches it
+    let tim = unsafe { &*pac::TIM5::ptr() };
+
+    // If the interrupt runs part way through, the wrap counts won't match,
+    // so read again
+    loop {
+        let wraps = WRAPS.load(Ordering::Acquire);
+        let count = tim.cnt.read().bits();
+        let pending = tim.sr.read().bits() & TIM_SR_UIF != 0;
+
+        if WRAPS.load(Ordering::Acquire) == wraps {
+            return extend(wraps, count, pending);
+        }
+    }
+}
+
+/// Raise the TIM5 interrupt once, `after_us` from now. This only wakes the
+/// core, whoever is sleeping still has to check why it woke.
+pub fn arm_wakeup(after_us: u32) {
+    // Safety: `init` took TIM5, after which only this module touches it
+    let tim = unsafe { &*pac::TIM5::ptr() };
+
+    let at = tim.cnt.read().bits().wrapping_add(after_us.max(1));
+    tim.ccr1.write(|w| unsafe { w.bits(at) });
+    tim.sr.write(|w| unsafe { w.bits(!TIM_SR_CC1IF) });
+    tim.dier
+        .modify(|r, w| unsafe { w.bits(r.bits() | TIM_DIER_CC1IE) });
+}
+
+/// Cancel a wake-up from `arm_wakeup`, if it hasn't fired yet
+pub fn disarm_wakeup() {
+    // Safety: `init` took TIM5, after which only this module touches it
+    let tim = unsafe { &*pac::TIM5::ptr() };
+
+    tim.dier
+        .modify(|r, w| unsafe { w.bits(r.bits() & !TIM_DIER_CC1IE) });
+    tim.sr.write(|w| unsafe { w.bits(!TIM_SR_CC1IF) });
+}
+
+#[interrupt]
+fn TIM5() {
+    // Safety: the flags are write 0 to clear, so this only clears the ones
+    // we saw. The compare is one-shot, so it disables itself.
+    let tim = unsafe { &*pac::TIM5::ptr() };
+    let sr = tim.sr.read().bits();
+
+    if sr & TIM_SR_CC1IF != 0 {
+        tim.dier
+            .modify(|r, w| unsafe { w.bits(r.bits() & !TIM_DIER_CC1IE) });
+        tim.sr.write(|w| unsafe { w.bits(!TIM_SR_CC1IF) });
+    }
+    if sr & TIM_SR_UIF != 0 {
+        tim.sr.write(|w| unsafe { w.bits(!TIM_SR_UIF) });
+        WRAPS.fetch_add(1, Ordering::Release);
+    }
+}
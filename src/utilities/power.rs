@@ -1,15 +1,16 @@
-//! Power Configuration for examples
+//! Power Configuration for examples. Deprecated: the supply is the board's
+//! business now, see `board::configure_power`. Kept for one release so forks
+//! that call `example_power!` still build, with a warning.
 
+#[macro_export]
 macro_rules! example_power {
     ($pwr:ident) => {{
-        cfg_if::cfg_if! {
-            if #[cfg(all(feature = "smps", feature = "example-smps"))] {
-                $pwr.smps()
-            } else if #[cfg(all(feature = "smps", feature = "example-ldo"))] {
-                $pwr.ldo()
-            } else {
-                $pwr
-            }
-        }
+        $crate::utilities::power::example_power($pwr)
     }};
 }
+
+/// What `example_power!` expands to, applying the board's supply
+#[deprecated(note = "use board::configure_power, which also logs the supply and VOS")]
+pub fn example_power(pwr: stm32h7xx_hal::pwr::Pwr) -> stm32h7xx_hal::pwr::Pwr {
+    crate::board::target::apply_supply(pwr)
+}
@@ -0,0 +1,453 @@
+//! Storing captures in a QSPI NOR flash, with the `qspi-flash` feature, in
+//! the ring of slots `crate::store` lays out.
+//!
+//! The flash is read memory-mapped at 0x9000_0000, so stored captures are
+//! plain slices, and written with indirect commands, leaving memory-mapped
+//! mode while it does. Only the single-line commands every 3-byte-address
+//! NOR flash has are used (Winbond W25Q, Macronix MX25L, ISSI IS25LP and
+//! the like), so no quad enable bit needs setting: CLK on PB2, NCS on PB6,
+//! IO0 and IO1 on PD11 and PD12. IO2 and IO3, the flash's /WP and /HOLD, are
+//! driven high on PE2 and PD13.
+//!
+//! Writing a slot erases its sector, programs the samples and then the
+//! header, and reads it all back through the memory map to check the CRCs.
+//! A slot only counts as stored once that passes.
+
+use cortex_m::peripheral::SCB;
+use log::{error, info, warn};
+use stm32h7xx_hal::gpio::{Speed, PB2, PB6, PD11, PD12, PD13, PE2};
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+
+use crate::store::{self, SlotHeader, HEADER_LEN, MAX_SAMPLES, SLOT_LEN};
+
+/// Slots in the ring, from the start of the flash
+pub const SLOTS: usize = 16;
+
+/// Flash size as a power of two, 16 MiB for a W25Q128
+const FLASH_SIZE_BITS: u32 = 24;
+
+/// Fastest the flash clock is run, well inside what fast read allows
+const MAX_CLOCK_HZ: u32 = 50_000_000;
+
+/// Where the memory-mapped flash appears
+const MAPPED_BASE: usize = 0x9000_0000;
+
+const PAGE_LEN: usize = 256;
+
+/// Datasheet worst cases, with some margin
+const ERASE_TIMEOUT_US: u64 = 500_000;
+const PROGRAM_TIMEOUT_US: u64 = 5_000;
+/// For the controller itself, which should never take this long
+const TRANSFER_TIMEOUT_US: u64 = 1_000;
+
+const WRITE_ENABLE: u8 = 0x06;
+const READ_STATUS: u8 = 0x05;
+const READ_ID: u8 = 0x9F;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+/// Single-line fast read, with 8 dummy cycles
+const FAST_READ: u8 = 0x0B;
+const FAST_READ_DUMMY_CYCLES: u32 = 8;
+
+/// Write in progress
+const STATUS_WIP: u8 = 1 << 0;
+/// Write enable latch
+const STATUS_WEL: u8 = 1 << 1;
+
+const QUADSPI_CR_EN: u32 = 1 << 0;
+const QUADSPI_CR_ABORT: u32 = 1 << 1;
+/// Sample half a clock late, which flying leads need
+const QUADSPI_CR_SSHIFT: u32 = 1 << 4;
+const QUADSPI_CR_PRESCALER_SHIFT: u32 = 24;
+const QUADSPI_DCR_CSHT_SHIFT: u32 = 8;
+const QUADSPI_DCR_FSIZE_SHIFT: u32 = 16;
+const QUADSPI_SR_TCF: u32 = 1 << 1;
+const QUADSPI_SR_BUSY: u32 = 1 << 5;
+const QUADSPI_SR_FLEVEL_SHIFT: u32 = 8;
+const QUADSPI_SR_FLEVEL_MASK: u32 = 0x3F;
+const QUADSPI_FCR_CTCF: u32 = 1 << 1;
+const QUADSPI_CCR_IMODE_SINGLE: u32 = 0b01 << 8;
+const QUADSPI_CCR_ADMODE_SINGLE: u32 = 0b01 << 10;
+const QUADSPI_CCR_ADSIZE_24: u32 = 0b10 << 12;
+const QUADSPI_CCR_DCYC_SHIFT: u32 = 18;
+const QUADSPI_CCR_DMODE_SINGLE: u32 = 0b01 << 24;
+const QUADSPI_CCR_FMODE_READ: u32 = 0b01 << 26;
+const QUADSPI_CCR_FMODE_MAPPED: u32 = 0b11 << 26;
+
+/// Bytes the FIFO holds
+const FIFO_LEN: u32 = 32;
+
+/// The QUADSPI pins, as they come out of reset: CLK, NCS, IO0 to IO3
+pub type Pins = (PB2, PB6, PD11, PD12, PE2, PD13);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FlashError {
+    /// The flash or the controller never finished
+    Timeout,
+    /// The flash didn't latch a write enable, so it's write protected
+    WriteEnable,
+    /// What reads back isn't what was written
+    Verify,
+}
+
+/// The data phase of a command
+enum Data<'a> {
+    None,
+    Write(&'a [u8]),
+    Read(&'a mut [u8]),
+}
+
+pub struct Flash {
+    qspi: pac::QUADSPI,
+    /// Whether a flash answered at all, without one nothing's stored
+    present: bool,
+    /// Each slot's header as last read or written, `None` if empty
+    headers: [Option<SlotHeader>; SLOTS],
+}
+
+/// Bring up QUADSPI, look for a flash and read what's stored on it
+pub fn init(qspi: pac::QUADSPI, pins: Pins, prec: rec::Qspi, clocks: &CoreClocks) -> Flash {
+    let (clk, ncs, io0, io1, io2, io3) = pins;
+    clk.into_alternate::<9>().speed(Speed::VeryHigh);
+    ncs.into_alternate::<10>()
+        .internal_pull_up(true)
+        .speed(Speed::VeryHigh);
+    io0.into_alternate::<9>().speed(Speed::VeryHigh);
+    io1.into_alternate::<9>().speed(Speed::VeryHigh);
+    io2.into_push_pull_output().set_high();
+    io3.into_push_pull_output().set_high();
+
+    prec.enable().reset();
+
+    // The kernel clock is HCLK3 out of reset
+    let prescaler = clocks.hclk().raw().div_ceil(MAX_CLOCK_HZ).max(1) - 1;
+    qspi.dcr.write(|w| unsafe {
+        w.bits(
+            (FLASH_SIZE_BITS - 1) << QUADSPI_DCR_FSIZE_SHIFT
+                // Two cycles high between commands
+                | 1 << QUADSPI_DCR_CSHT_SHIFT,
+        )
+    });
+    qspi.cr.write(|w| unsafe {
+        w.bits(prescaler << QUADSPI_CR_PRESCALER_SHIFT | QUADSPI_CR_SSHIFT | QUADSPI_CR_EN)
+    });
+
+    let mut flash = Flash {
+        qspi,
+        present: false,
+        headers: [None; SLOTS],
+    };
+
+    // Nothing driving IO1 reads as all ones or zeros, depending on the pull
+    let mut id = [0; 3];
+    let answered = flash.command(READ_ID, None, Data::Read(&mut id)).is_ok();
+    if !answered || id == [0; 3] || id == [0xFF; 3] {
+        warn!("No QSPI flash found, captures won't be stored");
+        return flash;
+    }
+    info!(
+        "QSPI flash {:02x}{:02x}{:02x} at {} Hz",
+        id[0],
+        id[1],
+        id[2],
+        clocks.hclk().raw() / (prescaler + 1)
+    );
+    flash.present = true;
+
+    flash.enter_mapped();
+    flash.headers = core::array::from_fn(|slot| flash.read_header(slot));
+    flash.log_contents();
+    flash
+}
+
+impl Flash {
+    /// Slots a `dump` can ask for, 0 with no flash
+    pub fn slot_count(&self) -> u8 {
+        if self.present {
+            SLOTS as u8
+        } else {
+            0
+        }
+    }
+
+    /// List the stored captures, checking each one's samples
+    fn log_contents(&self) {
+        if !self.present {
+            return;
+        }
+        let mut stored = 0;
+        for (slot, header) in self.headers.iter().enumerate() {
+            let Some(header) = header else {
+                continue;
+            };
+            stored += 1;
+            let intact = self.stored(slot).is_some();
+            info!(
+                "Flash slot {}: capture {} from {} us, {} samples at {} S/s{}",
+                slot,
+                header.sequence,
+                header.timestamp_us,
+                header.samples,
+                header.sample_rate_hz,
+                if intact { "" } else { ", CORRUPT" }
+            );
+        }
+        info!("{} of {} flash slots hold captures", stored, SLOTS);
+    }
+
+    /// A stored capture and its samples, if the slot has one that's intact
+    pub fn stored(&self, slot: usize) -> Option<(SlotHeader, &[u16])> {
+        let header = (*self.headers.get(slot)?)?;
+        let samples = self.mapped_samples(slot, header.samples as usize);
+        if super::crc::crc32_samples(samples) != header.samples_crc {
+            error!("Flash slot {} fails its CRC", slot);
+            return None;
+        }
+        Some((header, samples))
+    }
+
+    /// Store a capture in the next slot round the ring, overwriting the
+    /// oldest once they're all full. Errors are logged, not returned, as
+    /// there's nothing better to do with the capture.
+    pub fn save(
+        &mut self,
+        samples: &[u16],
+        timestamp_us: u64,
+        sample_rate_hz: f32,
+        resolution_bits: u8,
+        scb: &mut SCB,
+    ) {
+        if !self.present {
+            return;
+        }
+        if samples.len() > MAX_SAMPLES {
+            error!(
+                "{} samples don't fit in a flash slot, only {}",
+                samples.len(),
+                MAX_SAMPLES
+            );
+            return;
+        }
+
+        let (slot, sequence) = store::next_slot(&self.headers);
+        let header = SlotHeader {
+            sequence,
+            timestamp_us,
+            sample_rate_hz,
+            resolution_bits,
+            samples: samples.len() as u16,
+            samples_crc: super::crc::crc32_samples(samples),
+        };
+
+        // Whatever happens, the old capture in this slot is gone
+        self.headers[slot] = None;
+        self.leave_mapped();
+        let written = self.write_slot(slot, &header, samples);
+        self.enter_mapped();
+
+        // Anything cached from before the erase is stale
+        if SCB::dcache_enabled() {
+            let base = MAPPED_BASE + slot * SLOT_LEN;
+            scb.invalidate_dcache_by_address(base, SLOT_LEN);
+        }
+        let result = written.and_then(|()| match self.read_header(slot) {
+            Some(read) if read == header => {
+                let stored = self.mapped_samples(slot, samples.len());
+                if super::crc::crc32_samples(stored) == header.samples_crc {
+                    Ok(())
+                } else {
+                    Err(FlashError::Verify)
+                }
+            }
+            _ => Err(FlashError::Verify),
+        });
+
+        match result {
+            Ok(()) => {
+                self.headers[slot] = Some(header);
+                info!("Stored capture {} in flash slot {}", sequence, slot);
+            }
+            Err(e) => error!(
+                "Storing capture {} in flash slot {} failed: {:?}",
+                sequence, slot, e
+            ),
+        }
+    }
+
+    /// Erase, then program the samples and only then the header, so a slot
+    /// cut short by a reset has no valid header
+    fn write_slot(
+        &mut self,
+        slot: usize,
+        header: &SlotHeader,
+        samples: &[u16],
+    ) -> Result<(), FlashError> {
+        let base = (slot * SLOT_LEN) as u32;
+        self.write_enable()?;
+        self.command(SECTOR_ERASE, Some(base), Data::None)?;
+        self.wait_ready(ERASE_TIMEOUT_US)?;
+
+        // Safety: any u16 is two valid bytes, and u8 has no alignment to meet
+        let bytes = unsafe {
+            core::slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 2)
+        };
+        self.program(base + HEADER_LEN as u32, bytes)?;
+        self.program(base, &header.to_bytes(super::crc::crc32))
+    }
+
+    /// Program `bytes` from `address`, a page at a time as pages can't be
+    /// programmed across their end
+    fn program(&mut self, mut address: u32, mut bytes: &[u8]) -> Result<(), FlashError> {
+        while !bytes.is_empty() {
+            let room = PAGE_LEN - address as usize % PAGE_LEN;
+            let (page, rest) = bytes.split_at(room.min(bytes.len()));
+            self.write_enable()?;
+            self.command(PAGE_PROGRAM, Some(address), Data::Write(page))?;
+            self.wait_ready(PROGRAM_TIMEOUT_US)?;
+            address += page.len() as u32;
+            bytes = rest;
+        }
+        Ok(())
+    }
+
+    fn write_enable(&mut self) -> Result<(), FlashError> {
+        self.command(WRITE_ENABLE, None, Data::None)?;
+        if self.status()? & STATUS_WEL == 0 {
+            return Err(FlashError::WriteEnable);
+        }
+        Ok(())
+    }
+
+    fn status(&mut self) -> Result<u8, FlashError> {
+        let mut status = [0];
+        self.command(READ_STATUS, None, Data::Read(&mut status))?;
+        Ok(status[0])
+    }
+
+    /// Wait for an erase or program to finish
+    fn wait_ready(&mut self, timeout_us: u64) -> Result<(), FlashError> {
+        let start = super::monotonic::now_us();
+        while self.status()? & STATUS_WIP != 0 {
+            if super::monotonic::now_us() - start > timeout_us {
+                return Err(FlashError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send one indirect command, all on a single line
+    fn command(
+        &mut self,
+        instruction: u8,
+        address: Option<u32>,
+        data: Data,
+    ) -> Result<(), FlashError> {
+        let qspi = &self.qspi;
+        self.wait_until(|sr| sr & QUADSPI_SR_BUSY == 0)?;
+
+        let mut ccr = instruction as u32 | QUADSPI_CCR_IMODE_SINGLE;
+        if address.is_some() {
+            ccr |= QUADSPI_CCR_ADMODE_SINGLE | QUADSPI_CCR_ADSIZE_24;
+        }
+        let len = match &data {
+            Data::None => 0,
+            Data::Write(bytes) => bytes.len(),
+            Data::Read(bytes) => {
+                ccr |= QUADSPI_CCR_FMODE_READ;
+                bytes.len()
+            }
+        };
+        if len > 0 {
+            ccr |= QUADSPI_CCR_DMODE_SINGLE;
+            qspi.dlr.write(|w| unsafe { w.bits(len as u32 - 1) });
+        }
+
+        // The command starts on the CCR write, or the AR write if it has
+        // an address
+        qspi.ccr.write(|w| unsafe { w.bits(ccr) });
+        if let Some(address) = address {
+            qspi.ar.write(|w| unsafe { w.bits(address) });
+        }
+
+        // DR takes and gives single bytes for byte-wide accesses
+        let dr = core::ptr::addr_of!(qspi.dr) as *mut u8;
+        match data {
+            Data::None => {}
+            Data::Write(bytes) => {
+                for &byte in bytes {
+                    self.wait_until(|sr| fifo_level(sr) < FIFO_LEN)?;
+                    // Safety: an 8-bit write to DR queues one byte
+                    unsafe { core::ptr::write_volatile(dr, byte) };
+                }
+            }
+            Data::Read(bytes) => {
+                for byte in bytes {
+                    self.wait_until(|sr| fifo_level(sr) > 0)?;
+                    // Safety: an 8-bit read from DR takes one byte
+                    *byte = unsafe { core::ptr::read_volatile(dr) };
+                }
+            }
+        }
+
+        self.wait_until(|sr| sr & QUADSPI_SR_TCF != 0)?;
+        self.qspi.fcr.write(|w| unsafe { w.bits(QUADSPI_FCR_CTCF) });
+        Ok(())
+    }
+
+    fn wait_until(&self, done: impl Fn(u32) -> bool) -> Result<(), FlashError> {
+        let start = super::monotonic::now_us();
+        while !done(self.qspi.sr.read().bits()) {
+            if super::monotonic::now_us() - start > TRANSFER_TIMEOUT_US {
+                return Err(FlashError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Make the flash readable at `MAPPED_BASE`
+    fn enter_mapped(&mut self) {
+        if self.wait_until(|sr| sr & QUADSPI_SR_BUSY == 0).is_err() {
+            error!("QUADSPI stuck busy, stored captures can't be read");
+            return;
+        }
+        let ccr = FAST_READ as u32
+            | QUADSPI_CCR_IMODE_SINGLE
+            | QUADSPI_CCR_ADMODE_SINGLE
+            | QUADSPI_CCR_ADSIZE_24
+            | FAST_READ_DUMMY_CYCLES << QUADSPI_CCR_DCYC_SHIFT
+            | QUADSPI_CCR_DMODE_SINGLE
+            | QUADSPI_CCR_FMODE_MAPPED;
+        self.qspi.ccr.write(|w| unsafe { w.bits(ccr) });
+    }
+
+    /// Stop memory-mapped reads, which keep the controller busy, so
+    /// commands can be sent
+    fn leave_mapped(&mut self) {
+        self.qspi
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | QUADSPI_CR_ABORT) });
+        let aborted = self.wait_until(|_| self.qspi.cr.read().bits() & QUADSPI_CR_ABORT == 0);
+        if aborted.is_err() {
+            error!("QUADSPI didn't abort memory-mapped mode");
+        }
+    }
+
+    fn read_header(&self, slot: usize) -> Option<SlotHeader> {
+        // Safety: the slot is inside the mapped flash, which is only
+        // reprogrammed through `&mut self`
+        let bytes = unsafe { &*((MAPPED_BASE + slot * SLOT_LEN) as *const [u8; HEADER_LEN]) };
+        SlotHeader::from_bytes(bytes, super::crc::crc32)
+    }
+
+    fn mapped_samples(&self, slot: usize, len: usize) -> &[u16] {
+        let start = MAPPED_BASE + slot * SLOT_LEN + HEADER_LEN;
+        // Safety: as for `read_header`, and `len` is at most `MAX_SAMPLES`
+        unsafe { core::slice::from_raw_parts(start as *const u16, len.min(MAX_SAMPLES)) }
+    }
+}
+
+fn fifo_level(sr: u32) -> u32 {
+    (sr >> QUADSPI_SR_FLEVEL_SHIFT) & QUADSPI_SR_FLEVEL_MASK
+}
@@ -0,0 +1,114 @@
+//! Work out why we booted, from the RCC reset status register (RCC_RSR).
+//!
+//! The flags are sticky across resets until cleared, and a single event
+//! often sets several of them: a power-on reset also sets BOR and PIN, and
+//! every internal reset source drives NRST low, so PIN is set alongside
+//! IWDG, WWDG and software resets too. We therefore resolve them with a fixed
+//! precedence, most specific cause first:
+//!
+//! power-on > brown-out > IWDG > WWDG > low-power > software > pin
+//!
+//! `init` should be called once, as early as possible, so the flags it
+//! clears belong to this boot only.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use log::info;
+use stm32h7xx_hal::pac;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ResetCause {
+    Unknown = 0,
+    PowerOn,
+    Brownout,
+    IndependentWatchdog,
+    WindowWatchdog,
+    LowPower,
+    Software,
+    Pin,
+}
+
+impl ResetCause {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResetCause::Unknown => "unknown",
+            ResetCause::PowerOn => "power-on",
+            ResetCause::Brownout => "brown-out",
+            ResetCause::IndependentWatchdog => "IWDG",
+            ResetCause::WindowWatchdog => "WWDG",
+            ResetCause::LowPower => "low-power",
+            ResetCause::Software => "software",
+            ResetCause::Pin => "pin",
+        }
+    }
+
+    /// Whether a watchdog pulled us out of a hang
+    pub fn is_watchdog(self) -> bool {
+        matches!(
+            self,
+            ResetCause::IndependentWatchdog | ResetCause::WindowWatchdog
+        )
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ResetCause::PowerOn,
+            2 => ResetCause::Brownout,
+            3 => ResetCause::IndependentWatchdog,
+            4 => ResetCause::WindowWatchdog,
+            5 => ResetCause::LowPower,
+            6 => ResetCause::Software,
+            7 => ResetCause::Pin,
+            _ => ResetCause::Unknown,
+        }
+    }
+}
+
+static CAUSE: AtomicU8 = AtomicU8::new(ResetCause::Unknown as u8);
+
+/// Read and clear the reset flags, log the result, and remember it for `cause`.
+///
+/// This goes around the HAL with a raw register pointer so it can run before
+/// `dp.RCC` is constrained.
+pub fn init() -> ResetCause {
+    // Safety: a single read then a write to the RMVF bit, before anyone else
+    // owns the RCC
+    let rcc = unsafe { &*pac::RCC::ptr() };
+    let rsr = rcc.rsr.read();
+
+    let cause = if rsr.porrstf().bit_is_set() {
+        ResetCause::PowerOn
+    } else if rsr.borrstf().bit_is_set() {
+        ResetCause::Brownout
+    } else if rsr.iwdg1rstf().bit_is_set() {
+        ResetCause::IndependentWatchdog
+    } else if rsr.wwdg1rstf().bit_is_set() {
+        ResetCause::WindowWatchdog
+    } else if rsr.lpwrrstf().bit_is_set() {
+        ResetCause::LowPower
+    } else if rsr.sftrstf().bit_is_set() {
+        ResetCause::Software
+    } else if rsr.pinrstf().bit_is_set() {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    };
+
+    rcc.rsr.modify(|_, w| w.rmvf().set_bit());
+    CAUSE.store(cause as u8, Ordering::Relaxed);
+
+    info!("Reset cause: {}", cause.as_str());
+
+    cause
+}
+
+/// The cause recorded by `init`, `Unknown` if it hasn't run yet
+pub fn cause() -> ResetCause {
+    ResetCause::from_u8(CAUSE.load(Ordering::Relaxed))
+}
+
+/// Request a software reset through the SCB. Handy for checking that the
+/// next boot reports "software".
+pub fn system_reset() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
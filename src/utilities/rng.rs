@@ -0,0 +1,103 @@
+//! The hardware RNG, for dither. `init` starts it and `next_f32_pm` hands
+//! out uniform noise from it.
+//!
+//! The RNG stops on two errors, RM0433 §34.3.7. A clock error, its kernel
+//! clock too slow for the AHB, clears itself once the clock's back, so is
+//! only counted. A seed error, from entropy that failed its health checks,
+//! is recovered from by clearing SEIS and draining the 12 words queued up
+//! behind it, and if it comes straight back, by restarting the RNG. Either
+//! way only values read with neither error set are handed out.
+//!
+//! The registers are driven directly, as for the DAC.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use log::{info, warn};
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+
+use crate::dsp::dither;
+
+const RNG_CR_RNGEN: u32 = 1 << 2;
+const RNG_SR_DRDY: u32 = 1 << 0;
+const RNG_SR_CECS: u32 = 1 << 1;
+const RNG_SR_SECS: u32 = 1 << 2;
+const RNG_SR_CEIS: u32 = 1 << 5;
+const RNG_SR_SEIS: u32 = 1 << 6;
+
+/// Words queued behind a seed error, thrown away to clear them out
+const PIPELINE_WORDS: usize = 12;
+
+/// How long to poll for a value before giving up, far longer than one
+/// takes, about 50 cycles of the 48 MHz kernel clock
+const MAX_POLLS: u32 = 100_000;
+
+static SEED_ERRORS: AtomicU32 = AtomicU32::new(0);
+static CLOCK_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+fn rng() -> &'static pac::rng::RegisterBlock {
+    // Safety: `init` took the RNG, after which only this module touches it
+    unsafe { &*pac::RNG::ptr() }
+}
+
+/// Start the RNG on its default kernel clock, HSI48
+pub fn init(_rng: pac::RNG, prec: rec::Rng, clocks: &CoreClocks) {
+    if clocks.hsi48_ck().is_none() {
+        warn!("HSI48 is off, so the RNG has no clock");
+    }
+    prec.enable().reset();
+    rng().cr.write(|w| unsafe { w.bits(RNG_CR_RNGEN) });
+    info!("RNG started");
+}
+
+/// The next 32 random bits, or `None` if the RNG has stopped and won't
+/// come back
+pub fn next_u32() -> Option<u32> {
+    let rng = rng();
+    for _ in 0..MAX_POLLS {
+        let sr = rng.sr.read().bits();
+        if sr & RNG_SR_SEIS != 0 {
+            recover_seed();
+            continue;
+        }
+        if sr & RNG_SR_CEIS != 0 {
+            // rc_w0, so only the clock error is cleared
+            rng.sr.write(|w| unsafe { w.bits(!RNG_SR_CEIS) });
+            CLOCK_ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+        if sr & (RNG_SR_CECS | RNG_SR_SECS) == 0 && sr & RNG_SR_DRDY != 0 {
+            return Some(rng.dr.read().bits());
+        }
+    }
+    None
+}
+
+/// Uniform noise in `-amplitude..amplitude`, for dither. Nothing, rather
+/// than a stall, if the RNG has stopped.
+pub fn next_f32_pm(amplitude: f32) -> f32 {
+    next_u32().map_or(0.0, |bits| dither::uniform_pm(bits, amplitude))
+}
+
+/// Seed errors and clock errors since boot
+pub fn error_counts() -> (u32, u32) {
+    (
+        SEED_ERRORS.load(Ordering::Relaxed),
+        CLOCK_ERRORS.load(Ordering::Relaxed),
+    )
+}
+
+fn recover_seed() {
+    let rng = rng();
+    SEED_ERRORS.fetch_add(1, Ordering::Relaxed);
+    rng.sr.write(|w| unsafe { w.bits(!RNG_SR_SEIS) });
+    for _ in 0..PIPELINE_WORDS {
+        let _ = rng.dr.read();
+    }
+
+    if rng.sr.read().bits() & RNG_SR_SEIS != 0 {
+        warn!("RNG seed error persists, restarting it");
+        rng.cr.write(|w| unsafe { w.bits(0) });
+        rng.sr.write(|w| unsafe { w.bits(!RNG_SR_SEIS) });
+        rng.cr.write(|w| unsafe { w.bits(RNG_CR_RNGEN) });
+    }
+}
@@ -0,0 +1,318 @@
+//! The RTC: its clock source, the calendar, and the wakeup timer.
+//!
+//! The RTC lives in the backup domain, which the HAL's RCC doesn't manage,
+//! so this drives PWR, RCC and RTC registers directly (RM0433 §46). The
+//! backup domain survives resets, so once the calendar is set it keeps
+//! running and only a cold boot (without VBAT) loses it.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::warn;
+use stm32h7xx_hal::pac;
+
+use super::{backup, clocks};
+
+const RCC_CSR_LSION: u32 = 1 << 0;
+const RCC_CSR_LSIRDY: u32 = 1 << 1;
+const RCC_BDCR_LSEON: u32 = 1 << 0;
+const RCC_BDCR_LSERDY: u32 = 1 << 1;
+const RCC_BDCR_RTCSEL_MASK: u32 = 0b11 << 8;
+const RCC_BDCR_RTCSEL_LSE: u32 = 0b01 << 8;
+const RCC_BDCR_RTCSEL_LSI: u32 = 0b10 << 8;
+const RCC_BDCR_RTCEN: u32 = 1 << 15;
+const RCC_APB4ENR_RTCAPBEN: u32 = 1 << 16;
+
+const RTC_CR_WUCKSEL_MASK: u32 = 0b111;
+const RTC_CR_WUTE: u32 = 1 << 10;
+const RTC_CR_WUTIE: u32 = 1 << 14;
+const RTC_ISR_WUTWF: u32 = 1 << 2;
+const RTC_ISR_INITS: u32 = 1 << 4;
+const RTC_ISR_RSF: u32 = 1 << 5;
+const RTC_ISR_INITF: u32 = 1 << 6;
+const RTC_ISR_INIT: u32 = 1 << 7;
+const RTC_ISR_WUTF: u32 = 1 << 10;
+
+/// Crystals can take a couple of seconds to start
+const LSE_STARTUP_MS: u32 = 2000;
+
+/// What the wakeup timer counts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum WakeupClock {
+    /// RTCCLK / 16, 2 kHz from either source
+    RtcDiv16 = 0b000,
+    /// The 1 Hz calendar clock (ck_spre)
+    Seconds = 0b100,
+}
+
+/// What the RTC is clocked from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RtcClock {
+    /// The 32.768 kHz crystal, good to tens of ppm
+    Lse,
+    /// The internal RC, only good to a few percent
+    Lsi,
+}
+
+impl RtcClock {
+    pub const fn hz(self) -> u32 {
+        match self {
+            RtcClock::Lse => 32_768,
+            RtcClock::Lsi => 32_000,
+        }
+    }
+
+    /// Synchronous prescaler for a 1 Hz calendar, after the fixed /128
+    /// asynchronous one
+    const fn prediv_s(self) -> u32 {
+        self.hz() / 128 - 1
+    }
+}
+
+const PREDIV_A: u32 = 127;
+
+static USING_LSE: AtomicBool = AtomicBool::new(false);
+
+fn rtc() -> &'static pac::rtc::RegisterBlock {
+    unsafe { &*pac::RTC::ptr() }
+}
+
+/// Allow writes to RTC registers other than the flags
+fn unlock() {
+    rtc().wpr.write(|w| unsafe { w.bits(0xCA) });
+    rtc().wpr.write(|w| unsafe { w.bits(0x53) });
+}
+
+fn lock() {
+    rtc().wpr.write(|w| unsafe { w.bits(0xFF) });
+}
+
+/// Clock the RTC from LSE, falling back to LSI if the crystal won't start.
+///
+/// If the RTC is already running from a previous boot it's left alone, as
+/// RTCSEL can only be changed by resetting the whole backup domain. LSI is
+/// always started, as the watchdog and Stop wakeups can want it too.
+pub fn init() -> RtcClock {
+    // Safety: the HAL's RCC leaves LSI and the backup domain clocks alone
+    let rcc = unsafe { &*pac::RCC::ptr() };
+
+    // The backup domain is write protected out of reset
+    backup::allow_domain_writes();
+
+    rcc.csr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_CSR_LSION) });
+    while rcc.csr.read().bits() & RCC_CSR_LSIRDY == 0 {}
+
+    let bdcr = rcc.bdcr.read().bits();
+    let source = if bdcr & RCC_BDCR_RTCEN != 0 {
+        if bdcr & RCC_BDCR_RTCSEL_MASK == RCC_BDCR_RTCSEL_LSE {
+            RtcClock::Lse
+        } else {
+            RtcClock::Lsi
+        }
+    } else {
+        let source = if start_lse() {
+            RtcClock::Lse
+        } else {
+            warn!("LSE didn't start, running the RTC from LSI");
+            RtcClock::Lsi
+        };
+        let rtcsel = match source {
+            RtcClock::Lse => RCC_BDCR_RTCSEL_LSE,
+            RtcClock::Lsi => RCC_BDCR_RTCSEL_LSI,
+        };
+        rcc.bdcr.modify(|r, w| unsafe {
+            w.bits((r.bits() & !RCC_BDCR_RTCSEL_MASK) | rtcsel | RCC_BDCR_RTCEN)
+        });
+        source
+    };
+
+    rcc.apb4enr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_APB4ENR_RTCAPBEN) });
+
+    USING_LSE.store(source == RtcClock::Lse, Ordering::Relaxed);
+    source
+}
+
+fn start_lse() -> bool {
+    // Safety: only the LSE bits, with the backup domain unlocked by `init`
+    let rcc = unsafe { &*pac::RCC::ptr() };
+    rcc.bdcr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_BDCR_LSEON) });
+
+    let cycles_per_ms = (clocks::core_hz() / 1000).max(1);
+    for _ in 0..LSE_STARTUP_MS {
+        if rcc.bdcr.read().bits() & RCC_BDCR_LSERDY != 0 {
+            return true;
+        }
+        cortex_m::asm::delay(cycles_per_ms);
+    }
+
+    rcc.bdcr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !RCC_BDCR_LSEON) });
+    false
+}
+
+/// The RTC clock chosen by `init`
+pub fn source() -> RtcClock {
+    if USING_LSE.load(Ordering::Relaxed) {
+        RtcClock::Lse
+    } else {
+        RtcClock::Lsi
+    }
+}
+
+/// A calendar date and time, to the millisecond
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+impl DateTime {
+    /// Day of the week, 1 (Monday) to 7 (Sunday), as the RTC counts them
+    fn weekday(&self) -> u32 {
+        // Sakamoto's method, which gives 0 for Sunday
+        const T: [u32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let y = self.year as u32 - (self.month < 3) as u32;
+        let d = (y + y / 4 - y / 100 + y / 400 + T[self.month as usize - 1] + self.day as u32) % 7;
+        if d == 0 {
+            7
+        } else {
+            d
+        }
+    }
+}
+
+/// ISO 8601, e.g. `2026-10-14T12:34:56.789`
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millisecond
+        )
+    }
+}
+
+fn bcd(value: u8) -> u32 {
+    (((value / 10) << 4) | (value % 10)) as u32
+}
+
+fn from_bcd(bits: u32) -> u8 {
+    ((bits >> 4) * 10 + (bits & 0xF)) as u8
+}
+
+/// Whether the calendar has been set since the backup domain last lost power
+pub fn is_calendar_set() -> bool {
+    rtc().isr.read().bits() & RTC_ISR_INITS != 0
+}
+
+/// Set the calendar, and the prescalers for a 1 Hz count from `init`'s
+/// clock. Years are 2000 to 2099, and milliseconds are ignored.
+pub fn set_date_time(t: &DateTime) {
+    let rtc = rtc();
+    unlock();
+
+    rtc.isr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RTC_ISR_INIT) });
+    while rtc.isr.read().bits() & RTC_ISR_INITF == 0 {}
+
+    // Both prescalers go in one write, synchronous first per the RM
+    let prer = PREDIV_A << 16 | source().prediv_s();
+    rtc.prer.write(|w| unsafe { w.bits(prer) });
+    rtc.prer.write(|w| unsafe { w.bits(prer) });
+
+    let time = bcd(t.hour) << 16 | bcd(t.minute) << 8 | bcd(t.second);
+    let date = bcd((t.year.clamp(2000, 2099) - 2000) as u8) << 16
+        | t.weekday() << 13
+        | bcd(t.month) << 8
+        | bcd(t.day);
+    rtc.tr.write(|w| unsafe { w.bits(time) });
+    rtc.dr.write(|w| unsafe { w.bits(date) });
+
+    rtc.isr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !RTC_ISR_INIT) });
+    lock();
+}
+
+/// The current calendar time, or `None` if it's never been set
+pub fn now() -> Option<DateTime> {
+    if !is_calendar_set() {
+        return None;
+    }
+    let rtc = rtc();
+
+    // The shadow registers are stale until resynchronized, e.g. after Stop.
+    // RSF stays set from the last sync, so clear it and wait for a fresh
+    // one. The flags are write 0 to clear, and INIT has to stay 0.
+    unlock();
+    rtc.isr
+        .write(|w| unsafe { w.bits(!(RTC_ISR_RSF | RTC_ISR_INIT)) });
+    lock();
+    while rtc.isr.read().bits() & RTC_ISR_RSF == 0 {}
+
+    // Reading SSR locks TR and DR until DR is read, so these are coherent
+    let ssr = rtc.ssr.read().bits() & 0xFFFF;
+    let tr = rtc.tr.read().bits();
+    let dr = rtc.dr.read().bits();
+
+    let prediv_s = rtc.prer.read().bits() & 0x7FFF;
+    let millisecond = ((prediv_s.saturating_sub(ssr)) * 1000 / (prediv_s + 1)) as u16;
+
+    Some(DateTime {
+        year: 2000 + from_bcd((dr >> 16) & 0xFF) as u16,
+        month: from_bcd((dr >> 8) & 0x1F),
+        day: from_bcd(dr & 0x3F),
+        hour: from_bcd((tr >> 16) & 0x3F),
+        minute: from_bcd((tr >> 8) & 0x7F),
+        second: from_bcd(tr & 0x7F),
+        millisecond,
+    })
+}
+
+/// Fire the wakeup flag (and interrupt, if `interrupt`) every `ticks + 1`
+/// counts of `clock`
+pub fn start_wakeup_timer(ticks: u16, clock: WakeupClock, interrupt: bool) {
+    let rtc = rtc();
+    unlock();
+
+    // The reload register can only be written with the timer stopped
+    rtc.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !(RTC_CR_WUTE | RTC_CR_WUTIE)) });
+    while rtc.isr.read().bits() & RTC_ISR_WUTWF == 0 {}
+
+    rtc.wutr.write(|w| unsafe { w.bits(ticks as u32) });
+    clear_wakeup_flag();
+
+    let enable = RTC_CR_WUTE | if interrupt { RTC_CR_WUTIE } else { 0 };
+    rtc.cr.modify(|r, w| unsafe {
+        w.bits((r.bits() & !RTC_CR_WUCKSEL_MASK) | clock as u32 | enable)
+    });
+
+    lock();
+}
+
+pub fn stop_wakeup_timer() {
+    unlock();
+    rtc()
+        .cr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !(RTC_CR_WUTE | RTC_CR_WUTIE)) });
+    lock();
+}
+
+pub fn wakeup_flag() -> bool {
+    rtc().isr.read().bits() & RTC_ISR_WUTF != 0
+}
+
+/// The flags can be cleared without unlocking. Write 0 to clear, 1 to leave.
+pub fn clear_wakeup_flag() {
+    rtc()
+        .isr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !RTC_ISR_WUTF) });
+}
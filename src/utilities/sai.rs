@@ -0,0 +1,238 @@
+//! An I2S MEMS microphone (an INMP441 or similar) on SAI1 block A, with the
+//! `source-i2s` feature, captured by DMA2 stream 0 into AXISRAM.
+//!
+//! Wire the mic to CN9: SCK to PE5, WS to PE4 and SD to PE6, with its L/R
+//! pin setting which slot it talks in, `crate::i2s::Slot`. The SAI is the
+//! I2S master, clocking the mic at 64 bits a frame from PLL3 P, and
+//! receives 24 bits of just the one slot, so the buffer holds nothing but
+//! the mic's samples. See `crate::i2s` for how they're decoded.
+//!
+//! The SAI runs from `init` on, since the mic sleeps whenever its clock
+//! stops and takes tens of milliseconds to wake. Between captures the
+//! ignored samples overrun the SAI's FIFO, so each capture takes the eight
+//! the FIFO held as it started, and throws them away, as they're from
+//! before it. Only an overrun during a capture makes it untrusted.
+//!
+//! Like the ADC's buffer, this relies on the data cache being off. With it
+//! on, the buffer needs invalidating before each capture is read.
+//!
+//! The registers are driven directly, as for MDMA, since it's one fixed
+//! configuration: RM0433 §51 for the SAI and §15 for DMA2.
+
+use cortex_m::peripheral::DWT;
+use log::{error, info, warn};
+use stm32h7xx_hal::gpio::{PE4, PE5, PE6};
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+
+use crate::i2s::{self, Slot};
+
+use super::dma::{CaptureError, DmaError};
+use super::monotonic;
+
+/// The FIFO's depth in words, which are stale when a capture starts
+const FIFO_DEPTH: usize = 8;
+
+/// How long the mic needs after its clock starts before the data's good
+const WAKE_UP_US: u64 = 100_000;
+
+/// DMAMUX1 request for SAI1 block A (RM0433 table 121)
+const DMAREQ_SAI1_A: u32 = 87;
+/// DMAMUX1 channel feeding DMA2 stream 0
+const DMAMUX1_DMA2_S0: usize = 8;
+
+/// Peripheral to memory, memory increments, 32-bit both ends, high priority
+const DMA_CR_CAPTURE: u32 = (1 << 10) // MINC
+    | (0b10 << 11) // PSIZE: word
+    | (0b10 << 13) // MSIZE: word
+    | (0b10 << 16); // PL: high
+const DMA_CR_EN: u32 = 1 << 0;
+const DMA_LISR_FEIF0: u32 = 1 << 0;
+const DMA_LISR_DMEIF0: u32 = 1 << 2;
+const DMA_LISR_TEIF0: u32 = 1 << 3;
+const DMA_LISR_TCIF0: u32 = 1 << 5;
+const DMA_LIFCR_ALL0: u32 = 0b11_1101;
+
+/// Master receiver, 24-bit data, sampled on SCK rising edges, the divider
+/// straight to SCK (NODIV) with MCKDIV in bits 20..26
+const SAI_CR1_RECEIVE: u32 = 0b01 // MODE: master receiver
+    | (0b110 << 5) // DS: 24 bits
+    | (1 << 9) // CKSTR: sample on rising edges
+    | (1 << 19); // NODIV
+const SAI_CR1_MCKDIV_SHIFT: u32 = 20;
+const SAI_CR1_SAIEN: u32 = 1 << 16;
+const SAI_CR1_DMAEN: u32 = 1 << 17;
+/// Philips I2S: a 64-bit frame, WS low for the first (left) half, and WS
+/// changing one bit before the slot's MSB
+const SAI_FRCR_I2S: u32 = (i2s::FRAME_BITS - 1) // FRL
+    | ((i2s::FRAME_BITS / 2 - 1) << 8) // FSALL
+    | (1 << 16) // FSDEF: WS marks the channel
+    | (1 << 18); // FSOFF: one bit early
+/// Two 32-bit slots, SLOTEN chosen by `Slot::enable_mask`
+const SAI_SLOTR_STEREO: u32 = (0b10 << 6) // SLOTSZ: 32 bits
+    | (1 << 8); // NBSLOT: 2
+const SAI_SR_OVRUDR: u32 = 1 << 0;
+const SAI_CLRFR_COVRUDR: u32 = 1 << 0;
+
+/// The SAI1 block A pins, as they come out of reset: SCK, FS (WS) and SD
+pub type Pins = (PE5, PE4, PE6);
+
+pub struct Microphone {
+    buffer: &'static mut [u32; crate::board::SIZE + FIFO_DEPTH],
+    sample_rate_hz: f32,
+    /// How long a capture should take, with slack
+    timeout_us: u64,
+}
+
+fn sai() -> &'static pac::sai1::RegisterBlock {
+    // Safety: `init` took SAI1, after which only this module touches it
+    unsafe { &*pac::SAI1::ptr() }
+}
+
+fn dma2() -> &'static pac::dma1::RegisterBlock {
+    // Safety: as for SAI1, stream 0 is only driven from here
+    unsafe { &*pac::DMA2::ptr() }
+}
+
+/// Start the SAI clocking the mic at `sample_rate_hz`, as near as PLL3 P
+/// divides to, and wait for the mic to wake. Only call this once.
+pub fn init(
+    _sai1: pac::SAI1,
+    _dma2: pac::DMA2,
+    pins: Pins,
+    prec: rec::Sai1,
+    dma_prec: rec::Dma2,
+    clocks: &CoreClocks,
+    sample_rate_hz: u32,
+    slot: Slot,
+) -> Microphone {
+    let kernel_hz = clocks
+        .pll3_p_ck()
+        .expect("SAI1 is clocked from PLL3 P")
+        .raw();
+    let (divider, actual_hz) = i2s::bit_clock_divider(kernel_hz, sample_rate_hz)
+        .expect("PLL3 P can't make that sample rate");
+
+    let (sck, fs, sd) = pins;
+    // Dropping the pins leaves them on SAI1
+    let _ = (
+        sck.into_alternate::<6>(),
+        fs.into_alternate::<6>(),
+        sd.into_alternate::<6>(),
+    );
+    prec.kernel_clk_mux(rec::Sai1ClkSel::Pll3P).enable();
+    dma_prec.enable();
+
+    let ch = &sai().cha;
+    ch.cr1.write(|w| unsafe { w.bits(0) });
+    while ch.cr1.read().bits() & SAI_CR1_SAIEN != 0 {}
+    ch.frcr.write(|w| unsafe { w.bits(SAI_FRCR_I2S) });
+    ch.slotr
+        .write(|w| unsafe { w.bits(SAI_SLOTR_STEREO | slot.enable_mask()) });
+    ch.cr1.write(|w| unsafe {
+        w.bits(SAI_CR1_RECEIVE | divider << SAI_CR1_MCKDIV_SHIFT | SAI_CR1_SAIEN)
+    });
+
+    // Safety: only this channel's request is set
+    let dmamux = unsafe { &*pac::DMAMUX1::ptr() };
+    dmamux.ccr[DMAMUX1_DMA2_S0].write(|w| unsafe { w.bits(DMAREQ_SAI1_A) });
+
+    let start = monotonic::now_us();
+    while monotonic::now_us() - start < WAKE_UP_US {}
+
+    let capture_us = (crate::board::SIZE + FIFO_DEPTH) as f32 / actual_hz * 1e6;
+    info!(
+        "I2S mic on SAI1 at {} S/s ({:?} slot, SCK = {} Hz / {})",
+        actual_hz, slot, kernel_hz, divider
+    );
+    Microphone {
+        buffer: dma_buffer!(".axisram", u32, crate::board::SIZE + FIFO_DEPTH),
+        sample_rate_hz: actual_hz,
+        timeout_us: (capture_us * 4.0) as u64,
+    }
+}
+
+impl Microphone {
+    pub fn sample_rate_hz(&self) -> f32 {
+        self.sample_rate_hz
+    }
+
+    /// Capture the next `out.len()` samples, as signed 24-bit counts. Ok
+    /// with whether any were lost to an overrun on the way, and the
+    /// monotonic time it finished.
+    pub fn read(
+        &mut self,
+        out: &mut [f32; crate::board::SIZE],
+    ) -> Result<(bool, u64), CaptureError> {
+        let ch = &sai().cha;
+        let st = &dma2().st[0];
+
+        st.cr.write(|w| unsafe { w.bits(0) });
+        while st.cr.read().bits() & DMA_CR_EN != 0 {}
+        dma2().lifcr.write(|w| unsafe { w.bits(DMA_LIFCR_ALL0) });
+        st.par.write(|w| unsafe { w.bits(ch.dr.as_ptr() as u32) });
+        st.m0ar
+            .write(|w| unsafe { w.bits(self.buffer.as_mut_ptr() as u32) });
+        st.ndtr
+            .write(|w| unsafe { w.bits(self.buffer.len() as u32) });
+        st.cr
+            .write(|w| unsafe { w.bits(DMA_CR_CAPTURE | DMA_CR_EN) });
+
+        // Only overruns from here on count
+        ch.clrfr.write(|w| unsafe { w.bits(SAI_CLRFR_COVRUDR) });
+        ch.cr1
+            .modify(|r, w| unsafe { w.bits(r.bits() | SAI_CR1_DMAEN) });
+
+        let start = monotonic::now_us();
+        let result = loop {
+            let lisr = dma2().lisr.read().bits();
+            if lisr & DMA_LISR_TCIF0 != 0 {
+                break Ok(());
+            }
+            let error = if lisr & DMA_LISR_TEIF0 != 0 {
+                Some(DmaError::Transfer)
+            } else if lisr & DMA_LISR_DMEIF0 != 0 {
+                Some(DmaError::DirectMode)
+            } else if lisr & DMA_LISR_FEIF0 != 0 {
+                Some(DmaError::Fifo)
+            } else {
+                None
+            };
+            if let Some(e) = error {
+                error!(
+                    "DMA2 S0: LISR={:#010x} CR={:#010x} NDTR={}",
+                    lisr,
+                    st.cr.read().bits(),
+                    st.ndtr.read().bits()
+                );
+                break Err(e.into());
+            }
+            if monotonic::now_us() - start > self.timeout_us {
+                let remaining = st.ndtr.read().bits() as usize;
+                break Err(CaptureError::Timeout {
+                    received: (self.buffer.len() - remaining).saturating_sub(FIFO_DEPTH),
+                });
+            }
+        };
+        let captured_at = monotonic::now_us();
+
+        ch.cr1
+            .modify(|r, w| unsafe { w.bits(r.bits() & !SAI_CR1_DMAEN) });
+        let overran = ch.sr.read().bits() & SAI_SR_OVRUDR != 0;
+        if overran {
+            warn!("SAI overran during the capture");
+        }
+        result?;
+
+        let convert_start = DWT::cycle_count();
+        for (sample, &word) in out.iter_mut().zip(&self.buffer[FIFO_DEPTH..]) {
+            *sample = i2s::sign_extend_24(word) as f32;
+        }
+        info!(
+            "Sample conversion took {} cycles (24-bit I2S)",
+            DWT::cycle_count().wrapping_sub(convert_start)
+        );
+        Ok((!overran, captured_at))
+    }
+}
@@ -0,0 +1,261 @@
+//! Saving captures to an SD card on SDMMC1 as WAV files, with the `sd-card`
+//! feature, for opening in Audacity or anything else that reads WAV.
+//!
+//! The Nucleo has no card slot, so wire a breakout to the SDMMC pins on CN8:
+//! PC12 CK, PD2 CMD and PC8 to PC11 for D0 to D3. The card has to be FAT32
+//! (or FAT16) formatted, with its first partition holding the files.
+//!
+//! Each capture is its own file, `CAP0001.WAV` onwards, numbered on from the
+//! last one already on the card. Samples are centred and scaled to 16 bits
+//! with `AdcScale::to_q15`, so full scale in Audacity is the ADC's full
+//! range: ±1.0 is ±VREF/2 around mid-scale. The header carries the measured
+//! sample rate, rounded to the Hz, so frequencies read off the file line up
+//! with the logged spectrum.
+//!
+//! A missing card, a full card or any other SD error is logged and saving
+//! stops, but captures carry on. Nothing here panics.
+
+#[cfg(feature = "debug-clocks")]
+compile_error!("The sd-card and debug-clocks features both need PC9, for D1 and MCO2");
+
+use embedded_sdmmc::{
+    BlockDevice, File, Mode, TimeSource, Timestamp, Volume, VolumeIdx, VolumeManager,
+};
+use log::{error, info, warn};
+use stm32h7xx_hal::gpio::{Speed, PC10, PC11, PC12, PC8, PC9, PD2};
+use stm32h7xx_hal::pac::{self, SDMMC1};
+use stm32h7xx_hal::prelude::*;
+use stm32h7xx_hal::rcc::{rec, CoreClocks};
+use stm32h7xx_hal::sdmmc::{SdCard, Sdmmc, SdmmcBlockDevice, SdmmcExt};
+
+use crate::dsp::scaling::AdcScale;
+use crate::wav;
+
+/// Card clock after initialisation. Cards all manage 25 MHz, and a breakout
+/// on flying leads rarely manages the 50 MHz of high speed mode.
+const CARD_CLOCK_HZ: u32 = 25_000_000;
+
+/// Samples converted per write
+const CHUNK: usize = 256;
+
+type Card = SdmmcBlockDevice<Sdmmc<SDMMC1, SdCard>>;
+type Error = embedded_sdmmc::Error<<Card as BlockDevice>::Error>;
+
+/// The SDMMC1 pins, as they come out of reset
+pub type Pins = (PC12, PD2, PC8, PC9, PC10, PC11);
+
+/// File timestamps from the RTC
+pub struct RtcTime;
+
+impl TimeSource for RtcTime {
+    fn get_timestamp(&self) -> Timestamp {
+        // With the calendar lost, say so with FAT's earliest date
+        let Some(now) = super::rtc::now() else {
+            return Timestamp {
+                year_since_1970: 10,
+                zero_indexed_month: 0,
+                zero_indexed_day: 0,
+                hours: 0,
+                minutes: 0,
+                seconds: 0,
+            };
+        };
+        Timestamp {
+            year_since_1970: (now.year.saturating_sub(1970)) as u8,
+            zero_indexed_month: now.month - 1,
+            zero_indexed_day: now.day - 1,
+            hours: now.hour,
+            minutes: now.minute,
+            seconds: now.second,
+        }
+    }
+}
+
+pub struct SdLogger {
+    manager: VolumeManager<Card, RtcTime>,
+    /// `None` once saving has stopped
+    volume: Option<Volume>,
+    /// Number of the next file to write
+    next_index: u16,
+}
+
+/// Bring up the card and find where the capture files got to. With no card,
+/// or one that can't be read, the logger comes back but never saves.
+pub fn init(sdmmc1: pac::SDMMC1, pins: Pins, prec: rec::Sdmmc1, clocks: &CoreClocks) -> SdLogger {
+    let (clk, cmd, d0, d1, d2, d3) = pins;
+    // The card holds CK while idle, the rest need pulling up
+    let clk = clk.into_alternate().speed(Speed::VeryHigh);
+    let cmd = cmd
+        .into_alternate()
+        .internal_pull_up(true)
+        .speed(Speed::VeryHigh);
+    let d0 = d0
+        .into_alternate()
+        .internal_pull_up(true)
+        .speed(Speed::VeryHigh);
+    let d1 = d1
+        .into_alternate()
+        .internal_pull_up(true)
+        .speed(Speed::VeryHigh);
+    let d2 = d2
+        .into_alternate()
+        .internal_pull_up(true)
+        .speed(Speed::VeryHigh);
+    let d3 = d3
+        .into_alternate()
+        .internal_pull_up(true)
+        .speed(Speed::VeryHigh);
+
+    let mut sdmmc: Sdmmc<SDMMC1, SdCard> = sdmmc1.sdmmc((clk, cmd, d0, d1, d2, d3), prec, clocks);
+    let card_ok = match sdmmc.init(CARD_CLOCK_HZ.Hz()) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("No SD card ({:?}), captures won't be saved", e);
+            false
+        }
+    };
+
+    let mut logger = SdLogger {
+        manager: VolumeManager::new(sdmmc.sdmmc_block_device(), RtcTime),
+        volume: None,
+        next_index: 1,
+    };
+    if card_ok {
+        logger.mount();
+    }
+    logger
+}
+
+impl SdLogger {
+    /// Open the card's first partition and number on from the files on it
+    fn mount(&mut self) {
+        let volume = match self.manager.get_volume(VolumeIdx(0)) {
+            Ok(volume) => volume,
+            Err(e) => {
+                error!(
+                    "SD card has no FAT volume ({:?}), captures won't be saved",
+                    e
+                );
+                return;
+            }
+        };
+
+        let mut last = 0;
+        let listed = self.manager.open_root_dir(&volume).and_then(|root| {
+            let listed = self.manager.iterate_dir(&volume, &root, |entry| {
+                let name = &entry.name;
+                if let Some(index) = wav::file_index(name.base_name(), name.extension()) {
+                    last = last.max(index);
+                }
+            });
+            self.manager.close_dir(&volume, root);
+            listed
+        });
+        if let Err(e) = listed {
+            error!("Can't list the SD card ({:?}), captures won't be saved", e);
+            return;
+        }
+
+        if last == wav::MAX_FILE_INDEX {
+            warn!("SD card already has CAP9999.WAV, captures won't be saved");
+            return;
+        }
+        self.next_index = last + 1;
+        self.volume = Some(volume);
+        info!(
+            "Saving captures to the SD card from CAP{:04}.WAV",
+            self.next_index
+        );
+    }
+
+    /// Whether there's a card to save to, and saving hasn't stopped
+    pub fn saving(&self) -> bool {
+        self.volume.is_some()
+    }
+
+    /// Save `samples` as the next capture file, closing it so it's complete
+    /// on the card straight away. Stops saving on the first error rather
+    /// than fail every capture after it. Returns whether it was saved.
+    pub fn write_capture(
+        &mut self,
+        samples: &[u16],
+        sample_rate_hz: f32,
+        scale: &AdcScale,
+    ) -> bool {
+        let Some(volume) = self.volume.as_mut() else {
+            return false;
+        };
+        let index = self.next_index;
+        let name = wav::file_name(index);
+        // Safety: file names are plain ASCII
+        let name = unsafe { core::str::from_utf8_unchecked(&name) };
+
+        let header = wav::header(round_hz(sample_rate_hz), samples.len() as u32);
+        let manager = &mut self.manager;
+        let result = manager.open_root_dir(volume).and_then(|root| {
+            let written = manager
+                .open_file_in_dir(volume, &root, name, Mode::ReadWriteCreateOrTruncate)
+                .and_then(|mut file| {
+                    let written =
+                        write_samples(manager, volume, &mut file, &header, samples, scale);
+                    // Closing writes the file's length to its directory entry
+                    let closed = manager.close_file(volume, file);
+                    written.and(closed)
+                });
+            // Don't leave a file whose header promises samples it hasn't got
+            if written.is_err() {
+                let _ = manager.delete_file_in_dir(volume, &root, name);
+            }
+            manager.close_dir(volume, root);
+            written
+        });
+
+        match result {
+            Ok(()) => {
+                info!("Saved {} samples to {}", samples.len(), name);
+                self.next_index += 1;
+                if self.next_index > wav::MAX_FILE_INDEX {
+                    warn!("Out of capture file names, captures won't be saved");
+                    self.volume = None;
+                }
+                true
+            }
+            Err(embedded_sdmmc::Error::NotEnoughSpace) => {
+                error!("SD card full writing {}, captures won't be saved", name);
+                self.volume = None;
+                false
+            }
+            Err(e) => {
+                error!("Saving {} failed ({:?}), captures won't be saved", name, e);
+                self.volume = None;
+                false
+            }
+        }
+    }
+}
+
+/// The header and then the samples, converted a chunk at a time
+fn write_samples(
+    manager: &mut VolumeManager<Card, RtcTime>,
+    volume: &mut Volume,
+    file: &mut File,
+    header: &[u8],
+    samples: &[u16],
+    scale: &AdcScale,
+) -> Result<(), Error> {
+    manager.write(volume, file, header)?;
+
+    let mut bytes = [0; CHUNK * 2];
+    for chunk in samples.chunks(CHUNK) {
+        for (out, &sample) in bytes.chunks_exact_mut(2).zip(chunk) {
+            out.copy_from_slice(&scale.to_q15(sample).to_le_bytes());
+        }
+        manager.write(volume, file, &bytes[..chunk.len() * 2])?;
+    }
+    Ok(())
+}
+
+/// Nearest whole Hz for the header, which has no room for fractions
+fn round_hz(hz: f32) -> u32 {
+    (hz + 0.5) as u32
+}
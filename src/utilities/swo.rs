@@ -0,0 +1,85 @@
+//! SWO trace output, for viewing ITM stimulus port 0 alongside the RTT log.
+//!
+//! On the H7 the SWO pin is driven from the trace clock, PLL1 R, through the
+//! D3 domain's SWO block. Its prescaler is worked out from the clock `freeze`
+//! actually produced, so the baud stays right whichever clock profile is
+//! built. The probe has to be set to the same baud.
+
+use cortex_m::peripheral::ITM;
+use log::{info, warn};
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::rcc::CoreClocks;
+
+const DBGMCU_CR_TRACECLKEN: u32 = 1 << 20;
+const DBGMCU_CR_D1DBGCKEN: u32 = 1 << 21;
+const DBGMCU_CR_D3DBGCKEN: u32 = 1 << 22;
+
+/// SWO and its funnel, which the PAC doesn't describe
+const SWO_BASE: usize = 0x5C00_3000;
+const SWTF_BASE: usize = 0x5C00_4000;
+const CODR: usize = 0x010;
+const SPPR: usize = 0x0F0;
+const LAR: usize = 0xFB0;
+const SPPR_NRZ: u32 = 0b10;
+const CORESIGHT_UNLOCK: u32 = 0xC5AC_CE55;
+
+const ITM_TCR_ITMENA: u32 = 1 << 0;
+const ITM_TCR_TXENA: u32 = 1 << 3;
+const ITM_TCR_TRACE_BUS_ID: u32 = 1 << 16;
+
+/// How far from the requested baud we get before warning about it
+const BAUD_TOLERANCE_PERCENT: u32 = 3;
+
+/// SWO prescaler to get as close to `baud` as possible from `trace_hz`
+pub const fn baud_prescaler(trace_hz: u32, baud: u32) -> u32 {
+    let prescaler = (trace_hz + baud / 2) / baud;
+    if prescaler < 1 {
+        1
+    } else {
+        prescaler
+    }
+}
+
+/// Route ITM port 0 out of SWO at `baud`. Needs PLL1 R running, which
+/// `main` asks the RCC for when the `swo` feature is on.
+pub fn init(itm: &mut ITM, clocks: &CoreClocks, baud: u32) {
+    let Some(trace_ck) = clocks.pll1_r_ck() else {
+        warn!("PLL1 R isn't running, so there's no trace clock for SWO");
+        return;
+    };
+    let prescaler = baud_prescaler(trace_ck.raw(), baud);
+    let actual = trace_ck.raw() / prescaler;
+
+    // Safety: the debug registers are ours alone, the HAL never touches them
+    let dbgmcu = unsafe { &*pac::DBGMCU::ptr() };
+    dbgmcu.cr.modify(|r, w| unsafe {
+        w.bits(r.bits() | DBGMCU_CR_TRACECLKEN | DBGMCU_CR_D1DBGCKEN | DBGMCU_CR_D3DBGCKEN)
+    });
+
+    unsafe {
+        let reg = |base: usize, offset: usize| (base + offset) as *mut u32;
+        core::ptr::write_volatile(reg(SWO_BASE, LAR), CORESIGHT_UNLOCK);
+        core::ptr::write_volatile(reg(SWO_BASE, CODR), prescaler - 1);
+        core::ptr::write_volatile(reg(SWO_BASE, SPPR), SPPR_NRZ);
+        // The funnel's port 0 is the core's trace, and it resets enabled
+        core::ptr::write_volatile(reg(SWTF_BASE, LAR), CORESIGHT_UNLOCK);
+
+        itm.lar.write(CORESIGHT_UNLOCK);
+        itm.tcr
+            .write(ITM_TCR_TRACE_BUS_ID | ITM_TCR_TXENA | ITM_TCR_ITMENA);
+        itm.ter[0].write(1);
+    }
+
+    info!(
+        "SWO: {} baud from a {} MHz trace clock / {}",
+        actual,
+        trace_ck.raw() / 1_000_000,
+        prescaler
+    );
+    if actual.abs_diff(baud) * 100 > baud * BAUD_TOLERANCE_PERCENT {
+        warn!(
+            "SWO baud is {}, more than {}% off {}",
+            actual, BAUD_TOLERANCE_PERCENT, baud
+        );
+    }
+}
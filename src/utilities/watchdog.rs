@@ -0,0 +1,79 @@
+//! The independent watchdog (IWDG1), to reset the board if the main loop
+//! stops coming round, so a hang the capture timeouts don't cover ends in a
+//! reset that `reset_cause` reports, rather than a silent freeze.
+//!
+//! It counts the LSI, which starting it turns on, and carries on through
+//! Stop. Once started only a reset stops it, so anything that waits longer
+//! than its timeout has to feed it as it goes, as `delay_ms` does. It's
+//! frozen while the debugger has the core halted.
+
+use log::info;
+use stm32h7xx_hal::delay::Delay;
+use stm32h7xx_hal::pac;
+use stm32h7xx_hal::prelude::*;
+
+use crate::timing;
+
+/// The LSI's nominal frequency. It's only good to a few percent, so leave
+/// the timeout plenty of margin.
+const LSI_HZ: u32 = 32_000;
+
+const IWDG_KR_FEED: u32 = 0xAAAA;
+const IWDG_KR_UNLOCK: u32 = 0x5555;
+const IWDG_KR_START: u32 = 0xCCCC;
+/// PVU and RVU, set until a new prescaler and reload have taken
+const IWDG_SR_UPDATING: u32 = 0b11;
+
+/// DBGMCU_APB4FZ1.DBG_IWDG1, to stop the watchdog at breakpoints
+const DBGMCU_APB4FZ1_IWDG1: u32 = 1 << 18;
+
+/// Longest `delay_ms` sleeps between feeds
+const FEED_INTERVAL_MS: u32 = 100;
+
+fn iwdg() -> &'static pac::iwdg::RegisterBlock {
+    // Safety: `init` took the IWDG, after which only this module touches it
+    unsafe { &*pac::IWDG::ptr() }
+}
+
+/// Start the watchdog, resetting the board `timeout_ms` after the last
+/// `feed`. Only call this once.
+pub fn init(_iwdg: pac::IWDG, timeout_ms: u32) {
+    let (prescaler, reload) =
+        timing::watchdog_settings(LSI_HZ, timeout_ms).expect("the watchdog can't count that long");
+
+    // Safety: only our own bit is set, and nothing else freezes peripherals
+    let dbgmcu = unsafe { &*pac::DBGMCU::ptr() };
+    dbgmcu
+        .apb4fz1
+        .modify(|r, w| unsafe { w.bits(r.bits() | DBGMCU_APB4FZ1_IWDG1) });
+
+    let iwdg = iwdg();
+    iwdg.kr.write(|w| unsafe { w.bits(IWDG_KR_START) });
+    iwdg.kr.write(|w| unsafe { w.bits(IWDG_KR_UNLOCK) });
+    iwdg.pr.write(|w| unsafe { w.bits(prescaler as u32) });
+    iwdg.rlr.write(|w| unsafe { w.bits(reload as u32) });
+    while iwdg.sr.read().bits() & IWDG_SR_UPDATING != 0 {}
+    feed();
+
+    info!(
+        "Watchdog started, resets after {} ms without a feed",
+        timeout_ms
+    );
+}
+
+/// Put off the reset for another timeout
+pub fn feed() {
+    iwdg().kr.write(|w| unsafe { w.bits(IWDG_KR_FEED) });
+}
+
+/// Wait `ms`, feeding the watchdog along the way, for waits that can be
+/// longer than its timeout
+pub fn delay_ms(delay: &mut Delay, ms: u32) {
+    let mut left = ms;
+    while left > 0 {
+        let step = left.min(FEED_INTERVAL_MS);
+        delay.delay_ms(step);
+        feed();
+        left -= step;
+    }
+}
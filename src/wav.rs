@@ -0,0 +1,155 @@
+//! Headers for 16-bit mono PCM WAV files, the format captures are saved to
+//! the SD card in.
+//!
+//! A WAV file is a RIFF chunk holding a `fmt ` chunk that describes the
+//! samples and a `data` chunk of the samples themselves, little-endian.
+//! Everything's sized up front, so the sample count must be known before
+//! the header is written.
+//!
+//! Captures are numbered `CAP0001.WAV` to `CAP9999.WAV`, 8.3 names so any
+//! FAT driver takes them.
+
+/// Length of the header `header` produces, the data follows directly
+pub const HEADER_LEN: usize = 44;
+
+const CHANNELS: u16 = 1;
+const BITS_PER_SAMPLE: u16 = 16;
+const BYTES_PER_SAMPLE: u32 = (BITS_PER_SAMPLE / 8) as u32;
+/// `WAVE_FORMAT_PCM`
+const FORMAT_PCM: u16 = 1;
+
+/// Header for `samples` 16-bit mono samples taken at `sample_rate_hz`
+pub fn header(sample_rate_hz: u32, samples: u32) -> [u8; HEADER_LEN] {
+    let data_len = samples * BYTES_PER_SAMPLE;
+    let byte_rate = sample_rate_hz * CHANNELS as u32 * BYTES_PER_SAMPLE;
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+
+    let mut header = [0; HEADER_LEN];
+    let mut at = 0;
+    let mut put = |bytes: &[u8]| {
+        header[at..at + bytes.len()].copy_from_slice(bytes);
+        at += bytes.len();
+    };
+
+    put(b"RIFF");
+    // The RIFF size counts everything after itself
+    put(&(HEADER_LEN as u32 - 8 + data_len).to_le_bytes());
+    put(b"WAVE");
+
+    put(b"fmt ");
+    put(&16u32.to_le_bytes());
+    put(&FORMAT_PCM.to_le_bytes());
+    put(&CHANNELS.to_le_bytes());
+    put(&sample_rate_hz.to_le_bytes());
+    put(&byte_rate.to_le_bytes());
+    put(&block_align.to_le_bytes());
+    put(&BITS_PER_SAMPLE.to_le_bytes());
+
+    put(b"data");
+    put(&data_len.to_le_bytes());
+
+    header
+}
+
+/// Highest capture number a file name has room for
+pub const MAX_FILE_INDEX: u16 = 9999;
+
+/// File name for capture `index`, which must be from 1 to `MAX_FILE_INDEX`
+pub fn file_name(index: u16) -> [u8; 11] {
+    assert!(
+        (1..=MAX_FILE_INDEX).contains(&index),
+        "capture {index} has no file name"
+    );
+    let mut name = *b"CAP0000.WAV";
+    let mut rest = index;
+    for digit in name[3..7].iter_mut().rev() {
+        *digit = b'0' + (rest % 10) as u8;
+        rest /= 10;
+    }
+    name
+}
+
+/// Capture number of a file `file_name` could have made, matching the way
+/// FAT stores short names: any case, and the extension split off
+pub fn file_index(base: &[u8], extension: &[u8]) -> Option<u16> {
+    let digits = match base {
+        [c, a, p, digits @ ..] if [c, a, p].map(u8::to_ascii_uppercase) == *b"CAP" => digits,
+        _ => return None,
+    };
+    if digits.len() != 4 || !extension.eq_ignore_ascii_case(b"WAV") {
+        return None;
+    }
+
+    let index = digits.iter().try_fold(0u16, |acc, &d| {
+        d.is_ascii_digit().then(|| acc * 10 + (d - b'0') as u16)
+    })?;
+    (index > 0).then_some(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    #[test]
+    fn canonical_layout() {
+        let h = header(48_000, 1024);
+
+        assert_eq!(&h[0..4], b"RIFF");
+        assert_eq!(u32_at(&h, 4), 36 + 2048);
+        assert_eq!(&h[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&h, 16), 16);
+        assert_eq!(u16_at(&h, 20), 1);
+        assert_eq!(u16_at(&h, 22), 1);
+        assert_eq!(u32_at(&h, 24), 48_000);
+        assert_eq!(u32_at(&h, 28), 96_000);
+        assert_eq!(u16_at(&h, 32), 2);
+        assert_eq!(u16_at(&h, 34), 16);
+        assert_eq!(&h[36..40], b"data");
+        assert_eq!(u32_at(&h, 40), 2048);
+    }
+
+    #[test]
+    fn file_names_round_trip() {
+        assert_eq!(&file_name(1), b"CAP0001.WAV");
+        assert_eq!(&file_name(MAX_FILE_INDEX), b"CAP9999.WAV");
+
+        for index in [1, 42, 700, MAX_FILE_INDEX] {
+            let name = file_name(index);
+            assert_eq!(file_index(&name[..7], &name[8..]), Some(index));
+        }
+    }
+
+    #[test]
+    fn other_files_have_no_index() {
+        assert_eq!(file_index(b"cap0012", b"wav"), Some(12));
+        assert_eq!(file_index(b"CAP0000", b"WAV"), None);
+        assert_eq!(file_index(b"CAP001", b"WAV"), None);
+        assert_eq!(file_index(b"CAP00012", b"WAV"), None);
+        assert_eq!(file_index(b"CAP00A1", b"WAV"), None);
+        assert_eq!(file_index(b"CAP0001", b"TXT"), None);
+        assert_eq!(file_index(b"LOG0001", b"WAV"), None);
+        assert_eq!(file_index(b"", b""), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_zero_has_no_name() {
+        file_name(0);
+    }
+
+    #[test]
+    fn empty_file() {
+        let h = header(149_104, 0);
+        assert_eq!(u32_at(&h, 4), 36);
+        assert_eq!(u32_at(&h, 24), 149_104);
+        assert_eq!(u32_at(&h, 40), 0);
+    }
+}